@@ -1,3 +1,9 @@
 pub const ROOM_WIDTH: u8 = 50;
 pub const ROOM_HEIGHT: u8 = 50;
 pub const ROOM_BUILD_BORDER: u8 = 2;
+pub const TOWER_DAMAGE_MAX: u32 = 600;
+pub const TOWER_DAMAGE_MIN: u32 = 150;
+pub const TOWER_DAMAGE_OPTIMAL_RANGE: u32 = 5;
+pub const TOWER_DAMAGE_FALLOFF_RANGE: u32 = 20;
+pub const PLANNABLE_MIN_WALL_DISTANCE: u32 = 4;
+pub const PLANNABLE_MIN_BUILDABLE_TILES: usize = 150;
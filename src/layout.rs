@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use super::constants::*;
+use super::location::*;
 use super::planner::*;
 use super::utility::*;
 use super::*;
@@ -24,6 +25,16 @@ fn distance_to_storage_score_linear(
     }
 }
 
+// The inverse of `distance_to_storage_score_linear`, favouring positions furthest from storage.
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+fn distance_from_storage_score(
+    position: PlanLocation,
+    context: &mut NodeContext,
+    state: &PlannerState,
+) -> Option<f32> {
+    distance_to_storage_score_linear(position, context, state).map(|score| 1.0 - score)
+}
+
 #[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
 fn distance_to_storage_score_pathfind(
     position: PlanLocation,
@@ -44,6 +55,101 @@ fn distance_to_storage_score_pathfind(
     }
 }
 
+// Share of the score that depends on reusing existing roads rather than adding new ones.
+const ROAD_REUSE_WEIGHT: f32 = 0.25;
+
+fn road_reuse_fraction(
+    position: PlanLocation,
+    road_offsets: &[(i8, i8)],
+    state: &PlannerState,
+) -> f32 {
+    let reused_roads = road_offsets
+        .iter()
+        .filter_map(|offset| (position + offset).as_location())
+        .filter(|location| {
+            state
+                .get(location)
+                .iter()
+                .flat_map(|v| v.iter())
+                .any(|item| item.structure_type() == StructureType::Road)
+        })
+        .count();
+
+    reused_roads as f32 / road_offsets.len() as f32
+}
+
+// Share of the score that depends on the approach tiles being plain rather than swamp, so
+// fillers standing next to the structure don't crawl.
+const PLAIN_APPROACH_WEIGHT: f32 = 0.1;
+
+fn plain_approach_fraction(
+    position: PlanLocation,
+    approach_offsets: &[(i8, i8)],
+    context: &mut NodeContext,
+) -> f32 {
+    let terrain = context.terrain();
+
+    let (plain, walkable) = approach_offsets
+        .iter()
+        .filter_map(|offset| (position + offset).as_location())
+        .map(|location| terrain.get(&location))
+        .filter(|terrain_mask| !terrain_mask.contains(TerrainFlags::WALL))
+        .fold((0, 0), |(plain, walkable), terrain_mask| {
+            if terrain_mask.contains(TerrainFlags::SWAMP) {
+                (plain, walkable + 1)
+            } else {
+                (plain + 1, walkable + 1)
+            }
+        });
+
+    if walkable > 0 {
+        plain as f32 / walkable as f32
+    } else {
+        0.0
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+fn extension_score(
+    position: PlanLocation,
+    context: &mut NodeContext,
+    state: &PlannerState,
+) -> Option<f32> {
+    let distance_score = distance_to_storage_score_pathfind(position, context, state)?;
+    let road_reuse = road_reuse_fraction(position, ONE_OFFSET_CROSS, state);
+    let plain_approach = plain_approach_fraction(position, ONE_OFFSET_CROSS, context);
+
+    Some(
+        distance_score
+            * (1.0 - ROAD_REUSE_WEIGHT + ROAD_REUSE_WEIGHT * road_reuse)
+            * (1.0 - PLAIN_APPROACH_WEIGHT + PLAIN_APPROACH_WEIGHT * plain_approach),
+    )
+}
+
+// The road ring around an extension cross, which fillers stand on to refill it.
+const EXTENSION_CROSS_APPROACH: &[(i8, i8)] = &[
+    (0, -2),
+    (-1, -1),
+    (-2, 0),
+    (-1, 1),
+    (0, 2),
+    (1, 1),
+    (2, 0),
+    (1, -1),
+];
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+fn extension_cross_score(
+    position: PlanLocation,
+    context: &mut NodeContext,
+    state: &PlannerState,
+) -> Option<f32> {
+    let distance_score = distance_to_storage_score_pathfind(position, context, state)?;
+    let plain_approach = plain_approach_fraction(position, EXTENSION_CROSS_APPROACH, context);
+
+    Some(distance_score * (1.0 - PLAIN_APPROACH_WEIGHT + PLAIN_APPROACH_WEIGHT * plain_approach))
+}
+
 #[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
 fn distance_to_storage_score_flood_fill(
     position: PlanLocation,
@@ -71,33 +177,195 @@ fn distance_to_storage_score_flood_fill(
     }
 }
 
+// Wall distances at or beyond this depth score as fully defensible.
+const ANCHOR_DEFENSIBILITY_DEPTH: u32 = 10;
+
+fn anchor_defensibility_score(position: PlanLocation, context: &mut NodeContext) -> f32 {
+    if !position.in_room_bounds() {
+        return 0.0;
+    }
+
+    context
+        .wall_distance()
+        .get(position.x() as usize, position.y() as usize)
+        .map(|distance| distance.min(ANCHOR_DEFENSIBILITY_DEPTH) as f32)
+        .map(|distance| distance / ANCHOR_DEFENSIBILITY_DEPTH as f32)
+        .unwrap_or(0.0)
+}
+
+fn weight_anchor_defensibility(
+    distance_score: f32,
+    position: PlanLocation,
+    context: &mut NodeContext,
+) -> f32 {
+    let weight = context
+        .layout_options()
+        .anchor_defensibility_weight
+        .clamp(0.0, 1.0);
+
+    if weight <= 0.0 {
+        return distance_score;
+    }
+
+    let defensibility_score = anchor_defensibility_score(position, context);
+
+    distance_score * (1.0 - weight) + defensibility_score * weight
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+fn anchor_distance_score_linear(
+    position: PlanLocation,
+    context: &mut NodeContext,
+    _state: &PlannerState,
+) -> Option<f32> {
+    let mut targets = context.sources().to_vec();
+    targets.extend(context.controllers().iter());
+
+    let distance_score = if targets.is_empty() {
+        1.0
+    } else {
+        let max_distance = ROOM_WIDTH.max(ROOM_HEIGHT) as f32;
+
+        let total_score: f32 = targets
+            .iter()
+            .map(|target| 1.0 - (position.distance_to(*target) as f32 / max_distance))
+            .sum();
+
+        total_score / targets.len() as f32
+    };
+
+    Some(weight_anchor_defensibility(
+        distance_score,
+        position,
+        context,
+    ))
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+fn anchor_distance_score_flood_fill(
+    position: PlanLocation,
+    context: &mut NodeContext,
+    _state: &PlannerState,
+) -> Option<f32> {
+    if !position.in_room_bounds() {
+        return None;
+    }
+
+    let mut scores = Vec::new();
+
+    // Targets that can't be walked to from the anchor score as if they were as far away as
+    // possible, rather than ruling the anchor out.
+    let distance_score = |(data, max_distance): &(RoomDataArray<Option<u32>>, u32)| {
+        data.get(position.x() as usize, position.y() as usize)
+            .map(|distance| 1.0 - (distance as f32 / (*max_distance).max(1) as f32))
+            .unwrap_or(0.0)
+    };
+
+    for distances in context.source_distances() {
+        scores.push(distance_score(distances));
+    }
+
+    for distances in context.controller_distances() {
+        scores.push(distance_score(distances));
+    }
+
+    let distance_score = if !scores.is_empty() {
+        scores.iter().sum::<f32>() / scores.len() as f32
+    } else {
+        1.0
+    };
+
+    Some(weight_anchor_defensibility(
+        distance_score,
+        position,
+        context,
+    ))
+}
+
+fn anchor_distance_score(
+    position: PlanLocation,
+    context: &mut NodeContext,
+    state: &PlannerState,
+) -> Option<f32> {
+    if context.layout_options().pathfinding_anchor_distance {
+        anchor_distance_score_flood_fill(position, context, state)
+    } else {
+        anchor_distance_score_linear(position, context, state)
+    }
+}
+
+/// Average share of the maximum tower damage the placed towers deal across the placements'
+/// non-road tiles, so critical structures favor the spots towers defend best. Neutral when no
+/// towers have been placed yet.
+fn tower_coverage_score(
+    position: PlanLocation,
+    placements: &[PlanPlacement],
+    state: &PlannerState,
+) -> Option<f32> {
+    let towers = state.get_locations(StructureType::Tower);
+
+    let tiles: Vec<_> = placements
+        .iter()
+        .filter(|placement| placement.structure_type() != StructureType::Road)
+        .map(|placement| position + placement.offset())
+        .collect();
+
+    if towers.is_empty() || tiles.is_empty() {
+        return Some(1.0);
+    }
+
+    let max_damage = (towers.len() as u32 * TOWER_DAMAGE_MAX) as f32;
+
+    let total: f32 = tiles
+        .iter()
+        .map(|tile| {
+            let damage: u32 = towers
+                .iter()
+                .map(|tower| tower_damage_at_range(tile.distance_to(tower.into()) as u32))
+                .sum();
+
+            damage as f32 / max_damage
+        })
+        .sum();
+
+    Some(total / tiles.len() as f32)
+}
+
+const LAB_PLACEMENTS: &[PlanPlacement] = &[
+    placement(StructureType::Lab, 1, 2),
+    placement(StructureType::Lab, 2, 1),
+    placement(StructureType::Lab, 0, 1),
+    placement(StructureType::Lab, 0, 2),
+    placement(StructureType::Lab, 1, 3),
+    placement(StructureType::Lab, 2, 3),
+    placement(StructureType::Lab, 1, 0),
+    placement(StructureType::Lab, 2, 0),
+    placement(StructureType::Lab, 3, 1),
+    placement(StructureType::Lab, 3, 2),
+    placement(StructureType::Road, 0, 0),
+    placement(StructureType::Road, 1, 1),
+    placement(StructureType::Road, 2, 2),
+    placement(StructureType::Road, 3, 3),
+];
+
+// The lab stamp reaches 3 tiles from its anchor - anchors closer to a wall than this rarely fit,
+// so are skipped without trying the placement.
+const LAB_WALL_DISTANCE: u32 = 3;
+
 const LABS: &FixedPlanNode = &FixedPlanNode {
     id: uuid::Uuid::from_u128(0xd2d0_407f_9f30_4f98_9f40_8d1d_4c05_5981u128),
     placement_phase: PlacementPhase::Normal,
     must_place: false,
-    placements: &[
-        placement(StructureType::Lab, 1, 2),
-        placement(StructureType::Lab, 2, 1),
-        placement(StructureType::Lab, 0, 1),
-        placement(StructureType::Lab, 0, 2),
-        placement(StructureType::Lab, 1, 3),
-        placement(StructureType::Lab, 2, 3),
-        placement(StructureType::Lab, 1, 0),
-        placement(StructureType::Lab, 2, 0),
-        placement(StructureType::Lab, 3, 1),
-        placement(StructureType::Lab, 3, 2),
-        placement(StructureType::Road, 0, 0),
-        placement(StructureType::Road, 1, 1),
-        placement(StructureType::Road, 2, 2),
-        placement(StructureType::Road, 3, 3),
-    ],
+    placements: LAB_PLACEMENTS,
     child: PlanNodeStorage::Empty,
-    desires_placement: |_, state| {
-        state.get_count(StructureType::Lab) == 0 && state.get_count(StructureType::Storage) > 0
+    desires_placement: |context, state| {
+        context.rcl_limits().get_max_count(StructureType::Lab) > 0
+            && state.get_count(StructureType::Lab) == 0
+            && state.get_count(StructureType::Storage) > 0
     },
     desires_location: |_, _, _| true,
     maximum_scorer: |_, _, _| Some(1.0),
-    scorer: |_, _, _| Some(1.0),
+    scorer: |position, _, state| tower_coverage_score(position, LAB_PLACEMENTS, state),
 };
 
 const EXTENSION_CROSS: &FixedPlanNode = &FixedPlanNode {
@@ -120,13 +388,14 @@ const EXTENSION_CROSS: &FixedPlanNode = &FixedPlanNode {
         placement(StructureType::Road, 1, -1),
     ],
     child: PlanNodeStorage::Empty,
-    desires_placement: |_, state| {
-        state.get_count(StructureType::Extension) <= 55
+    desires_placement: |context, state| {
+        state.get_count(StructureType::Extension) + 5
+            <= context.rcl_limits().get_max_count(StructureType::Extension)
             && state.get_count(StructureType::Storage) > 0
     },
     desires_location: |_, _, _| true,
     maximum_scorer: distance_to_storage_score_linear,
-    scorer: distance_to_storage_score_pathfind,
+    scorer: extension_cross_score,
 };
 
 const EXTENSION: &FixedPlanNode = &FixedPlanNode {
@@ -141,13 +410,14 @@ const EXTENSION: &FixedPlanNode = &FixedPlanNode {
         placement(StructureType::Road, 0, -1).optional(),
     ],
     child: PlanNodeStorage::Empty,
-    desires_placement: |_, state| {
-        state.get_count(StructureType::Extension) < 60
+    desires_placement: |context, state| {
+        state.get_count(StructureType::Extension)
+            < context.rcl_limits().get_max_count(StructureType::Extension)
             && state.get_count(StructureType::Storage) > 0
     },
     desires_location: |_, _, _| true,
     maximum_scorer: distance_to_storage_score_linear,
-    scorer: distance_to_storage_score_pathfind,
+    scorer: extension_score,
 };
 
 const UTILITY_CROSS: &FixedPlanNode = &FixedPlanNode {
@@ -170,9 +440,10 @@ const UTILITY_CROSS: &FixedPlanNode = &FixedPlanNode {
         placement(StructureType::Road, 1, -1),
     ],
     child: PlanNodeStorage::Empty,
+    // A secondary core may already hold the second spawn - the cross then only adds the third.
     desires_placement: |_, state| {
         state.get_count(StructureType::Observer) == 0
-            && state.get_count(StructureType::Spawn) <= 1
+            && state.get_count(StructureType::Spawn) <= 2
             && state.get_count(StructureType::Factory) == 0
             && state.get_count(StructureType::PowerSpawn) == 0
     },
@@ -181,25 +452,73 @@ const UTILITY_CROSS: &FixedPlanNode = &FixedPlanNode {
     scorer: distance_to_storage_score_pathfind,
 };
 
+// A spawn, extensions and a link away from the hub, filled from the road ring around it.
+const SECONDARY_CORE: &FixedPlanNode = &FixedPlanNode {
+    id: uuid::Uuid::from_u128(0x9c3e_52a7_61d4_4f0b_a8e6_27b1_d05f_c913u128),
+    placement_phase: PlacementPhase::Normal,
+    must_place: false,
+    placements: &[
+        placement(StructureType::Link, 0, 0),
+        placement(StructureType::Spawn, 0, -1),
+        placement(StructureType::Extension, 1, 0),
+        placement(StructureType::Extension, 0, 1),
+        placement(StructureType::Extension, -1, 0),
+        placement(StructureType::Road, 0, -2),
+        placement(StructureType::Road, -1, -1),
+        placement(StructureType::Road, -2, 0),
+        placement(StructureType::Road, -1, 1),
+        placement(StructureType::Road, 0, 2),
+        placement(StructureType::Road, 1, 1),
+        placement(StructureType::Road, 2, 0),
+        placement(StructureType::Road, 1, -1),
+    ],
+    child: PlanNodeStorage::Empty,
+    desires_placement: |context, state| {
+        let rcl_limits = context.rcl_limits();
+
+        context.layout_options().secondary_core
+            && state.get_count(StructureType::Spawn) == 1
+            && rcl_limits.get_max_count(StructureType::Spawn) > 1
+            && state.get_count(StructureType::Link) < rcl_limits.get_max_count(StructureType::Link)
+            && state.get_count(StructureType::Storage) > 0
+    },
+    desires_location: |_, _, _| true,
+    // As far from the hub as the flood fill reaches, so one attack can't reach both.
+    maximum_scorer: distance_from_storage_score,
+    scorer: distance_from_storage_score,
+};
+
 const CONTROLLER_LINK: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPlanNode {
     id: uuid::Uuid::from_u128(0xc551_f09c_70d8_4148_a6a0_23af_6d95_e1bcu128),
     placement_phase: PlacementPhase::Normal,
     must_place: true,
     placements: &[placement(StructureType::Link, 0, 0)],
     child: PlanNodeStorage::Empty,
-    desires_placement: |_context, state| state.get_count(StructureType::Link) < 6,
-    desires_location: |location, _context, state| {
+    desires_placement: |context, state| {
+        state.get_count(StructureType::Link)
+            < context.rcl_limits().get_max_count(StructureType::Link)
+    },
+    desires_location: |location, context, state| {
+        let controller_locations = context.controllers().to_vec();
         let link_locations = state.get_locations(StructureType::Link);
         let container_locations = state.get_locations(StructureType::Container);
 
-        container_locations
-            .iter()
-            .filter(|&container_location| location.distance_to(container_location.into()) <= 1)
-            .any(|container_location| {
-                !link_locations
+        // The link must sit next to the upgrade container and within upgrade range of the
+        // controller, so an upgrader standing on the container can reach both.
+        controller_locations.iter().any(|controller_location| {
+            controller_location.distance_to(location) <= 3
+                && container_locations
                     .iter()
-                    .any(|link_location| link_location.distance_to(*container_location) <= 1)
-            })
+                    .filter(|&container_location| {
+                        location.distance_to(container_location.into()) <= 1
+                            && controller_location.distance_to(container_location.into()) <= 2
+                    })
+                    .any(|container_location| {
+                        !link_locations.iter().any(|link_location| {
+                            link_location.distance_to(*container_location) <= 1
+                        })
+                    })
+        })
     },
     maximum_scorer: |_, _, _| Some(1.0),
     scorer: |_, _, _| Some(1.0),
@@ -248,46 +567,216 @@ const CONTROLLERS: PlanNodeStorage = PlanNodeStorage::GlobalExpansion(&FixedLoca
     }),
 });
 
+// Links are limited, so sources furthest from storage are given links first as they benefit the
+// most from not being hauled.
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+fn source_link_priority_score(
+    position: PlanLocation,
+    context: &mut NodeContext,
+    state: &PlannerState,
+) -> Option<f32> {
+    distance_from_storage_score(position, context, state)
+}
+
 const SOURCE_LINK: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPlanNode {
     id: uuid::Uuid::from_u128(0x319d_c67c_8230_4905_afc3_e9c8_196c_2bd3u128),
     placement_phase: PlacementPhase::Normal,
     must_place: true,
     placements: &[placement(StructureType::Link, 0, 0)],
     child: PlanNodeStorage::Empty,
-    desires_placement: |_context, state| state.get_count(StructureType::Link) < 6,
-    desires_location: |location, _context, state| {
+    desires_placement: |context, state| {
+        state.get_count(StructureType::Link)
+            < context.rcl_limits().get_max_count(StructureType::Link)
+    },
+    desires_location: |location, context, state| {
+        let source_locations: Vec<_> = context
+            .sources()
+            .to_vec()
+            .into_iter()
+            .filter(|source_location| context.source_infra_policy(*source_location).places_link())
+            .collect();
+        let link_min_distance = context.source_link_min_distance();
         let link_locations = state.get_locations(StructureType::Link);
         let container_locations = state.get_locations(StructureType::Container);
 
         container_locations
             .iter()
             .filter(|&container_location| location.distance_to(container_location.into()) <= 1)
+            .filter(|&container_location| {
+                source_locations.iter().any(|source_location| {
+                    source_location.distance_to(container_location.into()) <= 1
+                })
+            })
+            .filter(|&container_location| {
+                link_min_distance == 0
+                    || state.with_structure_distances(
+                        StructureType::Storage,
+                        context.terrain(),
+                        |storage_distances| {
+                            storage_distances
+                                .and_then(|(storage_distances, _)| {
+                                    *storage_distances.get(
+                                        container_location.x() as usize,
+                                        container_location.y() as usize,
+                                    )
+                                })
+                                .map(|d| d > link_min_distance)
+                                .unwrap_or(false)
+                        },
+                    )
+            })
             .any(|container_location| {
                 !link_locations
                     .iter()
                     .any(|link_location| link_location.distance_to(*container_location) <= 1)
             })
     },
-    maximum_scorer: |_, _, _| Some(1.0),
-    scorer: |_, _, _| Some(1.0),
+    maximum_scorer: source_link_priority_score,
+    scorer: source_link_priority_score,
+});
+
+fn is_source_container(
+    location: Location,
+    context: &mut NodeContext,
+    _state: &PlannerState,
+) -> bool {
+    context
+        .sources()
+        .iter()
+        .any(|source_location| source_location.distance_to(location.into()) <= 1)
+}
+
+// Sources given a link without a container.
+fn direct_link_sources(context: &mut NodeContext) -> Vec<PlanLocation> {
+    context
+        .sources()
+        .to_vec()
+        .into_iter()
+        .filter(|source_location| {
+            let policy = context.source_infra_policy(*source_location);
+
+            policy.places_link() && !policy.places_container()
+        })
+        .collect()
+}
+
+fn is_direct_source_link(
+    location: Location,
+    context: &mut NodeContext,
+    _state: &PlannerState,
+) -> bool {
+    direct_link_sources(context)
+        .iter()
+        .any(|source_location| source_location.distance_to(location.into()) <= 1)
+}
+
+// A road next to each source container for haulers to stand on, on whichever side is nearest
+// storage. Sources with only a link instead get a road next to both the source and the link for
+// the harvester to stand on.
+const SOURCE_ROAD: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPlanNode {
+    id: uuid::Uuid::from_u128(0x8a41_f2c6_0d7e_4b93_a15c_6e29_d4b0_7f38u128),
+    placement_phase: PlacementPhase::Normal,
+    must_place: false,
+    placements: &[placement(StructureType::Road, 0, 0)],
+    child: PlanNodeStorage::Empty,
+    desires_placement: |_, _| true,
+    desires_location: |location, context, state| {
+        if context.sources().contains(&location) {
+            return false;
+        }
+
+        let road_locations = state.get_locations(StructureType::Road);
+
+        let needs_container_road = state
+            .get_locations(StructureType::Container)
+            .into_iter()
+            .filter(|container_location| location.distance_to(container_location.into()) <= 1)
+            .filter(|container_location| is_source_container(*container_location, context, state))
+            .any(|container_location| {
+                !road_locations
+                    .iter()
+                    .any(|road_location| road_location.distance_to(container_location) <= 1)
+            });
+
+        if needs_container_road {
+            return true;
+        }
+
+        let source_locations = direct_link_sources(context);
+
+        state
+            .get_locations(StructureType::Link)
+            .into_iter()
+            .filter(|link_location| location.distance_to(link_location.into()) <= 1)
+            .any(|link_location| {
+                source_locations
+                    .iter()
+                    .filter(|source_location| {
+                        source_location.distance_to(link_location.into()) <= 1
+                            && source_location.distance_to(location) <= 1
+                    })
+                    .any(|source_location| {
+                        !road_locations.iter().any(|road_location| {
+                            road_location.distance_to(link_location) <= 1
+                                && source_location.distance_to(road_location.into()) <= 1
+                        })
+                    })
+            })
+    },
+    maximum_scorer: distance_to_storage_score_linear,
+    scorer: distance_to_storage_score_linear,
 });
 
+const SOURCE_CONTAINER_ROADS: PlanNodeStorage =
+    PlanNodeStorage::GlobalExpansion(&StructureLocationsPlanNode {
+        structure_type: StructureType::Container,
+        filter: is_source_container,
+        child: PlanNodeStorage::LocationExpansion(&OffsetPlanNode {
+            offsets: ONE_OFFSET_SQUARE,
+            child: SOURCE_ROAD,
+        }),
+    });
+
+const SOURCE_LINK_ROADS: PlanNodeStorage =
+    PlanNodeStorage::GlobalExpansion(&StructureLocationsPlanNode {
+        structure_type: StructureType::Link,
+        filter: is_direct_source_link,
+        child: PlanNodeStorage::LocationExpansion(&OffsetPlanNode {
+            offsets: ONE_OFFSET_SQUARE,
+            child: SOURCE_ROAD,
+        }),
+    });
+
 const SOURCE_CONTAINER: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPlanNode {
     id: uuid::Uuid::from_u128(0xe2ba_7996_11a2_47d8_bb3d_57cc_2ade_bbf2u128),
     placement_phase: PlacementPhase::Normal,
     must_place: true,
     placements: &[placement(StructureType::Container, 0, 0)],
-    child: PlanNodeStorage::LocationExpansion(&NearestToStructureExpansionPlanNode {
-        structure_type: StructureType::Storage,
-        path_distance: 1,
-        child: SOURCE_LINK,
-        desires_placement: |_, _| true,
-        desires_location: |_, _, _| true,
-        scorer: |_, _, _| Some(1.0),
+    child: PlanNodeStorage::LocationExpansion(&MultiPlacementExpansionNode {
+        children: &[
+            PlanNodeStorage::LocationExpansion(&NearestToStructureExpansionPlanNode {
+                structure_type: StructureType::Storage,
+                path_distance: 1,
+                child: SOURCE_LINK,
+                desires_placement: |_, _| true,
+                desires_location: |_, _, _| true,
+                scorer: |_, _, _| Some(1.0),
+            }),
+            SOURCE_CONTAINER_ROADS,
+        ],
     }),
     desires_placement: |_context, state| state.get_count(StructureType::Container) < 5,
     desires_location: |location, context, state| {
-        let mut source_locations = context.sources().to_vec();
+        let mut source_locations: Vec<_> = context
+            .sources()
+            .to_vec()
+            .into_iter()
+            .filter(|source_location| {
+                context
+                    .source_infra_policy(*source_location)
+                    .places_container()
+            })
+            .collect();
         let mut container_locations = state.get_locations(StructureType::Container);
 
         let mut matched_sources = Vec::new();
@@ -313,12 +802,41 @@ const SOURCE_CONTAINER: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&Fi
     scorer: |_, _, _| Some(1.0),
 });
 
+const SOURCE_DIRECT_LINK: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPlanNode {
+    id: uuid::Uuid::from_u128(0x5b0e_2d4c_9f3a_4e61_8c27_d1a4_6f08_b3e9u128),
+    placement_phase: PlacementPhase::Normal,
+    must_place: true,
+    placements: &[placement(StructureType::Link, 0, 0)],
+    child: SOURCE_LINK_ROADS,
+    desires_placement: |context, state| {
+        state.get_count(StructureType::Link)
+            < context.rcl_limits().get_max_count(StructureType::Link)
+    },
+    desires_location: |location, context, state| {
+        let source_locations = direct_link_sources(context);
+        let link_locations = state.get_locations(StructureType::Link);
+
+        source_locations
+            .iter()
+            .filter(|&source_location| location.distance_to(*source_location) <= 1)
+            .any(|source_location| {
+                !link_locations
+                    .iter()
+                    .any(|link_location| source_location.distance_to(link_location.into()) <= 1)
+            })
+    },
+    maximum_scorer: |_, _, _| Some(1.0),
+    scorer: |_, _, _| Some(1.0),
+});
+
 const SOURCES: PlanNodeStorage = PlanNodeStorage::GlobalExpansion(&FixedLocationPlanNode {
     locations: |context| context.sources().to_vec(),
     child: PlanNodeStorage::LocationExpansion(&NearestToStructureExpansionPlanNode {
         structure_type: StructureType::Storage,
         path_distance: 1,
-        child: SOURCE_CONTAINER,
+        child: PlanNodeStorage::LocationExpansion(&MultiPlacementExpansionNode {
+            children: &[SOURCE_CONTAINER, SOURCE_DIRECT_LINK],
+        }),
         desires_placement: |_, _| true,
         desires_location: |_, _, _| true,
         scorer: |_, _, _| Some(1.0),
@@ -387,13 +905,19 @@ const MINERALS_NODE: &FixedLocationPlanNode = &FixedLocationPlanNode {
 
 const MINERALS: PlanNodeStorage = PlanNodeStorage::GlobalExpansion(MINERALS_NODE);
 
+/// The controller level the defense layer queues its walls and ramparts at, unless overridden
+/// with `RclLimits::with_defense_rcl`.
+pub const DEFENSE_RCL: u8 = 4;
+
 const RAMPARTS_NODE: &MinCutWallsPlanNode = &MinCutWallsPlanNode {
     id: uuid::Uuid::from_u128(0xb47c_cf90_44eb_4e7d_8d13_4c4e_f27b_264du128),
     placement_phase: PlacementPhase::Post,
     must_place: false,
     desires_placement: |_, _| true,
     ready_for_placement: |context, state| has_mandatory_buildings(state, context),
-    rcl_override: Some(4),
+    rcl_override: Some(DEFENSE_RCL),
+    chokepoint_only: false,
+    bounds_margin: None,
 };
 
 const RAMPARTS: PlanNodeStorage = PlanNodeStorage::GlobalPlacement(RAMPARTS_NODE);
@@ -403,72 +927,43 @@ const POST_BUNKER_NODES: PlanNodeStorage =
         children: &[CONTROLLERS, SOURCES, MINERALS],
     });
 
-const BUNKER_CORE: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPlanNode {
-    id: uuid::Uuid::from_u128(0x1533_4930_d790_4a49_b1e0_1e30_acc4_eb46u128),
-    placement_phase: PlacementPhase::Normal,
-    must_place: false,
-    placements: &[
-        placement(StructureType::Spawn, -2, 0),
-        placement(StructureType::Storage, 0, -1),
-        placement(StructureType::Terminal, 1, 0),
-        placement(StructureType::Link, -1, 1),
-        placement(StructureType::Tower, -2, 1),
-        placement(StructureType::Tower, -1, 2),
-        placement(StructureType::Tower, -1, -2),
-        placement(StructureType::Tower, 0, -2),
-        placement(StructureType::Tower, 2, 0),
-        placement(StructureType::Tower, 2, 1),
-        placement(StructureType::Nuker, 1, -1),
-        placement(StructureType::Extension, -2, -1),
-        placement(StructureType::Extension, -3, 0),
-        placement(StructureType::Extension, -3, 1),
-        placement(StructureType::Extension, -4, 1),
-        placement(StructureType::Extension, -3, 2),
-        placement(StructureType::Extension, -2, 2),
-        placement(StructureType::Extension, -2, 3),
-        placement(StructureType::Extension, -1, 3),
-        placement(StructureType::Extension, -1, 4),
-        placement(StructureType::Extension, 0, 3),
-        placement(StructureType::Extension, 0, 2),
-        placement(StructureType::Extension, 1, 2),
-        placement(StructureType::Extension, 0, -3),
-        placement(StructureType::Extension, 1, -3),
-        placement(StructureType::Extension, 1, -2),
-        placement(StructureType::Extension, 2, -2),
-        placement(StructureType::Extension, 2, -1),
-        placement(StructureType::Extension, 3, -1),
-        placement(StructureType::Extension, 3, 0),
-        placement(StructureType::Road, -1, -1),
-        placement(StructureType::Road, -1, 0),
-        placement(StructureType::Road, 0, 0),
-        placement(StructureType::Road, 0, 1),
-        placement(StructureType::Road, 1, 1),
-        placement(StructureType::Road, -5, 1).optional(),
-        placement(StructureType::Road, -4, 0).optional(),
-        placement(StructureType::Road, -3, -1).optional(),
-        placement(StructureType::Road, -2, -2).optional(),
-        placement(StructureType::Road, -1, -3).optional(),
-        placement(StructureType::Road, 0, -4).optional(),
-        placement(StructureType::Road, 1, -4).optional(),
-        placement(StructureType::Road, 2, -3).optional(),
-        placement(StructureType::Road, 3, -2).optional(),
-        placement(StructureType::Road, -1, 5).optional(),
-        placement(StructureType::Road, 0, 4).optional(),
-        placement(StructureType::Road, 1, 3).optional(),
-        placement(StructureType::Road, 2, 2).optional(),
-        placement(StructureType::Road, 3, 1).optional(),
-        placement(StructureType::Road, 4, 0).optional(),
-        placement(StructureType::Road, 4, -1).optional(),
-        placement(StructureType::Road, -4, 2).optional(),
-        placement(StructureType::Road, -3, 3).optional(),
-        placement(StructureType::Road, -2, 4).optional(),
-    ],
-    child: PlanNodeStorage::LocationExpansion(&MultiPlacementExpansionNode {
+const BUNKER_CHILDREN: PlanNodeStorage =
+    PlanNodeStorage::LocationExpansion(&MultiPlacementExpansionNode {
         children: &[
             POST_BUNKER_NODES,
             PlanNodeStorage::LocationExpansion(&OffsetPlanNode {
                 offsets: &[(-2, -2), (2, 2)],
-                child: PlanNodeStorage::LocationPlacement(LABS),
+                child: PlanNodeStorage::LocationExpansion(&MinWallDistancePlanNode {
+                    wall_distance: LAB_WALL_DISTANCE,
+                    child: PlanNodeStorage::LocationPlacement(LABS),
+                }),
+            }),
+            // Placed with the other required nodes, ahead of the extension fill, on the same
+            // lattice as the extension crosses.
+            PlanNodeStorage::LocationPlacement(&FloodFillPlanNode {
+                id: uuid::Uuid::from_u128(0x2f81_c6d0_3b9a_47e5_9d12_e4a8_7c60_5b1fu128),
+                placement_phase: PlacementPhase::Normal,
+                must_place: true,
+                start_offsets: &[(-3, -3), (-1, -5), (-5, -1), (3, 3), (5, 1), (1, 5)],
+                expansion_offsets: &[
+                    (-4, 0),
+                    (-2, 2),
+                    (0, 4),
+                    (2, 2),
+                    (4, 0),
+                    (2, -2),
+                    (0, -4),
+                    (-2, -2),
+                ],
+                maximum_expansion: 5,
+                minimum_candidates: 20,
+                levels: &[FloodFillPlanNodeLevel {
+                    offsets: &[(0, 0)],
+                    node: SECONDARY_CORE,
+                }],
+                desires_placement: |context, _| context.layout_options().secondary_core,
+                scorer: |_, _, _| Some(0.5),
+                validator: |_, _| Ok(()),
             }),
             PlanNodeStorage::LocationPlacement(&FloodFillPlanNode {
                 id: uuid::Uuid::from_u128(0xeff2_1b89_0149_4bc9_b4f4_8138_5cd6_5232u128),
@@ -506,8 +1001,8 @@ const BUNKER_CORE: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPl
                 ],
                 desires_placement: |_, _| true,
                 scorer: |_, _, _| Some(0.5),
-                validator: |_, state| {
-                    if state.get_count(StructureType::Extension) == 60 {
+                validator: |context, state| {
+                    if state.get_count(StructureType::Extension) >= context.min_extension_count() {
                         Ok(())
                     } else {
                         Err(())
@@ -516,19 +1011,908 @@ const BUNKER_CORE: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPl
             }),
             RAMPARTS,
         ],
-    }),
+    });
+
+// The bunker core placements shared by each hub variant, with the variant's own placements
+// slotted in. RCLs are handed out in placement order, so the `spawn_side` placements come after the
+// first four extensions and before the towers, and the `rest` after everything else. Towers are
+// optional so capping them below six leaves their tiles empty.
+macro_rules! hub_core_placements {
+    (spawn_side: [$($spawn_side:expr),* $(,)?], rest: [$($rest:expr),* $(,)?] $(,)?) => {
+        &[
+            placement(StructureType::Spawn, -2, 0),
+            placement(StructureType::Storage, 0, -1),
+            placement(StructureType::Terminal, 1, 0),
+            placement(StructureType::Link, -1, 1),
+            placement(StructureType::Nuker, 1, -1),
+            placement(StructureType::Extension, -2, -1),
+            placement(StructureType::Extension, -3, 0),
+            placement(StructureType::Extension, -4, 1),
+            placement(StructureType::Extension, -3, 2),
+            $($spawn_side,)*
+            placement(StructureType::Tower, -1, 2).optional(),
+            placement(StructureType::Tower, -1, -2).optional(),
+            placement(StructureType::Tower, 0, -2).optional(),
+            placement(StructureType::Tower, 2, 0).optional(),
+            placement(StructureType::Tower, 2, 1).optional(),
+            placement(StructureType::Extension, -2, 2),
+            placement(StructureType::Extension, -2, 3),
+            placement(StructureType::Extension, -1, 3),
+            placement(StructureType::Extension, -1, 4),
+            placement(StructureType::Extension, 0, 3),
+            placement(StructureType::Extension, 1, 2),
+            placement(StructureType::Extension, 0, -3),
+            placement(StructureType::Extension, 1, -3),
+            placement(StructureType::Extension, 1, -2),
+            placement(StructureType::Extension, 2, -2),
+            placement(StructureType::Extension, 2, -1),
+            placement(StructureType::Extension, 3, -1),
+            placement(StructureType::Extension, 3, 0),
+            placement(StructureType::Road, -1, -1),
+            placement(StructureType::Road, -1, 0),
+            placement(StructureType::Road, 0, 0),
+            placement(StructureType::Road, 0, 1),
+            placement(StructureType::Road, 1, 1),
+            placement(StructureType::Road, -5, 1).optional(),
+            placement(StructureType::Road, -3, -1).optional(),
+            placement(StructureType::Road, -2, -2).optional(),
+            placement(StructureType::Road, -1, -3).optional(),
+            placement(StructureType::Road, 0, -4).optional(),
+            placement(StructureType::Road, 1, -4).optional(),
+            placement(StructureType::Road, 2, -3).optional(),
+            placement(StructureType::Road, 3, -2).optional(),
+            placement(StructureType::Road, -1, 5).optional(),
+            placement(StructureType::Road, 0, 4).optional(),
+            placement(StructureType::Road, 1, 3).optional(),
+            placement(StructureType::Road, 2, 2).optional(),
+            placement(StructureType::Road, 3, 1).optional(),
+            placement(StructureType::Road, 4, 0).optional(),
+            placement(StructureType::Road, 4, -1).optional(),
+            placement(StructureType::Road, -3, 3).optional(),
+            placement(StructureType::Road, -2, 4).optional(),
+            $($rest,)*
+        ]
+    };
+}
+
+const BUNKER_CORE: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPlanNode {
+    id: uuid::Uuid::from_u128(0x1533_4930_d790_4a49_b1e0_1e30_acc4_eb46u128),
+    placement_phase: PlacementPhase::Normal,
+    must_place: false,
+    placements: hub_core_placements!(
+        spawn_side: [
+            placement(StructureType::Tower, -2, 1).optional(),
+            placement(StructureType::Extension, -3, 1),
+        ],
+        rest: [
+            placement(StructureType::Extension, 0, 2),
+            placement(StructureType::Road, -4, 0).optional(),
+            placement(StructureType::Road, -4, 2).optional(),
+        ],
+    ),
+    child: BUNKER_CHILDREN,
     desires_placement: |_, state| state.get_count(StructureType::Spawn) == 0,
     desires_location: |_, _, _| true,
-    maximum_scorer: |_, _, _| Some(1.0),
-    scorer: |_, _, _| Some(1.0),
+    maximum_scorer: anchor_distance_score_linear,
+    scorer: anchor_distance_score,
+});
+
+// The bunker core with the spawn side rearranged into a fast filler - the spawn, a link and four
+// extensions surround the road at (-3, 1), where a filler creep can refill all of them. The roads
+// either side of it are required so the filler tile is never walled in.
+const FAST_FILLER_CORE: PlanNodeStorage = PlanNodeStorage::LocationPlacement(&FixedPlanNode {
+    id: uuid::Uuid::from_u128(0x7a4e_0c93_b5d1_4e28_9f67_31c8_d2a0_5e4bu128),
+    placement_phase: PlacementPhase::Normal,
+    must_place: false,
+    placements: hub_core_placements!(
+        spawn_side: [placement(StructureType::Link, -2, 1)],
+        rest: [
+            placement(StructureType::Tower, 0, 2).optional(),
+            placement(StructureType::Road, -3, 1),
+            placement(StructureType::Road, -4, 0),
+            placement(StructureType::Road, -4, 2),
+        ],
+    ),
+    child: BUNKER_CHILDREN,
+    desires_placement: |context, state| {
+        context.layout_options().fast_filler_core && state.get_count(StructureType::Spawn) == 0
+    },
+    desires_location: |_, _, _| true,
+    maximum_scorer: anchor_distance_score_linear,
+    scorer: anchor_distance_score,
 });
 
 const ROOT_BUNKER: PlanNodeStorage =
     PlanNodeStorage::LocationExpansion(&MultiPlacementExpansionNode {
-        children: &[BUNKER_CORE],
+        children: &[BUNKER_CORE, FAST_FILLER_CORE],
     });
 
 pub const ALL_ROOT_NODES: &[&dyn PlanGlobalExpansionNode] = &[&PlaceAwayFromWallsNode {
     wall_distance: 4,
     child: ROOT_BUNKER,
 }];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planner::tests::*;
+
+    // Spawns sitting directly above a link, as only the secondary core places them.
+    fn secondary_core_spawns(plan: &Plan) -> Vec<Location> {
+        plan.build_order()
+            .iter()
+            .filter(|step| step.structure_type == StructureType::Spawn)
+            .map(|step| step.location)
+            .filter(|spawn| {
+                plan.has_structure(
+                    Location::from_coords(spawn.x() as u32, spawn.y() as u32 + 1),
+                    StructureType::Link,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn secondary_core_adds_spawn_away_from_hub() {
+        let default_plan = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        assert!(secondary_core_spawns(&default_plan).is_empty());
+
+        let planner = test_planner(PlanningConfig {
+            layout_options: LayoutOptions {
+                secondary_core: true,
+                ..LayoutOptions::default()
+            },
+            ..PlanningConfig::default()
+        });
+
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        let storage = plan
+            .build_order()
+            .iter()
+            .find(|step| step.structure_type == StructureType::Storage)
+            .map(|step| step.location)
+            .unwrap();
+
+        let secondary_spawns = secondary_core_spawns(&plan);
+
+        assert_eq!(secondary_spawns.len(), 1);
+        assert!(secondary_spawns[0].distance_to(storage) > 5);
+        assert_eq!(count(&plan, StructureType::Spawn), 3);
+    }
+
+    fn anchor_scores(
+        room: &mut TestRoom,
+        pathfinding: bool,
+        anchors: &[PlanLocation],
+    ) -> Vec<Option<f32>> {
+        anchor_scores_with_options(
+            room,
+            LayoutOptions {
+                pathfinding_anchor_distance: pathfinding,
+                ..LayoutOptions::default()
+            },
+            anchors,
+        )
+    }
+
+    fn anchor_scores_with_options(
+        room: &mut TestRoom,
+        layout_options: LayoutOptions,
+        anchors: &[PlanLocation],
+    ) -> Vec<Option<f32>> {
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let state = PlannerState::new();
+
+        let mut context =
+            NodeContext::new(room, &rcl_limits, &source_infra).with_layout_options(layout_options);
+
+        anchors
+            .iter()
+            .map(|anchor| anchor_distance_score(*anchor, &mut context, &state))
+            .collect()
+    }
+
+    #[test]
+    fn pathfinding_anchor_distance_avoids_detours() {
+        // A wall between the source and the nearer anchor, open only at its top end.
+        let mut room = TestRoom::with_walls(|x, y| x == 15 && y >= 15);
+        room.controllers = Vec::new();
+        room.sources = vec![PlanLocation::new(10, 30)];
+
+        let behind_wall = PlanLocation::new(20, 30);
+        let detour_free = PlanLocation::new(20, 10);
+
+        let linear = anchor_scores(&mut room, false, &[behind_wall, detour_free]);
+
+        assert!(linear[0].unwrap() > linear[1].unwrap());
+
+        let pathfinding = anchor_scores(&mut room, true, &[behind_wall, detour_free]);
+
+        assert!(pathfinding[1].unwrap() > pathfinding[0].unwrap());
+    }
+
+    #[test]
+    fn pathfinding_anchor_distance_penalizes_unreachable_targets() {
+        // The second source is sealed off by walls.
+        let mut room = TestRoom::with_walls(|x, y| {
+            (x == 38 || x == 42 || y == 38 || y == 42)
+                && (38..=42).contains(&x)
+                && (38..=42).contains(&y)
+        });
+        room.controllers = Vec::new();
+        room.sources = vec![PlanLocation::new(20, 20), PlanLocation::new(40, 40)];
+
+        let scores = anchor_scores(&mut room, true, &[PlanLocation::new(20, 20)]);
+
+        let mut reachable = TestRoom::open();
+        reachable.controllers = Vec::new();
+        reachable.sources = room.sources.clone();
+
+        let reachable_scores = anchor_scores(&mut reachable, true, &[PlanLocation::new(20, 20)]);
+
+        assert!(scores[0].unwrap() < reachable_scores[0].unwrap());
+    }
+
+    // Roads surrounded by a spawn, a link and at least four extensions.
+    fn fast_filler_tiles<F>(roads: &[Location], has_structure: F) -> Vec<Location>
+    where
+        F: Fn(Location, StructureType) -> bool,
+    {
+        roads
+            .iter()
+            .filter(|road| {
+                let neighbours: Vec<_> = ONE_OFFSET_SQUARE
+                    .iter()
+                    .filter_map(|offset| (PlanLocation::from(*road) + offset).as_location())
+                    .collect();
+
+                let count = |structure_type| {
+                    neighbours
+                        .iter()
+                        .filter(|neighbour| has_structure(**neighbour, structure_type))
+                        .count()
+                };
+
+                count(StructureType::Spawn) >= 1
+                    && count(StructureType::Link) >= 1
+                    && count(StructureType::Extension) >= 4
+            })
+            .copied()
+            .collect()
+    }
+
+    fn root_candidates(layout_options: LayoutOptions) -> Vec<(PlanLocation, uuid::Uuid)> {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let state = PlannerState::new();
+
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra)
+            .with_layout_options(layout_options);
+
+        let mut gather_data = PlanGatherChildrenData::new();
+
+        ALL_ROOT_NODES[0].get_children(&mut context, &state, &mut gather_data);
+
+        gather_data
+            .collect()
+            .iter()
+            .filter_map(|child| match child {
+                PlanNodeChild::LocationPlacement(location, node) => Some((*location, *node.id())),
+                PlanNodeChild::GlobalPlacement(_) => None,
+            })
+            .collect()
+    }
+
+    fn root_candidate_ids(layout_options: LayoutOptions) -> Vec<uuid::Uuid> {
+        root_candidates(layout_options)
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect()
+    }
+
+    #[test]
+    fn root_node_names_are_listed_in_order() {
+        assert_eq!(
+            root_node_names(ALL_ROOT_NODES),
+            vec!["Place Away From Walls"]
+        );
+        assert_eq!(
+            root_node_names(&[ALL_ROOT_NODES[0], ALL_ROOT_NODES[0]]),
+            vec!["Place Away From Walls", "Place Away From Walls"]
+        );
+        assert!(root_node_names(&[]).is_empty());
+    }
+
+    #[test]
+    fn fast_filler_core_is_a_hub_candidate() {
+        let (bunker_id, fast_filler_id) = match (BUNKER_CORE, FAST_FILLER_CORE) {
+            (
+                PlanNodeStorage::LocationPlacement(bunker),
+                PlanNodeStorage::LocationPlacement(fast_filler),
+            ) => (*bunker.id(), *fast_filler.id()),
+            _ => unreachable!(),
+        };
+
+        let default_ids = root_candidate_ids(LayoutOptions::default());
+
+        assert!(default_ids.contains(&bunker_id));
+        assert!(!default_ids.contains(&fast_filler_id));
+
+        let ids = root_candidate_ids(LayoutOptions {
+            fast_filler_core: true,
+            ..LayoutOptions::default()
+        });
+
+        assert!(ids.contains(&bunker_id));
+        assert!(ids.contains(&fast_filler_id));
+    }
+
+    #[test]
+    fn max_root_candidates_keeps_every_hub_at_best_anchor() {
+        let (bunker_id, fast_filler_id) = match (BUNKER_CORE, FAST_FILLER_CORE) {
+            (
+                PlanNodeStorage::LocationPlacement(bunker),
+                PlanNodeStorage::LocationPlacement(fast_filler),
+            ) => (*bunker.id(), *fast_filler.id()),
+            _ => unreachable!(),
+        };
+
+        let planner = test_planner(PlanningConfig {
+            layout_options: LayoutOptions {
+                fast_filler_core: true,
+                ..LayoutOptions::default()
+            },
+            ..PlanningConfig::default()
+        })
+        .with_trace(true);
+
+        let mut room = TestRoom::open();
+
+        let mut running = match planner.seed(ALL_ROOT_NODES, &mut room).unwrap() {
+            PlanSeedResult::Complete(_) => panic!("Seeding should not finish the search"),
+            PlanSeedResult::Running(running) => running,
+        };
+
+        while let PlanEvaluationResult::Running() = planner
+            .evaluate(ALL_ROOT_NODES, &mut room, &mut running, || true)
+            .unwrap()
+        {}
+
+        // Both hubs are tried, each only at the best anchor.
+        let trace = running.trace().unwrap();
+
+        assert_eq!(trace.get(&bunker_id).map(|node| node.placed), Some(1));
+        assert_eq!(trace.get(&fast_filler_id).map(|node| node.placed), Some(1));
+    }
+
+    #[test]
+    fn fast_filler_core_wins_under_the_default_scorer() {
+        let planner = test_planner(PlanningConfig {
+            layout_options: LayoutOptions {
+                fast_filler_core: true,
+                ..LayoutOptions::default()
+            },
+            ..PlanningConfig::default()
+        });
+
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        let roads: Vec<_> = plan
+            .build_order()
+            .iter()
+            .filter(|step| step.structure_type == StructureType::Road)
+            .map(|step| step.location)
+            .collect();
+
+        let filler_tiles = fast_filler_tiles(&roads, |location, structure_type| {
+            plan.has_structure(location, structure_type)
+        });
+
+        assert_eq!(filler_tiles.len(), 1);
+        assert_eq!(count(&plan, StructureType::Extension), 60);
+        assert_eq!(count(&plan, StructureType::Tower), 6);
+        assert_eq!(count(&plan, StructureType::Spawn), 3);
+    }
+
+    #[test]
+    fn min_exit_distance_rejects_anchors_near_exits() {
+        // The test room's only exit is on the top edge.
+        let exit_distance = |location: PlanLocation| {
+            (20..30)
+                .map(|x| location.distance_to_xy(x, 0))
+                .min()
+                .unwrap()
+        };
+
+        let default_locations: Vec<_> = root_candidates(LayoutOptions::default())
+            .into_iter()
+            .map(|(location, _)| location)
+            .collect();
+
+        assert!(default_locations
+            .iter()
+            .any(|location| exit_distance(*location) < 20));
+
+        let locations: Vec<_> = root_candidates(LayoutOptions {
+            min_exit_distance: 20,
+            ..LayoutOptions::default()
+        })
+        .into_iter()
+        .map(|(location, _)| location)
+        .collect();
+
+        assert!(!locations.is_empty());
+        assert!(locations
+            .iter()
+            .all(|location| exit_distance(*location) >= 20));
+    }
+
+    #[test]
+    fn min_controller_distance_rejects_anchors_near_controller() {
+        // The test room's controller is at (25, 12).
+        let controller = PlanLocation::new(25, 12);
+
+        let default_locations: Vec<_> = root_candidates(LayoutOptions::default())
+            .into_iter()
+            .map(|(location, _)| location)
+            .collect();
+
+        assert!(default_locations
+            .iter()
+            .any(|location| controller.distance_to(*location) < 7));
+
+        let locations: Vec<_> = root_candidates(LayoutOptions {
+            min_controller_distance: 7,
+            ..LayoutOptions::default()
+        })
+        .into_iter()
+        .map(|(location, _)| location)
+        .collect();
+
+        assert!(!locations.is_empty());
+        assert!(locations
+            .iter()
+            .all(|location| controller.distance_to(*location) >= 7));
+    }
+
+    #[test]
+    fn anchor_defensibility_weight_prefers_deep_pockets() {
+        // A small area holding the sources and controller, and a large open area away from them.
+        let mut room = TestRoom::with_walls(|x, y| {
+            let shallow = (3..=12).contains(&x) && (3..=12).contains(&y);
+            let deep = (20..=46).contains(&x) && (20..=46).contains(&y);
+
+            !shallow && !deep
+        });
+        room.controllers = vec![PlanLocation::new(8, 4)];
+        room.sources = vec![PlanLocation::new(4, 4), PlanLocation::new(11, 11)];
+        room.minerals = Vec::new();
+
+        let shallow = PlanLocation::new(8, 8);
+        let deep = PlanLocation::new(33, 33);
+
+        let default_scores =
+            anchor_scores_with_options(&mut room, LayoutOptions::default(), &[shallow, deep]);
+
+        assert!(default_scores[0].unwrap() > default_scores[1].unwrap());
+
+        let weighted_scores = anchor_scores_with_options(
+            &mut room,
+            LayoutOptions {
+                anchor_defensibility_weight: 0.8,
+                ..LayoutOptions::default()
+            },
+            &[shallow, deep],
+        );
+
+        assert!(weighted_scores[1].unwrap() > weighted_scores[0].unwrap());
+    }
+
+    fn source_containers(plan: &Plan, room: &TestRoom) -> Vec<Location> {
+        plan.build_order()
+            .iter()
+            .filter(|step| step.structure_type == StructureType::Container)
+            .map(|step| step.location)
+            .filter(|container| {
+                room.sources
+                    .iter()
+                    .any(|source| source.distance_to((*container).into()) <= 1)
+            })
+            .collect()
+    }
+
+    fn adjacent_count(plan: &Plan, location: Location, structure_type: StructureType) -> usize {
+        plan.structures_in_range(location, 1)
+            .into_iter()
+            .filter(|(_, other)| *other == structure_type)
+            .count()
+    }
+
+    #[test]
+    fn extensions_prefer_reusing_existing_roads() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+
+        let mut state = PlannerState::new();
+
+        state.insert(
+            Location::from_coords(25, 25),
+            room_item(StructureType::Storage, 4),
+        );
+
+        // Two placements the same distance from storage, one of them already next to roads.
+        let bare = PlanLocation::new(25, 20);
+        let paved = PlanLocation::new(25, 30);
+
+        for offset in &[(-1, 0), (1, 0), (0, 1)] {
+            state.insert(
+                (paved + offset).as_location().unwrap(),
+                room_item(StructureType::Road, 1),
+            );
+        }
+
+        assert_eq!(
+            distance_to_storage_score_pathfind(bare, &mut context, &state),
+            distance_to_storage_score_pathfind(paved, &mut context, &state)
+        );
+
+        let bare_score = extension_score(bare, &mut context, &state).unwrap();
+        let paved_score = extension_score(paved, &mut context, &state).unwrap();
+
+        assert!(paved_score > bare_score);
+    }
+
+    #[test]
+    fn labs_prefer_positions_covered_by_towers() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+
+        let mut state = PlannerState::new();
+
+        // Two spots the lab stamp fits equally well, one right next to the towers.
+        let covered = PlanLocation::new(22, 22);
+        let exposed = PlanLocation::new(8, 40);
+
+        assert_eq!(
+            (LABS.scorer)(covered, &mut context, &state),
+            (LABS.scorer)(exposed, &mut context, &state)
+        );
+
+        for x in 25..28 {
+            state.insert(
+                Location::from_coords(x, 25),
+                room_item(StructureType::Tower, 3),
+            );
+        }
+
+        let covered_score = (LABS.scorer)(covered, &mut context, &state).unwrap();
+        let exposed_score = (LABS.scorer)(exposed, &mut context, &state).unwrap();
+
+        assert!(covered_score > exposed_score);
+    }
+
+    #[test]
+    fn extensions_prefer_plain_approach_tiles() {
+        let right_side_extensions = |room: &mut TestRoom| {
+            let plan = plan_room(&test_planner(PlanningConfig::default()), room)
+                .unwrap_or_else(|err| panic!("{}", err));
+
+            plan.build_order()
+                .iter()
+                .filter(|step| step.structure_type == StructureType::Extension)
+                .filter(|step| step.location.x() > 25)
+                .count()
+        };
+
+        let mut half_swamp = TestRoom::open();
+        let mut buffer = Vec::new();
+
+        for y in 0..ROOM_HEIGHT {
+            for x in 0..ROOM_WIDTH {
+                let mut terrain = half_swamp.terrain.get_xy(x, y);
+
+                if x > 25 && !terrain.contains(TerrainFlags::WALL) {
+                    terrain |= TerrainFlags::SWAMP;
+                }
+
+                buffer.push(terrain.bits());
+            }
+        }
+
+        half_swamp.terrain = FastRoomTerrain::new(buffer);
+
+        let open = right_side_extensions(&mut TestRoom::open());
+        let swamp = right_side_extensions(&mut half_swamp);
+
+        assert!(swamp < open);
+    }
+
+    #[test]
+    fn road_placed_next_to_each_source_container() {
+        let mut room = TestRoom::open();
+
+        // Without the roads added after the search, any road next to a container comes from the
+        // layout.
+        let planner = test_planner(PlanningConfig {
+            container_roads: false,
+            ..PlanningConfig::default()
+        });
+
+        let plan = plan_room(&planner, &mut room).unwrap_or_else(|err| panic!("{}", err));
+        let containers = source_containers(&plan, &room);
+
+        assert_eq!(containers.len(), 2);
+
+        for container in containers {
+            assert!(adjacent_count(&plan, container, StructureType::Road) >= 1);
+        }
+    }
+
+    #[test]
+    fn min_wall_distance_skips_shallow_anchors() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+        let state = PlannerState::new();
+
+        let node = MinWallDistancePlanNode {
+            wall_distance: 4,
+            child: PlanNodeStorage::LocationPlacement(LABS),
+        };
+
+        let tile_at_distance = |context: &mut NodeContext, distance| {
+            (1..25)
+                .map(|x| PlanLocation::new(x, 25))
+                .find(|location| {
+                    *context
+                        .wall_distance()
+                        .get(location.x() as usize, location.y() as usize)
+                        == Some(distance)
+                })
+                .unwrap()
+        };
+
+        let shallow = tile_at_distance(&mut context, 3);
+        let deep = tile_at_distance(&mut context, 4);
+
+        assert!(LABS.desires_location(
+            shallow,
+            &mut context,
+            &state,
+            &mut PlanGatherChildrenData::new()
+        ));
+        assert!(!node.desires_location(
+            shallow,
+            &mut context,
+            &state,
+            &mut PlanGatherChildrenData::new()
+        ));
+        assert!(node.desires_location(
+            deep,
+            &mut context,
+            &state,
+            &mut PlanGatherChildrenData::new()
+        ));
+    }
+
+    #[test]
+    fn labs_keep_clear_of_walls() {
+        let mut room = TestRoom::open();
+
+        let plan = plan_room(&test_planner(PlanningConfig::default()), &mut room)
+            .unwrap_or_else(|err| panic!("{}", err));
+
+        let labs: Vec<_> = plan
+            .build_order()
+            .iter()
+            .filter(|step| step.structure_type == StructureType::Lab)
+            .map(|step| step.location)
+            .collect();
+
+        assert_eq!(labs.len(), 10);
+
+        // The stamp anchor is the top left corner of the lab cluster.
+        let anchor_x = labs.iter().map(|lab| lab.x()).min().unwrap();
+        let anchor_y = labs.iter().map(|lab| lab.y()).min().unwrap();
+
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+
+        let wall_distance = *context
+            .wall_distance()
+            .get(anchor_x as usize, anchor_y as usize);
+
+        assert!(wall_distance.unwrap() >= LAB_WALL_DISTANCE);
+    }
+
+    // Counts of the containers and links next to each source, and whether the source has a road
+    // next to its container or, without one, next to both the source and its link.
+    fn source_infra(policy: SourceInfraPolicy) -> Vec<(usize, usize, bool)> {
+        source_infra_with(SourceInfraPolicies::new(policy))
+    }
+
+    fn source_infra_with(source_infra: SourceInfraPolicies) -> Vec<(usize, usize, bool)> {
+        let mut room = TestRoom::open();
+
+        let planner = test_planner(PlanningConfig {
+            source_infra,
+            ..PlanningConfig::default()
+        });
+
+        let plan = plan_room(&planner, &mut room).unwrap_or_else(|err| panic!("{}", err));
+
+        room.sources
+            .iter()
+            .map(|source| {
+                let source_location = Location::from_coords(source.x() as u32, source.y() as u32);
+                let in_range = plan.structures_in_range(source_location, 1);
+
+                let of_type = |structure_type| {
+                    in_range
+                        .iter()
+                        .filter(|(_, other)| *other == structure_type)
+                        .map(|(location, _)| *location)
+                        .collect::<Vec<_>>()
+                };
+
+                let containers = of_type(StructureType::Container);
+                let roads = of_type(StructureType::Road);
+
+                // Links serving a container sit next to the container rather than the source.
+                let links: Vec<_> = plan
+                    .build_order()
+                    .iter()
+                    .filter(|step| step.structure_type == StructureType::Link)
+                    .map(|step| step.location)
+                    .filter(|link| {
+                        link.distance_to(source_location) <= 1
+                            || containers
+                                .iter()
+                                .any(|container| link.distance_to(*container) <= 1)
+                    })
+                    .collect();
+
+                let has_road = match containers.first() {
+                    Some(container) => adjacent_count(&plan, *container, StructureType::Road) > 0,
+                    None => roads
+                        .iter()
+                        .any(|road| links.iter().any(|link| road.distance_to(*link) <= 1)),
+                };
+
+                (containers.len(), links.len(), has_road)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn link_min_distance_skips_links_for_near_sources() {
+        let with_link_min_distance = |link_min_distance| {
+            source_infra_with(
+                SourceInfraPolicies::default().with_link_min_distance(link_min_distance),
+            )
+        };
+
+        // The first source sits closer to storage than the second.
+        assert_eq!(with_link_min_distance(14), vec![(1, 0, true), (1, 1, true)]);
+        assert_eq!(
+            with_link_min_distance(100),
+            vec![(1, 0, true), (1, 0, true)]
+        );
+    }
+
+    #[test]
+    fn limited_links_go_to_the_furthest_source() {
+        let mut room = TestRoom::open();
+        room.sources.push(PlanLocation::new(40, 12));
+
+        // Three links at RCL 6 - for the hub, the controller and only one of the sources.
+        let planner = test_planner(PlanningConfig {
+            rcl_limits: RclLimits::official().with_max_rcl(6),
+            ..PlanningConfig::default()
+        });
+
+        let plan = plan_room(&planner, &mut room).unwrap_or_else(|err| panic!("{}", err));
+
+        let storage = plan
+            .build_order()
+            .iter()
+            .find(|step| step.structure_type == StructureType::Storage)
+            .map(|step| step.location)
+            .unwrap();
+
+        let links: Vec<_> = plan
+            .build_order()
+            .iter()
+            .filter(|step| step.structure_type == StructureType::Link)
+            .map(|step| step.location)
+            .collect();
+
+        let sources: Vec<_> = room
+            .sources
+            .iter()
+            .map(|source| Location::from_coords(source.x() as u32, source.y() as u32))
+            .collect();
+
+        let linked: Vec<_> = sources
+            .iter()
+            .filter(|source| links.iter().any(|link| link.distance_to(**source) <= 2))
+            .collect();
+
+        let furthest = sources
+            .iter()
+            .max_by_key(|source| source.distance_to(storage))
+            .unwrap();
+
+        assert_eq!(linked, vec![furthest]);
+    }
+
+    #[test]
+    fn controller_link_pairs_with_upgrade_container() {
+        // The controller in a corner, backed against a wall on the right.
+        let walled = || {
+            let mut room = TestRoom::with_walls(|x, _| x > 40);
+            room.controllers = vec![PlanLocation::new(40, 8)];
+            room
+        };
+
+        for room in &mut [TestRoom::open(), walled()] {
+            let plan = plan_room(&test_planner(PlanningConfig::default()), room)
+                .unwrap_or_else(|err| panic!("{}", err));
+
+            let controller = room.controllers[0];
+            let container = plan.controller_container().unwrap();
+
+            let link = plan
+                .build_order()
+                .iter()
+                .filter(|step| step.structure_type == StructureType::Link)
+                .map(|step| step.location)
+                .find(|link| link.distance_to(container) <= 1)
+                .unwrap();
+
+            assert!(controller.distance_to(container.into()) <= 2);
+            assert!(controller.distance_to(link.into()) <= 3);
+            assert_eq!(plan.controller_link(), Some(link));
+        }
+    }
+
+    #[test]
+    fn container_only_sources_have_no_links() {
+        assert_eq!(
+            source_infra(SourceInfraPolicy::ContainerOnly),
+            vec![(1, 0, true), (1, 0, true)]
+        );
+    }
+
+    #[test]
+    fn container_and_link_sources_have_both() {
+        assert_eq!(
+            source_infra(SourceInfraPolicy::ContainerAndLink),
+            vec![(1, 1, true), (1, 1, true)]
+        );
+    }
+
+    #[test]
+    fn link_only_sources_have_no_containers() {
+        assert_eq!(
+            source_infra(SourceInfraPolicy::LinkOnly),
+            vec![(0, 1, true), (0, 1, true)]
+        );
+    }
+}
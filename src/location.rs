@@ -48,6 +48,27 @@ impl Location {
     }
 }
 
+#[cfg(not(feature = "shim"))]
+impl Location {
+    pub fn to_room_position(self, room_name: screeps::RoomName) -> screeps::RoomPosition {
+        screeps::RoomPosition::new(self.x() as u32, self.y() as u32, room_name)
+    }
+}
+
+#[cfg(not(feature = "shim"))]
+impl From<screeps::RoomPosition> for Location {
+    fn from(position: screeps::RoomPosition) -> Self {
+        Location::from_coords(position.x(), position.y())
+    }
+}
+
+#[cfg(not(feature = "shim"))]
+impl From<&screeps::RoomPosition> for Location {
+    fn from(position: &screeps::RoomPosition) -> Self {
+        Location::from_coords(position.x(), position.y())
+    }
+}
+
 impl Serialize for Location {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -65,3 +86,24 @@ impl<'de> Deserialize<'de> for Location {
         u16::deserialize(deserializer).map(Location::from_packed)
     }
 }
+
+#[cfg(all(test, not(feature = "shim")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_position_round_trips() {
+        let room_name = screeps::RoomName::new("W1N1").unwrap();
+
+        for (x, y) in &[(0, 0), (49, 49), (0, 49), (49, 0), (25, 25), (1, 48)] {
+            let location = Location::from_coords(*x, *y);
+            let position = location.to_room_position(room_name);
+
+            assert_eq!(position.x(), *x);
+            assert_eq!(position.y(), *y);
+            assert_eq!(position.room_name(), room_name);
+            assert_eq!(Location::from(position), location);
+            assert_eq!(Location::from(&position), location);
+        }
+    }
+}
@@ -4,6 +4,7 @@ use super::visual::*;
 use super::*;
 use crate::constants::*;
 use bitflags::*;
+use itertools::Itertools;
 use log::*;
 use pathfinding::directed::astar::*;
 use rs_graph::linkedlistgraph::*;
@@ -11,7 +12,8 @@ use rs_graph::maxflow::*;
 use rs_graph::traits::*;
 use rs_graph::{Buildable, Builder};
 use serde::*;
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{OnceCell, RefCell};
 use std::collections::hash_map::*;
 use std::collections::*;
 use std::convert::*;
@@ -184,6 +186,17 @@ impl RoomItem {
     }
 }
 
+/// Whether creeps can walk over a tile containing this structure. Ramparts are passable to their
+/// owner, while constructed walls block movement.
+pub fn is_walkable_structure(structure_type: StructureType) -> bool {
+    match structure_type {
+        StructureType::Road => true,
+        StructureType::Container => true,
+        StructureType::Rampart => true,
+        _ => false,
+    }
+}
+
 impl InBoundsUnsigned for Location {
     fn in_room_bounds(&self) -> bool {
         in_room_bounds_unsigned(self.x(), self.y())
@@ -222,123 +235,258 @@ impl TryFrom<&PlanLocation> for Location {
     }
 }
 
-fn get_min_rcl_for_spawn(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1 => Some(1),
-        2 => Some(7),
-        3 => Some(8),
-        _ => None,
-    }
+/// Maximum number of each structure type allowed at each controller level (index 0 through 8).
+#[derive(Clone, Debug)]
+pub struct RclLimits {
+    limits: FnvHashMap<StructureType, [u8; 9]>,
+    max_rcl: u8,
+    defense_rcl: Option<u8>,
 }
 
-fn get_min_rcl_for_extension(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=5 => Some(2),
-        6..=10 => Some(3),
-        11..=20 => Some(4),
-        21..=30 => Some(5),
-        31..=40 => Some(6),
-        41..=50 => Some(7),
-        51..=60 => Some(8),
-        _ => None,
+impl RclLimits {
+    pub fn new() -> RclLimits {
+        RclLimits {
+            limits: FnvHashMap::default(),
+            max_rcl: 8,
+            defense_rcl: None,
+        }
     }
-}
 
-fn get_min_rcl_for_link(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=2 => Some(5),
-        3..=3 => Some(6),
-        4..=4 => Some(7),
-        5..=6 => Some(8),
-        _ => None,
+    /// The structure limits used by the official Screeps servers.
+    pub fn official() -> RclLimits {
+        RclLimits::new()
+            .with_limits(StructureType::Spawn, [0, 1, 1, 1, 1, 1, 1, 2, 3])
+            .with_limits(StructureType::Extension, [0, 0, 5, 10, 20, 30, 40, 50, 60])
+            .with_limits(StructureType::Link, [0, 0, 0, 0, 0, 2, 3, 4, 6])
+            .with_limits(StructureType::Storage, [0, 0, 0, 0, 1, 1, 1, 1, 1])
+            .with_limits(StructureType::Tower, [0, 0, 0, 1, 1, 2, 2, 3, 6])
+            .with_limits(StructureType::Observer, [0, 0, 0, 0, 0, 0, 0, 0, 1])
+            .with_limits(StructureType::PowerSpawn, [0, 0, 0, 0, 0, 0, 0, 0, 1])
+            .with_limits(StructureType::Extractor, [0, 0, 0, 0, 0, 0, 1, 1, 1])
+            .with_limits(StructureType::Terminal, [0, 0, 0, 0, 0, 0, 1, 1, 1])
+            .with_limits(StructureType::Lab, [0, 0, 0, 0, 0, 0, 3, 6, 10])
+            .with_limits(StructureType::Container, [5, 5, 5, 5, 5, 5, 5, 5, 5])
+            .with_limits(StructureType::Nuker, [0, 0, 0, 0, 0, 0, 0, 0, 1])
+            .with_limits(StructureType::Factory, [0, 0, 0, 0, 0, 0, 0, 1, 1])
     }
-}
 
-fn get_min_rcl_for_storage(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=1 => Some(4),
-        _ => None,
+    pub fn with_limits(mut self, structure_type: StructureType, limits: [u8; 9]) -> Self {
+        self.set_limits(structure_type, limits);
+
+        self
     }
-}
 
-fn get_min_rcl_for_tower(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=1 => Some(3),
-        2..=2 => Some(5),
-        3..=3 => Some(7),
-        4..=6 => Some(8),
-        _ => None,
+    /// Cap the number of structures of the given type at every controller level, such as
+    /// planning fewer towers or extensions than the official limits allow.
+    pub fn with_max_count(mut self, structure_type: StructureType, count: u8) -> Self {
+        if let Some(limits) = self.limits.get_mut(&structure_type) {
+            for limit in limits.iter_mut() {
+                *limit = (*limit).min(count);
+            }
+        }
+
+        self
     }
-}
 
-fn get_min_rcl_for_observer(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=1 => Some(8),
-        _ => None,
+    /// Plan without any structures of the given type, such as labs in rooms that only support
+    /// energy or upgrading.
+    pub fn without(self, structure_type: StructureType) -> Self {
+        self.with_limits(structure_type, [0; 9])
     }
-}
 
-fn get_min_rcl_for_power_spawn(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=1 => Some(8),
-        _ => None,
+    pub fn set_limits(&mut self, structure_type: StructureType, limits: [u8; 9]) {
+        self.limits.insert(structure_type, limits);
     }
-}
 
-pub fn get_min_rcl_for_extractor(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=1 => Some(6),
-        _ => None,
+    pub fn get_limits(&self, structure_type: StructureType) -> Option<&[u8; 9]> {
+        self.limits.get(&structure_type)
     }
-}
 
-fn get_min_rcl_for_terminal(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=1 => Some(6),
-        _ => None,
+    /// Plan only for rooms that will never grow past `max_rcl`. Structures that would require a
+    /// higher controller level are left out of the plan.
+    pub fn with_max_rcl(mut self, max_rcl: u8) -> Self {
+        self.max_rcl = max_rcl.min(8);
+
+        self
     }
-}
 
-fn get_min_rcl_for_lab(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=3 => Some(6),
-        4..=6 => Some(7),
-        7..=10 => Some(8),
-        _ => None,
+    pub fn max_rcl(&self) -> u8 {
+        self.max_rcl
+    }
+
+    /// Queue the defensive walls and ramparts at the given controller level instead of the
+    /// defense layer's default. Clamped to RCL 2, the lowest level walls and ramparts can be built.
+    pub fn with_defense_rcl(mut self, defense_rcl: u8) -> Self {
+        self.defense_rcl = Some(defense_rcl.clamp(2, 8));
+
+        self
+    }
+
+    pub fn defense_rcl(&self) -> Option<u8> {
+        self.defense_rcl
+    }
+
+    /// The lowest controller level at which `count` structures of the given type can exist.
+    pub fn get_min_rcl(&self, structure_type: StructureType, count: u8) -> Option<u8> {
+        match structure_type {
+            StructureType::Road => return Some(1),
+            StructureType::Wall | StructureType::Rampart => return Some(2),
+            _ => {}
+        }
+
+        if count == 0 {
+            return Some(0);
+        }
+
+        self.get_limits(structure_type).and_then(|limits| {
+            limits
+                .iter()
+                .position(|limit| *limit >= count)
+                .map(|rcl| rcl as u8)
+        })
+    }
+
+    /// The number of structures of the given type allowed at the maximum controller level.
+    pub fn get_max_count(&self, structure_type: StructureType) -> u8 {
+        self.get_limits(structure_type)
+            .map(|limits| limits[self.max_rcl as usize])
+            .unwrap_or(0)
     }
 }
 
-fn get_min_rcl_for_container(count: u8) -> Option<u8> {
-    match count {
-        0..=5 => Some(0),
-        _ => None,
+impl Default for RclLimits {
+    fn default() -> RclLimits {
+        RclLimits::official()
     }
 }
 
-fn get_min_rcl_for_nuker(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=1 => Some(8),
-        _ => None,
+/// Which structures are placed next to a source for harvesting.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum SourceInfraPolicy {
+    ContainerOnly,
+    #[default]
+    ContainerAndLink,
+    LinkOnly,
+}
+
+impl SourceInfraPolicy {
+    pub fn places_container(self) -> bool {
+        match self {
+            SourceInfraPolicy::ContainerOnly | SourceInfraPolicy::ContainerAndLink => true,
+            SourceInfraPolicy::LinkOnly => false,
+        }
+    }
+
+    pub fn places_link(self) -> bool {
+        match self {
+            SourceInfraPolicy::ContainerAndLink | SourceInfraPolicy::LinkOnly => true,
+            SourceInfraPolicy::ContainerOnly => false,
+        }
     }
 }
 
-fn get_min_rcl_for_factory(count: u8) -> Option<u8> {
-    match count {
-        0 => Some(0),
-        1..=1 => Some(7),
-        _ => None,
+/// Source infrastructure policy for each source, with optional per-source overrides.
+#[derive(Clone, Debug, Default)]
+pub struct SourceInfraPolicies {
+    default: SourceInfraPolicy,
+    overrides: FnvHashMap<PlanLocation, SourceInfraPolicy>,
+    link_min_distance: u32,
+}
+
+impl SourceInfraPolicies {
+    pub fn new(default: SourceInfraPolicy) -> SourceInfraPolicies {
+        SourceInfraPolicies {
+            default,
+            overrides: FnvHashMap::default(),
+            link_min_distance: 0,
+        }
     }
+
+    /// Only place a link next to a source container when the container is further than this
+    /// path distance from storage. Sources closer than that are left to haulers.
+    pub fn with_link_min_distance(self, link_min_distance: u32) -> Self {
+        SourceInfraPolicies {
+            link_min_distance,
+            ..self
+        }
+    }
+
+    pub fn link_min_distance(&self) -> u32 {
+        self.link_min_distance
+    }
+
+    pub fn with_policy(mut self, source: PlanLocation, policy: SourceInfraPolicy) -> Self {
+        self.set_policy(source, policy);
+
+        self
+    }
+
+    pub fn set_policy(&mut self, source: PlanLocation, policy: SourceInfraPolicy) {
+        self.overrides.insert(source, policy);
+    }
+
+    pub fn get_policy(&self, source: PlanLocation) -> SourceInfraPolicy {
+        self.overrides.get(&source).copied().unwrap_or(self.default)
+    }
+}
+
+/// Optional features and constraints of the built in layout and scoring. The defaults plan the
+/// standard bunker.
+#[derive(Copy, Clone, Debug)]
+pub struct LayoutOptions {
+    /// Place a second, smaller spawn cluster - a spawn, extensions and a link - away from the
+    /// hub, so the room can still spawn while the hub is under attack.
+    pub secondary_core: bool,
+    /// Score bunker anchors by walking distance to the sources and controller rather than
+    /// straight line distance. More accurate in rooms with walls in the way, at some CPU cost.
+    pub pathfinding_anchor_distance: bool,
+    /// The fewest walkable tiles each spawn and the storage must keep around them, so creeps
+    /// don't bottleneck in a densely packed hub.
+    pub min_open_hub_tiles: usize,
+    /// Also try a hub variant that wraps a spawn, a link and four extensions around a single
+    /// filler tile, so one stationary creep can refill them. The default scorer also scores
+    /// plans by how many structures a filler next to a spawn and a link can refill, and the
+    /// search keeps whichever hub scores best.
+    pub fast_filler_core: bool,
+    /// The closest the hub anchor may be to any exit, to keep the base away from the border
+    /// where it is expensive to defend. Opt-in: anchors must already be 4 tiles from walls and
+    /// the unbuildable border, so the default of 0 and values up to 5 leave anchors unconstrained.
+    /// Larger values can leave small rooms without an anchor.
+    pub min_exit_distance: u32,
+    /// The closest the hub anchor may be to the controller, leaving space for the upgrade
+    /// container and link. The core reaches 5 tiles from the anchor and the upgrade container
+    /// sits within 2 of the controller, so 7 keeps them apart. Opt-in, as it moves the hub in
+    /// rooms where it would otherwise sit near the controller.
+    pub min_controller_distance: u32,
+    /// Share of the anchor score, from 0 to 1, given to how deep the anchor sits in open space -
+    /// a cheap proxy for how easy the room is to wall off. Favors anchors in large open
+    /// interiors over ones closer to the sources and controller.
+    pub anchor_defensibility_weight: f32,
+    /// How many of the best scoring optional candidates, such as extension and lab stamp
+    /// locations, the search branches on at each step, bounding the CPU spent in large open
+    /// rooms. Flood fills only score this many of their best candidates per placement. Root
+    /// candidates are bounded by `PlanningConfig::max_root_candidates` instead. `None` tries
+    /// them all.
+    pub max_stamp_candidates: Option<usize>,
+}
+
+impl Default for LayoutOptions {
+    fn default() -> LayoutOptions {
+        LayoutOptions {
+            secondary_core: false,
+            pathfinding_anchor_distance: false,
+            min_open_hub_tiles: 2,
+            fast_filler_core: false,
+            min_exit_distance: 0,
+            min_controller_distance: 0,
+            anchor_defensibility_weight: 0.0,
+            max_stamp_candidates: None,
+        }
+    }
+}
+
+pub fn get_min_rcl_for_extractor(count: u8) -> Option<u8> {
+    RclLimits::official().get_min_rcl(StructureType::Extractor, count)
 }
 
 pub type PlanState = FnvHashMap<Location, Vec<RoomItem>>;
@@ -347,6 +495,8 @@ pub type PlanState = FnvHashMap<Location, Vec<RoomItem>>;
 pub struct PlannerStateLayer {
     #[serde(rename = "d")]
     data: FnvHashMap<Location, Vec<RoomItem>>,
+    #[serde(rename = "r", default)]
+    reserved: FnvHashSet<Location>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -364,6 +514,7 @@ impl PlannerStateLayer {
     pub fn new() -> PlannerStateLayer {
         PlannerStateLayer {
             data: FnvHashMap::default(),
+            reserved: FnvHashSet::default(),
         }
     }
 
@@ -371,6 +522,14 @@ impl PlannerStateLayer {
         self.data.is_empty()
     }
 
+    pub fn reserve(&mut self, location: Location) {
+        self.reserved.insert(location);
+    }
+
+    pub fn is_reserved(&self, location: &Location) -> bool {
+        self.reserved.contains(location)
+    }
+
     pub fn insert(&mut self, location: Location, item: RoomItem) {
         let slot = self.data.entry(location).or_insert_with(Vec::new);
 
@@ -429,7 +588,9 @@ impl PlannerStateCacheLayer {
             .entry(item.structure_type)
             .or_insert(0);
 
-        *current_count += 1;
+        // Roads are counted once per stamp that places them, so large custom schedules can
+        // push the count past the range of a u8.
+        *current_count = current_count.saturating_add(1);
 
         self.structure_distances.borrow_mut().clear();
 
@@ -613,12 +774,7 @@ impl PlannerState {
                     .get(&location)
                     .iter()
                     .flat_map(|v| v.iter())
-                    .any(|item| match item.structure_type {
-                        StructureType::Road => false,
-                        StructureType::Container => false,
-                        StructureType::Rampart => false,
-                        _ => true,
-                    });
+                    .any(|item| !is_walkable_structure(item.structure_type));
 
                 !blocked
             } else {
@@ -708,14 +864,11 @@ impl PlannerState {
 
                 let is_passable = |location: PlanLocation| {
                     if let Ok(location) = Location::try_from(location) {
-                        let blocked = self.get(&location).iter().flat_map(|v| v.iter()).any(
-                            |item| match item.structure_type {
-                                StructureType::Road => false,
-                                StructureType::Container => false,
-                                StructureType::Rampart => false,
-                                _ => true,
-                            },
-                        );
+                        let blocked = self
+                            .get(&location)
+                            .iter()
+                            .flat_map(|v| v.iter())
+                            .any(|item| !is_walkable_structure(item.structure_type));
 
                         !blocked
                     } else {
@@ -746,6 +899,31 @@ impl PlannerState {
         layer.insert(location, item);
     }
 
+    /// Keep a tile empty for structures that will be added outside of this plan. Reserved
+    /// tiles are rejected by placements and listed in the final plan.
+    pub fn reserve(&mut self, location: Location) {
+        self.layers.last_mut().unwrap().reserve(location);
+    }
+
+    pub fn is_reserved(&self, location: &Location) -> bool {
+        self.layers.iter().any(|layer| layer.is_reserved(location))
+    }
+
+    pub fn get_reserved_locations(&self) -> Vec<Location> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.reserved.iter().copied())
+            .collect()
+    }
+
+    /// Total number of room items placed across all layers.
+    pub fn get_item_count(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| layer.data.values().map(|items| items.len()).sum::<usize>())
+            .sum()
+    }
+
     pub fn snapshot(&self) -> PlanState {
         let mut state = PlanState::default();
 
@@ -770,28 +948,14 @@ impl PlannerState {
         }
     }
 
-    pub fn get_rcl_for_next_structure(&self, structure_type: StructureType) -> Option<u8> {
+    pub fn get_rcl_for_next_structure(
+        &self,
+        structure_type: StructureType,
+        rcl_limits: &RclLimits,
+    ) -> Option<u8> {
         let current_count = self.get_count(structure_type);
 
-        match structure_type {
-            StructureType::Spawn => get_min_rcl_for_spawn(current_count + 1),
-            StructureType::Extension => get_min_rcl_for_extension(current_count + 1),
-            StructureType::Road => Some(1),
-            StructureType::Wall => Some(2),
-            StructureType::Rampart => Some(2),
-            StructureType::Link => get_min_rcl_for_link(current_count + 1),
-            StructureType::Storage => get_min_rcl_for_storage(current_count + 1),
-            StructureType::Tower => get_min_rcl_for_tower(current_count + 1),
-            StructureType::Observer => get_min_rcl_for_observer(current_count + 1),
-            StructureType::PowerSpawn => get_min_rcl_for_power_spawn(current_count + 1),
-            StructureType::Extractor => get_min_rcl_for_extractor(current_count + 1),
-            StructureType::Lab => get_min_rcl_for_lab(current_count + 1),
-            StructureType::Terminal => get_min_rcl_for_terminal(current_count + 1),
-            StructureType::Container => get_min_rcl_for_container(current_count + 1),
-            StructureType::Nuker => get_min_rcl_for_nuker(current_count + 1),
-            StructureType::Factory => get_min_rcl_for_factory(current_count + 1),
-            _ => None,
-        }
+        rcl_limits.get_min_rcl(structure_type, current_count.saturating_add(1))
     }
 }
 
@@ -962,10 +1126,96 @@ fn visualize_room_items<'a, T: IntoIterator<Item = (&'a Location, &'a RoomItem)>
     }
 }
 
+pub fn structure_type_name(structure_type: StructureType) -> Option<&'static str> {
+    match structure_type {
+        StructureType::Spawn => Some("spawn"),
+        StructureType::Extension => Some("extension"),
+        StructureType::Road => Some("road"),
+        StructureType::Wall => Some("constructedWall"),
+        StructureType::Rampart => Some("rampart"),
+        StructureType::KeeperLair => Some("keeperLair"),
+        StructureType::Portal => Some("portal"),
+        StructureType::Controller => Some("controller"),
+        StructureType::Link => Some("link"),
+        StructureType::Storage => Some("storage"),
+        StructureType::Tower => Some("tower"),
+        StructureType::Observer => Some("observer"),
+        StructureType::PowerBank => Some("powerBank"),
+        StructureType::PowerSpawn => Some("powerSpawn"),
+        StructureType::Extractor => Some("extractor"),
+        StructureType::Lab => Some("lab"),
+        StructureType::Terminal => Some("terminal"),
+        StructureType::Container => Some("container"),
+        StructureType::Nuker => Some("nuker"),
+        StructureType::Factory => Some("factory"),
+        StructureType::InvaderCore => Some("invaderCore"),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+pub fn structure_type_from_name(name: &str) -> Option<StructureType> {
+    match name {
+        "spawn" => Some(StructureType::Spawn),
+        "extension" => Some(StructureType::Extension),
+        "road" => Some(StructureType::Road),
+        "constructedWall" => Some(StructureType::Wall),
+        "rampart" => Some(StructureType::Rampart),
+        "keeperLair" => Some(StructureType::KeeperLair),
+        "portal" => Some(StructureType::Portal),
+        "controller" => Some(StructureType::Controller),
+        "link" => Some(StructureType::Link),
+        "storage" => Some(StructureType::Storage),
+        "tower" => Some(StructureType::Tower),
+        "observer" => Some(StructureType::Observer),
+        "powerBank" => Some(StructureType::PowerBank),
+        "powerSpawn" => Some(StructureType::PowerSpawn),
+        "extractor" => Some(StructureType::Extractor),
+        "lab" => Some(StructureType::Lab),
+        "terminal" => Some(StructureType::Terminal),
+        "container" => Some(StructureType::Container),
+        "nuker" => Some(StructureType::Nuker),
+        "factory" => Some(StructureType::Factory),
+        "invaderCore" => Some(StructureType::InvaderCore),
+        _ => None,
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Plan {
     #[serde(rename = "s")]
     state: PlanState,
+    #[serde(rename = "r", default, skip_serializing_if = "Vec::is_empty")]
+    reserved: Vec<Location>,
+    #[serde(rename = "cc", default, skip_serializing_if = "Option::is_none")]
+    controller_container: Option<Location>,
+    #[serde(rename = "cl", default, skip_serializing_if = "Option::is_none")]
+    controller_link: Option<Location>,
+    #[serde(rename = "er", default, skip_serializing_if = "Vec::is_empty")]
+    existing_roads: Vec<Location>,
+    #[serde(rename = "rr", default, skip_serializing_if = "Vec::is_empty")]
+    routed_roads: Vec<Location>,
+    #[serde(rename = "or", default, skip_serializing_if = "std::ops::Not::not")]
+    orthogonal_roads: bool,
+    #[serde(rename = "fp", default, skip_serializing_if = "Option::is_none")]
+    filler_position: Option<Location>,
+    #[serde(skip)]
+    build_order: OnceCell<Vec<BuildStep>>,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct PlanReport {
+    pub structure_counts: Vec<(StructureType, u32)>,
+    pub extension_count: u32,
+    pub extension_target: u32,
+    pub extension_shortfall: u32,
+    pub tower_count: u32,
+    pub storage_count: u32,
+    pub has_defenses: bool,
+    pub road_count: u32,
+    /// Walkable tiles on and around the controller container, where upgraders can stand. `None`
+    /// without a controller container, or when reported without the room terrain.
+    pub upgrade_area: Option<u32>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Ord, PartialOrd)]
@@ -997,24 +1247,216 @@ pub fn get_build_priority(structure: StructureType, rcl: u32) -> BuildPriority {
     }
 }
 
+/// Construction phase a structure belongs to, for rolling out a plan in stages.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize)]
+pub enum BuildGroup {
+    Core,
+    Economy,
+    Defense,
+    Utility,
+}
+
+pub fn get_build_group(structure: StructureType) -> BuildGroup {
+    match structure {
+        StructureType::Spawn => BuildGroup::Core,
+        StructureType::Storage => BuildGroup::Core,
+        StructureType::Terminal => BuildGroup::Core,
+        StructureType::Extension => BuildGroup::Economy,
+        StructureType::Container => BuildGroup::Economy,
+        StructureType::Link => BuildGroup::Economy,
+        StructureType::Extractor => BuildGroup::Economy,
+        StructureType::Tower => BuildGroup::Defense,
+        StructureType::Wall => BuildGroup::Defense,
+        StructureType::Rampart => BuildGroup::Defense,
+        _ => BuildGroup::Utility,
+    }
+}
+
+/// Build priority overrides per structure type. Types without an override use
+/// `get_build_priority`.
+#[derive(Clone, Debug, Default)]
+pub struct BuildPriorityMap {
+    overrides: FnvHashMap<StructureType, BuildPriority>,
+}
+
+impl BuildPriorityMap {
+    pub fn with_priority(mut self, structure_type: StructureType, priority: BuildPriority) -> Self {
+        self.set_priority(structure_type, priority);
+
+        self
+    }
+
+    pub fn set_priority(&mut self, structure_type: StructureType, priority: BuildPriority) {
+        self.overrides.insert(structure_type, priority);
+    }
+
+    pub fn get_priority(&self, structure_type: StructureType, rcl: u32) -> BuildPriority {
+        self.overrides
+            .get(&structure_type)
+            .copied()
+            .unwrap_or_else(|| get_build_priority(structure_type, rcl))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct BuildStep {
+    pub location: Location,
+    pub structure_type: StructureType,
+    pub required_rcl: u8,
+    pub group: BuildGroup,
+}
+
+/// A regular hauling trip in a finished plan and its round trip length in tiles - out to `to`
+/// and back to `from`.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct HaulRoute {
+    pub from: Location,
+    pub to: Location,
+    pub distance: u32,
+}
+
+/// Reaction roles for the planned labs - every output lab is within range 2 of both inputs.
+#[derive(Clone, Debug, Serialize)]
+pub struct LabRoles {
+    pub inputs: [Location; 2],
+    pub outputs: Vec<Location>,
+}
+
+/// Where a planned road came from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RoadOrigin {
+    /// Already built in the room and seeded into the plan.
+    Existing,
+    /// Placed by a layout stamp while searching.
+    Stamp,
+    /// Routed once the structures were placed, such as the roads joining containers to storage.
+    Routed,
+}
+
+#[derive(Debug)]
+pub enum PlanInconsistency {
+    MissingStructure,
+    OutOfBounds,
+    Wall,
+    Occupied(StructureType),
+    Unreachable,
+    Stranded(Location),
+}
+
+impl std::fmt::Display for PlanInconsistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlanInconsistency::MissingStructure => {
+                write!(f, "No matching structure at source location")
+            }
+            PlanInconsistency::OutOfBounds => {
+                write!(f, "Target location is outside the build area")
+            }
+            PlanInconsistency::Wall => write!(f, "Target location is a wall"),
+            PlanInconsistency::Occupied(existing) => {
+                write!(f, "Target location is occupied by {:?}", existing)
+            }
+            PlanInconsistency::Unreachable => {
+                write!(f, "Target location is not reachable from the road network")
+            }
+            PlanInconsistency::Stranded(location) => write!(
+                f,
+                "Structure at {}, {} is no longer reachable from the road network",
+                location.x(),
+                location.y()
+            ),
+        }
+    }
+}
+
+fn ascii_code(structure_type: StructureType) -> char {
+    match structure_type {
+        StructureType::Spawn => 'S',
+        StructureType::Extension => 'E',
+        StructureType::Road => '.',
+        StructureType::Wall => '#',
+        StructureType::Rampart => 'r',
+        StructureType::Link => 'L',
+        StructureType::Storage => 'O',
+        StructureType::Tower => 'T',
+        StructureType::Observer => 'V',
+        StructureType::PowerSpawn => 'P',
+        StructureType::Extractor => 'X',
+        StructureType::Lab => 'A',
+        StructureType::Terminal => 'M',
+        StructureType::Container => 'C',
+        StructureType::Nuker => 'N',
+        StructureType::Factory => 'F',
+        _ => '?',
+    }
+}
+
+pub fn tower_damage_at_range(range: u32) -> u32 {
+    if range <= TOWER_DAMAGE_OPTIMAL_RANGE {
+        TOWER_DAMAGE_MAX
+    } else if range >= TOWER_DAMAGE_FALLOFF_RANGE {
+        TOWER_DAMAGE_MIN
+    } else {
+        let falloff = (TOWER_DAMAGE_MAX - TOWER_DAMAGE_MIN) * (range - TOWER_DAMAGE_OPTIMAL_RANGE)
+            / (TOWER_DAMAGE_FALLOFF_RANGE - TOWER_DAMAGE_OPTIMAL_RANGE);
+
+        TOWER_DAMAGE_MAX - falloff
+    }
+}
+
 #[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
 impl Plan {
+    /// Places construction sites for the planned structures the room's RCL allows, highest build
+    /// priority first by `get_build_priority`.
     #[cfg(not(feature = "shim"))]
     pub fn execute(&self, room: &Room, max_placements: u32) {
+        self.execute_filtered(room, max_placements, None)
+    }
+
+    /// As `execute`, ordering the construction sites by the given priorities.
+    #[cfg(not(feature = "shim"))]
+    pub fn execute_with_priorities(
+        &self,
+        room: &Room,
+        max_placements: u32,
+        priorities: &BuildPriorityMap,
+    ) {
+        self.execute_filtered_with_priorities(room, max_placements, None, priorities)
+    }
+
+    /// As `execute`, only placing construction sites for the given structure types.
+    #[cfg(not(feature = "shim"))]
+    pub fn execute_filtered(
+        &self,
+        room: &Room,
+        max_placements: u32,
+        allowed_types: Option<&[StructureType]>,
+    ) {
+        self.execute_filtered_with_priorities(
+            room,
+            max_placements,
+            allowed_types,
+            &BuildPriorityMap::default(),
+        )
+    }
+
+    /// As `execute_filtered`, ordering the construction sites by the given priorities.
+    #[cfg(not(feature = "shim"))]
+    pub fn execute_filtered_with_priorities(
+        &self,
+        room: &Room,
+        max_placements: u32,
+        allowed_types: Option<&[StructureType]>,
+        priorities: &BuildPriorityMap,
+    ) {
         let room_name = room.name();
         let room_level = room.controller().map(|c| c.level()).unwrap_or(0);
 
         let mut current_placements = 0;
 
-        let mut ordered_entries: Vec<_> = self
-            .state
-            .iter()
-            .flat_map(|(loc, entries)| entries.iter().map(move |item| (loc, item)))
-            .collect();
-
-        ordered_entries.sort_by_key(|(_, item)| get_build_priority(item.structure_type(), room_level));
+        let ordered_entries = self.execution_order(room_level, allowed_types, priorities);
 
-        for (loc, entry) in ordered_entries.iter().rev() {
+        for (loc, entry) in ordered_entries.iter() {
             let required_rcl = entry.required_rcl.into();
 
             if entry.structure_type == StructureType::Storage && room_level < required_rcl {
@@ -1061,38 +1503,55 @@ impl Plan {
         }
     }
 
-    #[cfg(not(feature = "shim"))]
-    pub fn cleanup(&self, structures: &[Structure]) {
-        let mut invalid_structures = Vec::new();
-        let mut valid_structures = Vec::new();
+    /// The entries `execute_filtered_with_priorities` considers, highest priority first, limited
+    /// to the allowed types if given.
+    fn execution_order(
+        &self,
+        room_level: u32,
+        allowed_types: Option<&[StructureType]>,
+        priorities: &BuildPriorityMap,
+    ) -> Vec<(&Location, &RoomItem)> {
+        let mut ordered_entries: Vec<_> = self
+            .state
+            .iter()
+            .flat_map(|(loc, entries)| entries.iter().map(move |item| (loc, item)))
+            .filter(|(_, item)| {
+                allowed_types
+                    .map(|types| types.contains(&item.structure_type))
+                    .unwrap_or(true)
+            })
+            .collect();
 
-        for structure in structures {
-            let structure_pos = structure.pos();
-            let structure_type = structure.structure_type();
+        ordered_entries.sort_by_key(|(_, item)| {
+            std::cmp::Reverse(priorities.get_priority(item.structure_type(), room_level))
+        });
 
-            let is_valid = self
-                .state
-                .get(&Location::from_coords(structure_pos.x(), structure_pos.y()))
-                .iter()
-                .flat_map(|v| *v)
-                .any(|r| r.structure_type() == structure_type || (r.structure_type() == StructureType::Storage && structure_type == StructureType::Container));
+        ordered_entries
+    }
 
-            if is_valid {
-                valid_structures.push(structure);
-            } else {
-                invalid_structures.push(structure);
-            }
-        }
+    #[cfg(not(feature = "shim"))]
+    pub fn cleanup(&self, structures: &[Structure]) {
+        self.cleanup_filtered(structures, &[])
+    }
 
-        let has_valid_spawn = valid_structures
+    /// As `cleanup`, never destroying structures of the protected types even when they are not
+    /// part of the plan.
+    #[cfg(not(feature = "shim"))]
+    pub fn cleanup_filtered(&self, structures: &[Structure], protected_types: &[StructureType]) {
+        let existing: Vec<_> = structures
             .iter()
-            .any(|s| s.structure_type() == StructureType::Spawn);
+            .map(|structure| {
+                let structure_pos = structure.pos();
 
-        for structure in invalid_structures {
-            let can_destroy = match structure.structure_type() {
-                StructureType::Spawn => has_valid_spawn,
-                _ => true,
-            };
+                (
+                    Location::from_coords(structure_pos.x(), structure_pos.y()),
+                    structure.structure_type(),
+                )
+            })
+            .collect();
+
+        for index in self.cleanup_targets(&existing, protected_types) {
+            let structure = &structures[index];
 
             let has_store = structure
                 .as_has_store()
@@ -1103,3084 +1562,9260 @@ impl Plan {
                 })
                 .unwrap_or(false);
 
-            if can_destroy && !has_store {
+            if !has_store {
                 structure.destroy();
             }
         }
     }
 
-    pub fn visualize<V>(&self, visualizer: &mut V)
-    where
-        V: RoomVisualizer,
-    {
-        let items = self
-            .state
+    /// Indices of the existing structures that cleanup would destroy - those not part of the plan
+    /// and not of a protected type. Off plan spawns are only destroyed once a planned spawn has
+    /// been built, and cleanup additionally keeps any structure still holding resources.
+    pub fn cleanup_targets(
+        &self,
+        structures: &[(Location, StructureType)],
+        protected_types: &[StructureType],
+    ) -> Vec<usize> {
+        let is_valid = |(location, structure_type): &(Location, StructureType)| {
+            self.state.get(location).iter().flat_map(|v| *v).any(|r| {
+                r.structure_type() == *structure_type
+                    || (r.structure_type() == StructureType::Storage
+                        && *structure_type == StructureType::Container)
+            })
+        };
+
+        let has_valid_spawn = structures
             .iter()
-            .flat_map(|(location, entries)| entries.iter().map(move |entry| (location, entry)));
+            .any(|structure| structure.1 == StructureType::Spawn && is_valid(structure));
 
-        visualize_room_items(items, visualizer);
+        structures
+            .iter()
+            .enumerate()
+            .filter(|(_, structure)| !is_valid(structure))
+            .filter(|(_, (_, structure_type))| match structure_type {
+                structure_type if protected_types.contains(structure_type) => false,
+                StructureType::Spawn => has_valid_spawn,
+                _ => true,
+            })
+            .map(|(index, _)| index)
+            .collect()
     }
-}
-
-struct RoomDataArrayIterator<'a, T>
-where
-    T: Copy,
-{
-    data: &'a RoomDataArray<T>,
-    x: u8,
-    y: u8,
-}
-
-impl<'a, T> Iterator for RoomDataArrayIterator<'a, T>
-where
-    T: Copy,
-{
-    type Item = ((usize, usize), &'a T);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.x < ROOM_WIDTH && self.y < ROOM_HEIGHT {
-            let current_x = self.x as usize;
-            let current_y = self.y as usize;
 
-            self.x += 1;
+    /// Exports the plan in the `{ structureType: [[x, y], ...] }` format accepted by community
+    /// room planner tools. The format has no room for required RCLs, so they are lost - see
+    /// `from_shareable_json_with_rcl_limits`.
+    pub fn to_shareable_json(&self) -> String {
+        let mut data: BTreeMap<&'static str, Vec<(u8, u8)>> = BTreeMap::new();
 
-            if self.x >= ROOM_WIDTH {
-                self.x = 0;
-                self.y += 1;
+        for (location, entries) in self.state.iter() {
+            for entry in entries.iter() {
+                if let Some(name) = structure_type_name(entry.structure_type) {
+                    data.entry(name)
+                        .or_default()
+                        .push((location.x(), location.y()));
+                }
             }
+        }
 
-            Some(((current_x, current_y), self.data.get(current_x, current_y)))
-        } else {
-            None
+        for locations in data.values_mut() {
+            locations.sort();
         }
+
+        serde_json::to_string(&data).unwrap_or_default()
     }
-}
 
-#[derive(Clone)]
-pub struct RoomDataArray<T>
-where
-    T: Copy,
-{
-    data: [T; (ROOM_WIDTH as usize) * (ROOM_HEIGHT as usize)],
-}
-
-impl<T> RoomDataArray<T>
-where
-    T: Copy,
-{
-    pub fn new(initial: T) -> Self {
-        RoomDataArray {
-            data: [initial; (ROOM_WIDTH as usize) * (ROOM_HEIGHT as usize)],
-        }
+    /// Imports a plan exported by `to_shareable_json` or a community room planner, assigning
+    /// required RCLs using the official limits.
+    pub fn from_shareable_json(json: &str) -> Result<Plan, String> {
+        Plan::from_shareable_json_with_rcl_limits(json, &RclLimits::official())
     }
 
-    pub fn get(&self, x: usize, y: usize) -> &T {
-        let index = (y * (ROOM_WIDTH as usize)) + x;
-        &self.data[index]
-    }
+    /// Imports a plan exported by `to_shareable_json` or a community room planner. Required RCL
+    /// is not part of the format, so structures of each type are assigned the lowest RCL the
+    /// limits allow in listed (location) order. The structures always round trip, but their
+    /// required RCLs only match the original plan where it happened to assign them in the same
+    /// order with the same limits - pass the limits the plan was made with to get closest. Fails
+    /// if the plan lists more structures of a type than the limits allow at any RCL.
+    pub fn from_shareable_json_with_rcl_limits(
+        json: &str,
+        rcl_limits: &RclLimits,
+    ) -> Result<Plan, String> {
+        let data: BTreeMap<String, Vec<(u8, u8)>> =
+            serde_json::from_str(json).map_err(|err| err.to_string())?;
 
-    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut T {
-        let index = (y * (ROOM_WIDTH as usize)) + x;
-        &mut self.data[index]
-    }
+        let mut state = PlanState::default();
 
-    pub fn set(&mut self, x: usize, y: usize, value: T) {
-        *self.get_mut(x, y) = value;
-    }
+        for (name, locations) in data.iter() {
+            let structure_type = structure_type_from_name(name)
+                .ok_or_else(|| format!("Unknown structure type: {}", name))?;
 
-    pub fn iter(&self) -> impl Iterator<Item = ((usize, usize), &T)> {
-        RoomDataArrayIterator {
-            data: &self,
-            x: 0,
-            y: 0,
-        }
-    }
-}
+            for (index, (x, y)) in locations.iter().enumerate() {
+                if !in_room_bounds_unsigned(*x, *y) {
+                    return Err(format!("Location out of bounds: {}, {}", x, y));
+                }
 
-#[derive(Clone)]
-pub enum PlanNodeChild<'a> {
-    GlobalPlacement(&'a dyn PlanGlobalPlacementNode),
-    LocationPlacement(PlanLocation, &'a dyn PlanLocationPlacementNode),
-}
+                let count = (index + 1).min(u8::MAX as usize) as u8;
+                let required_rcl = rcl_limits
+                    .get_min_rcl(structure_type, count)
+                    .ok_or_else(|| format!("More {} structures than the limits allow", name))?;
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanNodeChild<'a> {
-    fn name(&self) -> &str {
-        match self {
-            PlanNodeChild::GlobalPlacement(n) => n.name(),
-            PlanNodeChild::LocationPlacement(_, n) => n.name(),
+                state
+                    .entry(Location::from_coords(*x as u32, *y as u32))
+                    .or_insert_with(Vec::new)
+                    .push(RoomItem {
+                        structure_type,
+                        required_rcl,
+                    });
+            }
         }
-    }
 
-    fn placement_phase(&self) -> PlacementPhase {
-        match self {
-            PlanNodeChild::GlobalPlacement(n) => n.placement_phase(),
-            PlanNodeChild::LocationPlacement(_, n) => n.placement_phase(),
-        }
+        Ok(Plan {
+            state,
+            reserved: Vec::new(),
+            controller_container: None,
+            controller_link: None,
+            existing_roads: Vec::new(),
+            routed_roads: Vec::new(),
+            orthogonal_roads: false,
+            filler_position: None,
+            build_order: OnceCell::new(),
+        })
     }
 
-    fn must_place(&self) -> bool {
-        match self {
-            PlanNodeChild::GlobalPlacement(n) => n.must_place(),
-            PlanNodeChild::LocationPlacement(_, n) => n.must_place(),
-        }
+    /// Summarizes the plan against the official limits. Without the room terrain the upgrade
+    /// area is left out - see `report_with_room`.
+    pub fn report(&self) -> PlanReport {
+        self.report_from(&RclLimits::official(), None)
     }
 
-    fn place(&self, context: &mut NodeContext, state: &mut PlannerState) -> Result<(), ()> {
-        match self {
-            PlanNodeChild::GlobalPlacement(node) => node.place(context, state),
-            PlanNodeChild::LocationPlacement(location, node) => {
-                node.place(*location, context, state)
-            }
-        }
+    /// As `report`, with the extension target taken from the limits the plan was made with and
+    /// the upgrade area measured on the room terrain.
+    pub fn report_with_room(
+        &self,
+        rcl_limits: &RclLimits,
+        terrain: &FastRoomTerrain,
+    ) -> PlanReport {
+        self.report_from(rcl_limits, Some(terrain))
     }
 
-    fn get_score(&self, context: &mut NodeContext, state: &PlannerState) -> Option<f32> {
-        match self {
-            PlanNodeChild::GlobalPlacement(node) => node.get_score(context, state),
-            PlanNodeChild::LocationPlacement(location, node) => {
-                node.get_score(*location, context, state)
+    fn report_from(&self, rcl_limits: &RclLimits, terrain: Option<&FastRoomTerrain>) -> PlanReport {
+        let mut structure_counts: Vec<(StructureType, u32)> = Vec::new();
+
+        for entry in self.state.values().flat_map(|entries| entries.iter()) {
+            match structure_counts
+                .iter_mut()
+                .find(|(structure_type, _)| *structure_type == entry.structure_type)
+            {
+                Some((_, count)) => *count += 1,
+                None => structure_counts.push((entry.structure_type, 1)),
             }
         }
-    }
 
-    fn mark_visited(&self, gather_data: &mut PlanGatherChildrenData<'a>) {
-        match self {
-            PlanNodeChild::GlobalPlacement(node) => {
-                gather_data.mark_visited_global(node.as_global())
-            }
-            PlanNodeChild::LocationPlacement(location, node) => {
-                gather_data.mark_visited_location(*location, node.as_location())
-            }
+        let get_count = |structure_type| {
+            structure_counts
+                .iter()
+                .find(|(other, _)| *other == structure_type)
+                .map(|(_, count)| *count)
+                .unwrap_or(0)
+        };
+
+        let extension_count = get_count(StructureType::Extension);
+        let extension_target = rcl_limits.get_max_count(StructureType::Extension) as u32;
+
+        let upgrade_area = self
+            .controller_container
+            .zip(terrain)
+            .map(|(container, terrain)| {
+                let container: PlanLocation = container.into();
+
+                std::iter::once((0, 0))
+                    .chain(ONE_OFFSET_SQUARE.iter().copied())
+                    .filter_map(|offset| (container + offset).as_build_location())
+                    .filter(|location| !terrain.get(location).contains(TerrainFlags::WALL))
+                    .filter(|location| {
+                        self.tile_structures(*location)
+                            .iter()
+                            .all(|item| is_walkable_structure(item.structure_type))
+                    })
+                    .count() as u32
+            });
+
+        PlanReport {
+            extension_count,
+            extension_target,
+            extension_shortfall: extension_target.saturating_sub(extension_count),
+            tower_count: get_count(StructureType::Tower),
+            storage_count: get_count(StructureType::Storage),
+            has_defenses: get_count(StructureType::Rampart) > 0,
+            road_count: get_count(StructureType::Road),
+            upgrade_area,
+            structure_counts,
         }
     }
 
-    fn get_children(
-        &self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'a>,
-    ) {
-        match self {
-            PlanNodeChild::GlobalPlacement(node) => node.get_children(context, state, gather_data),
-            PlanNodeChild::LocationPlacement(location, node) => {
-                node.get_children(*location, context, state, gather_data)
-            }
-        }
+    /// Every planned structure in the order it should be built - by build priority at its
+    /// required RCL, then by required RCL. The order is sorted once and kept until the plan
+    /// changes, and backs `iter_build_ordered`.
+    pub fn build_order(&self) -> &[BuildStep] {
+        self.build_order
+            .get_or_init(|| self.build_order_with_priorities(&BuildPriorityMap::default()))
     }
 
-    fn desires_placement(
-        &self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'a>,
-    ) -> bool {
-        match self {
-            PlanNodeChild::GlobalPlacement(node) => {
-                gather_data.desires_placement(node.as_base(), context, state)
-            }
-            PlanNodeChild::LocationPlacement(_, node) => {
-                gather_data.desires_placement(node.as_base(), context, state)
-            }
-        }
+    /// Sorts the build order again from the planned structures. Edits through the plan, such as
+    /// `move_structure`, already drop the kept order so the next `build_order` sorts it again;
+    /// this sorts it straight away, so the cost isn't paid by the next reader.
+    pub fn rebuild_build_order(&mut self) {
+        self.build_order.take();
+        self.build_order();
     }
 
-    fn desires_location(
-        &self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'a>,
-    ) -> bool {
-        match self {
-            PlanNodeChild::GlobalPlacement(_) => true,
-            PlanNodeChild::LocationPlacement(location, node) => {
-                gather_data.desires_location(*location, node.as_location(), context, state)
-            }
-        }
+    /// As `build_order`, using the given priorities in place of the defaults.
+    pub fn build_order_with_priorities(&self, priorities: &BuildPriorityMap) -> Vec<BuildStep> {
+        let mut steps: Vec<_> = self
+            .state
+            .iter()
+            .flat_map(|(location, entries)| {
+                entries.iter().map(move |entry| BuildStep {
+                    location: *location,
+                    structure_type: entry.structure_type,
+                    required_rcl: entry.required_rcl,
+                    group: get_build_group(entry.structure_type),
+                })
+            })
+            .collect();
+
+        steps.sort_by(|a, b| {
+            priorities
+                .get_priority(a.structure_type, a.required_rcl as u32)
+                .cmp(&priorities.get_priority(b.structure_type, b.required_rcl as u32))
+                .reverse()
+                .then_with(|| a.required_rcl.cmp(&b.required_rcl))
+                .then_with(|| a.location.packed_repr().cmp(&b.location.packed_repr()))
+                .then_with(|| (a.structure_type as u32).cmp(&(b.structure_type as u32)))
+        });
+
+        steps
     }
 
-    fn ready_for_placement(&self, context: &mut NodeContext, state: &PlannerState) -> bool {
-        match self {
-            PlanNodeChild::GlobalPlacement(node) => node.ready_for_placement(context, state),
-            PlanNodeChild::LocationPlacement(_, node) => node.ready_for_placement(context, state),
-        }
+    /// Canonical ordered view of the planned structures, borrowed from `build_order`. Ties are
+    /// broken by location, then structure type, so identical plans always iterate identically.
+    pub fn iter_build_ordered(&self) -> impl Iterator<Item = &BuildStep> {
+        self.build_order().iter()
     }
 
-    fn insert(&self, gather_data: &mut PlanGatherChildrenData<'a>) -> bool {
-        match self {
-            PlanNodeChild::GlobalPlacement(node) => gather_data.insert_global_placement(*node),
-            PlanNodeChild::LocationPlacement(location, node) => {
-                gather_data.insert_location_placement(*location, *node)
-            }
+    /// Pairs each step of `build_order` with the cumulative number of ticks needed to build it
+    /// and everything before it, assuming energy is spent on construction at a constant rate.
+    /// Empty if `energy_per_tick` is not a positive number, as nothing would ever be built.
+    pub fn build_schedule(&self, energy_per_tick: f32) -> Vec<(u32, BuildStep)> {
+        if energy_per_tick.is_nan() || energy_per_tick <= 0.0 {
+            return Vec::new();
         }
+
+        let mut total_cost = 0;
+
+        self.build_order()
+            .iter()
+            .map(|step| {
+                total_cost += step.structure_type.construction_cost().unwrap_or(0);
+
+                let ticks = (total_cost as f32 / energy_per_tick).ceil() as u32;
+
+                (ticks, *step)
+            })
+            .collect()
     }
 
-    fn to_serialized(&self, index_lookup: &FnvHashMap<uuid::Uuid, usize>) -> SerializedPlanNodeChild {
-        match self {
-            PlanNodeChild::GlobalPlacement(node) => {
-                let node_type = 0;
-                let node = index_lookup.get(node.id()).unwrap();
-                if (node & !0x7F) != 0 {
-                    panic!("Not enough bits to represent packed value!");
-                }
-                let node = node & 0x7F;
+    /// Spawn and extension energy capacity available once the room reaches the given RCL.
+    pub fn energy_capacity_at_rcl(&self, rcl: u8) -> u32 {
+        let count_of = |structure_type: StructureType| -> u32 {
+            self.state
+                .values()
+                .flat_map(|entries| entries.iter())
+                .filter(|entry| entry.structure_type == structure_type && entry.required_rcl <= rcl)
+                .count() as u32
+        };
 
-                let packed = (node_type) | ((node as u32) << 1);
+        let spawns = count_of(StructureType::Spawn);
+        let extensions = count_of(StructureType::Extension);
 
-                SerializedPlanNodeChild { packed }
-            }
-            PlanNodeChild::LocationPlacement(location, node) => {
-                let node_type = 1;
-                let location = location.packed_repr();
-                let node = index_lookup.get(node.id()).unwrap();
-                if (node & !0x7F) != 0 {
-                    panic!("Not enough bits to represent packed value!");
-                }
-                let node = node & 0x7F;
+        spawns * SPAWN_ENERGY_CAPACITY + extensions * extension_energy_capacity(rcl as u32)
+    }
 
-                let packed = (node_type) | ((node as u32) << 1) | ((location as u32) << 16);
+    /// Renders the plan as a 50x50 grid of characters, one row per line, for debugging in a
+    /// terminal. Tiles holding several structures show the most significant one - a rampart is
+    /// only shown on a tile without any other structure, and a road only when nothing else is
+    /// there.
+    pub fn to_ascii_grid(&self) -> String {
+        let display_rank = |structure_type: StructureType| match structure_type {
+            StructureType::Road => 0,
+            StructureType::Rampart => 1,
+            _ => 2,
+        };
 
-                SerializedPlanNodeChild { packed }
+        let mut grid = String::with_capacity((ROOM_WIDTH as usize + 1) * ROOM_HEIGHT as usize);
+
+        for y in 0..ROOM_HEIGHT as u32 {
+            for x in 0..ROOM_WIDTH as u32 {
+                let tile = self
+                    .tile_structures(Location::from_coords(x, y))
+                    .iter()
+                    .max_by_key(|entry| display_rank(entry.structure_type))
+                    .map(|entry| ascii_code(entry.structure_type))
+                    .unwrap_or(' ');
+
+                grid.push(tile);
             }
+
+            grid.push('\n');
         }
+
+        grid
     }
-}
 
-#[derive(Clone, Serialize, Deserialize)]
-#[repr(transparent)]
-#[serde(transparent)]
-struct SerializedPlanNodeChild {
-    packed: u32,
-}
+    /// Length in bytes of the plan serialized as JSON, for deciding whether it fits in Memory or
+    /// should be compacted first.
+    pub fn estimated_serialized_size(&self) -> usize {
+        serde_json::to_string(self)
+            .map(|json| json.len())
+            .unwrap_or(0)
+    }
 
-impl SerializedPlanNodeChild {
-    pub fn as_entry<'b>(
-        &self,
-        nodes: &PlanGatherNodesData<'b>,
-        index_lookup: &Vec<uuid::Uuid>,
-    ) -> Result<PlanNodeChild<'b>, String> {
-        let node_type = self.packed & 0x1;
+    /// Drops where each road came from to shrink the plan for storage in Memory. Only the road
+    /// origins are dropped, as they can be told apart again from the roads built in the room:
+    /// building the plan, the reserved tiles, the controller container and link, the filler
+    /// position and `haul_routes` are unaffected. Afterwards every road is reported as a stamp
+    /// road and paths routed over the plan no longer favour the roads that already existed.
+    pub fn compact(&mut self) {
+        self.existing_roads = Vec::new();
+        self.routed_roads = Vec::new();
+    }
 
-        match node_type {
-            0 => {
-                let node_index = (self.packed >> 1) & 0x7F;
-                let node_id = index_lookup
-                    .get(node_index as usize)
-                    .ok_or("Invalid node id")?;
-                let node = nodes
-                    .global_placement_nodes
-                    .get(node_id)
-                    .ok_or("Invalid node")?;
+    /// Hash of the planned structures and their required RCLs, independent of map iteration
+    /// order. Structurally identical plans produce the same fingerprint.
+    pub fn fingerprint(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
 
-                Ok(PlanNodeChild::GlobalPlacement(*node))
-            }
-            1 => {
-                let node_index = (self.packed >> 1) & 0x7F;
-                let node_id = index_lookup
-                    .get(node_index as usize)
-                    .ok_or("Invalid node id")?;
-                let node = nodes
-                    .location_placement_nodes
-                    .get(node_id)
-                    .ok_or("Invalid node")?;
+        let mut entries: Vec<_> = self
+            .state
+            .iter()
+            .flat_map(|(location, entries)| {
+                entries.iter().map(move |entry| {
+                    (
+                        location.packed_repr(),
+                        entry.structure_type as u32,
+                        entry.required_rcl,
+                    )
+                })
+            })
+            .collect();
 
-                let location = PlanLocation::from_packed((self.packed >> 16) as u16);
+        entries.sort();
 
-                Ok(PlanNodeChild::LocationPlacement(location, *node))
-            }
-            _ => Err("Unknown node type".to_string()),
-        }
-    }
-}
+        let mut hasher = FnvHasher::default();
 
-pub struct PlanGatherNodesData<'b> {
-    global_placement_nodes: FnvHashMap<uuid::Uuid, &'b dyn PlanGlobalPlacementNode>,
-    location_placement_nodes: FnvHashMap<uuid::Uuid, &'b dyn PlanLocationPlacementNode>,
-}
+        entries.hash(&mut hasher);
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'b> PlanGatherNodesData<'b> {
-    pub fn new<'a>() -> PlanGatherNodesData<'a> {
-        PlanGatherNodesData {
-            global_placement_nodes: FnvHashMap::default(),
-            location_placement_nodes: FnvHashMap::default(),
-        }
+        hasher.finish()
     }
 
-    pub fn get_all_ids(&self) -> Vec<uuid::Uuid> {
-        self.global_placement_nodes
-            .keys()
-            .chain(self.location_placement_nodes.keys())
-            .cloned()
-            .collect()
+    /// The container upgraders stand on, if the plan was produced by the planner.
+    pub fn controller_container(&self) -> Option<Location> {
+        self.controller_container
     }
 
-    pub fn insert_global_placement(
-        &mut self,
-        id: uuid::Uuid,
-        node: &'b dyn PlanGlobalPlacementNode,
-    ) -> bool {
-        match self.global_placement_nodes.entry(id) {
-            Entry::Occupied(_) => false,
-            Entry::Vacant(e) => {
-                e.insert(node);
-
-                true
-            }
-        }
+    /// The link feeding the controller container, if the plan was produced by the planner.
+    pub fn controller_link(&self) -> Option<Location> {
+        self.controller_link
     }
 
-    pub fn insert_location_placement(
-        &mut self,
-        id: uuid::Uuid,
-        node: &'b dyn PlanLocationPlacementNode,
-    ) -> bool {
-        match self.location_placement_nodes.entry(id) {
-            Entry::Occupied(_) => false,
-            Entry::Vacant(e) => {
-                e.insert(node);
+    /// Records the controller container and link, matching the rules the controller nodes place
+    /// them by - a container within range 2 of the controller and a link next to it.
+    fn locate_controller_infra(&mut self, controllers: &[PlanLocation]) {
+        let locations_of = |structure_type: StructureType| -> Vec<Location> {
+            let mut locations: Vec<_> = self
+                .state
+                .iter()
+                .filter(|(_, entries)| entries.iter().any(|e| e.structure_type == structure_type))
+                .map(|(location, _)| *location)
+                .collect();
 
-                true
-            }
-        }
-    }
-}
-struct PlanGatherChildrenGlobalData<'s> {
-    visited: Vec<&'s dyn PlanGlobalNode>,
-    inserted: Vec<&'s dyn PlanGlobalPlacementNode>,
-}
+            locations.sort_by_key(|location| location.packed_repr());
 
-impl<'s> PlanGatherChildrenGlobalData<'s> {
-    pub fn has_visited(&self, node: &dyn PlanGlobalNode) -> bool {
-        self.visited.iter().any(|other| std::ptr::eq(node, *other))
-    }
+            locations
+        };
 
-    pub fn mark_visited(&mut self, node: &'s dyn PlanGlobalNode) {
-        if !self.has_visited(node) {
-            self.visited.push(node);
-        }
-    }
+        let containers = locations_of(StructureType::Container);
+        let links = locations_of(StructureType::Link);
 
-    pub fn insert(&mut self, node: &'s dyn PlanGlobalPlacementNode) -> bool {
-        if !self.inserted.iter().any(|other| std::ptr::eq(node, *other)) {
-            self.inserted.push(node);
+        let container = containers.into_iter().find(|container| {
+            controllers
+                .iter()
+                .any(|controller| controller.distance_to((*container).into()) <= 2)
+        });
 
-            true
-        } else {
-            false
-        }
+        let link = container.and_then(|container| {
+            links
+                .into_iter()
+                .find(|link| link.distance_to(container) <= 1)
+        });
+
+        self.controller_container = container;
+        self.controller_link = link;
     }
-}
 
-struct PlanGatherChildrenLocationData<'s> {
-    desires_location_cache: Vec<(&'s dyn PlanLocationNode, bool)>,
-    visited: Vec<&'s dyn PlanLocationNode>,
-    inserted: Vec<&'s dyn PlanLocationPlacementNode>,
-}
+    /// Tiles kept empty by the plan for structures added later outside of it.
+    pub fn reserved(&self) -> &[Location] {
+        &self.reserved
+    }
 
-impl<'s> PlanGatherChildrenLocationData<'s> {
-    pub fn has_visited(&self, node: &dyn PlanLocationNode) -> bool {
-        self.visited.iter().any(|other| std::ptr::eq(node, *other))
+    /// Every structure planned on a tile. A tile may hold a rampart over another structure, such
+    /// as a ramparted road at a chokepoint.
+    pub fn tile_structures(&self, location: Location) -> &[RoomItem] {
+        self.state
+            .get(&location)
+            .map(|entries| entries.as_slice())
+            .unwrap_or(&[])
     }
 
-    pub fn mark_visited(&mut self, node: &'s dyn PlanLocationNode) {
-        if !self.has_visited(node) {
-            self.visited.push(node);
-        }
+    /// Number of non-road structures in this plan that the given state does not place at the
+    /// same location. Roads are cheap to rebuild and are not counted.
+    pub fn diff_count(&self, state: &PlannerState) -> usize {
+        self.state
+            .iter()
+            .flat_map(|(location, entries)| entries.iter().map(move |entry| (location, entry)))
+            .filter(|(_, entry)| entry.structure_type != StructureType::Road)
+            .filter(|(location, entry)| {
+                !state
+                    .get(location)
+                    .map(|items| {
+                        items
+                            .iter()
+                            .any(|item| item.structure_type == entry.structure_type)
+                    })
+                    .unwrap_or(false)
+            })
+            .count()
     }
 
-    pub fn insert(&mut self, node: &'s dyn PlanLocationPlacementNode) -> bool {
-        if !self.inserted.iter().any(|other| std::ptr::eq(node, *other)) {
-            self.inserted.push(node);
+    // The planned structures for changing, dropping the sorted build order so it is rebuilt.
+    fn state_mut(&mut self) -> &mut PlanState {
+        self.build_order.take();
 
-            true
-        } else {
-            false
-        }
+        &mut self.state
     }
-}
-
-pub struct PlanGatherChildrenData<'a> {
-    desires_placement_cache: Vec<(&'a dyn PlanBaseNode, bool)>,
-    global_nodes: PlanGatherChildrenGlobalData<'a>,
-    location_nodes: FnvHashMap<PlanLocation, PlanGatherChildrenLocationData<'a>>,
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanGatherChildrenData<'a> {
-    pub fn new<'b>() -> PlanGatherChildrenData<'b> {
-        PlanGatherChildrenData {
-            desires_placement_cache: Vec::new(),
-            global_nodes: PlanGatherChildrenGlobalData {
-                visited: Vec::new(),
-                inserted: Vec::new(),
-            },
-            location_nodes: FnvHashMap::default(),
-        }
+    pub fn has_structure(&self, location: Location, structure_type: StructureType) -> bool {
+        self.state
+            .get(&location)
+            .map(|entries| entries.iter().any(|e| e.structure_type == structure_type))
+            .unwrap_or(false)
     }
 
-    pub fn desires_placement(
-        &mut self,
-        node: &'a dyn PlanBaseNode,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> bool {
-        match self
-            .desires_placement_cache
+    /// Damage per tick all planned towers deal together on each tile, the sum of
+    /// `tower_damage_at_range` over the towers. Each tower adds from `TOWER_DAMAGE_MIN` (150) at
+    /// range 20 or more up to `TOWER_DAMAGE_MAX` (600) within range 5, so a walkable tile holds
+    /// between 150 and 600 times the tower count. Walls are left at 0, as is every tile of a plan
+    /// without towers.
+    pub fn tower_coverage_map(&self, terrain: &FastRoomTerrain) -> RoomDataArray<u32> {
+        let towers: Vec<_> = self
+            .state
             .iter()
-            .position(|(other, _)| std::ptr::eq(node, *other))
-        {
-            Some(index) => self.desires_placement_cache[index].1,
-            None => {
-                let desires_placement = node.desires_placement(context, state, self);
+            .filter(|(_, entries)| {
+                entries
+                    .iter()
+                    .any(|e| e.structure_type == StructureType::Tower)
+            })
+            .map(|(location, _)| *location)
+            .collect();
 
-                self.desires_placement_cache.push((node, desires_placement));
+        let mut data = RoomDataArray::new(0);
 
-                desires_placement
+        for y in 0..ROOM_HEIGHT {
+            for x in 0..ROOM_WIDTH {
+                if terrain.get_xy(x, y).contains(TerrainFlags::WALL) {
+                    continue;
+                }
+
+                let location = Location::from_coords(x as u32, y as u32);
+
+                let damage = towers
+                    .iter()
+                    .map(|tower| tower_damage_at_range(tower.distance_to(location) as u32))
+                    .sum();
+
+                data.set(x as usize, y as usize, damage);
             }
         }
+
+        data
     }
 
-    pub fn desires_location(
+    /// Moves one planned structure from `from` to `to`, keeping its required RCL. The target must
+    /// be inside the build area and not a wall, and may only hold a rampart - or, when moving a
+    /// rampart, any other structure. The moved structure must then be able to reach the
+    /// remaining roads, and when a road is moved so must every structure next to the tile it
+    /// left. If any check fails the plan is left as it was. A moved road no longer counts as one
+    /// already built in the room.
+    pub fn move_structure(
         &mut self,
-        position: PlanLocation,
-        node: &'a dyn PlanLocationNode,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> bool {
-        {
-            if let Some(location_data) = self.try_get_location_data(position) {
-                if let Some(index) = location_data
-                    .desires_location_cache
+        from: Location,
+        to: Location,
+        structure_type: StructureType,
+        terrain: &FastRoomTerrain,
+    ) -> Result<(), PlanInconsistency> {
+        let source_index = self
+            .state
+            .get(&from)
+            .and_then(|entries| {
+                entries
                     .iter()
-                    .position(|(other, _)| std::ptr::eq(node, *other))
-                {
-                    return location_data.desires_location_cache[index].1;
-                }
-            }
+                    .position(|e| e.structure_type == structure_type)
+            })
+            .ok_or(PlanInconsistency::MissingStructure)?;
+
+        if from == to {
+            return Ok(());
         }
 
-        let desires_location = node.desires_location(position, context, state, self);
+        if !to.in_room_from_edge(ROOM_BUILD_BORDER as u32 + 1) {
+            return Err(PlanInconsistency::OutOfBounds);
+        }
 
-        let location_data = self.get_location_data(position);
+        if terrain.get(&to).contains(TerrainFlags::WALL) {
+            return Err(PlanInconsistency::Wall);
+        }
 
-        if !location_data
-            .desires_location_cache
-            .iter()
-            .any(|(other, _)| std::ptr::eq(node, *other))
-        {
-            location_data
-                .desires_location_cache
-                .push((node, desires_location));
+        for existing in self.state.get(&to).iter().flat_map(|v| v.iter()) {
+            let valid = match existing.structure_type {
+                StructureType::Rampart => structure_type != StructureType::Rampart,
+                _ => structure_type == StructureType::Rampart,
+            };
+
+            if !valid {
+                return Err(PlanInconsistency::Occupied(existing.structure_type));
+            }
         }
 
-        desires_location
-    }
+        let item = {
+            let entries = self.state_mut().get_mut(&from).unwrap();
+            let item = entries.remove(source_index);
 
-    fn get_location_data(
-        &mut self,
-        position: PlanLocation,
-    ) -> &mut PlanGatherChildrenLocationData<'a> {
-        self.location_nodes
-            .entry(position)
-            .or_insert_with(|| PlanGatherChildrenLocationData {
-                desires_location_cache: Vec::new(),
-                visited: Vec::new(),
-                inserted: Vec::new(),
+            if entries.is_empty() {
+                self.state_mut().remove(&from);
+            }
+
+            item
+        };
+
+        self.state_mut().entry(to).or_default().push(item);
+
+        let roads: Vec<Location> = self
+            .state
+            .iter()
+            .filter(|(location, entries)| {
+                **location != to
+                    && entries
+                        .iter()
+                        .any(|e| e.structure_type == StructureType::Road)
             })
-    }
+            .map(|(location, _)| *location)
+            .collect();
 
-    fn try_get_location_data(
-        &self,
-        position: PlanLocation,
-    ) -> Option<&PlanGatherChildrenLocationData<'a>> {
-        self.location_nodes.get(&position)
-    }
+        let check = if !roads.is_empty() && self.find_path_to(to, &roads, 1, terrain).is_none() {
+            Err(PlanInconsistency::Unreachable)
+        } else if structure_type == StructureType::Road {
+            let remaining_roads: Vec<Location> =
+                roads.iter().copied().chain(std::iter::once(to)).collect();
+
+            // Structures the road served must still reach the roads left.
+            let stranded = self
+                .structures_in_range(from, 1)
+                .into_iter()
+                .filter(|(_, other)| {
+                    !matches!(
+                        other,
+                        StructureType::Road | StructureType::Rampart | StructureType::Wall
+                    )
+                })
+                .map(|(location, _)| location)
+                .find(|location| {
+                    self.find_path_to(*location, &remaining_roads, 1, terrain)
+                        .is_none()
+                });
 
-    pub fn has_visited_global(&self, node: &'a dyn PlanGlobalNode) -> bool {
-        self.global_nodes.has_visited(node)
-    }
+            match stranded {
+                Some(location) => Err(PlanInconsistency::Stranded(location)),
+                None => Ok(()),
+            }
+        } else {
+            Ok(())
+        };
 
-    pub fn mark_visited_global(&mut self, node: &'a dyn PlanGlobalNode) {
-        self.global_nodes.mark_visited(node);
-    }
+        if let Err(err) = check {
+            let entries = self.state_mut().get_mut(&to).unwrap();
+            let item = entries.pop().unwrap();
 
-    pub fn has_visited_location(
-        &self,
-        position: PlanLocation,
-        node: &'a dyn PlanLocationNode,
-    ) -> bool {
-        self.try_get_location_data(position)
-            .map(|l| l.has_visited(node))
-            .unwrap_or(false)
-    }
+            if entries.is_empty() {
+                self.state_mut().remove(&to);
+            }
 
-    pub fn mark_visited_location(
-        &mut self,
-        position: PlanLocation,
-        node: &'a dyn PlanLocationNode,
-    ) {
-        let location_data = self.get_location_data(position);
+            let entries = self.state_mut().entry(from).or_default();
+            entries.insert(source_index.min(entries.len()), item);
 
-        location_data.mark_visited(node);
-    }
+            return Err(err);
+        }
 
-    pub fn insert_global_placement(&mut self, node: &'a dyn PlanGlobalPlacementNode) -> bool {
-        self.global_nodes.insert(node)
-    }
+        // A moved road is no longer the one built in the room, but a routed road stays routed.
+        if structure_type == StructureType::Road {
+            self.existing_roads.retain(|location| *location != from);
 
-    pub fn insert_location_placement(
-        &mut self,
-        position: PlanLocation,
-        node: &'a dyn PlanLocationPlacementNode,
-    ) -> bool {
-        let location_data = self.get_location_data(position);
+            for location in self.routed_roads.iter_mut().filter(|l| **l == from) {
+                *location = to;
+            }
+        }
 
-        location_data.insert(node)
+        Ok(())
     }
 
-    pub fn collect(self) -> Vec<PlanNodeChild<'a>> {
-        let globals = self
-            .global_nodes
-            .inserted
-            .iter()
-            .map(|node| PlanNodeChild::GlobalPlacement(*node));
+    /// Walls and ramparts ranked by how close they are to an exit, with the most exposed
+    /// structures given priority 1. Structures unreachable from an exit are ranked last.
+    pub fn defense_priority(&self, terrain: &FastRoomTerrain) -> Vec<(Location, u8)> {
+        let exits: FnvHashSet<PlanLocation> = terrain.get_exits().map(|l| l.into()).collect();
 
-        self.location_nodes
+        let mut exit_distances = RoomDataArray::new(None);
+
+        flood_fill_distance(exits, terrain, &mut exit_distances, |_| true);
+
+        let mut defenses: Vec<_> = self
+            .state
             .iter()
-            .flat_map(|(location, location_data)| {
-                location_data
-                    .inserted
-                    .iter()
-                    .map(move |node| PlanNodeChild::LocationPlacement(*location, *node))
+            .filter(|(_, entries)| {
+                entries.iter().any(|e| {
+                    matches!(
+                        e.structure_type,
+                        StructureType::Wall | StructureType::Rampart
+                    )
+                })
             })
-            .chain(globals)
-            .collect()
-    }
-}
+            .map(|(location, _)| {
+                let distance = *exit_distances.get(location.x() as usize, location.y() as usize);
 
-pub struct NodeContext<'d> {
-    data_source: &'d mut dyn PlannerRoomDataSource,
+                (*location, distance.unwrap_or(u32::MAX))
+            })
+            .collect();
 
-    wall_distance: Option<RoomDataArray<Option<u32>>>,
-    source_distances: Option<Vec<(RoomDataArray<Option<u32>>, u32)>>,
-}
+        defenses.sort_by_key(|(location, distance)| (*distance, location.packed_repr()));
 
-impl<'d> NodeContext<'d> {
-    pub fn new<'a>(data_source: &'a mut dyn PlannerRoomDataSource) -> NodeContext<'a> {
-        NodeContext {
-            data_source,
-            wall_distance: None,
-            source_distances: None,
-        }
-    }
+        let mut priority: u8 = 0;
+        let mut last_distance = None;
 
-    pub fn terrain(&mut self) -> &FastRoomTerrain {
-        self.data_source.get_terrain()
-    }
+        defenses
+            .into_iter()
+            .map(|(location, distance)| {
+                if last_distance != Some(distance) {
+                    priority = priority.saturating_add(1);
+                    last_distance = Some(distance);
+                }
 
-    pub fn controllers(&mut self) -> &[PlanLocation] {
-        self.data_source.get_controllers()
+                (location, priority)
+            })
+            .collect()
     }
 
-    pub fn sources(&mut self) -> &[PlanLocation] {
-        self.data_source.get_sources()
-    }
+    /// Tiles inside the defensive perimeter - every walkable tile that can't be reached from an
+    /// exit without crossing a wall or rampart. The perimeter itself is not included.
+    pub fn interior_tiles(&self, terrain: &FastRoomTerrain) -> FnvHashSet<Location> {
+        let is_defense = |location: &Location| {
+            self.state
+                .get(location)
+                .map(|entries| {
+                    entries.iter().any(|e| {
+                        matches!(
+                            e.structure_type,
+                            StructureType::Wall | StructureType::Rampart
+                        )
+                    })
+                })
+                .unwrap_or(false)
+        };
 
-    pub fn minerals(&mut self) -> &[PlanLocation] {
-        self.data_source.get_minerals()
-    }
+        let exits: FnvHashSet<PlanLocation> = terrain.get_exits().map(|l| l.into()).collect();
 
-    pub fn wall_distance(&mut self) -> &RoomDataArray<Option<u32>> {
-        if self.wall_distance.is_none() {
-            let mut data: RoomDataArray<Option<u32>> = RoomDataArray::new(None);
-            let mut to_apply: FnvHashSet<PlanLocation> = FnvHashSet::default();
+        let mut outside = RoomDataArray::new(None);
 
-            let terrain = self.terrain();
+        flood_fill_distance(exits, terrain, &mut outside, |location| {
+            location
+                .as_location()
+                .map(|location| !is_defense(&location))
+                .unwrap_or(false)
+        });
 
-            for y in 0..ROOM_HEIGHT {
-                for x in 0..ROOM_WIDTH {
-                    let terrain_cell = terrain.get_xy(x, y);
+        let mut interior = FnvHashSet::default();
 
-                    if terrain_cell.contains(TerrainFlags::WALL) || !in_room_build_bounds(x, y) {
-                        to_apply.insert(PlanLocation::new(x as i8, y as i8));
-                    }
+        for y in 0..ROOM_HEIGHT {
+            for x in 0..ROOM_WIDTH {
+                let location = Location::from_coords(x as u32, y as u32);
+
+                if !terrain.get_xy(x, y).contains(TerrainFlags::WALL)
+                    && outside.get(x as usize, y as usize).is_none()
+                    && !is_defense(&location)
+                {
+                    interior.insert(location);
                 }
             }
+        }
 
-            flood_fill_distance(to_apply, terrain, &mut data, |_| true);
+        interior
+    }
 
-            self.wall_distance = Some(data);
-        }
+    /// Tiles inside the defensive perimeter that the plan leaves without any structure.
+    pub fn empty_interior_tiles(&self, terrain: &FastRoomTerrain) -> Vec<Location> {
+        let mut tiles: Vec<_> = self
+            .interior_tiles(terrain)
+            .into_iter()
+            .filter(|location| {
+                self.state
+                    .get(location)
+                    .map(|entries| entries.is_empty())
+                    .unwrap_or(true)
+            })
+            .collect();
 
-        self.wall_distance.as_ref().unwrap()
+        tiles.sort_by_key(|location| location.packed_repr());
+
+        tiles
     }
 
-    pub fn source_distances(&mut self) -> &[(RoomDataArray<Option<u32>>, u32)] {
-        if self.source_distances.is_none() {
-            let mut sources_data = Vec::new();
+    /// Whether each exit tile is sealed off from the base - that is, whether a hostile creep
+    /// entering at that exit can't reach any tile next to a planned structure without crossing a
+    /// wall or rampart.
+    pub fn exit_seal_status(&self, terrain: &FastRoomTerrain) -> Vec<(Location, bool)> {
+        let is_passable = |location: PlanLocation| {
+            location
+                .as_location()
+                .and_then(|location| self.state.get(&location))
+                .map(|entries| {
+                    entries.iter().all(|e| {
+                        matches!(
+                            e.structure_type,
+                            StructureType::Road | StructureType::Container
+                        )
+                    })
+                })
+                .unwrap_or(true)
+        };
 
-            let sources = { self.sources().to_vec() };
-            let terrain = self.terrain();
+        let core_locations: Vec<_> = self
+            .state
+            .iter()
+            .filter(|(_, entries)| {
+                entries.iter().any(|e| {
+                    !is_walkable_structure(e.structure_type)
+                        && e.structure_type != StructureType::Wall
+                })
+            })
+            .map(|(location, _)| *location)
+            .collect();
 
-            for source in sources.iter() {
-                let mut data: RoomDataArray<Option<u32>> = RoomDataArray::new(None);
-                let mut to_apply: FnvHashSet<PlanLocation> = FnvHashSet::default();
+        let mut exit_status: FnvHashMap<Location, bool> = FnvHashMap::default();
+
+        let exits: Vec<_> = terrain.get_exits().collect();
+
+        for exit in exits.iter() {
+            if exit_status.contains_key(exit) {
+                continue;
+            }
+
+            let mut reachable = RoomDataArray::new(None);
 
-                to_apply.insert(*source);
+            let mut seeds = FnvHashSet::default();
+            seeds.insert((*exit).into());
 
-                let max_distance = flood_fill_distance(to_apply, terrain, &mut data, |_| true);
+            flood_fill_distance(seeds, terrain, &mut reachable, is_passable);
 
-                sources_data.push((data, max_distance));
+            let is_reachable = |location: &Location| {
+                reachable
+                    .get(location.x() as usize, location.y() as usize)
+                    .is_some()
+            };
+
+            let breached = core_locations.iter().any(|location| {
+                ONE_OFFSET_SQUARE
+                    .iter()
+                    .filter_map(|offset| (PlanLocation::from(*location) + offset).as_location())
+                    .any(|adjacent| is_reachable(&adjacent))
+            });
+
+            for other_exit in exits.iter().filter(|other_exit| is_reachable(other_exit)) {
+                exit_status.insert(*other_exit, !breached);
             }
 
-            self.source_distances = Some(sources_data);
+            exit_status.entry(*exit).or_insert(!breached);
         }
 
-        self.source_distances.as_ref().unwrap()
+        exits
+            .into_iter()
+            .map(|exit| (exit, exit_status[&exit]))
+            .collect()
     }
-}
 
-pub trait PlanBaseNode {
-    fn name(&self) -> &str;
+    /// Energy per tick needed to hold all planned ramparts at `rampart_target_hits`. A rampart
+    /// loses at most `RAMPART_DECAY_AMOUNT` hits per decay, however many it has, so targets above
+    /// that only cost energy to reach - the steady state upkeep is the same. Constructed walls do
+    /// not decay and so cost nothing to maintain once built.
+    pub fn defense_upkeep_per_tick(&self, rampart_target_hits: u32) -> f32 {
+        let rampart_count = self
+            .state
+            .values()
+            .flat_map(|entries| entries.iter())
+            .filter(|entry| entry.structure_type == StructureType::Rampart)
+            .count();
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool;
+        let decayed_hits = rampart_target_hits.min(RAMPART_DECAY_AMOUNT);
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>);
-}
+        let rampart_upkeep = (decayed_hits as f32 / RAMPART_DECAY_TIME as f32) * REPAIR_COST;
 
-pub trait PlanGlobalNode: PlanBaseNode {
-    fn as_base(&self) -> &dyn PlanBaseNode;
+        rampart_count as f32 * rampart_upkeep
+    }
 
-    fn get_children<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    );
-}
+    /// Picks two input labs that every other planned lab can react with, or `None` if there are
+    /// fewer than three labs or no pair reaches all of them.
+    pub fn lab_roles(&self) -> Option<LabRoles> {
+        let mut labs: Vec<_> = self
+            .state
+            .iter()
+            .filter(|(_, entries)| {
+                entries
+                    .iter()
+                    .any(|e| e.structure_type == StructureType::Lab)
+            })
+            .map(|(location, _)| *location)
+            .collect();
 
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub enum PlacementPhase {
-    Pre,
-    Normal,
-    Post,
-}
+        if labs.len() < 3 {
+            return None;
+        }
 
-pub trait PlanGlobalPlacementNode: PlanGlobalNode {
-    fn as_global(&self) -> &dyn PlanGlobalNode;
+        labs.sort_by_key(|location| location.packed_repr());
 
-    fn id(&self) -> &uuid::Uuid;
+        labs.iter()
+            .tuple_combinations()
+            .find(|(first, second)| {
+                labs.iter()
+                    .filter(|lab| lab != first && lab != second)
+                    .all(|lab| lab.distance_to(**first) <= 2 && lab.distance_to(**second) <= 2)
+            })
+            .map(|(first, second)| LabRoles {
+                inputs: [*first, *second],
+                outputs: labs
+                    .iter()
+                    .filter(|lab| *lab != first && *lab != second)
+                    .copied()
+                    .collect(),
+            })
+    }
 
-    fn placement_phase(&self) -> PlacementPhase;
+    /// Round trip path lengths of the main hauling routes - each source and mineral container to
+    /// storage, and storage to the controller container. Each leg ends next to storage, where a
+    /// hauler can transfer. Paths avoid planned structures other than roads, containers and
+    /// ramparts. Empty if the plan has no storage.
+    pub fn haul_routes(&self, terrain: &FastRoomTerrain) -> Vec<HaulRoute> {
+        let locations_of = |structure_type: StructureType| -> Vec<Location> {
+            let mut locations: Vec<_> = self
+                .state
+                .iter()
+                .filter(|(_, entries)| entries.iter().any(|e| e.structure_type == structure_type))
+                .map(|(location, _)| *location)
+                .collect();
 
-    fn must_place(&self) -> bool;
+            locations.sort_by_key(|location| location.packed_repr());
 
-    fn get_maximum_score(&self, context: &mut NodeContext, state: &PlannerState) -> Option<f32>;
+            locations
+        };
 
-    fn get_score(&self, context: &mut NodeContext, state: &PlannerState) -> Option<f32>;
+        let storage = match locations_of(StructureType::Storage).first() {
+            Some(storage) => *storage,
+            None => return Vec::new(),
+        };
 
-    fn ready_for_placement(&self, context: &mut NodeContext, state: &PlannerState) -> bool;
+        // paths always run from the container to storage so both directions of a route agree,
+        // and the path includes the container tile the hauler starts on
+        let route_distance = |container: Location| {
+            self.find_path_to(container, &[storage], 1, terrain)
+                .map(|path| 2 * path.len().saturating_sub(1) as u32)
+        };
 
-    fn place(&self, context: &mut NodeContext, state: &mut PlannerState) -> Result<(), ()>;
-}
+        let mut routes = Vec::new();
 
-pub trait PlanGlobalExpansionNode: PlanGlobalNode {
-    fn as_global(&self) -> &dyn PlanGlobalNode;
-}
+        for container in locations_of(StructureType::Container) {
+            let distance = match route_distance(container) {
+                Some(distance) => distance,
+                None => continue,
+            };
 
-pub trait PlanLocationNode: PlanBaseNode {
-    fn as_base(&self) -> &dyn PlanBaseNode;
+            if Some(container) == self.controller_container {
+                routes.push(HaulRoute {
+                    from: storage,
+                    to: container,
+                    distance,
+                });
+            } else {
+                routes.push(HaulRoute {
+                    from: container,
+                    to: storage,
+                    distance,
+                });
+            }
+        }
 
-    fn desires_location<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool;
+        routes
+    }
 
-    fn get_children<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    );
-}
+    /// The planned spawn closest to `to` by linear distance.
+    pub fn nearest_spawn(&self, to: Location) -> Option<Location> {
+        self.state
+            .iter()
+            .filter(|(_, entries)| {
+                entries
+                    .iter()
+                    .any(|e| e.structure_type == StructureType::Spawn)
+            })
+            .map(|(location, _)| *location)
+            .min_by_key(|location| (location.distance_to(to), location.packed_repr()))
+    }
 
-pub trait PlanLocationPlacementNode: PlanLocationNode {
-    fn as_location(&self) -> &dyn PlanLocationNode;
+    /// The tile a filler creep stands on to keep the most spawns, extensions, links, storage
+    /// and terminal topped up, such as the center of the bunker core. Recorded when the plan is
+    /// produced by the planner.
+    pub fn filler_position(&self) -> Option<Location> {
+        self.filler_position
+    }
 
-    fn id(&self) -> &uuid::Uuid;
+    /// Records the filler position - the open tile within range 1 of the most fillable
+    /// structures. The tile must not be a wall or reserved and may hold nothing but roads and
+    /// ramparts. Ties are broken by location.
+    fn locate_filler_position(&mut self, terrain: &FastRoomTerrain) {
+        let is_fillable = |structure_type: StructureType| {
+            matches!(
+                structure_type,
+                StructureType::Spawn
+                    | StructureType::Extension
+                    | StructureType::Link
+                    | StructureType::Storage
+                    | StructureType::Terminal
+            )
+        };
 
-    fn placement_phase(&self) -> PlacementPhase;
+        let candidates: FnvHashSet<Location> = self
+            .state
+            .iter()
+            .filter(|(_, entries)| entries.iter().any(|e| is_fillable(e.structure_type)))
+            .flat_map(|(location, _)| {
+                ONE_OFFSET_SQUARE.iter().filter_map(move |offset| {
+                    (PlanLocation::from(*location) + offset).as_location()
+                })
+            })
+            .filter(|location| {
+                !terrain
+                    .get_xy(location.x(), location.y())
+                    .contains(TerrainFlags::WALL)
+            })
+            .filter(|location| !self.reserved.contains(location))
+            .filter(|location| {
+                self.tile_structures(*location).iter().all(|item| {
+                    matches!(
+                        item.structure_type,
+                        StructureType::Road | StructureType::Rampart
+                    )
+                })
+            })
+            .collect();
 
-    fn must_place(&self) -> bool;
+        self.filler_position = candidates
+            .into_iter()
+            .map(|location| {
+                let fillable = self
+                    .structures_in_range(location, 1)
+                    .into_iter()
+                    .filter(|(_, structure_type)| is_fillable(*structure_type))
+                    .count();
 
-    fn get_maximum_score(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> Option<f32>;
+                (location, fillable)
+            })
+            .max_by_key(|(location, fillable)| {
+                (*fillable, std::cmp::Reverse(location.packed_repr()))
+            })
+            .map(|(location, _)| location);
+    }
 
-    fn get_score(
+    pub fn structures_in_range(
         &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> Option<f32>;
+        center: Location,
+        range: u8,
+    ) -> Vec<(Location, StructureType)> {
+        self.state
+            .iter()
+            .filter(|(location, _)| location.distance_to(center) <= range)
+            .flat_map(|(location, entries)| {
+                entries
+                    .iter()
+                    .map(move |entry| (*location, entry.structure_type))
+            })
+            .collect()
+    }
 
-    fn ready_for_placement(&self, context: &mut NodeContext, state: &PlannerState) -> bool;
+    fn nearest_road_rcl(&self, location: Location) -> u8 {
+        self.state
+            .iter()
+            .filter(|(other, _)| other.in_room_from_edge(1))
+            .flat_map(|(other, entries)| entries.iter().map(move |entry| (other, entry)))
+            .filter(|(_, entry)| entry.structure_type == StructureType::Road)
+            .min_by_key(|(other, _)| other.distance_to(location))
+            .map(|(_, entry)| entry.required_rcl)
+            .unwrap_or(1)
+    }
 
-    fn place(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &mut PlannerState,
-    ) -> Result<(), ()>;
-}
+    fn insert_road(&mut self, location: Location) {
+        let required_rcl = self.nearest_road_rcl(location);
 
-pub trait PlanPlacementExpansionNode: PlanLocationNode {
-    fn as_location(&self) -> &dyn PlanLocationNode;
-}
+        self.insert_routed_road(location, required_rcl);
+    }
 
-pub enum PlanNodeStorage<'a> {
-    Empty,
-    GlobalPlacement(&'a dyn PlanGlobalPlacementNode),
-    GlobalExpansion(&'a dyn PlanGlobalExpansionNode),
-    LocationPlacement(&'a dyn PlanLocationPlacementNode),
-    LocationExpansion(&'a dyn PlanPlacementExpansionNode),
-}
+    fn insert_routed_road(&mut self, location: Location, required_rcl: u8) {
+        self.state_mut()
+            .entry(location)
+            .or_default()
+            .push(RoomItem {
+                structure_type: StructureType::Road,
+                required_rcl,
+            });
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanNodeStorage<'a> {
-    fn gather_nodes(&self, data: &mut PlanGatherNodesData<'a>) {
-        match self {
-            PlanNodeStorage::Empty => {}
-            PlanNodeStorage::GlobalPlacement(n) => n.gather_nodes(data),
-            PlanNodeStorage::GlobalExpansion(n) => n.gather_nodes(data),
-            PlanNodeStorage::LocationPlacement(n) => n.gather_nodes(data),
-            PlanNodeStorage::LocationExpansion(n) => n.gather_nodes(data),
+        self.routed_roads.push(location);
+    }
+
+    /// Where the road at the location came from, or `None` without a planned road. Roads not
+    /// seeded or routed by the planner, including every road of a compacted plan, are reported
+    /// as stamp roads.
+    pub fn road_origin(&self, location: Location) -> Option<RoadOrigin> {
+        if !self.has_structure(location, StructureType::Road) {
+            None
+        } else if self.existing_roads.contains(&location) {
+            Some(RoadOrigin::Existing)
+        } else if self.routed_roads.contains(&location) {
+            Some(RoadOrigin::Routed)
+        } else {
+            Some(RoadOrigin::Stamp)
         }
     }
 
-    fn desires_placement(
-        &self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'a>,
-    ) -> bool {
-        match self {
-            PlanNodeStorage::Empty => false,
-            PlanNodeStorage::GlobalPlacement(n) => {
-                gather_data.desires_placement(n.as_base(), context, state)
-            }
-            PlanNodeStorage::GlobalExpansion(n) => {
-                gather_data.desires_placement(n.as_base(), context, state)
-            }
-            PlanNodeStorage::LocationPlacement(n) => {
-                gather_data.desires_placement(n.as_base(), context, state)
-            }
-            PlanNodeStorage::LocationExpansion(n) => {
-                gather_data.desires_placement(n.as_base(), context, state)
+    // Adds a road at the location along with the roads joining it to the nearest existing road.
+    fn insert_connected_road(&mut self, location: Location, terrain: &FastRoomTerrain) {
+        let path = self
+            .find_path_to_road(location, terrain)
+            .unwrap_or_default();
+
+        for path_location in std::iter::once(location).chain(path) {
+            if !self.has_structure(path_location, StructureType::Road) {
+                self.insert_road(path_location);
             }
         }
     }
 
-    fn desires_location(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'a>,
-    ) -> bool {
-        match self {
-            PlanNodeStorage::Empty => false,
-            PlanNodeStorage::GlobalPlacement(_) => true,
-            PlanNodeStorage::GlobalExpansion(_) => true,
-            PlanNodeStorage::LocationPlacement(n) => {
-                gather_data.desires_location(position, n.as_location(), context, state)
+    /// Joins each container to the roads around storage, so haulers servicing it are not forced
+    /// onto plain or swamp terrain. Containers already next to a road joined to storage are left
+    /// alone, otherwise the cheapest route avoiding walls and reserved tiles is paved. Without a
+    /// storage every road counts as joined. Fails if a container has no route to the roads.
+    pub fn connect_container_roads(&mut self, terrain: &FastRoomTerrain) -> Result<(), String> {
+        let containers: Vec<_> = self
+            .state
+            .iter()
+            .flat_map(|(location, entries)| entries.iter().map(move |entry| (*location, entry)))
+            .filter(|(_, entry)| entry.structure_type == StructureType::Container)
+            .map(|(location, entry)| (location, entry.required_rcl))
+            .collect();
+
+        for (container_location, container_rcl) in containers {
+            let network = self.hub_road_network();
+
+            let has_adjacent_road = network
+                .iter()
+                .any(|location| location.distance_to(container_location) <= 1);
+
+            if has_adjacent_road {
+                continue;
             }
-            PlanNodeStorage::LocationExpansion(n) => {
-                gather_data.desires_location(position, n.as_location(), context, state)
+
+            let path = self
+                .find_road_path_to(container_location, &network, 0, terrain)
+                .ok_or_else(|| {
+                    format!(
+                        "No road route to container at {}, {}",
+                        container_location.x(),
+                        container_location.y()
+                    )
+                })?;
+
+            for location in path {
+                if location == container_location
+                    || self.has_structure(location, StructureType::Road)
+                {
+                    continue;
+                }
+
+                self.insert_routed_road(location, container_rcl);
             }
         }
+
+        Ok(())
     }
 
-    fn insert_or_expand(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'a>,
-    ) {
-        match self {
-            PlanNodeStorage::Empty => {}
-            PlanNodeStorage::GlobalPlacement(n) => {
-                gather_data.insert_global_placement(*n);
+    /// Extends the roads around storage onto each open side of the room, so haulers heading to
+    /// other rooms stay on roads up to the border. On each side with an exit, the exit tile
+    /// nearest the hub roads is joined to them by the cheapest route, paved at the RCL of the
+    /// road it joins. Sides already with a border road, or whose nearest exit has no route, are
+    /// left alone.
+    pub fn connect_exit_roads(&mut self, terrain: &FastRoomTerrain) {
+        let sides = [
+            ExitSide::Top,
+            ExitSide::Right,
+            ExitSide::Bottom,
+            ExitSide::Left,
+        ];
+
+        for side in sides.iter() {
+            let exits: Vec<_> = (1..(ROOM_WIDTH.min(ROOM_HEIGHT) - 1))
+                .map(|index| side.border_location(index))
+                .filter(|location| !terrain.get(location).contains(TerrainFlags::WALL))
+                .collect();
+
+            if exits
+                .iter()
+                .any(|exit| self.has_structure(*exit, StructureType::Road))
+            {
+                continue;
             }
-            PlanNodeStorage::GlobalExpansion(n) => n.get_children(context, state, gather_data),
-            PlanNodeStorage::LocationPlacement(n) => {
-                gather_data.insert_location_placement(position, *n);
+
+            let network = self.hub_road_network();
+
+            let nearest = exits.iter().min_by_key(|exit| {
+                let distance = network
+                    .iter()
+                    .map(|road| road.distance_to(**exit))
+                    .min()
+                    .unwrap_or(u8::MAX);
+
+                (distance, exit.packed_repr())
+            });
+
+            let path = match nearest
+                .and_then(|exit| self.find_road_path_to(*exit, &network, 0, terrain))
+            {
+                Some(path) => path,
+                None => continue,
+            };
+
+            // The path ends on the road it joins.
+            let required_rcl = path
+                .last()
+                .and_then(|joined| self.state.get(joined))
+                .and_then(|entries| {
+                    entries
+                        .iter()
+                        .find(|e| e.structure_type == StructureType::Road)
+                })
+                .map(|road| road.required_rcl)
+                .unwrap_or(1);
+
+            for location in path {
+                if !self.has_structure(location, StructureType::Road) {
+                    self.insert_routed_road(location, required_rcl);
+                }
             }
-            PlanNodeStorage::LocationExpansion(n) => {
-                n.get_children(position, context, state, gather_data)
+        }
+    }
+
+    // Roads joined to a road next to storage through neighbouring roads, or every road if there is
+    // no storage.
+    fn hub_road_network(&self) -> Vec<Location> {
+        let roads: FnvHashSet<Location> = self
+            .state
+            .iter()
+            .filter(|(_, entries)| {
+                entries
+                    .iter()
+                    .any(|e| e.structure_type == StructureType::Road)
+            })
+            .map(|(location, _)| *location)
+            .collect();
+
+        let storage_locations: Vec<_> = self
+            .state
+            .iter()
+            .filter(|(_, entries)| {
+                entries
+                    .iter()
+                    .any(|e| e.structure_type == StructureType::Storage)
+            })
+            .map(|(location, _)| *location)
+            .collect();
+
+        if storage_locations.is_empty() {
+            return roads.into_iter().collect();
+        }
+
+        let mut reached: FnvHashSet<Location> = roads
+            .iter()
+            .filter(|road| {
+                storage_locations
+                    .iter()
+                    .any(|storage| storage.distance_to(**road) <= 1)
+            })
+            .copied()
+            .collect();
+
+        let mut to_visit: Vec<_> = reached.iter().copied().collect();
+
+        while let Some(current) = to_visit.pop() {
+            let neighbours = ONE_OFFSET_SQUARE
+                .iter()
+                .filter_map(|offset| (PlanLocation::from(current) + offset).as_location());
+
+            for neighbour in neighbours {
+                if roads.contains(&neighbour) && reached.insert(neighbour) {
+                    to_visit.push(neighbour);
+                }
             }
         }
+
+        reached.into_iter().collect()
     }
-}
 
-fn flood_fill_distance<F>(
-    initial_seeds: FnvHashSet<PlanLocation>,
-    terrain: &FastRoomTerrain,
-    data: &mut RoomDataArray<Option<u32>>,
-    is_passable: F,
-) -> u32
-where
-    F: Fn(PlanLocation) -> bool,
-{
-    let mut to_apply = initial_seeds;
-    let mut current_distance: u32 = 0;
+    /// Adds roads from each extension without a road within `max_distance` to the nearest
+    /// existing road, so fillers are never left walking across open terrain. The planner runs
+    /// this with a distance of 1 by default - see `Planner::with_extension_road_distance`.
+    pub fn connect_extension_roads(&mut self, terrain: &FastRoomTerrain, max_distance: u8) {
+        let extensions: Vec<_> = self
+            .state
+            .iter()
+            .flat_map(|(location, entries)| entries.iter().map(move |entry| (*location, entry)))
+            .filter(|(_, entry)| entry.structure_type == StructureType::Extension)
+            .map(|(location, entry)| (location, entry.required_rcl))
+            .collect();
 
-    loop {
-        let eval_locations = std::mem::replace(&mut to_apply, FnvHashSet::default());
+        for (extension_location, extension_rcl) in extensions {
+            let has_nearby_road = self
+                .state
+                .iter()
+                .filter(|(location, _)| location.distance_to(extension_location) <= max_distance)
+                .any(|(_, entries)| {
+                    entries
+                        .iter()
+                        .any(|e| e.structure_type == StructureType::Road)
+                });
 
-        for pos in &eval_locations {
-            let current = data.get_mut(pos.x() as usize, pos.y() as usize);
+            if has_nearby_road {
+                continue;
+            }
 
-            let allow_expand = if current.is_none() {
-                if is_passable(*pos) {
-                    *current = Some(current_distance);
+            if let Some(path) = self.find_path_to_road(extension_location, terrain) {
+                for location in path {
+                    if location == extension_location
+                        || self.has_structure(location, StructureType::Road)
+                    {
+                        continue;
+                    }
 
-                    true
-                } else {
-                    current_distance == 0
+                    self.insert_routed_road(location, extension_rcl);
                 }
-            } else {
-                false
-            };
+            }
+        }
+    }
 
-            if allow_expand {
-                for offset in ONE_OFFSET_SQUARE {
-                    let next_location = *pos + offset;
-                    if next_location.in_room_bounds() {
-                        let terrain =
-                            terrain.get_xy(next_location.x() as u8, next_location.y() as u8);
-                        if !terrain.contains(TerrainFlags::WALL) {
-                            to_apply.insert(next_location);
-                        }
+    /// Adds a road from each mineral container (a container next to an extractor) to the lab
+    /// cluster, so minerals can be hauled straight to the labs.
+    pub fn connect_mineral_containers_to_labs(&mut self, terrain: &FastRoomTerrain) {
+        let locations_of = |structure_type: StructureType| -> Vec<Location> {
+            self.state
+                .iter()
+                .filter(|(_, entries)| entries.iter().any(|e| e.structure_type == structure_type))
+                .map(|(location, _)| *location)
+                .collect()
+        };
+
+        let labs = locations_of(StructureType::Lab);
+        let extractors = locations_of(StructureType::Extractor);
+
+        if labs.is_empty() {
+            return;
+        }
+
+        let mineral_containers: Vec<_> = self
+            .state
+            .iter()
+            .flat_map(|(location, entries)| entries.iter().map(move |entry| (*location, entry)))
+            .filter(|(location, entry)| {
+                entry.structure_type == StructureType::Container
+                    && extractors
+                        .iter()
+                        .any(|extractor| extractor.distance_to(*location) <= 1)
+            })
+            .map(|(location, entry)| (location, entry.required_rcl))
+            .collect();
+
+        for (container_location, container_rcl) in mineral_containers {
+            if let Some(path) = self.find_road_path_to(container_location, &labs, 1, terrain) {
+                for location in path {
+                    if location == container_location
+                        || self.has_structure(location, StructureType::Road)
+                    {
+                        continue;
                     }
+
+                    self.insert_routed_road(location, container_rcl);
                 }
             }
         }
+    }
 
-        if to_apply.is_empty() {
-            break current_distance;
+    /// Repeatedly removes routed roads with at most one neighbouring road that are not next to a
+    /// structure or one of the `served` locations. Roads leading somewhere and roads serving the
+    /// core are kept, as every road placed next to a structure counts as serving it. Seeded and
+    /// stamp roads are never removed - see `road_origin`.
+    pub fn prune_dead_end_roads(&mut self, served: &[PlanLocation]) {
+        let is_road = |entries: &Vec<RoomItem>| {
+            entries
+                .iter()
+                .any(|e| e.structure_type == StructureType::Road)
+        };
+
+        let is_served_structure = |entries: &Vec<RoomItem>| {
+            entries.iter().any(|e| {
+                !matches!(
+                    e.structure_type,
+                    StructureType::Road | StructureType::Rampart | StructureType::Wall
+                )
+            })
+        };
+
+        loop {
+            let dead_ends: Vec<Location> = self
+                .state
+                .iter()
+                .filter(|(_, entries)| is_road(entries))
+                .map(|(location, _)| *location)
+                .filter(|location| self.road_origin(*location) == Some(RoadOrigin::Routed))
+                .filter(|location| {
+                    let neighbours: Vec<_> = ONE_OFFSET_SQUARE
+                        .iter()
+                        .map(|offset| PlanLocation::from(*location) + offset)
+                        .filter_map(|neighbour| Location::try_from(neighbour).ok())
+                        .filter_map(|neighbour| self.state.get(&neighbour))
+                        .collect();
+
+                    let road_neighbours = neighbours.iter().filter(|e| is_road(e)).count();
+                    let serves_structure = neighbours.iter().any(|e| is_served_structure(e));
+                    let serves_location = served
+                        .iter()
+                        .any(|target| target.distance_to((*location).into()) <= 1);
+
+                    road_neighbours <= 1 && !serves_structure && !serves_location
+                })
+                .collect();
+
+            if dead_ends.is_empty() {
+                break;
+            }
+
+            for location in dead_ends {
+                if let Some(entries) = self.state_mut().get_mut(&location) {
+                    entries.retain(|e| e.structure_type != StructureType::Road);
+
+                    if entries.is_empty() {
+                        self.state_mut().remove(&location);
+                    }
+                }
+
+                self.routed_roads.retain(|routed| *routed != location);
+            }
+        }
+    }
+
+    // Joins the start to the nearest planned road, routed as a new road.
+    fn find_path_to_road(
+        &self,
+        start: Location,
+        terrain: &FastRoomTerrain,
+    ) -> Option<Vec<Location>> {
+        let goals: Vec<Location> = self
+            .state
+            .iter()
+            .filter(|(_, entries)| {
+                entries
+                    .iter()
+                    .any(|e| e.structure_type == StructureType::Road)
+            })
+            .map(|(location, _)| *location)
+            .collect();
+
+        self.find_road_path_to(start, &goals, 0, terrain)
+    }
+
+    // As `find_path_to`, for laying new roads - only stepping orthogonally when the plan was
+    // made without diagonal roads.
+    fn find_road_path_to(
+        &self,
+        start: Location,
+        goals: &[Location],
+        range: u32,
+        terrain: &FastRoomTerrain,
+    ) -> Option<Vec<Location>> {
+        let offsets = if self.orthogonal_roads {
+            ONE_OFFSET_CROSS
+        } else {
+            ONE_OFFSET_SQUARE
+        };
+
+        self.find_path_with_offsets(start, goals, range, terrain, offsets)
+    }
+
+    /// Finds the cheapest walkable path from `start` to within `range` of any of `goals`,
+    /// preferring already built roads over plain and plain over swamp, so new roads join the
+    /// built ones rather than running alongside them. Reserved tiles are avoided so roads are
+    /// never laid on them.
+    fn find_path_to(
+        &self,
+        start: Location,
+        goals: &[Location],
+        range: u32,
+        terrain: &FastRoomTerrain,
+    ) -> Option<Vec<Location>> {
+        self.find_path_with_offsets(start, goals, range, terrain, ONE_OFFSET_SQUARE)
+    }
+
+    fn find_path_with_offsets(
+        &self,
+        start: Location,
+        goals: &[Location],
+        range: u32,
+        terrain: &FastRoomTerrain,
+        offsets: &[(i8, i8)],
+    ) -> Option<Vec<Location>> {
+        if goals.is_empty() {
+            return None;
         }
 
-        current_distance += 1;
+        let is_passable = |location: PlanLocation| {
+            if !location.in_room_from_edge(1) {
+                return false;
+            }
+
+            if let Ok(location) = Location::try_from(location) {
+                if terrain.get(&location).contains(TerrainFlags::WALL)
+                    || self.reserved.contains(&location)
+                {
+                    return false;
+                }
+
+                !self
+                    .state
+                    .get(&location)
+                    .iter()
+                    .flat_map(|v| v.iter())
+                    .any(|item| !is_walkable_structure(item.structure_type))
+            } else {
+                false
+            }
+        };
+
+        let existing_roads: FnvHashSet<Location> = self.existing_roads.iter().copied().collect();
+
+        // Built roads cost half of plain, keeping every step at a cost of at least 1 as the
+        // heuristic assumes.
+        let get_neighbours = |location: &PlanLocation| {
+            let start_location = *location;
+
+            offsets
+                .iter()
+                .map(move |offset| start_location + *offset)
+                .filter(|location| is_passable(*location))
+                .map(|location| {
+                    let cost = Location::try_from(location)
+                        .map(|l| {
+                            if existing_roads.contains(&l) {
+                                1
+                            } else if terrain.get(&l).contains(TerrainFlags::SWAMP) {
+                                10
+                            } else {
+                                2
+                            }
+                        })
+                        .unwrap_or(2);
+
+                    (location, cost)
+                })
+        };
+
+        let distance_to_goals = |p: &PlanLocation| {
+            goals
+                .iter()
+                .map(|g| p.distance_to(g.into()) as u32)
+                .min()
+                .unwrap()
+        };
+
+        astar(
+            &start.into(),
+            get_neighbours,
+            |p| distance_to_goals(p).saturating_sub(range),
+            |p| distance_to_goals(p) <= range,
+        )
+        .map(|(path, _)| {
+            path.into_iter()
+                .filter_map(|l| Location::try_from(l).ok())
+                .collect()
+        })
+    }
+
+    pub fn visualize<V>(&self, visualizer: &mut V)
+    where
+        V: RoomVisualizer,
+    {
+        let items = self
+            .state
+            .iter()
+            .flat_map(|(location, entries)| entries.iter().map(move |entry| (location, entry)));
+
+        visualize_room_items(items, visualizer);
+    }
+
+    /// As `visualize`, only rendering the given structure types.
+    pub fn visualize_filtered<V>(&self, types: &[StructureType], visualizer: &mut V)
+    where
+        V: RoomVisualizer,
+    {
+        let items = self
+            .state
+            .iter()
+            .flat_map(|(location, entries)| entries.iter().map(move |entry| (location, entry)))
+            .filter(|(_, entry)| types.contains(&entry.structure_type));
+
+        visualize_room_items(items, visualizer);
+    }
+}
+
+/// Ensures both plans have roads on the matching tiles of their shared border so that
+/// creeps can cross between the rooms on roads. `side_a` of the first room must face `side_b` of
+/// the second, such as the top of a room and the bottom of the room above it. Only border tiles
+/// walkable in both rooms are used, and each added border road is joined to the rest of its plan's
+/// roads. If neither plan has one, such as plans made without `Planner::with_exit_roads`, a single
+/// crossing is added at the shared exit nearest both road networks.
+pub fn align_exit_roads(
+    plan_a: &mut Plan,
+    terrain_a: &FastRoomTerrain,
+    side_a: ExitSide,
+    plan_b: &mut Plan,
+    terrain_b: &FastRoomTerrain,
+    side_b: ExitSide,
+) -> Result<(), String> {
+    if side_a.opposite() != side_b {
+        return Err(format!("{:?} exit does not face {:?} exit", side_a, side_b));
+    }
+
+    let crossings: Vec<_> = (1..(ROOM_WIDTH.min(ROOM_HEIGHT) - 1))
+        .map(|index| (side_a.border_location(index), side_b.border_location(index)))
+        .filter(|(location_a, location_b)| {
+            !terrain_a.get(location_a).contains(TerrainFlags::WALL)
+                && !terrain_b.get(location_b).contains(TerrainFlags::WALL)
+        })
+        .collect();
+
+    let mut has_crossing = false;
+
+    for (location_a, location_b) in crossings.iter() {
+        let has_road_a = plan_a.has_structure(*location_a, StructureType::Road);
+        let has_road_b = plan_b.has_structure(*location_b, StructureType::Road);
+
+        if has_road_a && !has_road_b {
+            plan_b.insert_connected_road(*location_b, terrain_b);
+        } else if has_road_b && !has_road_a {
+            plan_a.insert_connected_road(*location_a, terrain_a);
+        }
+
+        has_crossing |= has_road_a || has_road_b;
+    }
+
+    if !has_crossing {
+        let nearest = crossings
+            .iter()
+            .filter_map(|(location_a, location_b)| {
+                let path_a = plan_a.find_path_to_road(*location_a, terrain_a)?;
+                let path_b = plan_b.find_path_to_road(*location_b, terrain_b)?;
+
+                Some((path_a.len() + path_b.len(), *location_a, *location_b))
+            })
+            .min_by_key(|(length, location_a, _)| (*length, location_a.packed_repr()));
+
+        if let Some((_, location_a, location_b)) = nearest {
+            plan_a.insert_connected_road(location_a, terrain_a);
+            plan_b.insert_connected_road(location_b, terrain_b);
+        }
+    }
+
+    Ok(())
+}
+
+struct RoomDataArrayIterator<'a, T>
+where
+    T: Copy,
+{
+    data: &'a RoomDataArray<T>,
+    x: u8,
+    y: u8,
+}
+
+impl<'a, T> Iterator for RoomDataArrayIterator<'a, T>
+where
+    T: Copy,
+{
+    type Item = ((usize, usize), &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.x < ROOM_WIDTH && self.y < ROOM_HEIGHT {
+            let current_x = self.x as usize;
+            let current_y = self.y as usize;
+
+            self.x += 1;
+
+            if self.x >= ROOM_WIDTH {
+                self.x = 0;
+                self.y += 1;
+            }
+
+            Some(((current_x, current_y), self.data.get(current_x, current_y)))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RoomDataArray<T>
+where
+    T: Copy,
+{
+    data: [T; (ROOM_WIDTH as usize) * (ROOM_HEIGHT as usize)],
+}
+
+impl<T> RoomDataArray<T>
+where
+    T: Copy,
+{
+    pub fn new(initial: T) -> Self {
+        RoomDataArray {
+            data: [initial; (ROOM_WIDTH as usize) * (ROOM_HEIGHT as usize)],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &T {
+        let index = (y * (ROOM_WIDTH as usize)) + x;
+        &self.data[index]
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut T {
+        let index = (y * (ROOM_WIDTH as usize)) + x;
+        &mut self.data[index]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: T) {
+        *self.get_mut(x, y) = value;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((usize, usize), &T)> {
+        RoomDataArrayIterator {
+            data: &self,
+            x: 0,
+            y: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum PlanNodeChild<'a> {
+    GlobalPlacement(&'a dyn PlanGlobalPlacementNode),
+    LocationPlacement(PlanLocation, &'a dyn PlanLocationPlacementNode),
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanNodeChild<'a> {
+    fn name(&self) -> &str {
+        match self {
+            PlanNodeChild::GlobalPlacement(n) => n.name(),
+            PlanNodeChild::LocationPlacement(_, n) => n.name(),
+        }
+    }
+
+    fn id(&self) -> &uuid::Uuid {
+        match self {
+            PlanNodeChild::GlobalPlacement(n) => n.id(),
+            PlanNodeChild::LocationPlacement(_, n) => n.id(),
+        }
+    }
+
+    fn placement_phase(&self) -> PlacementPhase {
+        match self {
+            PlanNodeChild::GlobalPlacement(n) => n.placement_phase(),
+            PlanNodeChild::LocationPlacement(_, n) => n.placement_phase(),
+        }
+    }
+
+    fn must_place(&self) -> bool {
+        match self {
+            PlanNodeChild::GlobalPlacement(n) => n.must_place(),
+            PlanNodeChild::LocationPlacement(_, n) => n.must_place(),
+        }
+    }
+
+    fn place(&self, context: &mut NodeContext, state: &mut PlannerState) -> Result<(), ()> {
+        match self {
+            PlanNodeChild::GlobalPlacement(node) => node.place(context, state),
+            PlanNodeChild::LocationPlacement(location, node) => {
+                node.place(*location, context, state)
+            }
+        }
+    }
+
+    fn get_score(&self, context: &mut NodeContext, state: &PlannerState) -> Option<f32> {
+        match self {
+            PlanNodeChild::GlobalPlacement(node) => node.get_score(context, state),
+            PlanNodeChild::LocationPlacement(location, node) => {
+                node.get_score(*location, context, state)
+            }
+        }
+    }
+
+    fn mark_visited(&self, gather_data: &mut PlanGatherChildrenData<'a>) {
+        match self {
+            PlanNodeChild::GlobalPlacement(node) => {
+                gather_data.mark_visited_global(node.as_global())
+            }
+            PlanNodeChild::LocationPlacement(location, node) => {
+                gather_data.mark_visited_location(*location, node.as_location())
+            }
+        }
+    }
+
+    fn get_children(
+        &self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'a>,
+    ) {
+        match self {
+            PlanNodeChild::GlobalPlacement(node) => node.get_children(context, state, gather_data),
+            PlanNodeChild::LocationPlacement(location, node) => {
+                node.get_children(*location, context, state, gather_data)
+            }
+        }
+    }
+
+    fn desires_placement(
+        &self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'a>,
+    ) -> bool {
+        match self {
+            PlanNodeChild::GlobalPlacement(node) => {
+                gather_data.desires_placement(node.as_base(), context, state)
+            }
+            PlanNodeChild::LocationPlacement(_, node) => {
+                gather_data.desires_placement(node.as_base(), context, state)
+            }
+        }
+    }
+
+    fn desires_location(
+        &self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'a>,
+    ) -> bool {
+        match self {
+            PlanNodeChild::GlobalPlacement(_) => true,
+            PlanNodeChild::LocationPlacement(location, node) => {
+                gather_data.desires_location(*location, node.as_location(), context, state)
+            }
+        }
+    }
+
+    fn ready_for_placement(&self, context: &mut NodeContext, state: &PlannerState) -> bool {
+        match self {
+            PlanNodeChild::GlobalPlacement(node) => node.ready_for_placement(context, state),
+            PlanNodeChild::LocationPlacement(_, node) => node.ready_for_placement(context, state),
+        }
+    }
+
+    fn insert(&self, gather_data: &mut PlanGatherChildrenData<'a>) -> bool {
+        match self {
+            PlanNodeChild::GlobalPlacement(node) => gather_data.insert_global_placement(*node),
+            PlanNodeChild::LocationPlacement(location, node) => {
+                gather_data.insert_location_placement(*location, *node)
+            }
+        }
+    }
+
+    fn to_serialized(&self, index_lookup: &FnvHashMap<uuid::Uuid, usize>) -> SerializedPlanNodeChild {
+        match self {
+            PlanNodeChild::GlobalPlacement(node) => {
+                let node_type = 0;
+                let node = index_lookup.get(node.id()).unwrap();
+                if (node & !0x7F) != 0 {
+                    panic!("Not enough bits to represent packed value!");
+                }
+                let node = node & 0x7F;
+
+                let packed = (node_type) | ((node as u32) << 1);
+
+                SerializedPlanNodeChild { packed }
+            }
+            PlanNodeChild::LocationPlacement(location, node) => {
+                let node_type = 1;
+                let location = location.packed_repr();
+                let node = index_lookup.get(node.id()).unwrap();
+                if (node & !0x7F) != 0 {
+                    panic!("Not enough bits to represent packed value!");
+                }
+                let node = node & 0x7F;
+
+                let packed = (node_type) | ((node as u32) << 1) | ((location as u32) << 16);
+
+                SerializedPlanNodeChild { packed }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[repr(transparent)]
+#[serde(transparent)]
+struct SerializedPlanNodeChild {
+    packed: u32,
+}
+
+impl SerializedPlanNodeChild {
+    pub fn as_entry<'b>(
+        &self,
+        nodes: &PlanGatherNodesData<'b>,
+        index_lookup: &Vec<uuid::Uuid>,
+    ) -> Result<PlanNodeChild<'b>, String> {
+        let node_type = self.packed & 0x1;
+
+        match node_type {
+            0 => {
+                let node_index = (self.packed >> 1) & 0x7F;
+                let node_id = index_lookup
+                    .get(node_index as usize)
+                    .ok_or("Invalid node id")?;
+                let node = nodes
+                    .global_placement_nodes
+                    .get(node_id)
+                    .ok_or("Invalid node")?;
+
+                Ok(PlanNodeChild::GlobalPlacement(*node))
+            }
+            1 => {
+                let node_index = (self.packed >> 1) & 0x7F;
+                let node_id = index_lookup
+                    .get(node_index as usize)
+                    .ok_or("Invalid node id")?;
+                let node = nodes
+                    .location_placement_nodes
+                    .get(node_id)
+                    .ok_or("Invalid node")?;
+
+                let location = PlanLocation::from_packed((self.packed >> 16) as u16);
+
+                Ok(PlanNodeChild::LocationPlacement(location, *node))
+            }
+            _ => Err("Unknown node type".to_string()),
+        }
+    }
+}
+
+pub struct PlanGatherNodesData<'b> {
+    global_placement_nodes: FnvHashMap<uuid::Uuid, &'b dyn PlanGlobalPlacementNode>,
+    location_placement_nodes: FnvHashMap<uuid::Uuid, &'b dyn PlanLocationPlacementNode>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'b> PlanGatherNodesData<'b> {
+    pub fn new<'a>() -> PlanGatherNodesData<'a> {
+        PlanGatherNodesData {
+            global_placement_nodes: FnvHashMap::default(),
+            location_placement_nodes: FnvHashMap::default(),
+        }
+    }
+
+    pub fn get_all_ids(&self) -> Vec<uuid::Uuid> {
+        self.global_placement_nodes
+            .keys()
+            .chain(self.location_placement_nodes.keys())
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_name(&self, id: &uuid::Uuid) -> Option<&str> {
+        self.global_placement_nodes
+            .get(id)
+            .map(|node| node.name())
+            .or_else(|| {
+                self.location_placement_nodes
+                    .get(id)
+                    .map(|node| node.name())
+            })
+    }
+
+    pub fn insert_global_placement(
+        &mut self,
+        id: uuid::Uuid,
+        node: &'b dyn PlanGlobalPlacementNode,
+    ) -> bool {
+        match self.global_placement_nodes.entry(id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(e) => {
+                e.insert(node);
+
+                true
+            }
+        }
+    }
+
+    pub fn insert_location_placement(
+        &mut self,
+        id: uuid::Uuid,
+        node: &'b dyn PlanLocationPlacementNode,
+    ) -> bool {
+        match self.location_placement_nodes.entry(id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(e) => {
+                e.insert(node);
+
+                true
+            }
+        }
+    }
+}
+struct PlanGatherChildrenGlobalData<'s> {
+    visited: Vec<&'s dyn PlanGlobalNode>,
+    inserted: Vec<&'s dyn PlanGlobalPlacementNode>,
+}
+
+impl<'s> PlanGatherChildrenGlobalData<'s> {
+    pub fn has_visited(&self, node: &dyn PlanGlobalNode) -> bool {
+        self.visited.iter().any(|other| std::ptr::eq(node, *other))
+    }
+
+    pub fn mark_visited(&mut self, node: &'s dyn PlanGlobalNode) {
+        if !self.has_visited(node) {
+            self.visited.push(node);
+        }
+    }
+
+    pub fn insert(&mut self, node: &'s dyn PlanGlobalPlacementNode) -> bool {
+        if !self.inserted.iter().any(|other| std::ptr::eq(node, *other)) {
+            self.inserted.push(node);
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct PlanGatherChildrenLocationData<'s> {
+    desires_location_cache: Vec<(&'s dyn PlanLocationNode, bool)>,
+    visited: Vec<&'s dyn PlanLocationNode>,
+    inserted: Vec<&'s dyn PlanLocationPlacementNode>,
+}
+
+impl<'s> PlanGatherChildrenLocationData<'s> {
+    pub fn has_visited(&self, node: &dyn PlanLocationNode) -> bool {
+        self.visited.iter().any(|other| std::ptr::eq(node, *other))
+    }
+
+    pub fn mark_visited(&mut self, node: &'s dyn PlanLocationNode) {
+        if !self.has_visited(node) {
+            self.visited.push(node);
+        }
+    }
+
+    pub fn insert(&mut self, node: &'s dyn PlanLocationPlacementNode) -> bool {
+        if !self.inserted.iter().any(|other| std::ptr::eq(node, *other)) {
+            self.inserted.push(node);
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub struct PlanGatherChildrenData<'a> {
+    desires_placement_cache: Vec<(&'a dyn PlanBaseNode, bool)>,
+    global_nodes: PlanGatherChildrenGlobalData<'a>,
+    location_nodes: FnvHashMap<PlanLocation, PlanGatherChildrenLocationData<'a>>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanGatherChildrenData<'a> {
+    pub fn new<'b>() -> PlanGatherChildrenData<'b> {
+        PlanGatherChildrenData {
+            desires_placement_cache: Vec::new(),
+            global_nodes: PlanGatherChildrenGlobalData {
+                visited: Vec::new(),
+                inserted: Vec::new(),
+            },
+            location_nodes: FnvHashMap::default(),
+        }
+    }
+
+    pub fn desires_placement(
+        &mut self,
+        node: &'a dyn PlanBaseNode,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> bool {
+        match self
+            .desires_placement_cache
+            .iter()
+            .position(|(other, _)| std::ptr::eq(node, *other))
+        {
+            Some(index) => self.desires_placement_cache[index].1,
+            None => {
+                let desires_placement = node.desires_placement(context, state, self);
+
+                self.desires_placement_cache.push((node, desires_placement));
+
+                desires_placement
+            }
+        }
+    }
+
+    pub fn desires_location(
+        &mut self,
+        position: PlanLocation,
+        node: &'a dyn PlanLocationNode,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> bool {
+        {
+            if let Some(location_data) = self.try_get_location_data(position) {
+                if let Some(index) = location_data
+                    .desires_location_cache
+                    .iter()
+                    .position(|(other, _)| std::ptr::eq(node, *other))
+                {
+                    return location_data.desires_location_cache[index].1;
+                }
+            }
+        }
+
+        let desires_location = node.desires_location(position, context, state, self);
+
+        let location_data = self.get_location_data(position);
+
+        if !location_data
+            .desires_location_cache
+            .iter()
+            .any(|(other, _)| std::ptr::eq(node, *other))
+        {
+            location_data
+                .desires_location_cache
+                .push((node, desires_location));
+        }
+
+        desires_location
+    }
+
+    fn get_location_data(
+        &mut self,
+        position: PlanLocation,
+    ) -> &mut PlanGatherChildrenLocationData<'a> {
+        self.location_nodes
+            .entry(position)
+            .or_insert_with(|| PlanGatherChildrenLocationData {
+                desires_location_cache: Vec::new(),
+                visited: Vec::new(),
+                inserted: Vec::new(),
+            })
+    }
+
+    fn try_get_location_data(
+        &self,
+        position: PlanLocation,
+    ) -> Option<&PlanGatherChildrenLocationData<'a>> {
+        self.location_nodes.get(&position)
+    }
+
+    pub fn has_visited_global(&self, node: &'a dyn PlanGlobalNode) -> bool {
+        self.global_nodes.has_visited(node)
+    }
+
+    pub fn mark_visited_global(&mut self, node: &'a dyn PlanGlobalNode) {
+        self.global_nodes.mark_visited(node);
+    }
+
+    pub fn has_visited_location(
+        &self,
+        position: PlanLocation,
+        node: &'a dyn PlanLocationNode,
+    ) -> bool {
+        self.try_get_location_data(position)
+            .map(|l| l.has_visited(node))
+            .unwrap_or(false)
+    }
+
+    pub fn mark_visited_location(
+        &mut self,
+        position: PlanLocation,
+        node: &'a dyn PlanLocationNode,
+    ) {
+        let location_data = self.get_location_data(position);
+
+        location_data.mark_visited(node);
+    }
+
+    pub fn insert_global_placement(&mut self, node: &'a dyn PlanGlobalPlacementNode) -> bool {
+        self.global_nodes.insert(node)
+    }
+
+    pub fn insert_location_placement(
+        &mut self,
+        position: PlanLocation,
+        node: &'a dyn PlanLocationPlacementNode,
+    ) -> bool {
+        let location_data = self.get_location_data(position);
+
+        location_data.insert(node)
+    }
+
+    pub fn collect(self) -> Vec<PlanNodeChild<'a>> {
+        let globals = self
+            .global_nodes
+            .inserted
+            .iter()
+            .map(|node| PlanNodeChild::GlobalPlacement(*node));
+
+        self.location_nodes
+            .iter()
+            .flat_map(|(location, location_data)| {
+                location_data
+                    .inserted
+                    .iter()
+                    .map(move |node| PlanNodeChild::LocationPlacement(*location, *node))
+            })
+            .chain(globals)
+            .collect()
+    }
+}
+
+/// Distance from a set of locations to every tile, with the largest distance found.
+pub type RoomDistance = (RoomDataArray<Option<u32>>, u32);
+
+pub type RoomDistances = Vec<RoomDistance>;
+
+#[derive(Clone)]
+pub struct AnalysisOutput {
+    pub exits: Vec<Location>,
+    pub wall_distance: RoomDataArray<Option<u32>>,
+    pub source_distances: RoomDistances,
+    pub controller_distances: RoomDistances,
+    pub mineral_distances: RoomDistances,
+    room_fingerprint: u64,
+}
+
+impl AnalysisOutput {
+    /// Hash of the terrain, sources, controllers and minerals the analysis was run on. The
+    /// planner rejects analysis whose fingerprint does not match the room being planned.
+    pub fn room_fingerprint(&self) -> u64 {
+        self.room_fingerprint
+    }
+
+    /// Renders the wall distance transform as a heatmap, with tiles furthest from walls hottest.
+    pub fn visualize_distance_transform<V>(&self, visualizer: &mut V)
+    where
+        V: RoomVisualizer,
+    {
+        let max_distance = self
+            .wall_distance
+            .iter()
+            .filter_map(|(_, distance)| *distance)
+            .max()
+            .unwrap_or(0);
+
+        if max_distance == 0 {
+            return;
+        }
+
+        for ((x, y), distance) in self.wall_distance.iter() {
+            if let Some(distance) = distance.filter(|d| *d > 0) {
+                visualizer.render_heat(
+                    Location::from_coords(x as u32, y as u32),
+                    distance as f32 / max_distance as f32,
+                );
+            }
+        }
+    }
+}
+
+/// Hash of the room data the analysis depends on, used to detect stale or foreign analysis.
+pub fn room_fingerprint(data_source: &mut dyn PlannerRoomDataSource) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = FnvHasher::default();
+
+    data_source.get_terrain().buffer.hash(&mut hasher);
+
+    for locations in [
+        data_source.get_sources().to_vec(),
+        data_source.get_controllers().to_vec(),
+        data_source.get_minerals().to_vec(),
+    ]
+    .iter()
+    {
+        locations
+            .iter()
+            .map(|location| (location.x(), location.y()))
+            .collect::<Vec<_>>()
+            .hash(&mut hasher);
+    }
+
+    hasher.finish()
+}
+
+pub fn run_analysis(data_source: &mut dyn PlannerRoomDataSource) -> AnalysisOutput {
+    let rcl_limits = RclLimits::official();
+    let source_infra = SourceInfraPolicies::default();
+
+    NodeContext::new(data_source, &rcl_limits, &source_infra).into_analysis()
+}
+
+fn get_wall_distance(terrain: &FastRoomTerrain) -> RoomDataArray<Option<u32>> {
+    let mut data: RoomDataArray<Option<u32>> = RoomDataArray::new(None);
+    let mut to_apply: FnvHashSet<PlanLocation> = FnvHashSet::default();
+
+    for y in 0..ROOM_HEIGHT {
+        for x in 0..ROOM_WIDTH {
+            let terrain_cell = terrain.get_xy(x, y);
+
+            if terrain_cell.contains(TerrainFlags::WALL) || !in_room_build_bounds(x, y) {
+                to_apply.insert(PlanLocation::new(x as i8, y as i8));
+            }
+        }
+    }
+
+    flood_fill_distance(to_apply, terrain, &mut data, |_| true);
+
+    data
+}
+
+/// Cheap check for whether a room has any chance of fitting a plan, for skipping hopeless rooms
+/// before running the planner. The room needs a tile at least `PLANNABLE_MIN_WALL_DISTANCE` from
+/// any wall or the room border to anchor the hub, and at least `PLANNABLE_MIN_BUILDABLE_TILES`
+/// buildable tiles in total.
+pub fn room_is_plannable(terrain: &FastRoomTerrain) -> bool {
+    let buildable_tiles = (0..ROOM_HEIGHT)
+        .flat_map(|y| (0..ROOM_WIDTH).map(move |x| (x, y)))
+        .filter(|(x, y)| {
+            in_room_build_bounds(*x, *y) && !terrain.get_xy(*x, *y).contains(TerrainFlags::WALL)
+        })
+        .count();
+
+    if buildable_tiles < PLANNABLE_MIN_BUILDABLE_TILES {
+        return false;
+    }
+
+    get_wall_distance(terrain).iter().any(|(_, distance)| {
+        distance
+            .map(|d| d >= PLANNABLE_MIN_WALL_DISTANCE)
+            .unwrap_or(false)
+    })
+}
+
+fn get_distances_from(locations: &[PlanLocation], terrain: &FastRoomTerrain) -> RoomDistances {
+    let mut distances = Vec::new();
+
+    for location in locations.iter() {
+        let mut data: RoomDataArray<Option<u32>> = RoomDataArray::new(None);
+        let mut to_apply: FnvHashSet<PlanLocation> = FnvHashSet::default();
+
+        to_apply.insert(*location);
+
+        let max_distance = flood_fill_distance(to_apply, terrain, &mut data, |_| true);
+
+        distances.push((data, max_distance));
+    }
+
+    distances
+}
+
+pub struct NodeContext<'d> {
+    data_source: &'d mut dyn PlannerRoomDataSource,
+    rcl_limits: &'d RclLimits,
+    source_infra: &'d SourceInfraPolicies,
+    max_extension_shortfall: u8,
+    layout_options: LayoutOptions,
+
+    exits: Option<Cow<'d, [Location]>>,
+    wall_distance: Option<Cow<'d, RoomDataArray<Option<u32>>>>,
+    source_distances: Option<Cow<'d, [RoomDistance]>>,
+    controller_distances: Option<Cow<'d, [RoomDistance]>>,
+    mineral_distances: Option<Cow<'d, [RoomDistance]>>,
+}
+
+impl<'d> NodeContext<'d> {
+    pub fn new<'a>(
+        data_source: &'a mut dyn PlannerRoomDataSource,
+        rcl_limits: &'a RclLimits,
+        source_infra: &'a SourceInfraPolicies,
+    ) -> NodeContext<'a> {
+        NodeContext {
+            data_source,
+            rcl_limits,
+            source_infra,
+            max_extension_shortfall: 0,
+            layout_options: LayoutOptions::default(),
+            exits: None,
+            wall_distance: None,
+            source_distances: None,
+            controller_distances: None,
+            mineral_distances: None,
+        }
+    }
+
+    pub fn with_analysis<'a>(
+        data_source: &'a mut dyn PlannerRoomDataSource,
+        rcl_limits: &'a RclLimits,
+        source_infra: &'a SourceInfraPolicies,
+        analysis: &'a AnalysisOutput,
+    ) -> NodeContext<'a> {
+        NodeContext {
+            data_source,
+            rcl_limits,
+            source_infra,
+            max_extension_shortfall: 0,
+            layout_options: LayoutOptions::default(),
+            exits: Some(Cow::Borrowed(&analysis.exits)),
+            wall_distance: Some(Cow::Borrowed(&analysis.wall_distance)),
+            source_distances: Some(Cow::Borrowed(&analysis.source_distances)),
+            controller_distances: Some(Cow::Borrowed(&analysis.controller_distances)),
+            mineral_distances: Some(Cow::Borrowed(&analysis.mineral_distances)),
+        }
+    }
+
+    pub fn terrain(&mut self) -> &FastRoomTerrain {
+        self.data_source.get_terrain()
+    }
+
+    pub fn rcl_limits(&self) -> &'d RclLimits {
+        self.rcl_limits
+    }
+
+    pub fn source_infra_policy(&self, source: PlanLocation) -> SourceInfraPolicy {
+        self.source_infra.get_policy(source)
+    }
+
+    pub fn source_link_min_distance(&self) -> u32 {
+        self.source_infra.link_min_distance()
+    }
+
+    /// Allow plans to place up to this many fewer extensions than the RCL limit.
+    pub fn with_max_extension_shortfall(self, max_extension_shortfall: u8) -> Self {
+        NodeContext {
+            max_extension_shortfall,
+            ..self
+        }
+    }
+
+    pub fn max_extension_shortfall(&self) -> u8 {
+        self.max_extension_shortfall
+    }
+
+    pub fn with_layout_options(self, layout_options: LayoutOptions) -> Self {
+        NodeContext {
+            layout_options,
+            ..self
+        }
+    }
+
+    pub fn layout_options(&self) -> &LayoutOptions {
+        &self.layout_options
+    }
+
+    /// The fewest extensions a complete plan can have.
+    pub fn min_extension_count(&self) -> u8 {
+        self.rcl_limits
+            .get_max_count(StructureType::Extension)
+            .saturating_sub(self.max_extension_shortfall)
+    }
+
+    pub fn controllers(&mut self) -> &[PlanLocation] {
+        self.data_source.get_controllers()
+    }
+
+    pub fn sources(&mut self) -> &[PlanLocation] {
+        self.data_source.get_sources()
+    }
+
+    pub fn minerals(&mut self) -> &[PlanLocation] {
+        self.data_source.get_minerals()
+    }
+
+    pub fn exits(&mut self) -> &[Location] {
+        if self.exits.is_none() {
+            let exits: Vec<_> = self.terrain().get_exits().collect();
+
+            self.exits = Some(Cow::Owned(exits));
+        }
+
+        self.exits.as_ref().unwrap()
+    }
+
+    pub fn wall_distance(&mut self) -> &RoomDataArray<Option<u32>> {
+        if self.wall_distance.is_none() {
+            let data = get_wall_distance(self.terrain());
+
+            self.wall_distance = Some(Cow::Owned(data));
+        }
+
+        self.wall_distance.as_ref().unwrap()
+    }
+
+    pub fn source_distances(&mut self) -> &[(RoomDataArray<Option<u32>>, u32)] {
+        if self.source_distances.is_none() {
+            let sources = { self.sources().to_vec() };
+
+            self.source_distances = Some(Cow::Owned(get_distances_from(&sources, self.terrain())));
+        }
+
+        self.source_distances.as_ref().unwrap()
+    }
+
+    pub fn controller_distances(&mut self) -> &[(RoomDataArray<Option<u32>>, u32)] {
+        if self.controller_distances.is_none() {
+            let controllers = { self.controllers().to_vec() };
+
+            self.controller_distances =
+                Some(Cow::Owned(get_distances_from(&controllers, self.terrain())));
+        }
+
+        self.controller_distances.as_ref().unwrap()
+    }
+
+    pub fn mineral_distances(&mut self) -> &[(RoomDataArray<Option<u32>>, u32)] {
+        if self.mineral_distances.is_none() {
+            let minerals = { self.minerals().to_vec() };
+
+            self.mineral_distances =
+                Some(Cow::Owned(get_distances_from(&minerals, self.terrain())));
+        }
+
+        self.mineral_distances.as_ref().unwrap()
+    }
+
+    pub fn into_analysis(mut self) -> AnalysisOutput {
+        self.exits();
+        self.wall_distance();
+        self.source_distances();
+        self.controller_distances();
+        self.mineral_distances();
+
+        let room_fingerprint = room_fingerprint(self.data_source);
+
+        AnalysisOutput {
+            exits: self.exits.unwrap().into_owned(),
+            wall_distance: self.wall_distance.unwrap().into_owned(),
+            source_distances: self.source_distances.unwrap().into_owned(),
+            controller_distances: self.controller_distances.unwrap().into_owned(),
+            mineral_distances: self.mineral_distances.unwrap().into_owned(),
+            room_fingerprint,
+        }
+    }
+}
+
+pub trait PlanBaseNode {
+    fn name(&self) -> &str;
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool;
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>);
+}
+
+pub trait PlanGlobalNode: PlanBaseNode {
+    fn as_base(&self) -> &dyn PlanBaseNode;
+
+    fn get_children<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    );
+}
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+pub enum PlacementPhase {
+    Pre,
+    Normal,
+    Post,
+}
+
+pub trait PlanGlobalPlacementNode: PlanGlobalNode {
+    fn as_global(&self) -> &dyn PlanGlobalNode;
+
+    fn id(&self) -> &uuid::Uuid;
+
+    fn placement_phase(&self) -> PlacementPhase;
+
+    fn must_place(&self) -> bool;
+
+    fn get_maximum_score(&self, context: &mut NodeContext, state: &PlannerState) -> Option<f32>;
+
+    fn get_score(&self, context: &mut NodeContext, state: &PlannerState) -> Option<f32>;
+
+    fn ready_for_placement(&self, context: &mut NodeContext, state: &PlannerState) -> bool;
+
+    fn place(&self, context: &mut NodeContext, state: &mut PlannerState) -> Result<(), ()>;
+}
+
+pub trait PlanGlobalExpansionNode: PlanGlobalNode {
+    fn as_global(&self) -> &dyn PlanGlobalNode;
+}
+
+/// Names of the given root nodes in order, without evaluating them.
+pub fn root_node_names<'a>(root_nodes: &[&'a dyn PlanGlobalExpansionNode]) -> Vec<&'a str> {
+    root_nodes.iter().map(|node| node.name()).collect()
+}
+
+pub trait PlanLocationNode: PlanBaseNode {
+    fn as_base(&self) -> &dyn PlanBaseNode;
+
+    fn desires_location<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool;
+
+    fn get_children<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    );
+}
+
+pub trait PlanLocationPlacementNode: PlanLocationNode {
+    fn as_location(&self) -> &dyn PlanLocationNode;
+
+    fn id(&self) -> &uuid::Uuid;
+
+    fn placement_phase(&self) -> PlacementPhase;
+
+    fn must_place(&self) -> bool;
+
+    fn get_maximum_score(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> Option<f32>;
+
+    fn get_score(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> Option<f32>;
+
+    fn ready_for_placement(&self, context: &mut NodeContext, state: &PlannerState) -> bool;
+
+    fn place(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &mut PlannerState,
+    ) -> Result<(), ()>;
+}
+
+pub trait PlanPlacementExpansionNode: PlanLocationNode {
+    fn as_location(&self) -> &dyn PlanLocationNode;
+}
+
+pub enum PlanNodeStorage<'a> {
+    Empty,
+    GlobalPlacement(&'a dyn PlanGlobalPlacementNode),
+    GlobalExpansion(&'a dyn PlanGlobalExpansionNode),
+    LocationPlacement(&'a dyn PlanLocationPlacementNode),
+    LocationExpansion(&'a dyn PlanPlacementExpansionNode),
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanNodeStorage<'a> {
+    fn gather_nodes(&self, data: &mut PlanGatherNodesData<'a>) {
+        match self {
+            PlanNodeStorage::Empty => {}
+            PlanNodeStorage::GlobalPlacement(n) => n.gather_nodes(data),
+            PlanNodeStorage::GlobalExpansion(n) => n.gather_nodes(data),
+            PlanNodeStorage::LocationPlacement(n) => n.gather_nodes(data),
+            PlanNodeStorage::LocationExpansion(n) => n.gather_nodes(data),
+        }
+    }
+
+    fn desires_placement(
+        &self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'a>,
+    ) -> bool {
+        match self {
+            PlanNodeStorage::Empty => false,
+            PlanNodeStorage::GlobalPlacement(n) => {
+                gather_data.desires_placement(n.as_base(), context, state)
+            }
+            PlanNodeStorage::GlobalExpansion(n) => {
+                gather_data.desires_placement(n.as_base(), context, state)
+            }
+            PlanNodeStorage::LocationPlacement(n) => {
+                gather_data.desires_placement(n.as_base(), context, state)
+            }
+            PlanNodeStorage::LocationExpansion(n) => {
+                gather_data.desires_placement(n.as_base(), context, state)
+            }
+        }
+    }
+
+    fn desires_location(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'a>,
+    ) -> bool {
+        match self {
+            PlanNodeStorage::Empty => false,
+            PlanNodeStorage::GlobalPlacement(_) => true,
+            PlanNodeStorage::GlobalExpansion(_) => true,
+            PlanNodeStorage::LocationPlacement(n) => {
+                gather_data.desires_location(position, n.as_location(), context, state)
+            }
+            PlanNodeStorage::LocationExpansion(n) => {
+                gather_data.desires_location(position, n.as_location(), context, state)
+            }
+        }
+    }
+
+    fn insert_or_expand(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'a>,
+    ) {
+        match self {
+            PlanNodeStorage::Empty => {}
+            PlanNodeStorage::GlobalPlacement(n) => {
+                gather_data.insert_global_placement(*n);
+            }
+            PlanNodeStorage::GlobalExpansion(n) => n.get_children(context, state, gather_data),
+            PlanNodeStorage::LocationPlacement(n) => {
+                gather_data.insert_location_placement(position, *n);
+            }
+            PlanNodeStorage::LocationExpansion(n) => {
+                n.get_children(position, context, state, gather_data)
+            }
+        }
+    }
+}
+
+fn flood_fill_distance<F>(
+    initial_seeds: FnvHashSet<PlanLocation>,
+    terrain: &FastRoomTerrain,
+    data: &mut RoomDataArray<Option<u32>>,
+    is_passable: F,
+) -> u32
+where
+    F: Fn(PlanLocation) -> bool,
+{
+    let mut to_apply = initial_seeds;
+    let mut current_distance: u32 = 0;
+
+    loop {
+        let eval_locations = std::mem::replace(&mut to_apply, FnvHashSet::default());
+
+        for pos in &eval_locations {
+            let current = data.get_mut(pos.x() as usize, pos.y() as usize);
+
+            let allow_expand = if current.is_none() {
+                if is_passable(*pos) {
+                    *current = Some(current_distance);
+
+                    true
+                } else {
+                    current_distance == 0
+                }
+            } else {
+                false
+            };
+
+            if allow_expand {
+                for offset in ONE_OFFSET_SQUARE {
+                    let next_location = *pos + offset;
+                    if next_location.in_room_bounds() {
+                        let terrain =
+                            terrain.get_xy(next_location.x() as u8, next_location.y() as u8);
+                        if !terrain.contains(TerrainFlags::WALL) {
+                            to_apply.insert(next_location);
+                        }
+                    }
+                }
+            }
+        }
+
+        if to_apply.is_empty() {
+            break current_distance;
+        }
+
+        current_distance += 1;
+    }
+}
+
+/// Passes locations to the child that are at least `wall_distance` from the nearest wall,
+/// `LayoutOptions::min_controller_distance` from every controller and
+/// `LayoutOptions::min_exit_distance` from every exit.
+pub struct PlaceAwayFromWallsNode<'a> {
+    pub wall_distance: u32,
+    pub child: PlanNodeStorage<'a>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for PlaceAwayFromWallsNode<'a> {
+    fn name(&self) -> &str {
+        "Place Away From Walls"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        self.child.gather_nodes(data);
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.child.desires_placement(context, state, gather_data)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanGlobalNode for PlaceAwayFromWallsNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_global(self) {
+            gather_data.mark_visited_global(self);
+
+            if self.child.desires_placement(context, state, gather_data) {
+                let exits: Vec<PlanLocation> = context.exits().iter().map(|l| l.into()).collect();
+                let controllers = context.controllers().to_vec();
+                let min_exit_distance = context.layout_options().min_exit_distance;
+                let min_controller_distance = context.layout_options().min_controller_distance;
+
+                let locations: Vec<PlanLocation> = context
+                    .wall_distance()
+                    .iter()
+                    .filter(|(_, distance)| {
+                        distance.map(|d| d >= self.wall_distance).unwrap_or(false)
+                    })
+                    .map(|((x, y), _)| PlanLocation::new(x as i8, y as i8))
+                    .filter(|location| {
+                        exits
+                            .iter()
+                            .all(|exit| exit.distance_to(*location) as u32 >= min_exit_distance)
+                    })
+                    .filter(|location| {
+                        controllers.iter().all(|controller| {
+                            controller.distance_to(*location) as u32 >= min_controller_distance
+                        })
+                    })
+                    .collect();
+
+                for location in &locations {
+                    if self
+                        .child
+                        .desires_location(*location, context, state, gather_data)
+                    {
+                        self.child
+                            .insert_or_expand(*location, context, state, gather_data);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanGlobalExpansionNode for PlaceAwayFromWallsNode<'a> {
+    fn as_global(&self) -> &dyn PlanGlobalNode {
+        self
+    }
+}
+
+/// Only passes locations to the child whose distance from the nearest wall is at least
+/// `wall_distance`, pruning stamp anchors that cannot fit before trying each placement.
+pub struct MinWallDistancePlanNode<'a> {
+    pub wall_distance: u32,
+    pub child: PlanNodeStorage<'a>,
+}
+
+impl<'a> MinWallDistancePlanNode<'a> {
+    fn is_far_enough(&self, position: PlanLocation, context: &mut NodeContext) -> bool {
+        if !position.in_room_bounds() {
+            return false;
+        }
+
+        context
+            .wall_distance()
+            .get(position.x() as usize, position.y() as usize)
+            .map(|d| d >= self.wall_distance)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for MinWallDistancePlanNode<'a> {
+    fn name(&self) -> &str {
+        "Min Wall Distance"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        self.child.gather_nodes(data);
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.child.desires_placement(context, state, gather_data)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationNode for MinWallDistancePlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn desires_location<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.is_far_enough(position, context)
+            && self
+                .child
+                .desires_location(position, context, state, gather_data)
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_location(position, self) {
+            gather_data.mark_visited_location(position, self);
+
+            if self.is_far_enough(position, context)
+                && self.child.desires_placement(context, state, gather_data)
+                && self
+                    .child
+                    .desires_location(position, context, state, gather_data)
+            {
+                self.child
+                    .insert_or_expand(position, context, state, gather_data);
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanPlacementExpansionNode for MinWallDistancePlanNode<'a> {
+    fn as_location(&self) -> &dyn PlanLocationNode {
+        self
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PlanPlacement {
+    structure_type: StructureType,
+    offset: PlanLocation,
+    optional: bool,
+    rcl_override: Option<u8>,
+}
+
+impl PlanPlacement {
+    pub const fn optional(self) -> Self {
+        Self {
+            optional: true,
+            ..self
+        }
+    }
+
+    pub const fn rcl(self, rcl: u8) -> Self {
+        Self {
+            rcl_override: Some(rcl),
+            ..self
+        }
+    }
+
+    pub fn structure_type(&self) -> StructureType {
+        self.structure_type
+    }
+
+    pub fn offset(&self) -> PlanLocation {
+        self.offset
+    }
+
+    fn can_place(
+        &self,
+        plan_location: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> bool {
+        if let Some(placement_location) = plan_location.as_build_location() {
+            if self.structure_type == StructureType::Extractor {
+                if !context.minerals().contains(&plan_location) {
+                    return false;
+                }
+            } else if context
+                .terrain()
+                .get(&placement_location)
+                .contains(TerrainFlags::WALL)
+            {
+                return false;
+            } else if !placement_location.in_room_from_edge(ROOM_BUILD_BORDER as u32 + 1) {
+                return false;
+            }
+
+            if state.is_reserved(&placement_location) {
+                return false;
+            }
+
+            for existing in state.get(&placement_location).iter().flat_map(|v| v.iter()) {
+                // Ramparts can cover any structure, including roads at chokepoints.
+                let valid = match existing.structure_type {
+                    StructureType::Road => {
+                        self.structure_type == StructureType::Road
+                            || self.structure_type == StructureType::Rampart
+                    }
+                    StructureType::Rampart => true,
+                    _ => self.structure_type == StructureType::Rampart,
+                };
+
+                if !valid {
+                    return false;
+                }
+            }
+        } else {
+            return false;
+        }
+
+        true
+    }
+}
+
+pub const fn placement(structure_type: StructureType, x: i8, y: i8) -> PlanPlacement {
+    PlanPlacement {
+        structure_type,
+        offset: PlanLocation { x, y },
+        optional: false,
+        rcl_override: None,
+    }
+}
+
+pub struct FixedPlanNode<'a> {
+    pub id: uuid::Uuid,
+    pub placement_phase: PlacementPhase,
+    pub must_place: bool,
+    pub placements: &'a [PlanPlacement],
+    pub child: PlanNodeStorage<'a>,
+    pub desires_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
+    pub desires_location:
+        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> bool,
+    pub maximum_scorer:
+        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> Option<f32>,
+    pub scorer:
+        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> Option<f32>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for FixedPlanNode<'a> {
+    fn name(&self) -> &str {
+        "Fixed"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        if data.insert_location_placement(self.id, self) {
+            self.child.gather_nodes(data);
+        }
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        _gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        (self.desires_placement)(context, state)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationNode for FixedPlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn desires_location<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        _gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        if (self.desires_location)(position, context, state) {
+            self.placements.iter().all(|placement| {
+                placement.optional
+                    || placement.can_place(position + placement.offset, context, state)
+            })
+        } else {
+            false
+        }
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_location(position, self) {
+            gather_data.mark_visited_location(position, self);
+
+            if self.child.desires_placement(context, state, gather_data)
+                && self
+                    .child
+                    .desires_location(position, context, state, gather_data)
+            {
+                self.child
+                    .insert_or_expand(position, context, state, gather_data);
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationPlacementNode for FixedPlanNode<'a> {
+    fn as_location(&self) -> &dyn PlanLocationNode {
+        self
+    }
+
+    fn id(&self) -> &uuid::Uuid {
+        &self.id
+    }
+
+    fn placement_phase(&self) -> PlacementPhase {
+        self.placement_phase
+    }
+
+    fn must_place(&self) -> bool {
+        self.must_place
+    }
+
+    fn get_maximum_score(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> Option<f32> {
+        (self.maximum_scorer)(position, context, state)
+    }
+
+    fn get_score(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> Option<f32> {
+        (self.scorer)(position, context, state)
+    }
+
+    fn ready_for_placement(&self, _context: &mut NodeContext, _state: &PlannerState) -> bool {
+        true
+    }
+
+    fn place(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &mut PlannerState,
+    ) -> Result<(), ()> {
+        let mut min_rcl = None;
+
+        for placement in self
+            .placements
+            .iter()
+            .filter(|p| p.structure_type != StructureType::Road)
+        {
+            let placement_location = (position + placement.offset).as_location().unwrap();
+
+            if !placement.optional || placement.can_place(placement_location.into(), context, state)
+            {
+                // Placements held back to a level above the plan's maximum are left out.
+                let rcl = if let Some(rcl) = placement.rcl_override {
+                    rcl
+                } else {
+                    //TODO: This isn't quite right - should find the lowest unused RCL.
+                    // Limits capped below the stamp's count (such as fewer towers) leave the
+                    // remaining optional tiles of the stamp empty, but fail the stamp when a
+                    // required tile can't be placed.
+                    match state
+                        .get_rcl_for_next_structure(placement.structure_type, context.rcl_limits())
+                    {
+                        Some(rcl) => rcl,
+                        None if placement.optional => continue,
+                        None => return Err(()),
+                    }
+                };
+
+                if rcl > context.rcl_limits().max_rcl() {
+                    continue;
+                }
+
+                min_rcl = min_rcl.map(|r| if rcl < r { rcl } else { r }).or(Some(rcl));
+
+                state.insert(
+                    placement_location,
+                    RoomItem {
+                        structure_type: placement.structure_type,
+                        required_rcl: rcl,
+                    },
+                );
+            }
+        }
+
+        let road_rcl = min_rcl.unwrap_or(1);
+
+        for placement in self
+            .placements
+            .iter()
+            .filter(|p| p.structure_type == StructureType::Road)
+        {
+            let placement_location = (position + placement.offset).as_location().unwrap();
+
+            if !placement.optional || placement.can_place(placement_location.into(), context, state)
+            {
+                state.insert(
+                    placement_location,
+                    RoomItem {
+                        structure_type: placement.structure_type,
+                        required_rcl: road_rcl,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct OffsetPlanNode<'a> {
+    pub offsets: &'a [(i8, i8)],
+    pub child: PlanNodeStorage<'a>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for OffsetPlanNode<'a> {
+    fn name(&self) -> &str {
+        "Offset"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        self.child.gather_nodes(data);
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.child.desires_placement(context, state, gather_data)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationNode for OffsetPlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn desires_location<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.offsets.iter().any(|offset| {
+            let offset_position = position + offset;
+
+            self.child
+                .desires_location(offset_position, context, state, gather_data)
+        })
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_location(position, self) {
+            gather_data.mark_visited_location(position, self);
+
+            if self.child.desires_placement(context, state, gather_data) {
+                for offset in self.offsets.iter() {
+                    let offset_position = position + offset;
+
+                    if self
+                        .child
+                        .desires_location(offset_position, context, state, gather_data)
+                    {
+                        self.child
+                            .insert_or_expand(offset_position, context, state, gather_data);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanPlacementExpansionNode for OffsetPlanNode<'a> {
+    fn as_location(&self) -> &dyn PlanLocationNode {
+        self
+    }
+}
+
+pub struct MultiPlacementExpansionNode<'a> {
+    pub children: &'a [PlanNodeStorage<'a>],
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for MultiPlacementExpansionNode<'a> {
+    fn name(&self) -> &str {
+        "Multi Placement Expansion"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        for child in self.children.iter() {
+            child.gather_nodes(data);
+        }
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.children
+            .iter()
+            .any(|child| child.desires_placement(context, state, gather_data))
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationNode for MultiPlacementExpansionNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn desires_location<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.children
+            .iter()
+            .any(|child| child.desires_location(position, context, state, gather_data))
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_location(position, self) {
+            gather_data.mark_visited_location(position, self);
+
+            for child in self.children.iter() {
+                if child.desires_placement(context, state, gather_data)
+                    && child.desires_location(position, context, state, gather_data)
+                {
+                    child.insert_or_expand(position, context, state, gather_data);
+                }
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanPlacementExpansionNode for MultiPlacementExpansionNode<'a> {
+    fn as_location(&self) -> &dyn PlanLocationNode {
+        self
+    }
+}
+
+pub struct LazyPlanNode<'a> {
+    pub child: fn() -> PlanNodeStorage<'a>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for LazyPlanNode<'a> {
+    fn name(&self) -> &str {
+        "Lazy"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        let node = (self.child)();
+
+        node.gather_nodes(data);
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        let node = (self.child)();
+
+        node.desires_placement(context, state, gather_data)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationNode for LazyPlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn desires_location<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        let node = (self.child)();
+
+        node.desires_location(position, context, state, gather_data)
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_location(position, self) {
+            gather_data.mark_visited_location(position, self);
+
+            let node = (self.child)();
+
+            if node.desires_placement(context, state, gather_data)
+                && node.desires_location(position, context, state, gather_data)
+            {
+                node.insert_or_expand(position, context, state, gather_data);
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanPlacementExpansionNode for LazyPlanNode<'a> {
+    fn as_location(&self) -> &dyn PlanLocationNode {
+        self
+    }
+}
+
+pub struct FixedLocationPlanNode<'a> {
+    pub locations: fn(context: &mut NodeContext) -> Vec<PlanLocation>,
+    pub child: PlanNodeStorage<'a>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for FixedLocationPlanNode<'a> {
+    fn name(&self) -> &str {
+        "Fixed Locations"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        self.child.gather_nodes(data);
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.child.desires_placement(context, state, gather_data)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanGlobalNode for FixedLocationPlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_global(self) {
+            gather_data.mark_visited_global(self);
+
+            if self.child.desires_placement(context, state, gather_data) {
+                let locations = (self.locations)(context);
+
+                for location in locations {
+                    if self
+                        .child
+                        .desires_location(location, context, state, gather_data)
+                    {
+                        self.child
+                            .insert_or_expand(location, context, state, gather_data);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanGlobalExpansionNode for FixedLocationPlanNode<'a> {
+    fn as_global(&self) -> &dyn PlanGlobalNode {
+        self
+    }
+}
+
+/// Expands the child at the location of each placed structure of a given type, such as each
+/// source container.
+pub struct StructureLocationsPlanNode<'a> {
+    pub structure_type: StructureType,
+    pub filter: fn(location: Location, context: &mut NodeContext, state: &PlannerState) -> bool,
+    pub child: PlanNodeStorage<'a>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for StructureLocationsPlanNode<'a> {
+    fn name(&self) -> &str {
+        "Structure Locations"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        self.child.gather_nodes(data);
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.child.desires_placement(context, state, gather_data)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanGlobalNode for StructureLocationsPlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_global(self) {
+            gather_data.mark_visited_global(self);
+
+            if self.child.desires_placement(context, state, gather_data) {
+                let locations = state.get_locations(self.structure_type);
+
+                for location in locations {
+                    if !(self.filter)(location, context, state) {
+                        continue;
+                    }
+
+                    let location = location.into();
+
+                    if self
+                        .child
+                        .desires_location(location, context, state, gather_data)
+                    {
+                        self.child
+                            .insert_or_expand(location, context, state, gather_data);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanGlobalExpansionNode for StructureLocationsPlanNode<'a> {
+    fn as_global(&self) -> &dyn PlanGlobalNode {
+        self
+    }
+}
+
+pub struct MinCutWallsPlanNode {
+    pub id: uuid::Uuid,
+    pub placement_phase: PlacementPhase,
+    pub must_place: bool,
+    pub desires_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
+    pub ready_for_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
+    pub rcl_override: Option<u8>,
+    /// Only protect the hub (spawns, storage and terminal) rather than every structure and the
+    /// controller, so the cut falls at the narrowest chokepoint leading to the base. Intended for
+    /// early game rooms that can't afford a full perimeter.
+    pub chokepoint_only: bool,
+    /// Restrict the flow graph to a box around the protected tiles, expanded by this many tiles,
+    /// treating the edge of the box as an exit. Bounds CPU use in open rooms, at the cost of a
+    /// possibly longer wall if the margin is too small to reach the natural chokepoints.
+    pub bounds_margin: Option<u8>,
+}
+
+impl MinCutWallsPlanNode {
+    /// Computes the minimum cut separating the placed structures (and controller) from the room
+    /// exits, returning the cut size, the nodes on the protected side of the cut and the number
+    /// of nodes in the flow graph. Cut nodes are numbered by tile - `y * 50 + x` for a tile's top
+    /// node and 2500 past that for its bottom node. When `chokepoint_only` is set only the hub
+    /// structures are protected. Tiles around the controller that can't be ramparted because they
+    /// are next to an exit are left unprotected. Returns `None` if a structure is next to an exit.
+    fn compute_min_cut(
+        context: &mut NodeContext,
+        state: &PlannerState,
+        chokepoint_only: bool,
+        bounds_margin: Option<u8>,
+    ) -> Option<(usize, Vec<usize>, usize)> {
+        // unbuildable is for tiles near room exits that can't be ramparted
+        let mut unbuildable = FnvHashSet::default();
+
+        // and exits is for the exit tiles themselves, for later attachment to the sink
+        let mut exits = FnvHashSet::default();
+
+        for exit_position in context.terrain().get_exits() {
+            unbuildable.insert(exit_position);
+            exits.insert(exit_position);
+
+            // and mark all tiles within range 1 as unbuildable
+            let adjacent_positions = ONE_OFFSET_SQUARE
+                .iter()
+                .map(|offset| {
+                    PlanLocation::new(exit_position.x() as i8, exit_position.y() as i8) + offset
+                })
+                .filter_map(|offset_location| offset_location.try_into().ok());
+
+            for exit_adjacent_position in adjacent_positions {
+                unbuildable.insert(exit_adjacent_position);
+            }
+        }
+
+        // protected is for tiles that will hook to the source
+        let mut protected = FnvHashSet::default();
+
+        let room_items = state.get_all();
+
+        // Protect all tiles we've put structures on so far
+        for (location, room_item) in room_items.iter() {
+            let should_protect = match room_item.structure_type {
+                StructureType::KeeperLair | StructureType::Portal | StructureType::InvaderCore => {
+                    false
+                }
+                StructureType::Wall | StructureType::Rampart => false,
+                StructureType::Spawn | StructureType::Storage | StructureType::Terminal => true,
+                _ => !chokepoint_only,
+            };
+
+            if should_protect {
+                protected.insert(*location);
+            }
+        }
+
+        // also explicitly protect range:1 of the controller
+        let controllers: &[PlanLocation] = if chokepoint_only {
+            &[]
+        } else {
+            context.controllers()
+        };
+
+        // a controller close to an exit keeps what protection the terrain allows, rather than
+        // failing the whole cut
+        for controller_position in controllers {
+            if let Some(controller_location) = controller_position.try_into().ok() {
+                let adjacent_positions = ONE_OFFSET_SQUARE
+                    .iter()
+                    .map(|offset| *controller_position + offset)
+                    .filter(|offset_location| offset_location.in_room_build_bounds())
+                    .filter_map(|offset_location| offset_location.try_into().ok());
+
+                let controller_area = std::iter::once(controller_location)
+                    .chain(adjacent_positions)
+                    .filter(|location| !unbuildable.contains(location));
+
+                for location in controller_area {
+                    protected.insert(location);
+                }
+            }
+        }
+
+        // a protected structure that can't be ramparted can never be separated from the exits
+        if protected
+            .iter()
+            .any(|location| unbuildable.contains(location))
+        {
+            return None;
+        }
+
+        // optionally limit the graph to a box around the protected tiles - the edge of the box
+        // hooks to the sink like an exit
+        let bounds = bounds_margin
+            .filter(|_| !protected.is_empty())
+            .map(|margin| {
+                let margin = margin.max(1) as u32;
+
+                let min_x = protected.iter().map(|l| l.x() as u32).min().unwrap();
+                let max_x = protected.iter().map(|l| l.x() as u32).max().unwrap();
+                let min_y = protected.iter().map(|l| l.y() as u32).min().unwrap();
+                let max_y = protected.iter().map(|l| l.y() as u32).max().unwrap();
+
+                (
+                    min_x.saturating_sub(margin),
+                    min_y.saturating_sub(margin),
+                    (max_x + margin).min(ROOM_WIDTH as u32 - 1),
+                    (max_y + margin).min(ROOM_HEIGHT as u32 - 1),
+                )
+            });
+
+        let in_bounds = |x: u32, y: u32| {
+            bounds
+                .map(|(min_x, min_y, max_x, max_y)| {
+                    x >= min_x && x <= max_x && y >= min_y && y <= max_y
+                })
+                .unwrap_or(true)
+        };
+
+        let on_bounds_edge = |x: u32, y: u32| {
+            bounds
+                .map(|(min_x, min_y, max_x, max_y)| {
+                    x == min_x || x == max_x || y == min_y || y == max_y
+                })
+                .unwrap_or(false)
+        };
+
+        let room_node_count = ROOM_WIDTH as usize * ROOM_HEIGHT as usize;
+
+        // only walkable tiles inside the bounds get nodes in the graph
+        let graph_tiles: Vec<usize> = {
+            let terrain = context.terrain();
+
+            (0..room_node_count)
+                .filter(|index| {
+                    let (x, y) = ((index % 50) as u32, (index / 50) as u32);
+
+                    in_bounds(x, y)
+                        && !terrain
+                            .get(&Location::from_coords(x, y))
+                            .contains(TerrainFlags::WALL)
+                })
+                .collect()
+        };
+
+        let mut graph_indices = vec![None; room_node_count];
+
+        for (graph_index, tile_index) in graph_tiles.iter().enumerate() {
+            graph_indices[*tile_index] = Some(graph_index);
+        }
+
+        let mut builder = LinkedListGraph::<u32>::new_builder();
+
+        let top_nodes = builder.add_nodes(graph_tiles.len());
+        let bottom_nodes = builder.add_nodes(graph_tiles.len());
+
+        // source (protected) and sink (exit)
+        let source = builder.add_node();
+        let sink = builder.add_node();
+
+        // TODO improve this to support tunnels - top should hook to bottom if it's a wall, (assuming can't rampart a tunnel?)
+        // hook to neighboring walls like they're walkable if they're a road
+        // big ol' vector of the weights of edges we create
+        let mut edge_weights = vec![];
+
+        // step over all tiles in the room, creating a mesh of flow connections
+        // walkable tiles have a weight: 1 edge from their 'top' node to their 'bot' node,
+        // which is what limits the 'flow' through the tile and what will ultimately be cut if
+        // that tile should be protected.  Then, the bottom tile connects with max weight to
+        // walkable neighbors, with high weight to prevent these from being the bottleneck to cut
+        for (graph_index, tile_index) in graph_tiles.iter().enumerate() {
+            // for each tile there's a 'top' and 'bottom'
+            // 'top' is at y * 50 + x
+            // 'bottom' is at 2500 + top
+            // top hooks to bottom with cost 1 if it's a normal tile, max if non-buildable
+            // bottom hooks to surrounding tiles as long as they're not protected tiles
+            // protected tiles top hooks to source
+            // edge tiles' bottom hooks to the sink
+            let (x, y) = ((tile_index % 50) as u32, (tile_index / 50) as u32);
+            let current_location = Location::from_coords(x, y);
+
+            if unbuildable.contains(&current_location) {
+                // no cutting here, make a max value edge from top to bottom
+                builder.add_edge(top_nodes[graph_index], bottom_nodes[graph_index]);
+                edge_weights.push(std::usize::MAX);
+            } else {
+                // make an edge costing 1 from top to bottom
+                builder.add_edge(top_nodes[graph_index], bottom_nodes[graph_index]);
+                edge_weights.push(1);
+            }
+
+            // if it's an edge tile (or the edge of the bounds), connect bot to sink
+            if exits.contains(&current_location) || on_bounds_edge(x, y) {
+                builder.add_edge(bottom_nodes[graph_index], sink);
+                edge_weights.push(std::usize::MAX);
+            }
+
+            // if it's a protected tile, connect source to top
+            if protected.contains(&current_location) {
+                builder.add_edge(source, top_nodes[graph_index]);
+                edge_weights.push(std::usize::MAX);
+            }
+
+            let adjacent_locations = ONE_OFFSET_SQUARE
+                .iter()
+                .map(|offset| {
+                    PlanLocation::new(current_location.x() as i8, current_location.y() as i8)
+                        + offset
+                })
+                .filter_map(|offset_location| offset_location.as_location());
+
+            for adjacent_location in adjacent_locations {
+                let adjacent_tile =
+                    adjacent_location.x() as usize + adjacent_location.y() as usize * 50;
+
+                // walls and tiles outside the bounds have no nodes
+                let adjacent_index = match graph_indices[adjacent_tile] {
+                    Some(adjacent_index) => adjacent_index,
+                    None => continue,
+                };
+
+                if !protected.contains(&adjacent_location) {
+                    // walkable, link from this bottom to that top if it's not protected
+                    builder.add_edge(bottom_nodes[graph_index], top_nodes[adjacent_index]);
+                    edge_weights.push(std::usize::MAX);
+                }
+            }
+        }
+
+        let network = builder.to_graph();
+
+        // get the big math guns in here
+        let (value, _, mincut) = dinic(&network, source, sink, |e| edge_weights[e.index()]);
+
+        // map the graph nodes back to tile numbering, dropping the source and sink
+        let tile_count = graph_tiles.len();
+
+        let mincut = mincut
+            .into_iter()
+            .map(|node| network.node_id(node))
+            .filter_map(|node_id| {
+                if node_id < tile_count {
+                    Some(graph_tiles[node_id])
+                } else if node_id < tile_count * 2 {
+                    Some(room_node_count + graph_tiles[node_id - tile_count])
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Some((value, mincut, tile_count * 2 + 2))
+    }
+
+    /// Estimates the number of tiles needed to wall off the current state without placing them.
+    pub fn estimate_perimeter(context: &mut NodeContext, state: &PlannerState) -> Option<usize> {
+        Self::compute_min_cut(context, state, false, None).map(|(value, _, _)| value)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl PlanBaseNode for MinCutWallsPlanNode {
+    fn name(&self) -> &str {
+        "Min Cut Walls"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        data.insert_global_placement(self.id, self);
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        _gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        (self.desires_placement)(context, state)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl PlanGlobalNode for MinCutWallsPlanNode {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        _context: &mut NodeContext,
+        _state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_global(self) {
+            gather_data.mark_visited_global(self);
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl PlanGlobalPlacementNode for MinCutWallsPlanNode {
+    fn as_global(&self) -> &dyn PlanGlobalNode {
+        self
+    }
+
+    fn id(&self) -> &uuid::Uuid {
+        &self.id
+    }
+
+    fn placement_phase(&self) -> PlacementPhase {
+        self.placement_phase
+    }
+
+    fn must_place(&self) -> bool {
+        self.must_place
+    }
+
+    fn get_maximum_score(&self, _context: &mut NodeContext, _state: &PlannerState) -> Option<f32> {
+        None
+    }
+
+    fn get_score(&self, _context: &mut NodeContext, _state: &PlannerState) -> Option<f32> {
+        Some(0.0)
+    }
+
+    fn ready_for_placement(&self, context: &mut NodeContext, state: &PlannerState) -> bool {
+        (self.ready_for_placement)(context, state)
+    }
+
+    fn place(&self, context: &mut NodeContext, state: &mut PlannerState) -> Result<(), ()> {
+        let rcl_override = context.rcl_limits().defense_rcl().or(self.rcl_override);
+
+        // Plans limited to a lower RCL than the defenses go without them.
+        if rcl_override
+            .map(|rcl| rcl > context.rcl_limits().max_rcl())
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+
+        let (_, mincut, _) =
+            Self::compute_min_cut(context, state, self.chokepoint_only, self.bounds_margin)
+                .ok_or(())?;
+
+        // tracking for nodes of each 'type' that have been evaluated as 'part of the cut'
+        // (here meaning, on the 'source' side of protected).
+        // to find which tiles we want ramparts in, we want to find out which tiles have their
+        // top node in the set but their bottom node not in the set, meaning we cut the edge between
+        // the top and bottom for that tile.
+        let mut top_cut = FnvHashSet::default();
+        let mut bot_cut = FnvHashSet::default();
+
+        for node_id in mincut {
+            let room_node_count = ROOM_WIDTH as usize * ROOM_HEIGHT as usize;
+
+            //
+            // NOTE: This relies on cut nodes being numbered by tile - see compute_min_cut.
+            //
+
+            if node_id < room_node_count {
+                top_cut.insert(node_id);
+            } else if room_node_count < room_node_count * 2 {
+                bot_cut.insert(node_id - room_node_count);
+            }
+        }
+
+        let rcl_limits = context.rcl_limits();
+        let terrain = context.terrain();
+
+        let mut candidates: FnvHashSet<_> = top_cut.difference(&bot_cut).collect();
+
+        while !candidates.is_empty() {
+            let mut to_process: Vec<(Location, StructureType)> = Vec::new();
+
+            let candidate_node = **candidates.iter().next().expect("Expected seed");
+
+            let location =
+                Location::from_coords((candidate_node % 50) as u32, (candidate_node / 50) as u32);
+
+            to_process.push((location, StructureType::Rampart));
+
+            while let Some((location, structure_type)) = to_process.pop() {
+                let candidate_node = location.x() as usize + (location.y() as usize * 50);
+
+                if candidates.remove(&candidate_node) {
+                    let terrain_mask = terrain.get(&location);
+
+                    if !terrain_mask.contains(TerrainFlags::WALL) {
+                        if let Some(rcl) = rcl_override.or_else(|| {
+                            state.get_rcl_for_next_structure(structure_type, rcl_limits)
+                        }) {
+                            state.insert(
+                                location,
+                                RoomItem {
+                                    structure_type: structure_type,
+                                    required_rcl: rcl,
+                                },
+                            );
+
+                            let adjacent_positions = ONE_OFFSET_CROSS
+                                .iter()
+                                .map(|offset| PlanLocation::from(location) + offset)
+                                .filter(|offset_location| offset_location.in_room_build_bounds())
+                                .filter_map(|offset_location| offset_location.try_into().ok());
+
+                            for adjacent_position in adjacent_positions {
+                                let next_structure = if structure_type == StructureType::Rampart {
+                                    if state
+                                        .get(&adjacent_position)
+                                        .map(|e| e.is_empty())
+                                        .unwrap_or(true)
+                                    {
+                                        StructureType::Wall
+                                    } else {
+                                        StructureType::Rampart
+                                    }
+                                } else {
+                                    StructureType::Rampart
+                                };
+
+                                to_process.push((adjacent_position, next_structure));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        //TODO: Validate min cut actually succeeded...
+        Ok(())
+    }
+}
+
+/// Orders candidate scores with missing and NaN scores treated as the worst.
+fn compare_candidate_scores(a: Option<f32>, b: Option<f32>) -> std::cmp::Ordering {
+    let a = a.filter(|score| !score.is_nan());
+    let b = b.filter(|score| !score.is_nan());
+
+    match (a, b) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap(),
+        (Some(_), None) => std::cmp::Ordering::Greater,
+        (None, Some(_)) => std::cmp::Ordering::Less,
+        (None, None) => std::cmp::Ordering::Equal,
+    }
+}
+
+/// Sorts flood fill candidates for evaluation from the end, so the best score is sorted last.
+/// Equal scores fall back to the packed location so the lowest location is tried first.
+fn sort_candidates<T>(candidates: &mut [(PlanLocation, T, Option<f32>)]) {
+    candidates.sort_by(
+        |(location_a, _, max_score_a), (location_b, _, max_score_b)| {
+            compare_candidate_scores(*max_score_a, *max_score_b)
+                .then_with(|| location_b.packed_repr().cmp(&location_a.packed_repr()))
+        },
+    );
+}
+
+pub struct FloodFillPlanNodeLevel<'a> {
+    pub offsets: &'a [(i8, i8)],
+    pub node: &'a dyn PlanLocationPlacementNode,
+}
+
+pub struct FloodFillPlanNode<'a> {
+    pub id: uuid::Uuid,
+    pub placement_phase: PlacementPhase,
+    pub must_place: bool,
+    pub start_offsets: &'a [(i8, i8)],
+    pub expansion_offsets: &'a [(i8, i8)],
+    pub maximum_expansion: u32,
+    pub minimum_candidates: usize,
+    pub levels: &'a [FloodFillPlanNodeLevel<'a>],
+    pub desires_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
+    pub scorer:
+        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> Option<f32>,
+    pub validator: fn(context: &mut NodeContext, state: &PlannerState) -> Result<(), ()>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for FloodFillPlanNode<'a> {
+    fn name(&self) -> &str {
+        "Flood Fill"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        if data.insert_location_placement(*self.id(), self) {
+            for lod in self.levels.iter() {
+                lod.node.gather_nodes(data);
+            }
+        }
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        (self.desires_placement)(context, state)
+            && self
+                .levels
+                .iter()
+                .any(|l| l.node.desires_placement(context, state, gather_data))
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationNode for FloodFillPlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn desires_location<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        let mut locations: FnvHashSet<_> = self
+            .start_offsets
+            .into_iter()
+            .map(|o| position + o)
+            .collect();
+
+        for lod in self.levels.iter() {
+            let mut expanded_locations: FnvHashSet<PlanLocation> = locations
+                .iter()
+                .flat_map(|&location| lod.offsets.iter().map(move |offset| location + *offset))
+                .collect();
+
+            if expanded_locations.iter().any(|location| {
+                lod.node
+                    .desires_location(*location, context, state, gather_data)
+            }) {
+                return true;
+            }
+
+            locations = std::mem::replace(&mut expanded_locations, FnvHashSet::default());
+        }
+
+        false
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        _position: PlanLocation,
+        _context: &mut NodeContext,
+        _state: &PlannerState,
+        _gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationPlacementNode for FloodFillPlanNode<'a> {
+    fn as_location(&self) -> &dyn PlanLocationNode {
+        self
+    }
+
+    fn placement_phase(&self) -> PlacementPhase {
+        self.placement_phase
+    }
+
+    fn must_place(&self) -> bool {
+        self.must_place
+    }
+
+    fn id(&self) -> &uuid::Uuid {
+        &self.id
+    }
+
+    fn get_maximum_score(
+        &self,
+        _position: PlanLocation,
+        _context: &mut NodeContext,
+        _state: &PlannerState,
+    ) -> Option<f32> {
+        None
+    }
+
+    fn get_score(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> Option<f32> {
+        (self.scorer)(position, context, state)
+    }
+
+    fn ready_for_placement(&self, _context: &mut NodeContext, _state: &PlannerState) -> bool {
+        //TODO: Provide customization option?
+        true
+    }
+
+    fn place(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &mut PlannerState,
+    ) -> Result<(), ()> {
+        let mut locations: FnvHashSet<_> = self
+            .start_offsets
+            .into_iter()
+            .map(|o| position + o)
+            .collect();
+        let mut next_locations: FnvHashSet<_> = FnvHashSet::default();
+        let mut visited_locations: FnvHashSet<_> = FnvHashSet::default();
+
+        let mut current_expansion = 0;
+
+        let mut candidates = Vec::new();
+
+        while current_expansion < self.maximum_expansion && !locations.is_empty() {
+            let mut current_gather_data = PlanGatherChildrenData::<'a>::new();
+
+            while current_expansion < self.maximum_expansion
+                && !locations.is_empty()
+                && candidates.len() < self.minimum_candidates
+            {
+                for root_location in locations.iter() {
+                    if !visited_locations.contains(root_location) {
+                        visited_locations.insert(*root_location);
+
+                        let mut lod_locations = vec![*root_location];
+
+                        for lod in self.levels.iter() {
+                            let expanded_locations = lod_locations.iter().flat_map(|&location| {
+                                lod.offsets.iter().map(move |offset| location + *offset)
+                            });
+
+                            let mut next_lod_locations = Vec::new();
+
+                            for lod_location in expanded_locations {
+                                if !current_gather_data
+                                    .has_visited_location(lod_location, lod.node.as_location())
+                                {
+                                    current_gather_data.mark_visited_location(
+                                        lod_location,
+                                        lod.node.as_location(),
+                                    );
+
+                                    let got_candidate = if current_gather_data.desires_placement(
+                                        lod.node.as_base(),
+                                        context,
+                                        state,
+                                    ) && current_gather_data
+                                        .desires_location(
+                                            lod_location,
+                                            lod.node.as_location(),
+                                            context,
+                                            state,
+                                        ) {
+                                        let max_score = lod.node.get_maximum_score(
+                                            lod_location,
+                                            context,
+                                            state,
+                                        );
+
+                                        candidates.push((lod_location, lod.node, max_score));
+
+                                        true
+                                    } else {
+                                        false
+                                    };
+
+                                    if got_candidate {
+                                        for offset in self.expansion_offsets.into_iter() {
+                                            let next_location = *root_location + *offset;
+
+                                            next_locations.insert(next_location);
+                                        }
+                                    } else {
+                                        next_lod_locations.push(lod_location);
+                                    }
+                                }
+                            }
+
+                            if next_lod_locations.is_empty() {
+                                break;
+                            }
+
+                            lod_locations = next_lod_locations;
+                        }
+                    }
+                }
+
+                current_expansion += 1;
+
+                locations = std::mem::replace(&mut next_locations, FnvHashSet::default());
+            }
+
+            while (candidates.len() >= self.minimum_candidates
+                || locations.is_empty()
+                || current_expansion >= self.maximum_expansion)
+                && !candidates.is_empty()
+            {
+                sort_candidates(&mut candidates);
+
+                let mut current_gather_data = PlanGatherChildrenData::<'a>::new();
+
+                let mut best_candidate = None;
+
+                let mut to_remove = Vec::new();
+
+                let max_scored = context
+                    .layout_options()
+                    .max_stamp_candidates
+                    .unwrap_or(usize::MAX);
+
+                let mut scored = 0;
+
+                for (index, (location, node, max_score)) in candidates.iter_mut().enumerate().rev()
+                {
+                    if scored >= max_scored && best_candidate.is_some() {
+                        break;
+                    }
+
+                    let can_exceed_best_score = best_candidate
+                        .as_ref()
+                        .map(|(best_score, _)| best_score)
+                        .and_then(|best_score| max_score.map(|max| max > *best_score))
+                        .unwrap_or(true);
+
+                    if can_exceed_best_score {
+                        let can_place =
+                            current_gather_data.desires_placement(node.as_base(), context, state)
+                                && current_gather_data.desires_location(
+                                    *location,
+                                    node.as_location(),
+                                    context,
+                                    state,
+                                );
+
+                        if can_place {
+                            scored += 1;
+
+                            if let Some(score) = node.get_score(*location, context, state) {
+                                //TODO: Only allow modifying score if hint is set that score can only get worse?
+                                *max_score = Some(score);
+
+                                if best_candidate
+                                    .as_ref()
+                                    .map(|(best_score, _)| score > *best_score)
+                                    .unwrap_or(true)
+                                {
+                                    best_candidate = Some((score, (*location, node, index)));
+                                }
+                            } else {
+                                to_remove.push(index);
+                            }
+                        } else {
+                            //TODO: Should consider pushing to next LOD?
+
+                            to_remove.push(index);
+                        }
+                    }
+                }
+
+                if let Some((_, (location, node, index))) = best_candidate {
+                    node.place(location, context, state)?;
+
+                    match to_remove.binary_search_by(|probe| probe.cmp(&index).reverse()) {
+                        Ok(_) => {}
+                        Err(pos) => to_remove.insert(pos, index),
+                    }
+                }
+
+                for index in to_remove.into_iter() {
+                    candidates.remove(index);
+                }
+            }
+        }
+
+        (self.validator)(context, state)
+    }
+}
+
+pub struct FirstPossiblePlanNode<'a> {
+    pub id: uuid::Uuid,
+    pub placement_phase: PlacementPhase,
+    pub must_place: bool,
+    pub options: &'a [&'a dyn PlanLocationPlacementNode],
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for FirstPossiblePlanNode<'a> {
+    fn name(&self) -> &str {
+        "First Possible"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        if data.insert_location_placement(*self.id(), self) {
+            for option in self.options.iter() {
+                option.gather_nodes(data);
+            }
+        }
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.options
+            .iter()
+            .any(|option| option.desires_placement(context, state, gather_data))
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationNode for FirstPossiblePlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn desires_location<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        self.options
+            .iter()
+            .any(|option| option.desires_location(position, context, state, gather_data))
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        _position: PlanLocation,
+        _context: &mut NodeContext,
+        _state: &PlannerState,
+        _gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationPlacementNode for FirstPossiblePlanNode<'a> {
+    fn as_location(&self) -> &dyn PlanLocationNode {
+        self
+    }
+
+    fn placement_phase(&self) -> PlacementPhase {
+        self.placement_phase
+    }
+
+    fn must_place(&self) -> bool {
+        self.must_place
+    }
+
+    fn id(&self) -> &uuid::Uuid {
+        &self.id
+    }
+
+    fn get_maximum_score(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> Option<f32> {
+        self.options
+            .iter()
+            .filter_map(|option| option.get_maximum_score(position, context, state))
+            .max_by(|a, b| a.partial_cmp(b).unwrap())
+    }
+
+    fn get_score(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+    ) -> Option<f32> {
+        let mut current_gather_data = PlanGatherChildrenData::<'a>::new();
+
+        self.options
+            .iter()
+            .filter_map(|option| {
+                if current_gather_data.desires_placement(option.as_base(), context, state)
+                    && current_gather_data.desires_location(
+                        position,
+                        option.as_location(),
+                        context,
+                        state,
+                    )
+                {
+                    option.get_score(position, context, state)
+                } else {
+                    None
+                }
+            })
+            .next()
+    }
+
+    fn ready_for_placement(&self, _context: &mut NodeContext, _state: &PlannerState) -> bool {
+        //TODO: Provide customization option?
+        true
+    }
+
+    fn place(
+        &self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &mut PlannerState,
+    ) -> Result<(), ()> {
+        let mut current_gather_data = PlanGatherChildrenData::<'a>::new();
+
+        for option in self.options.iter() {
+            if current_gather_data.desires_placement(option.as_base(), context, state)
+                && current_gather_data.desires_location(
+                    position,
+                    option.as_location(),
+                    context,
+                    state,
+                )
+                && current_gather_data.insert_location_placement(position, *option)
+            {
+                //TODO: Should this allow recovery?
+                option.place(position, context, state)?;
+
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct NearestToStructureExpansionPlanNode<'a> {
+    pub structure_type: StructureType,
+    pub child: PlanNodeStorage<'a>,
+    pub path_distance: u32,
+    pub desires_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
+    pub desires_location:
+        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> bool,
+    pub scorer:
+        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> Option<f32>,
+}
+
+impl<'a> NearestToStructureExpansionPlanNode<'a> {
+    fn get_child_locations<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> Vec<PlanLocation> {
+        let mut result = Vec::new();
+
+        if self.child.desires_placement(context, state, gather_data) {
+            if let Some((path, _distance)) = state.get_pathfinding_distance_to_structure(
+                position,
+                self.structure_type,
+                1,
+                context.terrain(),
+            ) {
+                for offset_location in path.iter() {
+                    let distance = offset_location.distance_to(position) as u32;
+
+                    if distance == self.path_distance {
+                        result.push(*offset_location);
+                    } else if distance > self.path_distance {
+                        break;
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanBaseNode for NearestToStructureExpansionPlanNode<'a> {
+    fn name(&self) -> &str {
+        "Nearest To Structure"
+    }
+
+    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
+        self.child.gather_nodes(data);
+    }
+
+    fn desires_placement<'s>(
+        &'s self,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        (self.desires_placement)(context, state)
+            && self.child.desires_placement(context, state, gather_data)
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanLocationNode for NearestToStructureExpansionPlanNode<'a> {
+    fn as_base(&self) -> &dyn PlanBaseNode {
+        self
+    }
+
+    fn desires_location<'s>(
+        &'s self,
+        _position: PlanLocation,
+        _context: &mut NodeContext,
+        _state: &PlannerState,
+        _gather_data: &mut PlanGatherChildrenData<'s>,
+    ) -> bool {
+        true
+
+        /*
+        self.allowed_offsets.iter().any(|offset| {
+            self.child
+                .desires_location(position + *offset, context, state, gather_data)
+        })
+        */
+    }
+
+    fn get_children<'s>(
+        &'s self,
+        position: PlanLocation,
+        context: &mut NodeContext,
+        state: &PlannerState,
+        gather_data: &mut PlanGatherChildrenData<'s>,
+    ) {
+        if !gather_data.has_visited_location(position, self) {
+            gather_data.mark_visited_location(position, self);
+
+            if self.child.desires_placement(context, state, gather_data) {
+                if let Some((path, _distance)) = state.get_pathfinding_distance_to_structure(
+                    position,
+                    self.structure_type,
+                    1,
+                    context.terrain(),
+                ) {
+                    for offset_location in path.iter() {
+                        let distance = offset_location.distance_to(position) as u32;
+
+                        if distance == self.path_distance {
+                            if self.child.desires_location(
+                                *offset_location,
+                                context,
+                                state,
+                                gather_data,
+                            ) {
+                                self.child.insert_or_expand(
+                                    *offset_location,
+                                    context,
+                                    state,
+                                    gather_data,
+                                );
+
+                                break;
+                            }
+                        } else if distance > self.path_distance {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a> PlanPlacementExpansionNode for NearestToStructureExpansionPlanNode<'a> {
+    fn as_location(&self) -> &dyn PlanLocationNode {
+        self
+    }
+}
+
+pub struct FastRoomTerrain {
+    buffer: Vec<u8>,
+}
+
+bitflags! {
+    pub struct TerrainFlags: u8 {
+        const NONE = 0;
+        const WALL = TERRAIN_MASK_WALL;
+        const SWAMP = TERRAIN_MASK_SWAMP;
+        const LAVA = TERRAIN_MASK_LAVA;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExitSide {
+    Top,
+    Right,
+    Bottom,
+    Left,
+}
+
+impl ExitSide {
+    /// The side of the neighbouring room that this side borders.
+    pub fn opposite(self) -> ExitSide {
+        match self {
+            ExitSide::Top => ExitSide::Bottom,
+            ExitSide::Right => ExitSide::Left,
+            ExitSide::Bottom => ExitSide::Top,
+            ExitSide::Left => ExitSide::Right,
+        }
+    }
+
+    pub fn border_location(self, index: u8) -> Location {
+        let index = index as u32;
+
+        match self {
+            ExitSide::Top => Location::from_coords(index, 0),
+            ExitSide::Right => Location::from_coords(ROOM_WIDTH as u32 - 1, index),
+            ExitSide::Bottom => Location::from_coords(index, ROOM_HEIGHT as u32 - 1),
+            ExitSide::Left => Location::from_coords(0, index),
+        }
+    }
+}
+
+pub struct ExitIterator<'a> {
+    terrain: &'a FastRoomTerrain,
+    side: Option<ExitSide>,
+    index: u32,
+}
+
+impl<'a> Iterator for ExitIterator<'a> {
+    type Item = Location;
+
+    fn next(&mut self) -> Option<Location> {
+        loop {
+            let current = match self.side {
+                Some(ExitSide::Top) => {
+                    let res = Location::from_coords(self.index, 0);
+
+                    self.index += 1;
+
+                    if self.index >= ROOM_WIDTH as u32 - 1 {
+                        self.index = 0;
+                        self.side = Some(ExitSide::Right)
+                    }
+
+                    res
+                }
+                Some(ExitSide::Right) => {
+                    let res = Location::from_coords(ROOM_WIDTH as u32 - 1, self.index);
+
+                    self.index += 1;
+
+                    if self.index >= ROOM_HEIGHT as u32 - 1 {
+                        self.index = 0;
+                        self.side = Some(ExitSide::Bottom)
+                    }
+
+                    res
+                }
+                Some(ExitSide::Bottom) => {
+                    let res = Location::from_coords(
+                        (ROOM_WIDTH as u32 - 1) - self.index,
+                        ROOM_HEIGHT as u32 - 1,
+                    );
+
+                    self.index += 1;
+
+                    if self.index >= ROOM_WIDTH as u32 - 1 {
+                        self.index = 0;
+                        self.side = Some(ExitSide::Left)
+                    }
+
+                    res
+                }
+                Some(ExitSide::Left) => {
+                    let res = Location::from_coords(0, (ROOM_HEIGHT as u32 - 1) - self.index);
+
+                    self.index += 1;
+
+                    if self.index >= ROOM_HEIGHT as u32 - 1 {
+                        self.index = 0;
+                        self.side = None;
+                    }
+
+                    res
+                }
+                None => {
+                    return None;
+                }
+            };
+
+            let terrain_mask = self.terrain.get_xy(current.x(), current.y());
+
+            if !terrain_mask.intersects(TerrainFlags::WALL) {
+                return Some(current);
+            }
+        }
+    }
+}
+
+impl FastRoomTerrain {
+    pub fn new(buffer: Vec<u8>) -> FastRoomTerrain {
+        FastRoomTerrain { buffer }
+    }
+
+    pub fn get(&self, pos: &Location) -> TerrainFlags {
+        self.get_xy(pos.x(), pos.y())
+    }
+
+    pub fn get_xy(&self, x: u8, y: u8) -> TerrainFlags {
+        let index = (y as usize * ROOM_WIDTH as usize) + (x as usize);
+
+        TerrainFlags::from_bits_truncate(self.buffer[index])
+    }
+
+    pub fn get_exits(&self) -> ExitIterator {
+        ExitIterator {
+            terrain: self,
+            side: Some(ExitSide::Top),
+            index: 0,
+        }
+    }
+}
+
+struct EvaluationStackEntry<'b> {
+    children: Vec<PlanNodeChild<'b>>,
+    /// Optional children already tried from this entry, each in its own branch.
+    optional_attempts: usize,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'b> EvaluationStackEntry<'b> {
+    pub fn to_serialized(
+        &self,
+        index_lookup: &FnvHashMap<uuid::Uuid, usize>,
+    ) -> SerializedEvaluationStackEntry {
+        SerializedEvaluationStackEntry {
+            children: self
+                .children
+                .iter()
+                .map(|c| c.to_serialized(index_lookup))
+                .collect(),
+            optional_attempts: self.optional_attempts,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SerializedEvaluationStackEntry {
+    #[serde(rename = "c")]
+    children: Vec<SerializedPlanNodeChild>,
+    #[serde(rename = "a", default)]
+    optional_attempts: usize,
+}
+
+impl SerializedEvaluationStackEntry {
+    pub fn as_entry<'b>(
+        &self,
+        nodes: &PlanGatherNodesData<'b>,
+        index_lookup: &Vec<uuid::Uuid>,
+    ) -> Result<EvaluationStackEntry<'b>, String> {
+        let mut children = Vec::new();
+
+        for serialized_child in &self.children {
+            let child = serialized_child.as_entry(nodes, index_lookup)?;
+
+            children.push(child);
+        }
+
+        Ok(EvaluationStackEntry {
+            children,
+            optional_attempts: self.optional_attempts,
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SerializedEvaluationStack {
+    identifiers: Vec<uuid::Uuid>,
+    entries: Vec<SerializedEvaluationStackEntry>,
+}
+
+impl SerializedEvaluationStack {
+    pub fn from_stack(
+        gathered_nodes: &PlanGatherNodesData,
+        entries: &Vec<EvaluationStackEntry>,
+    ) -> SerializedEvaluationStack {
+        let identifiers: Vec<_> = gathered_nodes.get_all_ids();
+        let index_lookup: FnvHashMap<_, _> = identifiers
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (*id, index))
+            .collect();
+
+        let serialized_entries = entries
+            .iter()
+            .map(|e| e.to_serialized(&index_lookup))
+            .collect();
+
+        SerializedEvaluationStack {
+            identifiers,
+            entries: serialized_entries,
+        }
+    }
+
+    pub fn validate_nodes(&self, gathered_nodes: &PlanGatherNodesData) -> Result<(), String> {
+        let gathered_ids: FnvHashSet<_> = gathered_nodes.get_all_ids().into_iter().collect();
+        let serialized_ids: FnvHashSet<_> = self.identifiers.iter().cloned().collect();
+
+        if gathered_ids != serialized_ids {
+            return Err(format!(
+                "Node set does not match serialized state - expected {} nodes, got {} ({} unknown)",
+                serialized_ids.len(),
+                gathered_ids.len(),
+                serialized_ids.difference(&gathered_ids).count()
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn to_stack<'b>(
+        &self,
+        gathered_nodes: &PlanGatherNodesData<'b>,
+    ) -> Result<Vec<EvaluationStackEntry<'b>>, String> {
+        self.validate_nodes(gathered_nodes)?;
+
+        let mut stack = Vec::new();
+
+        for serialized_entry in self.entries.iter() {
+            let entry = serialized_entry.as_entry(&gathered_nodes, &self.identifiers)?;
+
+            stack.push(entry);
+        }
+
+        Ok(stack)
+    }
+}
+
+fn create_node_context<'a>(
+    data_source: &'a mut dyn PlannerRoomDataSource,
+    rcl_limits: &'a RclLimits,
+    source_infra: &'a SourceInfraPolicies,
+    analysis: Option<&'a AnalysisOutput>,
+) -> NodeContext<'a> {
+    if let Some(analysis) = analysis {
+        NodeContext::with_analysis(data_source, rcl_limits, source_infra, analysis)
+    } else {
+        NodeContext::new(data_source, rcl_limits, source_infra)
+    }
+}
+
+enum TreePlannerResult {
+    Complete,
+    Running(SerializedEvaluationStack),
+}
+
+struct TreePlanner<'t, H>
+where
+    H: FnMut(&PlannerState, &mut NodeContext),
+{
+    data_source: &'t mut dyn PlannerRoomDataSource,
+    rcl_limits: &'t RclLimits,
+    source_infra: &'t SourceInfraPolicies,
+    max_extension_shortfall: u8,
+    layout_options: LayoutOptions,
+    analysis: Option<&'t AnalysisOutput>,
+    handler: H,
+    stats: PlannerStats,
+    rejections: &'t mut PlannerRejections,
+    trace: Option<&'t mut PlannerTrace>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'t, H> TreePlanner<'t, H>
+where
+    H: FnMut(&PlannerState, &mut NodeContext),
+{
+    pub fn new<'a>(
+        data_source: &'a mut dyn PlannerRoomDataSource,
+        rcl_limits: &'a RclLimits,
+        source_infra: &'a SourceInfraPolicies,
+        max_extension_shortfall: u8,
+        analysis: Option<&'a AnalysisOutput>,
+        handler: H,
+        rejections: &'a mut PlannerRejections,
+        trace: Option<&'a mut PlannerTrace>,
+    ) -> TreePlanner<'a, H> {
+        TreePlanner {
+            data_source,
+            rcl_limits,
+            source_infra,
+            max_extension_shortfall,
+            layout_options: LayoutOptions::default(),
+            analysis,
+            handler,
+            stats: PlannerStats::default(),
+            rejections,
+            trace,
+        }
+    }
+
+    pub fn with_layout_options(self, layout_options: LayoutOptions) -> Self {
+        TreePlanner {
+            layout_options,
+            ..self
+        }
+    }
+
+    pub fn stats(&self) -> PlannerStats {
+        self.stats
+    }
+
+    pub fn seed<'r, 's>(
+        &mut self,
+        root_nodes: &[&'r dyn PlanGlobalExpansionNode],
+        state: &'s mut PlannerState,
+        max_root_candidates: Option<usize>,
+    ) -> Result<TreePlannerResult, PlanFailure> {
+        let mut context = create_node_context(
+            self.data_source,
+            self.rcl_limits,
+            self.source_infra,
+            self.analysis,
+        )
+        .with_max_extension_shortfall(self.max_extension_shortfall)
+        .with_layout_options(self.layout_options);
+
+        let mut stack = Vec::new();
+
+        let mut gathered_children = PlanGatherChildrenData::<'s>::new();
+
+        for node in root_nodes.iter() {
+            if gathered_children.desires_placement(node.as_base(), &mut context, state) {
+                node.get_children(&mut context, state, &mut gathered_children);
+            }
+        }
+
+        let children = gathered_children.collect();
+
+        let mut ordered_children: Vec<_> = children
+            .into_iter()
+            .filter_map(|node| {
+                node.get_score(&mut context, state)
+                    .map(|score| (node, score))
+            })
+            .collect();
+
+        if ordered_children.is_empty() {
+            return Err(PlanFailure::NoAnchor);
+        }
+
+        ordered_children.sort_by(|(node_a, score_a), (node_b, score_b)| {
+            node_a
+                .placement_phase()
+                .cmp(&node_b.placement_phase())
+                .reverse()
+                .then_with(|| node_a.must_place().cmp(&node_b.must_place()))
+                .then_with(|| score_a.partial_cmp(score_b).unwrap())
+        });
+
+        // Children are ordered best last. Pruning is by anchor location, so every hub variant at
+        // a kept anchor is still tried.
+        if let Some(max_root_candidates) = max_root_candidates {
+            let mut anchors = Vec::new();
+
+            for (node, _) in ordered_children.iter().rev() {
+                if let PlanNodeChild::LocationPlacement(location, _) = node {
+                    if anchors.len() < max_root_candidates && !anchors.contains(location) {
+                        anchors.push(*location);
+                    }
+                }
+            }
+
+            ordered_children.retain(|(node, _)| match node {
+                PlanNodeChild::LocationPlacement(location, _) => anchors.contains(location),
+                PlanNodeChild::GlobalPlacement(_) => true,
+            });
+        }
+
+        stack.push(EvaluationStackEntry {
+            children: ordered_children.into_iter().map(|(node, _)| node).collect(),
+            optional_attempts: 0,
+        });
+
+        self.stats.record_stack_depth(stack.len());
+
+        let mut gathered_nodes = PlanGatherNodesData::new::<'r>();
+
+        for node in root_nodes {
+            node.gather_nodes(&mut gathered_nodes);
+        }
+
+        let serialized = SerializedEvaluationStack::from_stack(&gathered_nodes, &stack);
+
+        Ok(TreePlannerResult::Running(serialized))
+    }
+
+    pub fn process<'r, 's, F>(
+        &mut self,
+        root_nodes: &[&'r dyn PlanGlobalExpansionNode],
+        state: &'s mut PlannerState,
+        serialized_stack: &SerializedEvaluationStack,
+        should_continue: F,
+    ) -> Result<TreePlannerResult, PlanFailure>
+    where
+        F: Fn() -> bool,
+    {
+        let mut context = create_node_context(
+            self.data_source,
+            self.rcl_limits,
+            self.source_infra,
+            self.analysis,
+        )
+        .with_max_extension_shortfall(self.max_extension_shortfall)
+        .with_layout_options(self.layout_options);
+
+        let mut processed_entries = 0;
+
+        let mut gathered_nodes = PlanGatherNodesData::new::<'r>();
+
+        for node in root_nodes {
+            node.gather_nodes(&mut gathered_nodes);
+        }
+
+        let mut stack = serialized_stack.to_stack(&gathered_nodes)?;
+
+        while !stack.is_empty() && should_continue() {
+            let mut placed_nodes = Vec::new();
+
+            // Root candidates are bounded separately by `max_root_candidates`.
+            let max_stamp_candidates = if stack.len() > 1 {
+                self.layout_options.max_stamp_candidates
+            } else {
+                None
+            };
+
+            let (entry_failed, finished_entry) = {
+                let entry = stack.last_mut().unwrap();
+                let mut entry_failed = false;
+
+                while !entry.children.is_empty()
+                    && placed_nodes.is_empty()
+                    && !entry_failed
+                    && should_continue()
+                {
+                    let mut to_place = Vec::new();
+
+                    let mut current_phase = None;
+
+                    let placeable_children = entry
+                        .children
+                        .iter()
+                        .enumerate()
+                        .rev()
+                        .filter(|(_, c)| c.ready_for_placement(&mut context, state));
+
+                    for (index, child) in placeable_children {
+                        let matches_phase = current_phase
+                            .map(|phase| phase == child.placement_phase())
+                            .unwrap_or(true);
+
+                        if child.must_place() && matches_phase {
+                            to_place.push(index);
+
+                            current_phase = Some(child.placement_phase());
+                        } else if to_place.is_empty() {
+                            to_place.push(index);
+
+                            break;
+                        } else {
+                            break;
+                        }
+                    }
+
+                    // Children are sorted ascending and taken from the end, best first, so once
+                    // the best optional children have each had a branch the rest are dropped.
+                    if let [index] = to_place[..] {
+                        if !entry.children[index].must_place() {
+                            if max_stamp_candidates
+                                .map(|max| entry.optional_attempts >= max)
+                                .unwrap_or(false)
+                            {
+                                entry.children.retain(|child| child.must_place());
+
+                                continue;
+                            }
+
+                            entry.optional_attempts += 1;
+                        }
+                    }
+
+                    if !to_place.is_empty() {
+                        processed_entries += to_place.len();
+                        self.stats.nodes_expanded += to_place.len();
+
+                        let to_place_nodes =
+                            to_place.iter().map(|index| entry.children.remove(*index));
+
+                        state.push_layer();
+
+                        let mut validate_location = false;
+
+                        for child in to_place_nodes {
+                            if validate_location
+                                && !child.desires_location(
+                                    &mut context,
+                                    state,
+                                    &mut PlanGatherChildrenData::new(),
+                                )
+                            {
+                                self.rejections
+                                    .record(child.id(), LayerRejection::LocationTaken);
+
+                                if let Some(trace) = &mut self.trace {
+                                    trace.record_rejected(
+                                        child.id(),
+                                        child.name(),
+                                        LayerRejection::LocationTaken,
+                                    );
+                                }
+
+                                entry_failed = true;
+
+                                break;
+                            }
+
+                            match child.place(&mut context, state) {
+                                Ok(()) => {}
+                                Err(()) => {
+                                    self.rejections
+                                        .record(child.id(), LayerRejection::PlacementFailed);
+
+                                    if let Some(trace) = &mut self.trace {
+                                        trace.record_rejected(
+                                            child.id(),
+                                            child.name(),
+                                            LayerRejection::PlacementFailed,
+                                        );
+                                    }
+
+                                    entry_failed = true;
+
+                                    break;
+                                }
+                            }
+
+                            if let Some(trace) = &mut self.trace {
+                                trace.record_placed(child.id(), child.name());
+                            }
+
+                            placed_nodes.push(child);
+
+                            validate_location = true;
+                        }
+                    } else {
+                        entry_failed = true;
+                    }
+
+                    if !entry_failed {
+                        self.stats.record_item_count(state.get_item_count());
+
+                        (self.handler)(state, &mut context);
+                    } else {
+                        state.pop_layer();
+                    }
+                }
+
+                (entry_failed, entry.children.is_empty())
+            };
+
+            if entry_failed {
+                state.pop_layer();
+
+                stack.pop();
+            } else if !placed_nodes.is_empty() {
+                let mut gathered_children = PlanGatherChildrenData::<'s>::new();
+
+                for child in placed_nodes.iter() {
+                    child.get_children(&mut context, state, &mut gathered_children);
+                }
+
+                for existing_child in stack.last().unwrap().children.iter() {
+                    if existing_child.desires_placement(&mut context, state, &mut gathered_children)
+                        && existing_child.desires_location(
+                            &mut context,
+                            state,
+                            &mut gathered_children,
+                        )
+                    {
+                        existing_child.insert(&mut gathered_children);
+                    }
+                }
+
+                let children = gathered_children.collect();
+
+                let mut ordered_children: Vec<_> = children
+                    .into_iter()
+                    .filter_map(|node| {
+                        node.get_score(&mut context, state)
+                            .map(|score| (node, score))
+                    })
+                    .collect();
+
+                ordered_children.sort_by(|(node_a, score_a), (node_b, score_b)| {
+                    node_a
+                        .placement_phase()
+                        .cmp(&node_b.placement_phase())
+                        .reverse()
+                        .then_with(|| node_a.must_place().cmp(&node_b.must_place()))
+                        .then_with(|| score_a.partial_cmp(score_b).unwrap())
+                });
+
+                stack.push(EvaluationStackEntry {
+                    children: ordered_children.into_iter().map(|(node, _)| node).collect(),
+                    optional_attempts: 0,
+                });
+
+                self.stats.record_stack_depth(stack.len());
+            } else if finished_entry {
+                state.pop_layer();
+
+                stack.pop();
+            }
+        }
+
+        info!(
+            "Processed planning entries: {} - Known children: {}",
+            processed_entries,
+            stack.iter().map(|e| e.children.len()).sum::<usize>()
+        );
+
+        if stack.is_empty() {
+            Ok(TreePlannerResult::Complete)
+        } else {
+            let serialized = SerializedEvaluationStack::from_stack(&gathered_nodes, &stack);
+
+            Ok(TreePlannerResult::Running(serialized))
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BestPlanData {
+    score: f32,
+    state: PlanState,
+    #[serde(default)]
+    reserved: Vec<Location>,
+}
+
+/// Counters describing how much work a planning run has done, accumulated across every seed and
+/// evaluate call for the run.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct PlannerStats {
+    #[serde(rename = "d")]
+    peak_stack_depth: usize,
+    #[serde(rename = "n")]
+    nodes_expanded: usize,
+    #[serde(rename = "i")]
+    peak_item_count: usize,
+}
+
+impl PlannerStats {
+    /// Deepest the candidate stack has grown.
+    pub fn peak_stack_depth(&self) -> usize {
+        self.peak_stack_depth
+    }
+
+    /// Total number of node placements attempted while searching, whether or not the node could
+    /// be placed.
+    pub fn nodes_expanded(&self) -> usize {
+        self.nodes_expanded
+    }
+
+    /// Largest number of room items held by the planner state at once, an approximation of the
+    /// peak structure map size.
+    pub fn peak_item_count(&self) -> usize {
+        self.peak_item_count
+    }
+
+    fn record_stack_depth(&mut self, depth: usize) {
+        self.peak_stack_depth = self.peak_stack_depth.max(depth);
+    }
+
+    fn record_item_count(&mut self, count: usize) {
+        self.peak_item_count = self.peak_item_count.max(count);
+    }
+
+    fn merge(&mut self, other: &PlannerStats) {
+        self.record_stack_depth(other.peak_stack_depth);
+        self.record_item_count(other.peak_item_count);
+        self.nodes_expanded += other.nodes_expanded;
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NodeTrace {
+    pub name: String,
+    pub placed: usize,
+    pub rejected: usize,
+    pub last_rejection: Option<LayerRejection>,
+}
+
+/// Why the search rejected a node it tried to place.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LayerRejection {
+    /// An earlier placement in the same step took the node's location.
+    LocationTaken,
+    /// The node could not be placed, such as a flood fill failing its validator.
+    PlacementFailed,
+}
+
+/// Per node placement counts gathered while searching, for finding which node is rejecting
+/// candidates when planning fails. Nodes are keyed by id.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlannerTrace {
+    nodes: FnvHashMap<uuid::Uuid, NodeTrace>,
+}
+
+impl PlannerTrace {
+    fn entry(&mut self, id: &uuid::Uuid, name: &str) -> &mut NodeTrace {
+        self.nodes.entry(*id).or_insert_with(|| NodeTrace {
+            name: name.to_owned(),
+            ..NodeTrace::default()
+        })
+    }
+
+    fn record_placed(&mut self, id: &uuid::Uuid, name: &str) {
+        self.entry(id, name).placed += 1;
+    }
+
+    fn record_rejected(&mut self, id: &uuid::Uuid, name: &str, reason: LayerRejection) {
+        let node = self.entry(id, name);
+
+        node.rejected += 1;
+        node.last_rejection = Some(reason);
+    }
+
+    pub fn get(&self, id: &uuid::Uuid) -> Option<&NodeTrace> {
+        self.nodes.get(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&uuid::Uuid, &NodeTrace)> {
+        self.nodes.iter()
+    }
+
+    /// The node that rejected the most placements - the most likely cause of a failed plan.
+    pub fn most_rejected(&self) -> Option<(&uuid::Uuid, &NodeTrace)> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.rejected > 0)
+            .max_by_key(|(_, node)| node.rejected)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+struct NodeRejections {
+    #[serde(rename = "c")]
+    count: usize,
+    #[serde(rename = "l")]
+    last: LayerRejection,
+}
+
+/// Rejection counts per node id, kept for every search so a failed one can name the node that
+/// rejected the most placements. Names are looked up from the root nodes rather than stored.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct PlannerRejections {
+    nodes: FnvHashMap<uuid::Uuid, NodeRejections>,
+}
+
+impl PlannerRejections {
+    fn record(&mut self, id: &uuid::Uuid, reason: LayerRejection) {
+        let node = self.nodes.entry(*id).or_insert(NodeRejections {
+            count: 0,
+            last: reason,
+        });
+
+        node.count += 1;
+        node.last = reason;
+    }
+
+    /// The failure for a search that ended without a valid plan, naming the most rejected node.
+    fn search_failure(&self, root_nodes: &[&dyn PlanGlobalExpansionNode]) -> PlanFailure {
+        let (id, node) = match self.nodes.iter().max_by_key(|(_, node)| node.count) {
+            Some(most_rejected) => most_rejected,
+            None => return PlanFailure::SearchExhausted,
+        };
+
+        let mut gathered_nodes = PlanGatherNodesData::new();
+
+        for root_node in root_nodes {
+            root_node.gather_nodes(&mut gathered_nodes);
+        }
+
+        PlanFailure::LayerRejected {
+            layer: *id,
+            name: gathered_nodes.get_name(id).unwrap_or_default().to_owned(),
+            reason: node.last,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PlanRunningStateData {
+    planner_state: PlannerState,
+    stack: SerializedEvaluationStack,
+    best_plan: Option<BestPlanData>,
+    #[serde(default)]
+    stats: PlannerStats,
+    #[serde(default)]
+    rejections: PlannerRejections,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace: Option<PlannerTrace>,
+}
+
+impl PlanRunningStateData {
+    pub fn stats(&self) -> &PlannerStats {
+        &self.stats
+    }
+
+    /// Placement trace for the run, if enabled with `Planner::with_trace`.
+    pub fn trace(&self) -> Option<&PlannerTrace> {
+        self.trace.as_ref()
+    }
+
+    /// Verifies that the root nodes supplied when resuming are the same set that was used to
+    /// produce the serialized evaluation stack.
+    pub fn validate_root_nodes(
+        &self,
+        root_nodes: &[&dyn PlanGlobalExpansionNode],
+    ) -> Result<(), String> {
+        let mut gathered_nodes = PlanGatherNodesData::new();
+
+        for node in root_nodes {
+            node.gather_nodes(&mut gathered_nodes);
+        }
+
+        self.stack.validate_nodes(&gathered_nodes)
+    }
+
+    pub fn visualize<V>(&self, visualizer: &mut V)
+    where
+        V: RoomVisualizer,
+    {
+        self.planner_state.visualize(visualizer);
+    }
+
+    /// The best plan found so far by an in-progress search and its score, without the road
+    /// connection passes that run once planning finishes.
+    pub fn best_plan_snapshot(&self) -> Option<(f32, Plan)> {
+        self.best_plan.as_ref().map(|best_plan| {
+            (
+                best_plan.score,
+                Plan {
+                    state: best_plan.state.clone(),
+                    reserved: best_plan.reserved.clone(),
+                    controller_container: None,
+                    controller_link: None,
+                    existing_roads: Vec::new(),
+                    routed_roads: Vec::new(),
+                    orthogonal_roads: false,
+                    filler_position: None,
+                    build_order: OnceCell::new(),
+                },
+            )
+        })
+    }
+
+    pub fn visualize_best<V>(&self, visualizer: &mut V)
+    where
+        V: RoomVisualizer,
+    {
+        if let Some(best_plan) = &self.best_plan {
+            let items = best_plan
+                .state
+                .iter()
+                .flat_map(|(location, entries)| entries.iter().map(move |entry| (location, entry)));
+
+            visualize_room_items(items, visualizer);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PlanFailure {
+    NoAnchor,
+    SearchExhausted,
+    NoSpawn,
+    InvalidRoomData(String),
+    InvalidState(String),
+    /// The best plan was found but could not be completed once the search finished.
+    FinalizeError(String),
+    /// The search found no valid plan, and the layer with the given node id rejected the most
+    /// placements. The name is the node's kind, such as "Fixed", and is shared by many layers.
+    LayerRejected {
+        layer: uuid::Uuid,
+        name: String,
+        reason: LayerRejection,
+    },
+}
+
+impl std::fmt::Display for PlanFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlanFailure::NoAnchor => write!(f, "No valid anchor location for plan"),
+            PlanFailure::SearchExhausted => write!(f, "Search exhausted without a valid plan"),
+            PlanFailure::NoSpawn => write!(f, "Best plan does not contain a spawn"),
+            PlanFailure::InvalidRoomData(err) => write!(f, "Invalid room data: {}", err),
+            PlanFailure::InvalidState(err) => write!(f, "Invalid planner state: {}", err),
+            PlanFailure::FinalizeError(err) => write!(f, "Failed to finalize plan: {}", err),
+            PlanFailure::LayerRejected {
+                layer,
+                name,
+                reason,
+            } => write!(
+                f,
+                "Search exhausted without a valid plan - {} layer {} rejected: {:?}",
+                name, layer, reason
+            ),
+        }
+    }
+}
+
+impl From<String> for PlanFailure {
+    fn from(err: String) -> PlanFailure {
+        PlanFailure::InvalidState(err)
+    }
+}
+
+/// Checks every source, mineral and controller supplied by the data source is inside the room
+/// and not on a terrain wall.
+fn validate_room_data(data_source: &mut dyn PlannerRoomDataSource) -> Result<(), PlanFailure> {
+    let mut locations = Vec::new();
+
+    locations.extend(data_source.get_sources().iter().map(|l| ("Source", *l)));
+    locations.extend(data_source.get_minerals().iter().map(|l| ("Mineral", *l)));
+    locations.extend(
+        data_source
+            .get_controllers()
+            .iter()
+            .map(|l| ("Controller", *l)),
+    );
+
+    let terrain = data_source.get_terrain();
+
+    for (name, location) in locations {
+        if !location.in_room_bounds() {
+            return Err(PlanFailure::InvalidRoomData(format!(
+                "{} at {}, {} is outside the room",
+                name,
+                location.x(),
+                location.y()
+            )));
+        }
+
+        if terrain
+            .get_xy(location.x() as u8, location.y() as u8)
+            .contains(TerrainFlags::WALL)
+        {
+            return Err(PlanFailure::InvalidRoomData(format!(
+                "{} at {}, {} is on a terrain wall",
+                name,
+                location.x(),
+                location.y()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+pub enum PlanSeedResult {
+    Complete(Plan),
+    Running(PlanRunningStateData),
+}
+
+pub enum PlanEvaluationResult {
+    Complete(Plan),
+    Running(),
+}
+
+pub trait PlannerRoomDataSource {
+    fn get_terrain(&mut self) -> &FastRoomTerrain;
+    fn get_controllers(&mut self) -> &[PlanLocation];
+    fn get_sources(&mut self) -> &[PlanLocation];
+    fn get_minerals(&mut self) -> &[PlanLocation];
+}
+
+/// Planning options in one place, as an alternative to the individual `Planner::with_*` calls.
+/// Each field matches the builder of the same name, and the defaults match `Planner::new`.
+#[derive(Clone, Debug)]
+pub struct PlanningConfig {
+    pub rcl_limits: RclLimits,
+    pub source_infra: SourceInfraPolicies,
+    pub container_roads: bool,
+    pub mineral_lab_road: bool,
+    pub exit_roads: bool,
+    pub prune_dead_end_roads: bool,
+    pub diagonal_roads: bool,
+    /// Defaults to `Some(1)`, giving every extension an adjacent road.
+    pub extension_road_distance: Option<u8>,
+    pub max_root_candidates: Option<usize>,
+    pub max_extension_shortfall: u8,
+    pub target_score: Option<f32>,
+    /// Cap on the number of extensions planned, applied on top of `rcl_limits`.
+    pub extension_target: Option<u8>,
+    /// Cap on the number of towers planned, applied on top of `rcl_limits`.
+    pub tower_count: Option<u8>,
+    /// Plan the lab cluster. When disabled labs are left out of `rcl_limits`, as with
+    /// `RclLimits::without`, so plans are complete without them. Enabled by default.
+    pub include_labs: bool,
+    pub layout_options: LayoutOptions,
+}
+
+impl Default for PlanningConfig {
+    fn default() -> PlanningConfig {
+        PlanningConfig {
+            rcl_limits: RclLimits::official(),
+            source_infra: SourceInfraPolicies::default(),
+            container_roads: true,
+            mineral_lab_road: false,
+            exit_roads: false,
+            prune_dead_end_roads: false,
+            diagonal_roads: true,
+            extension_road_distance: Some(1),
+            max_root_candidates: None,
+            max_extension_shortfall: 0,
+            target_score: None,
+            extension_target: None,
+            tower_count: None,
+            include_labs: true,
+            layout_options: LayoutOptions::default(),
+        }
+    }
+}
+
+pub struct Planner<S>
+where
+    S: Fn(&PlannerState, &mut NodeContext) -> Option<f32>,
+{
+    scorer: S,
+    rcl_limits: RclLimits,
+    source_infra: SourceInfraPolicies,
+    container_roads: bool,
+    mineral_lab_road: bool,
+    exit_roads: bool,
+    prune_dead_end_roads: bool,
+    diagonal_roads: bool,
+    extension_road_distance: Option<u8>,
+    max_root_candidates: Option<usize>,
+    max_extension_shortfall: u8,
+    existing_extensions: Vec<Location>,
+    existing_roads: Vec<Location>,
+    target_score: Option<f32>,
+    trace: bool,
+    layout_options: LayoutOptions,
+    analysis: Option<AnalysisOutput>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<S> Planner<S>
+where
+    S: Fn(&PlannerState, &mut NodeContext) -> Option<f32>,
+{
+    pub fn new(scorer: S) -> Planner<S> {
+        Planner::with_rcl_limits(scorer, RclLimits::official())
+    }
+
+    pub fn with_rcl_limits(scorer: S, rcl_limits: RclLimits) -> Planner<S> {
+        Planner::with_config(
+            scorer,
+            PlanningConfig {
+                rcl_limits,
+                ..PlanningConfig::default()
+            },
+        )
+    }
+
+    /// Create a planner with all planning options taken from `config`.
+    pub fn with_config(scorer: S, config: PlanningConfig) -> Planner<S> {
+        let mut rcl_limits = config.rcl_limits;
+
+        if let Some(extension_target) = config.extension_target {
+            rcl_limits = rcl_limits.with_max_count(StructureType::Extension, extension_target);
+        }
+
+        if let Some(tower_count) = config.tower_count {
+            rcl_limits = rcl_limits.with_max_count(StructureType::Tower, tower_count);
+        }
+
+        if !config.include_labs {
+            rcl_limits = rcl_limits.without(StructureType::Lab);
+        }
+
+        Planner {
+            scorer,
+            rcl_limits,
+            source_infra: config.source_infra,
+            container_roads: config.container_roads,
+            mineral_lab_road: config.mineral_lab_road,
+            exit_roads: config.exit_roads,
+            prune_dead_end_roads: config.prune_dead_end_roads,
+            diagonal_roads: config.diagonal_roads,
+            extension_road_distance: config.extension_road_distance,
+            max_root_candidates: config.max_root_candidates,
+            max_extension_shortfall: config.max_extension_shortfall,
+            existing_extensions: Vec::new(),
+            existing_roads: Vec::new(),
+            target_score: config.target_score,
+            trace: false,
+            layout_options: config.layout_options,
+            analysis: None,
+        }
+    }
+
+    /// Pave a route from each container to the roads around storage once planning finishes,
+    /// avoiding walls and reserved tiles. The layout already places a road next to each source
+    /// container during the search, which this joins up along with the controller and mineral
+    /// containers. Enabled by default.
+    pub fn with_container_roads(self, container_roads: bool) -> Planner<S> {
+        Planner {
+            container_roads,
+            ..self
+        }
+    }
+
+    /// Route a road from the mineral container to the labs once planning finishes, for shorter
+    /// hauling of minerals into reactions. Disabled by default.
+    pub fn with_mineral_lab_road(self, mineral_lab_road: bool) -> Planner<S> {
+        Planner {
+            mineral_lab_road,
+            ..self
+        }
+    }
+
+    /// Extend the roads around storage onto the nearest exit tile of each open side once
+    /// planning finishes, so haulers to other rooms stay on roads up to the border. Disabled by
+    /// default.
+    pub fn with_exit_roads(self, exit_roads: bool) -> Planner<S> {
+        Planner { exit_roads, ..self }
+    }
+
+    /// Let roads routed once planning finishes - joining containers, extensions and labs - step
+    /// diagonally. When disabled they only step orthogonally, for strictly orthogonal road
+    /// networks, and roads later added by `align_exit_roads` follow suit. Stamp roads are
+    /// unchanged. Enabled by default.
+    pub fn with_diagonal_roads(self, diagonal_roads: bool) -> Planner<S> {
+        Planner {
+            diagonal_roads,
+            ..self
+        }
+    }
+
+    /// Remove routed roads that dead end without serving any structure once planning finishes.
+    /// Roads ending at a source, controller, mineral or exit are kept, as are seeded and stamp
+    /// roads. Disabled by default.
+    pub fn with_dead_end_road_pruning(self, prune_dead_end_roads: bool) -> Planner<S> {
+        Planner {
+            prune_dead_end_roads,
+            ..self
+        }
+    }
+
+    /// Connect extensions without a road within the given distance to the road network once
+    /// planning finishes, or `None` to leave them as placed. Defaults to 1 (an adjacent road).
+    pub fn with_extension_road_distance(self, extension_road_distance: Option<u8>) -> Planner<S> {
+        Planner {
+            extension_road_distance,
+            ..self
+        }
+    }
+
+    /// Only keep the `max_root_candidates` best scoring anchor locations when seeding, pruning
+    /// the rest before any of their stamps are placed. Every hub variant at a kept anchor is
+    /// still tried. Bounds the number of branches the search evaluates in large open rooms.
+    pub fn with_max_root_candidates(self, max_root_candidates: usize) -> Planner<S> {
+        Planner {
+            max_root_candidates: Some(max_root_candidates),
+            ..self
+        }
+    }
+
+    /// Accept plans that fall up to `max_extension_shortfall` extensions short of the RCL limit
+    /// on rooms too tight to fit them all. Plans are penalized in proportion to the shortfall so
+    /// fuller plans are still preferred.
+    pub fn with_max_extension_shortfall(self, max_extension_shortfall: u8) -> Planner<S> {
+        Planner {
+            max_extension_shortfall,
+            ..self
+        }
+    }
+
+    /// Keep already built extensions, counting them towards the extension limit so only the
+    /// remainder are planned. Extensions beyond the limit are ignored.
+    pub fn with_existing_extensions(self, existing_extensions: Vec<Location>) -> Planner<S> {
+        Planner {
+            existing_extensions,
+            ..self
+        }
+    }
+
+    /// Stop searching as soon as a plan scoring at least `target_score` is found, rather than
+    /// exhausting the search for the best plan.
+    pub fn with_target_score(self, target_score: f32) -> Planner<S> {
+        Planner {
+            target_score: Some(target_score),
+            ..self
+        }
+    }
+
+    /// Record per node placement counts while searching, available from
+    /// `PlanRunningStateData::trace`. Disabled by default, when only the rejection counts needed
+    /// to explain a failed search are kept.
+    pub fn with_trace(self, trace: bool) -> Planner<S> {
+        Planner { trace, ..self }
+    }
+
+    /// Keep already built roads. Stamps may overlap them and connecting roads added once
+    /// planning finishes route onto them rather than alongside.
+    pub fn with_existing_roads(self, existing_roads: Vec<Location>) -> Planner<S> {
+        Planner {
+            existing_roads,
+            ..self
+        }
+    }
+
+    fn seed_existing_structures(&self, state: &mut PlannerState) {
+        let existing = self
+            .existing_extensions
+            .iter()
+            .map(|location| (location, StructureType::Extension))
+            .chain(
+                self.existing_roads
+                    .iter()
+                    .map(|location| (location, StructureType::Road)),
+            );
+
+        for (location, structure_type) in existing {
+            if let Some(required_rcl) =
+                state.get_rcl_for_next_structure(structure_type, &self.rcl_limits)
+            {
+                state.insert(
+                    *location,
+                    RoomItem {
+                        structure_type,
+                        required_rcl,
+                    },
+                );
+            }
+        }
+    }
+
+    fn finalize_plan(
+        &self,
+        state: PlanState,
+        reserved: Vec<Location>,
+        data_source: &mut dyn PlannerRoomDataSource,
+    ) -> Result<Plan, PlanFailure> {
+        let has_spawn = state
+            .values()
+            .flat_map(|entries| entries.iter())
+            .any(|entry| entry.structure_type == StructureType::Spawn);
+
+        // A custom scorer can accept states the default validators would reject - never hand
+        // back a plan that can't bootstrap the room.
+        if !has_spawn {
+            return Err(PlanFailure::NoSpawn);
+        }
+
+        let mut plan = Plan {
+            state,
+            reserved,
+            controller_container: None,
+            controller_link: None,
+            existing_roads: Vec::new(),
+            routed_roads: Vec::new(),
+            orthogonal_roads: !self.diagonal_roads,
+            filler_position: None,
+            build_order: OnceCell::new(),
+        };
+
+        plan.existing_roads = self
+            .existing_roads
+            .iter()
+            .copied()
+            .filter(|location| plan.has_structure(*location, StructureType::Road))
+            .collect();
+
+        plan.locate_controller_infra(data_source.get_controllers());
+
+        let terrain = data_source.get_terrain();
+
+        if let Some(extension_road_distance) = self.extension_road_distance {
+            plan.connect_extension_roads(terrain, extension_road_distance);
+        }
+
+        if self.mineral_lab_road {
+            plan.connect_mineral_containers_to_labs(terrain);
+        }
+
+        if self.container_roads {
+            plan.connect_container_roads(terrain)
+                .map_err(PlanFailure::FinalizeError)?;
+        }
+
+        if self.exit_roads {
+            plan.connect_exit_roads(data_source.get_terrain());
+        }
+
+        if self.prune_dead_end_roads {
+            let mut served = data_source.get_sources().to_vec();
+            served.extend(data_source.get_controllers().iter());
+            served.extend(data_source.get_minerals().iter());
+            served.extend(
+                data_source
+                    .get_terrain()
+                    .get_exits()
+                    .map(PlanLocation::from),
+            );
+
+            plan.prune_dead_end_roads(&served);
+        }
+
+        plan.locate_filler_position(data_source.get_terrain());
+
+        Ok(plan)
+    }
+
+    /// Choose which structures are placed at each source.
+    pub fn with_source_infra(self, source_infra: SourceInfraPolicies) -> Planner<S> {
+        Planner {
+            source_infra,
+            ..self
+        }
+    }
+
+    /// Enable optional features of the built in layout.
+    pub fn with_layout_options(self, layout_options: LayoutOptions) -> Planner<S> {
+        Planner {
+            layout_options,
+            ..self
+        }
+    }
+
+    /// Use a precomputed room analysis (see `run_analysis`) rather than recomputing it for each
+    /// search. Planning fails with `PlanFailure::InvalidRoomData` if the analysis was run on
+    /// different room data.
+    pub fn with_analysis(self, analysis: AnalysisOutput) -> Planner<S> {
+        Planner {
+            analysis: Some(analysis),
+            ..self
+        }
+    }
+
+    fn validate_analysis(
+        &self,
+        data_source: &mut dyn PlannerRoomDataSource,
+    ) -> Result<(), PlanFailure> {
+        match &self.analysis {
+            Some(analysis) if analysis.room_fingerprint != room_fingerprint(data_source) => Err(
+                PlanFailure::InvalidRoomData("Analysis was run on different room data".to_owned()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn seed(
+        &self,
+        root_nodes: &[&dyn PlanGlobalExpansionNode],
+        data_source: &mut dyn PlannerRoomDataSource,
+    ) -> Result<PlanSeedResult, PlanFailure> {
+        validate_room_data(data_source)?;
+
+        self.validate_analysis(data_source)?;
+
+        let mut planner_state = PlannerState::new();
+
+        self.seed_existing_structures(&mut planner_state);
+
+        let mut best_plan = None;
+
+        // Rejections are always recorded to explain a failed search, the full trace only on
+        // request.
+        let mut rejections = PlannerRejections::default();
+        let mut trace = Some(PlannerTrace::default()).filter(|_| self.trace);
+
+        let mut state_handler = |new_state: &PlannerState, context: &mut NodeContext| {
+            if let Some(score) = (self.scorer)(new_state, context) {
+                best_plan = Some(BestPlanData {
+                    score,
+                    state: new_state.snapshot(),
+                    reserved: new_state.get_reserved_locations(),
+                });
+            }
+        };
+
+        let mut planner = TreePlanner::new(
+            data_source,
+            &self.rcl_limits,
+            &self.source_infra,
+            self.max_extension_shortfall,
+            self.analysis.as_ref(),
+            &mut state_handler,
+            &mut rejections,
+            trace.as_mut(),
+        )
+        .with_layout_options(self.layout_options);
+
+        let seed_result = planner.seed(root_nodes, &mut planner_state, self.max_root_candidates)?;
+        let stats = planner.stats();
+
+        let seed_result = match seed_result {
+            TreePlannerResult::Complete => {
+                let plan = best_plan
+                    .take()
+                    .ok_or_else(|| rejections.search_failure(root_nodes))
+                    .and_then(|p| self.finalize_plan(p.state, p.reserved, data_source))?;
+
+                PlanSeedResult::Complete(plan)
+            }
+            TreePlannerResult::Running(stack) => {
+                let running_data = PlanRunningStateData {
+                    planner_state,
+                    stack,
+                    best_plan,
+                    stats,
+                    rejections,
+                    trace,
+                };
+
+                PlanSeedResult::Running(running_data)
+            }
+        };
+
+        Ok(seed_result)
+    }
+
+    pub fn evaluate<F>(
+        &self,
+        root_nodes: &[&dyn PlanGlobalExpansionNode],
+        data_source: &mut dyn PlannerRoomDataSource,
+        evaluation_state: &mut PlanRunningStateData,
+        should_continue: F,
+    ) -> Result<PlanEvaluationResult, PlanFailure>
+    where
+        F: Fn() -> bool,
+    {
+        self.validate_analysis(data_source)?;
+
+        let mut current_best = evaluation_state.best_plan.as_ref().map(|p| p.score);
+        let mut new_best_plan = None;
+
+        let target_reached = std::cell::Cell::new(false);
+
+        let mut state_handler = |new_state: &PlannerState, context: &mut NodeContext| {
+            if let Some(score) = (self.scorer)(new_state, context) {
+                if current_best.map(|s| score > s).unwrap_or(true) {
+                    new_best_plan = Some(BestPlanData {
+                        score,
+                        state: new_state.snapshot(),
+                        reserved: new_state.get_reserved_locations(),
+                    });
+
+                    current_best = Some(score);
+
+                    if self.target_score.map(|t| score >= t).unwrap_or(false) {
+                        target_reached.set(true);
+                    }
+                }
+            }
+        };
+
+        let mut planner = TreePlanner::new(
+            data_source,
+            &self.rcl_limits,
+            &self.source_infra,
+            self.max_extension_shortfall,
+            self.analysis.as_ref(),
+            &mut state_handler,
+            &mut evaluation_state.rejections,
+            evaluation_state.trace.as_mut(),
+        )
+        .with_layout_options(self.layout_options);
+
+        let process_result = planner.process(
+            root_nodes,
+            &mut evaluation_state.planner_state,
+            &evaluation_state.stack,
+            || !target_reached.get() && should_continue(),
+        )?;
+
+        evaluation_state.stats.merge(&planner.stats());
+
+        let process_result = match process_result {
+            TreePlannerResult::Running(_) if target_reached.get() => TreePlannerResult::Complete,
+            result => result,
+        };
+
+        let evaluate_result = match process_result {
+            TreePlannerResult::Complete => {
+                if new_best_plan.is_some() {
+                    evaluation_state.best_plan = new_best_plan;
+                }
+
+                let plan = evaluation_state
+                    .best_plan
+                    .take()
+                    .ok_or_else(|| evaluation_state.rejections.search_failure(root_nodes))
+                    .and_then(|p| self.finalize_plan(p.state, p.reserved, data_source))?;
+
+                PlanEvaluationResult::Complete(plan)
+            }
+            TreePlannerResult::Running(stack) => {
+                if new_best_plan.is_some() {
+                    evaluation_state.best_plan = new_best_plan;
+                }
+
+                evaluation_state.stack = stack;
+
+                PlanEvaluationResult::Running()
+            }
+        };
+
+        Ok(evaluate_result)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::layout;
+    use crate::scoring;
+    use crate::utility;
+
+    /// Room data for tests - terrain plus the controller, source and mineral locations.
+    pub(crate) struct TestRoom {
+        pub terrain: FastRoomTerrain,
+        pub controllers: Vec<PlanLocation>,
+        pub sources: Vec<PlanLocation>,
+        pub minerals: Vec<PlanLocation>,
+    }
+
+    impl TestRoom {
+        /// An open room walled along its border, apart from an exit on the top edge.
+        pub fn open() -> TestRoom {
+            TestRoom::with_walls(|_, _| false)
+        }
+
+        /// As `open`, with walls wherever `is_wall` returns true.
+        pub fn with_walls<F>(is_wall: F) -> TestRoom
+        where
+            F: Fn(u8, u8) -> bool,
+        {
+            let mut buffer = vec![0; (ROOM_WIDTH as usize) * (ROOM_HEIGHT as usize)];
+
+            for y in 0..ROOM_HEIGHT {
+                for x in 0..ROOM_WIDTH {
+                    let border = x == 0 || y == 0 || x == ROOM_WIDTH - 1 || y == ROOM_HEIGHT - 1;
+                    let exit = y == 0 && (20..30).contains(&x);
+
+                    if (border && !exit) || is_wall(x, y) {
+                        buffer[(y as usize) * (ROOM_WIDTH as usize) + (x as usize)] =
+                            TerrainFlags::WALL.bits();
+                    }
+                }
+            }
+
+            TestRoom {
+                terrain: FastRoomTerrain::new(buffer),
+                controllers: vec![PlanLocation::new(25, 12)],
+                sources: vec![PlanLocation::new(12, 30), PlanLocation::new(38, 35)],
+                minerals: vec![PlanLocation::new(30, 40)],
+            }
+        }
+    }
+
+    impl PlannerRoomDataSource for TestRoom {
+        fn get_terrain(&mut self) -> &FastRoomTerrain {
+            &self.terrain
+        }
+
+        fn get_controllers(&mut self) -> &[PlanLocation] {
+            &self.controllers
+        }
+
+        fn get_sources(&mut self) -> &[PlanLocation] {
+            &self.sources
+        }
+
+        fn get_minerals(&mut self) -> &[PlanLocation] {
+            &self.minerals
+        }
+    }
+
+    /// Runs the planner to completion over the bunker root nodes.
+    pub(crate) fn plan_room<S>(
+        planner: &Planner<S>,
+        room: &mut TestRoom,
+    ) -> Result<Plan, PlanFailure>
+    where
+        S: Fn(&PlannerState, &mut NodeContext) -> Option<f32>,
+    {
+        let mut running = match planner.seed(layout::ALL_ROOT_NODES, room)? {
+            PlanSeedResult::Complete(plan) => return Ok(plan),
+            PlanSeedResult::Running(running) => running,
+        };
+
+        loop {
+            if let PlanEvaluationResult::Complete(plan) =
+                planner.evaluate(layout::ALL_ROOT_NODES, room, &mut running, || true)?
+            {
+                return Ok(plan);
+            }
+        }
+    }
+
+    /// Runs the planner to completion, returning the search stats alongside the result.
+    pub(crate) fn plan_room_with_stats<S>(
+        planner: &Planner<S>,
+        room: &mut TestRoom,
+    ) -> (Result<Plan, PlanFailure>, PlannerStats)
+    where
+        S: Fn(&PlannerState, &mut NodeContext) -> Option<f32>,
+    {
+        let mut running = match planner.seed(layout::ALL_ROOT_NODES, room) {
+            Ok(PlanSeedResult::Complete(plan)) => return (Ok(plan), PlannerStats::default()),
+            Ok(PlanSeedResult::Running(running)) => running,
+            Err(err) => return (Err(err), PlannerStats::default()),
+        };
+
+        loop {
+            match planner.evaluate(layout::ALL_ROOT_NODES, room, &mut running, || true) {
+                Ok(PlanEvaluationResult::Running()) => {}
+                Ok(PlanEvaluationResult::Complete(plan)) => return (Ok(plan), *running.stats()),
+                Err(err) => return (Err(err), *running.stats()),
+            }
+        }
+    }
+
+    /// Default planner limited to the best root candidate, to keep tests quick.
+    pub(crate) fn test_planner(
+        config: PlanningConfig,
+    ) -> Planner<fn(&PlannerState, &mut NodeContext) -> Option<f32>> {
+        Planner::with_config(
+            scoring::score_state,
+            PlanningConfig {
+                max_root_candidates: Some(1),
+                ..config
+            },
+        )
     }
-}
 
-pub struct PlaceAwayFromWallsNode<'a> {
-    pub wall_distance: u32,
-    pub child: PlanNodeStorage<'a>,
-}
+    /// A plan holding exactly the given `(structure, x, y, rcl)` entries.
+    pub(crate) fn plan_from_items(items: &[(StructureType, u8, u8, u8)]) -> Plan {
+        let mut state = PlanState::default();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for PlaceAwayFromWallsNode<'a> {
-    fn name(&self) -> &str {
-        "Place Away From Walls"
-    }
+        for (structure_type, x, y, required_rcl) in items {
+            state
+                .entry(Location::from_coords(*x as u32, *y as u32))
+                .or_insert_with(Vec::new)
+                .push(RoomItem {
+                    structure_type: *structure_type,
+                    required_rcl: *required_rcl,
+                });
+        }
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        self.child.gather_nodes(data);
+        Plan {
+            state,
+            reserved: Vec::new(),
+            controller_container: None,
+            controller_link: None,
+            existing_roads: Vec::new(),
+            routed_roads: Vec::new(),
+            orthogonal_roads: false,
+            filler_position: None,
+            build_order: OnceCell::new(),
+        }
     }
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        self.child.desires_placement(context, state, gather_data)
+    pub(crate) fn room_item(structure_type: StructureType, required_rcl: u8) -> RoomItem {
+        RoomItem {
+            structure_type,
+            required_rcl,
+        }
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanGlobalNode for PlaceAwayFromWallsNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
+    pub(crate) fn count(plan: &Plan, structure_type: StructureType) -> usize {
+        plan.build_order()
+            .iter()
+            .filter(|step| step.structure_type == structure_type)
+            .count()
     }
 
-    fn get_children<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-        if !gather_data.has_visited_global(self) {
-            gather_data.mark_visited_global(self);
+    #[test]
+    fn config_caps_towers_and_extensions() {
+        let planner = test_planner(PlanningConfig {
+            extension_target: Some(50),
+            tower_count: Some(3),
+            ..PlanningConfig::default()
+        });
 
-            if self.child.desires_placement(context, state, gather_data) {
-                let locations: Vec<PlanLocation> = context
-                    .wall_distance()
-                    .iter()
-                    .filter(|(_, distance)| {
-                        distance.map(|d| d >= self.wall_distance).unwrap_or(false)
-                    })
-                    .map(|((x, y), _)| PlanLocation::new(x as i8, y as i8))
-                    .collect();
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        assert_eq!(count(&plan, StructureType::Tower), 3);
+        assert_eq!(count(&plan, StructureType::Extension), 50);
+    }
+
+    #[test]
+    fn fixed_node_only_skips_optional_placements_beyond_limits() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official().with_max_count(StructureType::Tower, 1);
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+
+        fn place_towers(second: PlanPlacement, context: &mut NodeContext) -> Result<u8, ()> {
+            let placements = [placement(StructureType::Tower, 0, 0), second];
+            let node = FixedPlanNode {
+                id: uuid::Uuid::from_u128(0),
+                placement_phase: PlacementPhase::Normal,
+                must_place: false,
+                placements: &placements,
+                child: PlanNodeStorage::Empty,
+                desires_placement: |_, _| true,
+                desires_location: |_, _, _| true,
+                maximum_scorer: |_, _, _| Some(0.0),
+                scorer: |_, _, _| Some(0.0),
+            };
 
-                for location in &locations {
-                    if self
-                        .child
-                        .desires_location(*location, context, state, gather_data)
-                    {
-                        self.child
-                            .insert_or_expand(*location, context, state, gather_data);
-                    }
-                }
-            }
+            let mut state = PlannerState::new();
+            let result = node.place(PlanLocation::new(25, 25), context, &mut state);
+
+            result.map(|_| state.get_count(StructureType::Tower))
         }
+
+        assert_eq!(
+            place_towers(placement(StructureType::Tower, 1, 0), &mut context),
+            Err(())
+        );
+        assert_eq!(
+            place_towers(
+                placement(StructureType::Tower, 1, 0).optional(),
+                &mut context
+            ),
+            Ok(1)
+        );
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanGlobalExpansionNode for PlaceAwayFromWallsNode<'a> {
-    fn as_global(&self) -> &dyn PlanGlobalNode {
-        self
+    #[test]
+    fn analysis_describes_its_room() {
+        let mut room = TestRoom::open();
+        let analysis = run_analysis(&mut room);
+
+        assert_eq!(analysis.exits.len(), 10);
+        assert_eq!(analysis.source_distances.len(), 2);
+        assert_eq!(*analysis.source_distances[0].0.get(12, 30), Some(0));
+        assert_eq!(*analysis.source_distances[0].0.get(17, 30), Some(5));
+        assert_eq!(
+            analysis.room_fingerprint(),
+            room_fingerprint(&mut TestRoom::open())
+        );
     }
-}
 
-#[derive(Copy, Clone)]
-pub struct PlanPlacement {
-    structure_type: StructureType,
-    offset: PlanLocation,
-    optional: bool,
-    rcl_override: Option<u8>,
-}
+    #[test]
+    fn analysis_of_other_room_data_is_rejected() {
+        let planner = test_planner(PlanningConfig::default())
+            .with_analysis(run_analysis(&mut TestRoom::open()));
 
-impl PlanPlacement {
-    pub const fn optional(self) -> Self {
-        Self {
-            optional: true,
-            ..self
+        let mut moved_source = TestRoom::open();
+        moved_source.sources[0] = PlanLocation::new(12, 32);
+
+        let mut walled = TestRoom::with_walls(|x, y| x == 5 && y > 40);
+
+        for room in [&mut moved_source, &mut walled].iter_mut() {
+            assert!(matches!(
+                plan_room(&planner, room),
+                Err(PlanFailure::InvalidRoomData(_))
+            ));
         }
     }
 
-    pub const fn rcl(self, rcl: u8) -> Self {
-        Self {
-            rcl_override: Some(rcl),
-            ..self
-        }
+    #[test]
+    fn analysis_is_checked_when_resuming() {
+        let planner = test_planner(PlanningConfig::default())
+            .with_analysis(run_analysis(&mut TestRoom::open()));
+        let expected = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        let mut room = TestRoom::open();
+
+        let running = match planner.seed(layout::ALL_ROOT_NODES, &mut room).unwrap() {
+            PlanSeedResult::Complete(_) => panic!("Seeding should not finish the search"),
+            PlanSeedResult::Running(running) => running,
+        };
+
+        let serialized = serde_json::to_string(&running).unwrap();
+
+        // A resumed search reusing the analysis finishes with the same plan.
+        let mut resumed: PlanRunningStateData = serde_json::from_str(&serialized).unwrap();
+
+        let plan = loop {
+            match planner.evaluate(layout::ALL_ROOT_NODES, &mut room, &mut resumed, || true) {
+                Ok(PlanEvaluationResult::Running()) => {}
+                Ok(PlanEvaluationResult::Complete(plan)) => break plan,
+                Err(err) => panic!("{}", err),
+            }
+        };
+
+        assert_eq!(plan.fingerprint(), expected.fingerprint());
+
+        // Resuming against different room data is rejected.
+        let mut resumed: PlanRunningStateData = serde_json::from_str(&serialized).unwrap();
+        let mut other_room = TestRoom::with_walls(|x, y| x == 5 && y > 40);
+
+        assert!(matches!(
+            planner.evaluate(
+                layout::ALL_ROOT_NODES,
+                &mut other_room,
+                &mut resumed,
+                || true
+            ),
+            Err(PlanFailure::InvalidRoomData(_))
+        ));
     }
 
-    fn can_place(
-        &self,
-        plan_location: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> bool {
-        if let Some(placement_location) = plan_location.as_build_location() {
-            if self.structure_type == StructureType::Extractor {
-                if !context.minerals().contains(&plan_location) {
-                    return false;
-                }
-            } else if context
-                .terrain()
-                .get(&placement_location)
-                .contains(TerrainFlags::WALL)
-            {
-                return false;
-            } else if !placement_location.in_room_from_edge(ROOM_BUILD_BORDER as u32 + 1) {
-                return false;
+    #[test]
+    fn interrupted_search_resumes_to_the_same_plan() {
+        let planner = test_planner(PlanningConfig::default());
+        let expected =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        let mut room = TestRoom::open();
+
+        let mut running = match planner.seed(layout::ALL_ROOT_NODES, &mut room).unwrap() {
+            PlanSeedResult::Complete(_) => panic!("Seeding should not finish the search"),
+            PlanSeedResult::Running(running) => running,
+        };
+
+        let mut interruptions = 0;
+
+        let plan = loop {
+            // Run a few steps at a time, round tripping the state between each as if the CPU
+            // budget ran out.
+            let serialized = serde_json::to_string(&running).unwrap();
+            running = serde_json::from_str(&serialized).unwrap();
+
+            assert!(running.validate_root_nodes(layout::ALL_ROOT_NODES).is_ok());
+            assert!(running.validate_root_nodes(&[]).is_err());
+
+            let steps = std::cell::Cell::new(0);
+
+            match planner.evaluate(layout::ALL_ROOT_NODES, &mut room, &mut running, || {
+                steps.set(steps.get() + 1);
+                steps.get() <= 20
+            }) {
+                Ok(PlanEvaluationResult::Running()) => interruptions += 1,
+                Ok(PlanEvaluationResult::Complete(plan)) => break plan,
+                Err(err) => panic!("{}", err),
             }
+        };
 
-            for existing in state.get(&placement_location).iter().flat_map(|v| v.iter()) {
-                let valid = match existing.structure_type {
-                    StructureType::Road => self.structure_type == StructureType::Road,
-                    StructureType::Rampart => true,
-                    _ => self.structure_type == StructureType::Rampart,
-                };
+        assert!(interruptions > 0);
+        assert_eq!(plan.fingerprint(), expected.fingerprint());
+    }
 
-                if !valid {
-                    return false;
-                }
+    #[test]
+    fn stats_grow_over_a_run() {
+        let planner = test_planner(PlanningConfig::default());
+        let mut room = TestRoom::open();
+
+        let mut running = match planner.seed(layout::ALL_ROOT_NODES, &mut room).unwrap() {
+            PlanSeedResult::Complete(_) => panic!("Seeding should not finish the search"),
+            PlanSeedResult::Running(running) => running,
+        };
+
+        let mut previous = *running.stats();
+
+        loop {
+            let steps = std::cell::Cell::new(0);
+
+            let result = planner.evaluate(layout::ALL_ROOT_NODES, &mut room, &mut running, || {
+                steps.set(steps.get() + 1);
+                steps.get() <= 20
+            });
+
+            let stats = *running.stats();
+
+            assert!(stats.peak_stack_depth() >= previous.peak_stack_depth());
+            assert!(stats.nodes_expanded() >= previous.nodes_expanded());
+            assert!(stats.peak_item_count() >= previous.peak_item_count());
+
+            previous = stats;
+
+            match result {
+                Ok(PlanEvaluationResult::Running()) => {}
+                Ok(PlanEvaluationResult::Complete(_)) => break,
+                Err(err) => panic!("{}", err),
             }
-        } else {
-            return false;
         }
 
-        true
+        assert!(previous.peak_stack_depth() > 1);
+        assert!(previous.nodes_expanded() > 0);
+        assert!(previous.peak_item_count() > 0);
     }
-}
 
-pub const fn placement(structure_type: StructureType, x: i8, y: i8) -> PlanPlacement {
-    PlanPlacement {
-        structure_type,
-        offset: PlanLocation { x, y },
-        optional: false,
-        rcl_override: None,
-    }
-}
+    #[test]
+    fn supplied_analysis_is_used() {
+        let mut analysis = run_analysis(&mut TestRoom::open());
 
-pub struct FixedPlanNode<'a> {
-    pub id: uuid::Uuid,
-    pub placement_phase: PlacementPhase,
-    pub must_place: bool,
-    pub placements: &'a [PlanPlacement],
-    pub child: PlanNodeStorage<'a>,
-    pub desires_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
-    pub desires_location:
-        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> bool,
-    pub maximum_scorer:
-        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> Option<f32>,
-    pub scorer:
-        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> Option<f32>,
-}
+        // With no tile clear of walls no bunker anchor fits, which the planner would only find if
+        // it read the supplied analysis rather than recomputing it.
+        analysis.wall_distance = RoomDataArray::new(Some(0));
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for FixedPlanNode<'a> {
-    fn name(&self) -> &str {
-        "Fixed"
+        let planner = test_planner(PlanningConfig::default()).with_analysis(analysis);
+
+        assert!(matches!(
+            plan_room(&planner, &mut TestRoom::open()),
+            Err(PlanFailure::NoAnchor)
+        ));
     }
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        if data.insert_location_placement(self.id, self) {
-            self.child.gather_nodes(data);
+    #[test]
+    fn shared_analysis_matches_fresh_plans() {
+        let analysis = run_analysis(&mut TestRoom::open());
+
+        for config in [
+            PlanningConfig::default(),
+            PlanningConfig {
+                tower_count: Some(3),
+                ..PlanningConfig::default()
+            },
+        ]
+        .iter()
+        {
+            let fresh = plan_room(&test_planner(config.clone()), &mut TestRoom::open())
+                .unwrap_or_else(|err| panic!("{}", err));
+            let shared = plan_room(
+                &test_planner(config.clone()).with_analysis(analysis.clone()),
+                &mut TestRoom::open(),
+            )
+            .unwrap_or_else(|err| panic!("{}", err));
+
+            assert_eq!(shared.fingerprint(), fresh.fingerprint());
         }
     }
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        _gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        (self.desires_placement)(context, state)
+    #[test]
+    fn low_target_score_ends_search_early() {
+        let (plan, stats) = plan_room_with_stats(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        );
+        let (early_plan, early_stats) = plan_room_with_stats(
+            &test_planner(PlanningConfig {
+                target_score: Some(f32::MIN),
+                ..PlanningConfig::default()
+            }),
+            &mut TestRoom::open(),
+        );
+
+        assert!(plan.is_ok());
+        assert!(early_plan.is_ok());
+        assert!(early_stats.nodes_expanded() < stats.nodes_expanded());
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationNode for FixedPlanNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
+    #[test]
+    fn source_outside_room_is_invalid() {
+        let mut room = TestRoom::open();
+        room.sources[0] = PlanLocation::new(60, 30);
+
+        let result = plan_room(&test_planner(PlanningConfig::default()), &mut room);
+
+        assert!(matches!(
+            result,
+            Err(PlanFailure::InvalidRoomData(ref err)) if err == "Source at 60, 30 is outside the room"
+        ));
     }
 
-    fn desires_location<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        _gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        if (self.desires_location)(position, context, state) {
-            self.placements.iter().all(|placement| {
-                placement.optional
-                    || placement.can_place(position + placement.offset, context, state)
-            })
-        } else {
-            false
-        }
+    #[test]
+    fn source_on_wall_is_invalid() {
+        let mut room = TestRoom::with_walls(|x, y| (x, y) == (12, 30));
+
+        let result = plan_room(&test_planner(PlanningConfig::default()), &mut room);
+
+        assert!(matches!(
+            result,
+            Err(PlanFailure::InvalidRoomData(ref err)) if err == "Source at 12, 30 is on a terrain wall"
+        ));
     }
 
-    fn get_children<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-        if !gather_data.has_visited_location(position, self) {
-            gather_data.mark_visited_location(position, self);
+    #[test]
+    fn room_without_sources_is_planned() {
+        let mut room = TestRoom::open();
+        room.sources.clear();
 
-            if self.child.desires_placement(context, state, gather_data)
-                && self
-                    .child
-                    .desires_location(position, context, state, gather_data)
-            {
-                self.child
-                    .insert_or_expand(position, context, state, gather_data);
-            }
+        let plan = plan_room(&test_planner(PlanningConfig::default()), &mut room)
+            .unwrap_or_else(|err| panic!("{}", err));
+
+        assert!(count(&plan, StructureType::Spawn) > 0);
+        assert_eq!(count(&plan, StructureType::Extension), 60);
+    }
+
+    #[test]
+    fn max_stamp_candidates_bounds_search() {
+        let (plan, stats) = plan_room_with_stats(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        );
+        let (capped_plan, capped_stats) = plan_room_with_stats(
+            &test_planner(PlanningConfig {
+                layout_options: LayoutOptions {
+                    max_stamp_candidates: Some(1),
+                    ..LayoutOptions::default()
+                },
+                ..PlanningConfig::default()
+            }),
+            &mut TestRoom::open(),
+        );
+
+        assert!(plan.is_ok());
+        assert!(capped_plan.is_ok());
+        assert!(capped_stats.nodes_expanded() < stats.nodes_expanded());
+    }
+
+    #[test]
+    fn plan_without_labs_is_complete() {
+        let planner = test_planner(PlanningConfig {
+            include_labs: false,
+            ..PlanningConfig::default()
+        });
+
+        let mut room = TestRoom::open();
+
+        let plan = plan_room(&planner, &mut room).unwrap_or_else(|err| panic!("{}", err));
+
+        assert_eq!(count(&plan, StructureType::Lab), 0);
+
+        let mut state = PlannerState::new();
+
+        for step in plan.build_order() {
+            state.insert(
+                step.location,
+                room_item(step.structure_type, step.required_rcl),
+            );
         }
-    }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationPlacementNode for FixedPlanNode<'a> {
-    fn as_location(&self) -> &dyn PlanLocationNode {
-        self
-    }
+        let rcl_limits = RclLimits::official().without(StructureType::Lab);
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
 
-    fn id(&self) -> &uuid::Uuid {
-        &self.id
+        assert!(utility::has_mandatory_buildings(&state, &mut context));
     }
 
-    fn placement_phase(&self) -> PlacementPhase {
-        self.placement_phase
-    }
+    #[test]
+    fn spawnless_best_state_is_rejected() {
+        // A custom scorer that only accepts states the default validators would reject.
+        let planner = Planner::with_config(
+            |state: &PlannerState, _: &mut NodeContext| {
+                if state.get_count(StructureType::Spawn) == 0 {
+                    Some(1.0)
+                } else {
+                    None
+                }
+            },
+            PlanningConfig {
+                max_root_candidates: Some(1),
+                ..PlanningConfig::default()
+            },
+        );
 
-    fn must_place(&self) -> bool {
-        self.must_place
-    }
+        let result = plan_room(&planner, &mut TestRoom::open());
 
-    fn get_maximum_score(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> Option<f32> {
-        (self.maximum_scorer)(position, context, state)
+        assert!(matches!(result, Err(PlanFailure::NoSpawn)));
     }
 
-    fn get_score(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> Option<f32> {
-        (self.scorer)(position, context, state)
-    }
+    #[test]
+    fn existing_extensions_count_towards_the_target() {
+        let existing: Vec<_> = (36..46)
+            .flat_map(|x| vec![Location::from_coords(x, 40), Location::from_coords(x, 42)])
+            .collect();
 
-    fn ready_for_placement(&self, _context: &mut NodeContext, _state: &PlannerState) -> bool {
-        true
+        let planner =
+            test_planner(PlanningConfig::default()).with_existing_extensions(existing.clone());
+
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        assert_eq!(existing.len(), 20);
+        assert_eq!(count(&plan, StructureType::Extension), 60);
+        assert!(existing
+            .iter()
+            .all(|location| plan.has_structure(*location, StructureType::Extension)));
     }
 
-    fn place(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &mut PlannerState,
-    ) -> Result<(), ()> {
-        let mut min_rcl = None;
+    #[test]
+    fn existing_source_road_is_reused() {
+        let baseline = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
 
-        for placement in self
-            .placements
+        // Already built from the hub road at (18, 25) to the source at (12, 30).
+        let existing: Vec<_> = [(17, 26), (16, 27), (15, 28), (14, 29)]
             .iter()
-            .filter(|p| p.structure_type != StructureType::Road)
-        {
-            let placement_location = (position + placement.offset).as_location().unwrap();
+            .map(|(x, y)| Location::from_coords(*x, *y))
+            .collect();
 
-            if !placement.optional || placement.can_place(placement_location.into(), context, state)
-            {
-                let rcl = if let Some(rcl) = placement.rcl_override {
-                    rcl
-                } else {
-                    //TODO: This isn't quite right - should find the lowest unused RCL.
-                    state
-                        .get_rcl_for_next_structure(placement.structure_type)
-                        .ok_or(())?
-                };
+        let planner = test_planner(PlanningConfig::default()).with_existing_roads(existing.clone());
 
-                min_rcl = min_rcl.map(|r| if rcl < r { rcl } else { r }).or(Some(rcl));
+        let mut room = TestRoom::open();
+        let plan = plan_room(&planner, &mut room).unwrap_or_else(|err| panic!("{}", err));
 
-                state.insert(
-                    placement_location,
-                    RoomItem {
-                        structure_type: placement.structure_type,
-                        required_rcl: rcl,
-                    },
-                );
-            }
-        }
+        assert!(existing
+            .iter()
+            .all(|location| plan.has_structure(*location, StructureType::Road)));
 
-        let road_rcl = min_rcl.unwrap_or(1);
+        // The source is served by the built road rather than a new one alongside it.
+        assert!(road_locations(&plan).len() <= road_locations(&baseline).len());
 
-        for placement in self
-            .placements
+        let network = plan.hub_road_network();
+
+        assert!(existing.iter().all(|location| network.contains(location)));
+
+        let storage = plan
+            .build_order()
             .iter()
-            .filter(|p| p.structure_type == StructureType::Road)
-        {
-            let placement_location = (position + placement.offset).as_location().unwrap();
+            .find(|step| step.structure_type == StructureType::Storage)
+            .map(|step| step.location)
+            .unwrap();
 
-            if !placement.optional || placement.can_place(placement_location.into(), context, state)
-            {
-                state.insert(
-                    placement_location,
-                    RoomItem {
-                        structure_type: placement.structure_type,
-                        required_rcl: road_rcl,
-                    },
-                );
-            }
-        }
+        let source = Location::from_coords(12, 30);
 
-        Ok(())
-    }
-}
+        let container = plan
+            .build_order()
+            .iter()
+            .filter(|step| step.structure_type == StructureType::Container)
+            .map(|step| step.location)
+            .min_by_key(|location| location.distance_to(source))
+            .unwrap();
 
-pub struct OffsetPlanNode<'a> {
-    pub offsets: &'a [(i8, i8)],
-    pub child: PlanNodeStorage<'a>,
-}
+        let path = plan
+            .find_path_to(container, &[storage], 1, &room.terrain)
+            .unwrap();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for OffsetPlanNode<'a> {
-    fn name(&self) -> &str {
-        "Offset"
+        assert!(existing.iter().all(|location| path.contains(location)));
     }
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        self.child.gather_nodes(data);
-    }
+    #[test]
+    fn container_road_routes_over_existing_road() {
+        let mut items = vec![
+            (StructureType::Storage, 25, 25, 4),
+            (StructureType::Road, 26, 25, 1),
+            (StructureType::Container, 36, 25, 1),
+        ];
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        self.child.desires_placement(context, state, gather_data)
-    }
-}
+        // A built road that is no shorter than the straight route to the container, and not yet
+        // joined to the roads around storage.
+        let existing: Vec<_> = (28..=34).map(|x| Location::from_coords(x, 27)).collect();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationNode for OffsetPlanNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
-    }
+        for location in existing.iter() {
+            items.push((StructureType::Road, location.x(), location.y(), 1));
+        }
 
-    fn desires_location<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        self.offsets.iter().any(|offset| {
-            let offset_position = position + offset;
+        let mut plan = plan_from_items(&items);
+        plan.existing_roads = existing;
 
-            self.child
-                .desires_location(offset_position, context, state, gather_data)
-        })
+        let roads_before = road_locations(&plan);
+
+        let room = TestRoom::open();
+
+        assert!(plan.connect_container_roads(&room.terrain).is_ok());
+
+        let mut added: Vec<_> = road_locations(&plan)
+            .into_iter()
+            .filter(|location| !roads_before.contains(location))
+            .collect();
+
+        added.sort_by_key(|location| location.packed_repr());
+
+        assert_eq!(
+            added,
+            vec![Location::from_coords(27, 26), Location::from_coords(35, 26)]
+        );
     }
 
-    fn get_children<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-        if !gather_data.has_visited_location(position, self) {
-            gather_data.mark_visited_location(position, self);
+    #[test]
+    fn orthogonal_container_road_steps_orthogonally() {
+        let mut plan = plan_from_items(&[
+            (StructureType::Storage, 25, 25, 4),
+            (StructureType::Road, 26, 25, 1),
+            (StructureType::Container, 36, 30, 1),
+        ]);
+        plan.orthogonal_roads = true;
 
-            if self.child.desires_placement(context, state, gather_data) {
-                for offset in self.offsets.iter() {
-                    let offset_position = position + offset;
+        let room = TestRoom::open();
+        let container = Location::from_coords(36, 30);
+        let hub_road = Location::from_coords(26, 25);
 
-                    if self
-                        .child
-                        .desires_location(offset_position, context, state, gather_data)
-                    {
-                        self.child
-                            .insert_or_expand(offset_position, context, state, gather_data);
-                    }
-                }
-            }
+        let path = plan
+            .find_road_path_to(container, &[hub_road], 0, &room.terrain)
+            .unwrap();
+
+        for pair in path.windows(2) {
+            let dx = (pair[0].x() as i32 - pair[1].x() as i32).abs();
+            let dy = (pair[0].y() as i32 - pair[1].y() as i32).abs();
+
+            assert_eq!(dx + dy, 1);
         }
-    }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanPlacementExpansionNode for OffsetPlanNode<'a> {
-    fn as_location(&self) -> &dyn PlanLocationNode {
-        self
-    }
-}
+        let roads_before = road_locations(&plan);
 
-pub struct MultiPlacementExpansionNode<'a> {
-    pub children: &'a [PlanNodeStorage<'a>],
-}
+        assert!(plan.connect_container_roads(&room.terrain).is_ok());
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for MultiPlacementExpansionNode<'a> {
-    fn name(&self) -> &str {
-        "Multi Placement Expansion"
+        // Ten steps across and five down, less the road already next to storage.
+        assert_eq!(road_locations(&plan).len() - roads_before.len(), 14);
     }
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        for child in self.children.iter() {
-            child.gather_nodes(data);
+    #[test]
+    fn planner_without_diagonal_roads_routes_orthogonally() {
+        let planner = test_planner(PlanningConfig {
+            diagonal_roads: false,
+            ..PlanningConfig::default()
+        });
+
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        let routed: Vec<_> = road_locations(&plan)
+            .into_iter()
+            .filter(|location| plan.road_origin(*location) == Some(RoadOrigin::Routed))
+            .collect();
+
+        assert!(plan.orthogonal_roads);
+        assert!(!routed.is_empty());
+
+        // Each routed road steps orthogonally on from a road or the structure it was routed from.
+        for location in routed {
+            let joined = ONE_OFFSET_CROSS
+                .iter()
+                .filter_map(|offset| (PlanLocation::from(location) + offset).as_location())
+                .any(|neighbour| {
+                    plan.tile_structures(neighbour).iter().any(|item| {
+                        matches!(
+                            item.structure_type(),
+                            StructureType::Road
+                                | StructureType::Container
+                                | StructureType::Extension
+                        )
+                    })
+                });
+
+            assert!(joined);
         }
     }
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        self.children
-            .iter()
-            .any(|child| child.desires_placement(context, state, gather_data))
-    }
-}
+    #[test]
+    fn seeding_more_roads_than_a_count_holds() {
+        let existing: Vec<_> = (0..300)
+            .map(|index| Location::from_coords(2 + index % 46, 2 + index / 46))
+            .collect();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationNode for MultiPlacementExpansionNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
-    }
+        let planner = test_planner(PlanningConfig::default()).with_existing_roads(existing.clone());
 
-    fn desires_location<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        self.children
-            .iter()
-            .any(|child| child.desires_location(position, context, state, gather_data))
-    }
+        let mut state = PlannerState::new();
 
-    fn get_children<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-        if !gather_data.has_visited_location(position, self) {
-            gather_data.mark_visited_location(position, self);
+        planner.seed_existing_structures(&mut state);
 
-            for child in self.children.iter() {
-                if child.desires_placement(context, state, gather_data)
-                    && child.desires_location(position, context, state, gather_data)
-                {
-                    child.insert_or_expand(position, context, state, gather_data);
-                }
-            }
+        assert_eq!(state.get_count(StructureType::Road), u8::MAX);
+        for location in existing.iter() {
+            let items = state.get(location).unwrap_or_default();
+
+            assert!(items
+                .iter()
+                .any(|item| item.structure_type == StructureType::Road));
         }
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanPlacementExpansionNode for MultiPlacementExpansionNode<'a> {
-    fn as_location(&self) -> &dyn PlanLocationNode {
-        self
+    #[test]
+    fn max_count_below_stamp_leaves_tiles_empty() {
+        let planner = Planner::with_rcl_limits(
+            scoring::score_state as fn(&PlannerState, &mut NodeContext) -> Option<f32>,
+            RclLimits::official().with_max_count(StructureType::Tower, 3),
+        )
+        .with_max_root_candidates(1);
+
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        assert_eq!(count(&plan, StructureType::Tower), 3);
+        assert_eq!(count(&plan, StructureType::Spawn), 3);
     }
-}
 
-pub struct LazyPlanNode<'a> {
-    pub child: fn() -> PlanNodeStorage<'a>,
-}
+    #[test]
+    fn custom_rcl_limits_schedule() {
+        let limits = RclLimits::official()
+            .with_limits(StructureType::Extension, [0, 0, 5, 10, 20, 30, 40, 60, 80]);
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for LazyPlanNode<'a> {
-    fn name(&self) -> &str {
-        "Lazy"
+        assert_eq!(limits.get_max_count(StructureType::Extension), 80);
+        assert_eq!(limits.get_min_rcl(StructureType::Extension, 61), Some(8));
+        assert_eq!(limits.get_min_rcl(StructureType::Extension, 81), None);
+        assert_eq!(
+            limits
+                .with_max_rcl(7)
+                .get_max_count(StructureType::Extension),
+            60
+        );
     }
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        let node = (self.child)();
+    #[test]
+    fn custom_rcl_limits_place_extra_extensions() {
+        let planner = test_planner(PlanningConfig {
+            rcl_limits: RclLimits::official()
+                .with_limits(StructureType::Extension, [0, 0, 5, 10, 20, 30, 40, 60, 80]),
+            ..PlanningConfig::default()
+        });
 
-        node.gather_nodes(data);
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        assert_eq!(count(&plan, StructureType::Extension), 80);
     }
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        let node = (self.child)();
+    #[test]
+    fn room_is_plannable_needs_depth_and_space() {
+        let pocket = |size: u8| {
+            TestRoom::with_walls(|x, y| {
+                !(20..20 + size).contains(&x) || !(20..20 + size).contains(&y)
+            })
+        };
 
-        node.desires_placement(context, state, gather_data)
+        assert!(room_is_plannable(&TestRoom::open().terrain));
+
+        // Plenty of buildable tiles, but none deep enough for a hub.
+        let grid = TestRoom::with_walls(|x, y| x % 3 == 0 || y % 3 == 0);
+        assert!(!room_is_plannable(&grid.terrain));
+
+        // Deep enough for a hub, but 81 tiles is short of the minimum.
+        assert!(!room_is_plannable(&pocket(9).terrain));
+        assert!(room_is_plannable(&pocket(13).terrain));
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationNode for LazyPlanNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
+    #[test]
+    fn room_without_open_space_has_no_anchor() {
+        let mut room = TestRoom::with_walls(|x, y| x % 3 == 0 || y % 3 == 0);
+        room.controllers = vec![PlanLocation::new(25, 13)];
+        room.sources = vec![PlanLocation::new(13, 31), PlanLocation::new(38, 34)];
+        room.minerals = vec![PlanLocation::new(31, 40)];
+
+        let result = plan_room(&test_planner(PlanningConfig::default()), &mut room);
+
+        assert!(matches!(result, Err(PlanFailure::NoAnchor)));
     }
 
-    fn desires_location<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        let node = (self.child)();
+    #[test]
+    fn unreachable_extension_target_exhausts_search() {
+        // A pocket joined to the exit by a corridor, far too small for 200 extensions.
+        let cramped = || {
+            let mut room = TestRoom::with_walls(|x, y| {
+                !(16..=32).contains(&x) || y > 32 || (y < 16 && !(24..27).contains(&x))
+            });
+            room.controllers = vec![PlanLocation::new(18, 16)];
+            room.sources = vec![PlanLocation::new(16, 32), PlanLocation::new(32, 32)];
+            room.minerals = vec![PlanLocation::new(32, 16)];
+            room
+        };
 
-        node.desires_location(position, context, state, gather_data)
+        assert!(plan_room(&test_planner(PlanningConfig::default()), &mut cramped()).is_ok());
+
+        let over_constrained = test_planner(PlanningConfig {
+            rcl_limits: RclLimits::official()
+                .with_limits(StructureType::Extension, [0, 0, 5, 10, 20, 30, 40, 50, 200]),
+            ..PlanningConfig::default()
+        });
+
+        let result = plan_room(&over_constrained, &mut cramped());
+
+        // The extension flood fill fails its validator every time it is placed.
+        let extension_fill_id = uuid::Uuid::from_u128(0xeff2_1b89_0149_4bc9_b4f4_8138_5cd6_5232);
+
+        assert!(matches!(
+            result,
+            Err(PlanFailure::LayerRejected {
+                layer,
+                ref name,
+                reason: LayerRejection::PlacementFailed,
+            }) if layer == extension_fill_id && name == "Flood Fill"
+        ));
     }
 
-    fn get_children<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-        if !gather_data.has_visited_location(position, self) {
-            gather_data.mark_visited_location(position, self);
+    #[test]
+    fn layer_rejected_identifies_layers_sharing_a_name() {
+        // The lab stamp and the extension cross are both fixed nodes.
+        let labs_id = uuid::Uuid::from_u128(0xd2d0_407f_9f30_4f98_9f40_8d1d_4c05_5981);
+        let extension_cross_id = uuid::Uuid::from_u128(0x68fd_8e22_e7b9_46f4_b798_5efa_0924_8095);
 
-            let node = (self.child)();
+        let failure_for = |id: &uuid::Uuid| {
+            let mut rejections = PlannerRejections::default();
+            rejections.record(id, LayerRejection::PlacementFailed);
 
-            if node.desires_placement(context, state, gather_data)
-                && node.desires_location(position, context, state, gather_data)
-            {
-                node.insert_or_expand(position, context, state, gather_data);
+            match rejections.search_failure(layout::ALL_ROOT_NODES) {
+                PlanFailure::LayerRejected { layer, name, .. } => (layer, name),
+                failure => panic!("Unexpected failure: {}", failure),
             }
-        }
-    }
-}
+        };
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanPlacementExpansionNode for LazyPlanNode<'a> {
-    fn as_location(&self) -> &dyn PlanLocationNode {
-        self
+        let (labs_layer, labs_name) = failure_for(&labs_id);
+        let (cross_layer, cross_name) = failure_for(&extension_cross_id);
+
+        assert_eq!(labs_name, "Fixed");
+        assert_eq!(cross_name, "Fixed");
+        assert_eq!(labs_layer, labs_id);
+        assert_eq!(cross_layer, extension_cross_id);
+        assert_ne!(labs_layer, cross_layer);
     }
-}
 
-pub struct FixedLocationPlanNode<'a> {
-    pub locations: fn(context: &mut NodeContext) -> Vec<PlanLocation>,
-    pub child: PlanNodeStorage<'a>,
-}
+    #[test]
+    fn trace_attributes_small_room_failures_to_extensions() {
+        // A pocket joined to the exit by a corridor, too small for 60 extensions.
+        let mut room = TestRoom::with_walls(|x, y| {
+            !(18..=30).contains(&x) || y > 30 || (y < 16 && !(24..27).contains(&x))
+        });
+        room.controllers = vec![PlanLocation::new(19, 16)];
+        room.sources = vec![PlanLocation::new(18, 30), PlanLocation::new(30, 30)];
+        room.minerals = vec![PlanLocation::new(30, 16)];
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for FixedLocationPlanNode<'a> {
-    fn name(&self) -> &str {
-        "Fixed Locations"
-    }
+        let planner = test_planner(PlanningConfig::default()).with_trace(true);
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        self.child.gather_nodes(data);
-    }
+        let mut running = match planner.seed(layout::ALL_ROOT_NODES, &mut room).unwrap() {
+            PlanSeedResult::Complete(_) => panic!("Seeding should not finish the search"),
+            PlanSeedResult::Running(running) => running,
+        };
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        self.child.desires_placement(context, state, gather_data)
-    }
-}
+        let result = loop {
+            match planner.evaluate(layout::ALL_ROOT_NODES, &mut room, &mut running, || true) {
+                Ok(PlanEvaluationResult::Running()) => {}
+                result => break result,
+            }
+        };
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanGlobalNode for FixedLocationPlanNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
+        assert!(result.is_err());
+
+        // The extension flood fill is the choke.
+        let extension_fill_id = uuid::Uuid::from_u128(0xeff2_1b89_0149_4bc9_b4f4_8138_5cd6_5232);
+        let (id, node) = running.trace().unwrap().most_rejected().unwrap();
+
+        assert_eq!(*id, extension_fill_id);
+        assert_eq!(node.name, "Flood Fill");
     }
 
-    fn get_children<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-        if !gather_data.has_visited_global(self) {
-            gather_data.mark_visited_global(self);
+    #[test]
+    fn trace_is_only_recorded_when_enabled() {
+        let planner = test_planner(PlanningConfig::default());
+        let mut room = TestRoom::open();
 
-            if self.child.desires_placement(context, state, gather_data) {
-                let locations = (self.locations)(context);
+        let mut running = match planner.seed(layout::ALL_ROOT_NODES, &mut room).unwrap() {
+            PlanSeedResult::Complete(_) => panic!("Seeding should not finish the search"),
+            PlanSeedResult::Running(running) => running,
+        };
 
-                for location in locations {
-                    if self
-                        .child
-                        .desires_location(location, context, state, gather_data)
-                    {
-                        self.child
-                            .insert_or_expand(location, context, state, gather_data);
-                    }
-                }
+        for _ in 0..10 {
+            let result =
+                planner.evaluate(layout::ALL_ROOT_NODES, &mut room, &mut running, || false);
+
+            if !matches!(result, Ok(PlanEvaluationResult::Running())) {
+                break;
             }
         }
-    }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanGlobalExpansionNode for FixedLocationPlanNode<'a> {
-    fn as_global(&self) -> &dyn PlanGlobalNode {
-        self
-    }
-}
+        assert!(running.trace().is_none());
 
-pub struct MinCutWallsPlanNode {
-    pub id: uuid::Uuid,
-    pub placement_phase: PlacementPhase,
-    pub must_place: bool,
-    pub desires_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
-    pub ready_for_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
-    pub rcl_override: Option<u8>,
-}
+        // Only the rejection counts are carried between ticks, not a named entry per node.
+        let serialized = serde_json::to_string(&running).unwrap();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl PlanBaseNode for MinCutWallsPlanNode {
-    fn name(&self) -> &str {
-        "Min Cut Walls"
+        assert!(!serialized.contains("\"placed\""));
     }
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        data.insert_global_placement(self.id, self);
-    }
+    #[test]
+    fn extension_shortfall_completes_cramped_rooms() {
+        // Fits around 150 extensions, short of the limit below.
+        let cramped = || {
+            let mut room = TestRoom::with_walls(|x, y| {
+                !(16..=32).contains(&x) || y > 32 || (y < 16 && !(24..27).contains(&x))
+            });
+            room.controllers = vec![PlanLocation::new(18, 16)];
+            room.sources = vec![PlanLocation::new(16, 32), PlanLocation::new(32, 32)];
+            room.minerals = vec![PlanLocation::new(32, 16)];
+            room
+        };
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        _gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        (self.desires_placement)(context, state)
-    }
-}
+        let rcl_limits = RclLimits::official()
+            .with_limits(StructureType::Extension, [0, 0, 5, 10, 20, 30, 40, 50, 163]);
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl PlanGlobalNode for MinCutWallsPlanNode {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
+        let strict = test_planner(PlanningConfig {
+            rcl_limits: rcl_limits.clone(),
+            ..PlanningConfig::default()
+        });
+
+        assert!(plan_room(&strict, &mut cramped()).is_err());
+
+        let lenient = test_planner(PlanningConfig {
+            rcl_limits: rcl_limits.clone(),
+            max_extension_shortfall: 12,
+            ..PlanningConfig::default()
+        });
+
+        let plan = plan_room(&lenient, &mut cramped()).unwrap_or_else(|err| panic!("{}", err));
+        let report = plan.report_with_room(&rcl_limits, &cramped().terrain);
+
+        assert!(report.extension_shortfall > 0);
+        assert!(report.extension_shortfall <= 12);
     }
 
-    fn get_children<'s>(
-        &'s self,
-        _context: &mut NodeContext,
-        _state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-        if !gather_data.has_visited_global(self) {
-            gather_data.mark_visited_global(self);
+    #[test]
+    fn unreachable_container_fails_to_finalize() {
+        // A container walled in on every side.
+        let mut room = TestRoom::with_walls(|x, y| {
+            (9..=11).contains(&x) && (9..=11).contains(&y) && (x, y) != (10, 10)
+        });
+
+        let state: PlanState = [
+            (StructureType::Spawn, 25, 25),
+            (StructureType::Storage, 25, 27),
+            (StructureType::Road, 26, 26),
+            (StructureType::Container, 10, 10),
+        ]
+        .iter()
+        .map(|(structure_type, x, y)| {
+            (
+                Location::from_coords(*x, *y),
+                vec![room_item(*structure_type, 1)],
+            )
+        })
+        .collect();
+
+        let result =
+            test_planner(PlanningConfig::default()).finalize_plan(state, Vec::new(), &mut room);
+
+        assert!(matches!(result, Err(PlanFailure::FinalizeError(_))));
+    }
+
+    const TEST_WALLS: &MinCutWallsPlanNode = &MinCutWallsPlanNode {
+        id: uuid::Uuid::from_u128(0),
+        placement_phase: PlacementPhase::Post,
+        must_place: false,
+        desires_placement: |_, _| true,
+        ready_for_placement: |_, _| true,
+        rcl_override: Some(4),
+        chokepoint_only: false,
+        bounds_margin: None,
+    };
+
+    fn hub_state() -> PlannerState {
+        let mut state = PlannerState::new();
+
+        for (structure_type, x, y) in &[
+            (StructureType::Spawn, 24, 25),
+            (StructureType::Storage, 25, 24),
+            (StructureType::Terminal, 26, 25),
+        ] {
+            state.insert(
+                Location::from_coords(*x, *y),
+                RoomItem {
+                    structure_type: *structure_type,
+                    required_rcl: 1,
+                },
+            );
         }
-    }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl PlanGlobalPlacementNode for MinCutWallsPlanNode {
-    fn as_global(&self) -> &dyn PlanGlobalNode {
-        self
+        state
     }
 
-    fn id(&self) -> &uuid::Uuid {
-        &self.id
-    }
+    #[test]
+    fn ramparts_are_passable_and_walls_block() {
+        // A pocket whose only way out is through (12, 10).
+        let room = TestRoom::with_walls(|x, y| {
+            let ring = (10..=14).contains(&x)
+                && (10..=14).contains(&y)
+                && (x == 10 || x == 14 || y == 10 || y == 14);
 
-    fn placement_phase(&self) -> PlacementPhase {
-        self.placement_phase
-    }
+            ring && !(x == 12 && y == 10)
+        });
 
-    fn must_place(&self) -> bool {
-        self.must_place
-    }
+        let distance_through = |structure_type: StructureType| {
+            let mut state = PlannerState::new();
 
-    fn get_maximum_score(&self, _context: &mut NodeContext, _state: &PlannerState) -> Option<f32> {
-        None
-    }
+            state.insert(
+                Location::from_coords(30, 30),
+                room_item(StructureType::Storage, 4),
+            );
+            state.insert(Location::from_coords(12, 10), room_item(structure_type, 2));
 
-    fn get_score(&self, _context: &mut NodeContext, _state: &PlannerState) -> Option<f32> {
-        Some(0.0)
+            state
+                .get_pathfinding_distance_to_structure(
+                    PlanLocation::new(12, 12),
+                    StructureType::Storage,
+                    1,
+                    &room.terrain,
+                )
+                .map(|(_, distance)| distance)
+        };
+
+        assert!(distance_through(StructureType::Rampart).is_some());
+        assert_eq!(distance_through(StructureType::Wall), None);
     }
 
-    fn ready_for_placement(&self, context: &mut NodeContext, state: &PlannerState) -> bool {
-        (self.ready_for_placement)(context, state)
+    #[test]
+    fn estimate_perimeter_of_open_room() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+
+        // The only exit is 10 tiles wide, so walling it off takes about as many tiles.
+        let perimeter = MinCutWallsPlanNode::estimate_perimeter(&mut context, &hub_state());
+
+        assert!(matches!(perimeter, Some(size) if (10..=14).contains(&size)));
     }
 
-    fn place(&self, context: &mut NodeContext, state: &mut PlannerState) -> Result<(), ()> {
-        let mut builder = LinkedListGraph::<u32>::new_builder();
+    #[test]
+    fn bounded_min_cut_matches_full_graph() {
+        // A pocket joined to the exit by a corridor, with the hub inside.
+        let mut room = TestRoom::with_walls(|x, y| {
+            !(16..=32).contains(&x) || y > 32 || (y < 16 && !(24..27).contains(&x))
+        });
+        room.controllers = vec![PlanLocation::new(20, 20)];
 
-        let top_nodes = builder.add_nodes(50 * 50);
-        let bottom_nodes = builder.add_nodes(50 * 50);
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
 
-        // source (protected) and sink (exit)
-        let source = builder.add_node();
-        let sink = builder.add_node();
+        let cut = |context: &mut NodeContext, bounds_margin| {
+            let (value, mut nodes, node_count) =
+                MinCutWallsPlanNode::compute_min_cut(context, &hub_state(), false, bounds_margin)
+                    .unwrap();
 
-        // unbuildable is for tiles near room exits that can't be ramparted
-        let mut unbuildable = FnvHashSet::default();
+            nodes.sort_unstable();
 
-        // and exits is for the exit tiles themselves, for later attachment to the sink
-        let mut exits = FnvHashSet::default();
+            (value, nodes, node_count)
+        };
 
-        for exit_position in context.terrain().get_exits() {
-            unbuildable.insert(exit_position);
-            exits.insert(exit_position);
+        let (full_value, full_nodes, full_node_count) = cut(&mut context, None);
+        let (bounded_value, bounded_nodes, bounded_node_count) = cut(&mut context, Some(12));
+
+        // The corridor is the chokepoint either way.
+        assert_eq!(full_value, 3);
+        assert_eq!(bounded_value, full_value);
+        assert_eq!(bounded_nodes, full_nodes);
+        assert!(bounded_node_count < full_node_count);
+    }
+
+    #[test]
+    fn estimate_perimeter_of_hub_next_to_exit() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+
+        let mut state = hub_state();
+        state.insert(
+            Location::from_coords(25, 1),
+            RoomItem {
+                structure_type: StructureType::Container,
+                required_rcl: 1,
+            },
+        );
 
-            // and mark all tiles within range 1 as unbuildable
-            let adjacent_positions = ONE_OFFSET_SQUARE
-                .iter()
-                .map(|offset| {
-                    PlanLocation::new(exit_position.x() as i8, exit_position.y() as i8) + offset
-                })
-                .filter_map(|offset_location| offset_location.try_into().ok());
+        assert!(MinCutWallsPlanNode::estimate_perimeter(&mut context, &state).is_none());
+    }
 
-            for exit_adjacent_position in adjacent_positions {
-                unbuildable.insert(exit_adjacent_position);
-            }
-        }
+    #[test]
+    fn controller_infra_accessors_survive_serialization() {
+        let room = TestRoom::open();
+        let plan = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
 
-        // protected is for tiles that will hook to the source
-        let mut protected = FnvHashSet::default();
+        let container = plan.controller_container().unwrap();
+        let link = plan.controller_link().unwrap();
 
-        let room_items = state.get_all();
+        assert!(plan.has_structure(container, StructureType::Container));
+        assert!(plan.has_structure(link, StructureType::Link));
+        assert!(room.controllers[0].distance_to(container.into()) <= 2);
+        assert!(link.distance_to(container) <= 1);
 
-        // Protect all tiles we've put structures on so far
-        for (location, room_item) in room_items.iter() {
-            let should_protect = match room_item.structure_type {
-                StructureType::KeeperLair | StructureType::Portal | StructureType::InvaderCore => {
-                    false
-                }
-                StructureType::Wall | StructureType::Rampart => false,
-                _ => true,
-            };
+        let restored: Plan = serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+
+        assert_eq!(restored.controller_container(), Some(container));
+        assert_eq!(restored.controller_link(), Some(link));
+    }
+
+    #[test]
+    fn filler_position_reaches_the_most_fillable_structures() {
+        let mut room = TestRoom::open();
+        let plan = plan_room(&test_planner(PlanningConfig::default()), &mut room)
+            .unwrap_or_else(|err| panic!("{}", err));
+
+        let is_fillable = |structure_type: StructureType| {
+            matches!(
+                structure_type,
+                StructureType::Spawn
+                    | StructureType::Extension
+                    | StructureType::Link
+                    | StructureType::Storage
+                    | StructureType::Terminal
+            )
+        };
+
+        let fillable_in_reach = |location: Location| {
+            plan.structures_in_range(location, 1)
+                .into_iter()
+                .filter(|(_, structure_type)| is_fillable(*structure_type))
+                .count()
+        };
+
+        let is_open = |location: Location| {
+            !room
+                .terrain
+                .get_xy(location.x(), location.y())
+                .contains(TerrainFlags::WALL)
+                && !plan.reserved().contains(&location)
+                && plan.tile_structures(location).iter().all(|item| {
+                    matches!(
+                        item.structure_type(),
+                        StructureType::Road | StructureType::Rampart
+                    )
+                })
+        };
 
-            if should_protect {
-                protected.insert(*location);
-            }
-        }
+        let filler = plan.filler_position().unwrap();
 
-        // also explicitly protect range:1 of the controller
-        for controller_position in context.controllers() {
-            if let Some(controller_location) = controller_position.try_into().ok() {
-                protected.insert(controller_location);
+        assert!(is_open(filler));
 
-                let adjacent_positions = ONE_OFFSET_SQUARE
-                    .iter()
-                    .map(|offset| *controller_position + offset)
-                    .filter(|offset_location| offset_location.in_room_build_bounds())
-                    .filter_map(|offset_location| offset_location.try_into().ok());
+        // Any open tile, planned or not, is a candidate standing spot.
+        let best = (0..ROOM_WIDTH)
+            .flat_map(|x| (0..ROOM_HEIGHT).map(move |y| Location::from_coords(x as u32, y as u32)))
+            .filter(|location| is_open(*location))
+            .map(fillable_in_reach)
+            .max()
+            .unwrap();
 
-                for controller_adjacent_position in adjacent_positions {
-                    protected.insert(controller_adjacent_position);
-                }
-            }
-        }
+        assert!(best > 0);
+        assert_eq!(fillable_in_reach(filler), best);
 
-        // TODO improve this to support tunnels - top should hook to bottom if it's a wall, (assuming can't rampart a tunnel?)
-        // hook to neighboring walls like they're walkable if they're a road
-        // big ol' vector of the weights of edges we create
-        let mut edge_weights = vec![];
+        let restored: Plan = serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
 
-        {
-            let terrain = context.terrain();
+        assert_eq!(restored.filler_position(), Some(filler));
+    }
 
-            // step over all tiles in the room, creating a mesh of flow connections
-            // walkable tiles have a weight: 1 edge from their 'top' node to their 'bot' node,
-            // which is what limits the 'flow' through the tile and what will ultimately be cut if
-            // that tile should be protected.  Then, the bottom tile connects with max weight to
-            // walkable neighbors, with high weight to prevent these from being the bottleneck to cut
-            for x in 0..ROOM_WIDTH as u32 {
-                for y in 0..ROOM_HEIGHT as u32 {
-                    // for each tile there's a 'top' and 'bottom'
-                    // 'top' is at y * 50 + x
-                    // 'bottom' is at 2500 + top
-                    // top hooks to bottom with cost 1 if it's a normal tile, max if non-buildable
-                    // bottom hooks to surrounding tiles as long as they're not protected tiles
-                    // protected tiles top hooks to source
-                    // edge tiles' bottom hooks to the sink
-                    let current_location = Location::from_coords(x, y);
-
-                    let terrain_mask = terrain.get(&current_location);
-
-                    if terrain_mask.contains(TerrainFlags::WALL) {
-                        continue;
-                    }
+    #[test]
+    fn planned_labs_satisfy_reaction_range() {
+        let plan = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
 
-                    if unbuildable.contains(&current_location) {
-                        // no cutting here, make a max value edge from top to bottom
-                        builder.add_edge(
-                            top_nodes[(x + y * 50) as usize],
-                            bottom_nodes[(x + y * 50) as usize],
-                        );
-                        edge_weights.push(std::usize::MAX);
-                    } else {
-                        // make an edge costing 1 from top to bottom
-                        builder.add_edge(
-                            top_nodes[(x + y * 50) as usize],
-                            bottom_nodes[(x + y * 50) as usize],
-                        );
-                        edge_weights.push(1);
-                    }
+        let roles = plan.lab_roles().unwrap();
 
-                    // if it's an edge tile, connect bot to sink
-                    if exits.contains(&current_location) {
-                        builder.add_edge(bottom_nodes[(x + y * 50) as usize], sink);
-                        edge_weights.push(std::usize::MAX);
-                    }
+        assert_eq!(roles.outputs.len(), 8);
+        assert!(roles.outputs.iter().all(|output| roles
+            .inputs
+            .iter()
+            .all(|input| input.distance_to(*output) <= 2)));
+    }
 
-                    // if it's a protected tile, connect source to top
-                    if protected.contains(&current_location) {
-                        builder.add_edge(source, top_nodes[(x + y * 50) as usize]);
-                        edge_weights.push(std::usize::MAX);
-                    }
+    #[test]
+    fn interior_holds_core_structures_but_not_exits() {
+        let room = TestRoom::open();
+        let plan = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
 
-                    let adjacent_locations = ONE_OFFSET_SQUARE
-                        .iter()
-                        .map(|offset| {
-                            PlanLocation::new(
-                                current_location.x() as i8,
-                                current_location.y() as i8,
-                            ) + offset
-                        })
-                        .filter_map(|offset_location| offset_location.try_into().ok());
+        let interior = plan.interior_tiles(&room.terrain);
 
-                    for adjacent_location in adjacent_locations {
-                        let adjacent_terrain_mask = terrain.get(&adjacent_location);
+        let core: Vec<_> = plan
+            .build_order()
+            .iter()
+            .filter(|step| {
+                matches!(
+                    step.structure_type,
+                    StructureType::Spawn
+                        | StructureType::Storage
+                        | StructureType::Terminal
+                        | StructureType::Tower
+                )
+            })
+            .map(|step| step.location)
+            .collect();
 
-                        if adjacent_terrain_mask.contains(TerrainFlags::WALL) {
-                            // good wall
-                            continue;
-                        }
+        assert!(!core.is_empty());
+        assert!(core.iter().all(|location| interior.contains(location)));
+        assert!(room
+            .terrain
+            .get_exits()
+            .all(|exit| !interior.contains(&exit)));
+    }
 
-                        if !protected.contains(&adjacent_location) {
-                            // walkable, link from this bottom to that top if it's not protected
-                            builder.add_edge(
-                                bottom_nodes[(x + y * 50) as usize],
-                                top_nodes[(adjacent_location.x() as u32
-                                    + adjacent_location.y() as u32 * 50)
-                                    as usize],
-                            );
-                            edge_weights.push(std::usize::MAX);
-                        }
-                    }
-                }
-            }
-        }
+    #[test]
+    fn empty_and_occupied_tiles_make_up_the_interior() {
+        let room = TestRoom::open();
+        let plan = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
 
-        let network = builder.to_graph();
+        let interior = plan.interior_tiles(&room.terrain);
+        let empty = plan.empty_interior_tiles(&room.terrain);
 
-        // get the big math guns in here
-        let (_, _, mincut) = dinic(&network, source, sink, |e| edge_weights[e.index()]);
+        let occupied: FnvHashSet<_> = plan
+            .build_order()
+            .iter()
+            .map(|step| step.location)
+            .filter(|location| interior.contains(location))
+            .collect();
 
-        // tracking for nodes of each 'type' that have been evaluated as 'part of the cut'
-        // (here meaning, on the 'source' side of protected).
-        // to find which tiles we want ramparts in, we want to find out which tiles have their
-        // top node in the set but their bottom node not in the set, meaning we cut the edge between
-        // the top and bottom for that tile.
-        let mut top_cut = FnvHashSet::default();
-        let mut bot_cut = FnvHashSet::default();
+        assert!(!empty.is_empty());
+        assert!(empty.iter().all(|location| !occupied.contains(location)
+            && !room.terrain.get(location).contains(TerrainFlags::WALL)));
+        assert_eq!(empty.len() + occupied.len(), interior.len());
+    }
 
-        for node in mincut {
-            let node_id = network.node_id(node);
+    #[test]
+    fn ramparts_with_controller_next_to_exit() {
+        let mut room = TestRoom::open();
+        room.controllers = vec![PlanLocation::new(25, 2)];
 
-            let room_node_count = ROOM_WIDTH as usize * ROOM_HEIGHT as usize;
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+        let mut state = hub_state();
 
-            //
-            // NOTE: This relies on room nodes to be added first in order to the graph.
-            //
+        assert!(MinCutWallsPlanNode::estimate_perimeter(&mut context, &state).is_some());
+        assert!(TEST_WALLS.place(&mut context, &mut state).is_ok());
 
-            if node_id < room_node_count {
-                top_cut.insert(node_id);
-            } else if room_node_count < room_node_count * 2 {
-                bot_cut.insert(node_id - room_node_count);
-            }
-        }
+        let ramparts = state.get_locations(StructureType::Rampart);
 
-        let terrain = context.terrain();
+        assert!(!ramparts.is_empty());
+        assert!(ramparts.iter().all(|rampart| rampart.y() >= 2));
+    }
 
-        let mut candidates: FnvHashSet<_> = top_cut.difference(&bot_cut).collect();
+    #[test]
+    fn chokepoint_only_ramparts_the_narrow_entrance() {
+        // A wall across the room with a 2 wide gap, leaving the controller on the exit side.
+        let defenses_for = |chokepoint_only: bool| {
+            let mut room = TestRoom::with_walls(|x, y| y == 15 && !(24..=25).contains(&x));
+            let rcl_limits = RclLimits::official();
+            let source_infra = SourceInfraPolicies::default();
+            let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+            let mut state = hub_state();
 
-        while !candidates.is_empty() {
-            let mut to_process: Vec<(Location, StructureType)> = Vec::new();
+            let node = MinCutWallsPlanNode {
+                chokepoint_only,
+                ..*TEST_WALLS
+            };
 
-            let candidate_node = **candidates.iter().next().expect("Expected seed");
+            assert!(node.place(&mut context, &mut state).is_ok());
 
-            let location =
-                Location::from_coords((candidate_node % 50) as u32, (candidate_node / 50) as u32);
+            // The cut alternates ramparts with constructed walls.
+            let mut defenses = state.get_locations(StructureType::Rampart);
+            defenses.extend(state.get_locations(StructureType::Wall));
+            defenses.sort_by_key(|location| location.packed_repr());
+            defenses
+        };
 
-            to_process.push((location, StructureType::Rampart));
+        assert_eq!(
+            defenses_for(true),
+            vec![Location::from_coords(24, 15), Location::from_coords(25, 15)]
+        );
+        assert!(defenses_for(false).len() > 2);
+    }
 
-            while let Some((location, structure_type)) = to_process.pop() {
-                let candidate_node = location.x() as usize + (location.y() as usize * 50);
+    fn structure_triples(plan: &Plan) -> Vec<(StructureType, u8, u8)> {
+        let mut triples: Vec<_> = plan
+            .build_order()
+            .iter()
+            .map(|step| (step.structure_type, step.location.x(), step.location.y()))
+            .collect();
 
-                if candidates.remove(&candidate_node) {
-                    let terrain_mask = terrain.get(&location);
+        triples.sort_by_key(|(structure_type, x, y)| (format!("{:?}", structure_type), *x, *y));
 
-                    if !terrain_mask.contains(TerrainFlags::WALL) {
-                        if let Some(rcl) = self
-                            .rcl_override
-                            .or_else(|| state.get_rcl_for_next_structure(structure_type))
-                        {
-                            state.insert(
-                                location,
-                                RoomItem {
-                                    structure_type: structure_type,
-                                    required_rcl: rcl,
-                                },
-                            );
+        triples
+    }
 
-                            let adjacent_positions = ONE_OFFSET_CROSS
-                                .iter()
-                                .map(|offset| PlanLocation::from(location) + offset)
-                                .filter(|offset_location| offset_location.in_room_build_bounds())
-                                .filter_map(|offset_location| offset_location.try_into().ok());
+    fn required_rcl(plan: &Plan, structure_type: StructureType, x: u8, y: u8) -> Option<u8> {
+        plan.tile_structures(Location::from_coords(x as u32, y as u32))
+            .iter()
+            .find(|item| item.structure_type() == structure_type)
+            .map(|item| item.required_rcl())
+    }
 
-                            for adjacent_position in adjacent_positions {
-                                let next_structure = if structure_type == StructureType::Rampart {
-                                    if state
-                                        .get(&adjacent_position)
-                                        .map(|e| e.is_empty())
-                                        .unwrap_or(true)
-                                    {
-                                        StructureType::Wall
-                                    } else {
-                                        StructureType::Rampart
-                                    }
-                                } else {
-                                    StructureType::Rampart
-                                };
+    #[test]
+    fn shareable_json_round_trips_structures() {
+        let plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Extension, 20, 20, 8),
+            (StructureType::Extension, 21, 20, 2),
+            (StructureType::Road, 20, 21, 1),
+            (StructureType::Rampart, 25, 25, 4),
+        ]);
 
-                                to_process.push((adjacent_position, next_structure));
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        let imported = Plan::from_shareable_json(&plan.to_shareable_json()).unwrap();
 
-        //TODO: Validate min cut actually succeeded...
-        Ok(())
+        assert_eq!(structure_triples(&imported), structure_triples(&plan));
     }
-}
 
-pub struct FloodFillPlanNodeLevel<'a> {
-    pub offsets: &'a [(i8, i8)],
-    pub node: &'a dyn PlanLocationPlacementNode,
-}
+    #[test]
+    fn shareable_json_reassigns_required_rcl() {
+        let plan = plan_from_items(&[
+            (StructureType::Extension, 20, 20, 8),
+            (StructureType::Extension, 21, 20, 2),
+        ]);
 
-pub struct FloodFillPlanNode<'a> {
-    pub id: uuid::Uuid,
-    pub placement_phase: PlacementPhase,
-    pub must_place: bool,
-    pub start_offsets: &'a [(i8, i8)],
-    pub expansion_offsets: &'a [(i8, i8)],
-    pub maximum_expansion: u32,
-    pub minimum_candidates: usize,
-    pub levels: &'a [FloodFillPlanNodeLevel<'a>],
-    pub desires_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
-    pub scorer:
-        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> Option<f32>,
-    pub validator: fn(context: &mut NodeContext, state: &PlannerState) -> Result<(), ()>,
-}
+        let json = plan.to_shareable_json();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for FloodFillPlanNode<'a> {
-    fn name(&self) -> &str {
-        "Flood Fill"
-    }
+        // RCL is not part of the format - the first listed extension gets the first RCL that
+        // allows one.
+        let imported = Plan::from_shareable_json(&json).unwrap();
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        if data.insert_location_placement(*self.id(), self) {
-            for lod in self.levels.iter() {
-                lod.node.gather_nodes(data);
-            }
-        }
-    }
+        assert_eq!(
+            required_rcl(&imported, StructureType::Extension, 20, 20),
+            Some(2)
+        );
+        assert_eq!(
+            required_rcl(&imported, StructureType::Extension, 21, 20),
+            Some(2)
+        );
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        (self.desires_placement)(context, state)
-            && self
-                .levels
-                .iter()
-                .any(|l| l.node.desires_placement(context, state, gather_data))
+        let rcl_limits = RclLimits::official()
+            .with_limits(StructureType::Extension, [0, 0, 0, 0, 1, 1, 2, 2, 2]);
+
+        let imported = Plan::from_shareable_json_with_rcl_limits(&json, &rcl_limits).unwrap();
+
+        assert_eq!(
+            required_rcl(&imported, StructureType::Extension, 20, 20),
+            Some(4)
+        );
+        assert_eq!(
+            required_rcl(&imported, StructureType::Extension, 21, 20),
+            Some(6)
+        );
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationNode for FloodFillPlanNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
+    #[test]
+    fn shareable_json_rejects_more_structures_than_the_limits_allow() {
+        let json = plan_from_items(&[
+            (StructureType::Extension, 20, 20, 2),
+            (StructureType::Extension, 21, 20, 2),
+        ])
+        .to_shareable_json();
+
+        let rcl_limits = RclLimits::official().with_max_count(StructureType::Extension, 1);
+
+        assert!(Plan::from_shareable_json_with_rcl_limits(&json, &rcl_limits).is_err());
+        assert!(Plan::from_shareable_json(&json).is_ok());
     }
 
-    fn desires_location<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        let mut locations: FnvHashSet<_> = self
-            .start_offsets
-            .into_iter()
-            .map(|o| position + o)
-            .collect();
+    #[test]
+    fn report_summarizes_full_bunker() {
+        let mut room = TestRoom::open();
 
-        for lod in self.levels.iter() {
-            let mut expanded_locations: FnvHashSet<PlanLocation> = locations
-                .iter()
-                .flat_map(|&location| lod.offsets.iter().map(move |offset| location + *offset))
-                .collect();
+        let plan = plan_room(&test_planner(PlanningConfig::default()), &mut room)
+            .unwrap_or_else(|err| panic!("{}", err));
 
-            if expanded_locations.iter().any(|location| {
-                lod.node
-                    .desires_location(*location, context, state, gather_data)
-            }) {
-                return true;
-            }
+        let report = plan.report();
 
-            locations = std::mem::replace(&mut expanded_locations, FnvHashSet::default());
-        }
+        assert_eq!(report.extension_count, 60);
+        assert_eq!(report.extension_target, 60);
+        assert_eq!(report.extension_shortfall, 0);
+        assert_eq!(report.tower_count, 6);
+        assert!(report.storage_count >= 1);
+        assert!(report.has_defenses);
+        assert!(report.road_count > 0);
+        assert_eq!(report.upgrade_area, None);
 
-        false
+        // The container and its neighbours, less the controller link.
+        let report = plan.report_with_room(&RclLimits::official(), &room.terrain);
+
+        assert_eq!(report.extension_count, 60);
+        assert_eq!(report.upgrade_area, Some(8));
     }
 
-    fn get_children<'s>(
-        &'s self,
-        _position: PlanLocation,
-        _context: &mut NodeContext,
-        _state: &PlannerState,
-        _gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
+    #[test]
+    fn report_uses_given_extension_target() {
+        let plan = plan_from_items(&[
+            (StructureType::Extension, 20, 20, 2),
+            (StructureType::Extension, 21, 20, 2),
+        ]);
+
+        let rcl_limits = RclLimits::official()
+            .with_limits(StructureType::Extension, [0, 0, 5, 10, 20, 30, 40, 60, 80]);
+
+        let report = plan.report_with_room(&rcl_limits, &TestRoom::open().terrain);
+
+        assert_eq!(report.extension_target, 80);
+        assert_eq!(report.extension_shortfall, 78);
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationPlacementNode for FloodFillPlanNode<'a> {
-    fn as_location(&self) -> &dyn PlanLocationNode {
-        self
+    #[test]
+    fn structures_in_range_includes_boundary() {
+        let plan = plan_from_items(&[
+            (StructureType::Tower, 25, 25, 3),
+            (StructureType::Rampart, 25, 25, 4),
+            (StructureType::Extension, 27, 23, 2),
+            (StructureType::Extension, 28, 25, 2),
+        ]);
+
+        let mut in_range = plan.structures_in_range(Location::from_coords(25, 25), 2);
+        in_range.sort_by_key(|(location, _)| location.packed_repr());
+
+        assert_eq!(in_range.len(), 3);
+        assert!(in_range
+            .iter()
+            .all(|(location, _)| location.distance_to(Location::from_coords(25, 25)) <= 2));
+        assert!(in_range.contains(&(Location::from_coords(27, 23), StructureType::Extension)));
+        assert!(!in_range.contains(&(Location::from_coords(28, 25), StructureType::Extension)));
+    }
+
+    #[test]
+    fn structures_in_range_zero_is_center_only() {
+        let plan = plan_from_items(&[
+            (StructureType::Tower, 25, 25, 3),
+            (StructureType::Rampart, 25, 25, 4),
+            (StructureType::Road, 25, 26, 1),
+        ]);
+
+        let mut in_range = plan.structures_in_range(Location::from_coords(25, 25), 0);
+        in_range.sort_by_key(|(_, structure_type)| format!("{:?}", structure_type));
+
+        assert_eq!(
+            in_range,
+            vec![
+                (Location::from_coords(25, 25), StructureType::Rampart),
+                (Location::from_coords(25, 25), StructureType::Tower),
+            ]
+        );
     }
 
-    fn placement_phase(&self) -> PlacementPhase {
-        self.placement_phase
+    #[test]
+    fn move_structure_to_open_tile() {
+        let room = TestRoom::open();
+        let mut plan = plan_from_items(&[
+            (StructureType::Extension, 20, 20, 2),
+            (StructureType::Road, 21, 20, 1),
+        ]);
+
+        let result = plan.move_structure(
+            Location::from_coords(20, 20),
+            Location::from_coords(20, 22),
+            StructureType::Extension,
+            &room.terrain,
+        );
+
+        assert!(result.is_ok());
+        assert!(plan
+            .tile_structures(Location::from_coords(20, 20))
+            .is_empty());
+        assert_eq!(
+            required_rcl(&plan, StructureType::Extension, 20, 22),
+            Some(2)
+        );
+        assert_eq!(count(&plan, StructureType::Extension), 1);
     }
 
-    fn must_place(&self) -> bool {
-        self.must_place
+    #[test]
+    fn move_structure_onto_wall_fails() {
+        let room = TestRoom::with_walls(|x, y| x == 20 && y == 22);
+        let mut plan = plan_from_items(&[
+            (StructureType::Extension, 20, 20, 2),
+            (StructureType::Road, 21, 20, 1),
+        ]);
+
+        let from = Location::from_coords(20, 20);
+        let extension = StructureType::Extension;
+
+        let result = plan.move_structure(
+            from,
+            Location::from_coords(20, 22),
+            extension,
+            &room.terrain,
+        );
+        assert!(matches!(result, Err(PlanInconsistency::Wall)));
+
+        let result = plan.move_structure(
+            from,
+            Location::from_coords(21, 20),
+            extension,
+            &room.terrain,
+        );
+        assert!(matches!(
+            result,
+            Err(PlanInconsistency::Occupied(StructureType::Road))
+        ));
+
+        assert_eq!(
+            required_rcl(&plan, StructureType::Extension, 20, 20),
+            Some(2)
+        );
     }
 
-    fn id(&self) -> &uuid::Uuid {
-        &self.id
+    #[test]
+    fn move_structure_to_unreachable_tile_fails() {
+        let room = TestRoom::with_walls(|x, y| {
+            (9..=11).contains(&x) && (9..=11).contains(&y) && !(x == 10 && y == 10)
+        });
+        let mut plan = plan_from_items(&[
+            (StructureType::Extension, 20, 20, 2),
+            (StructureType::Road, 21, 20, 1),
+        ]);
+
+        let result = plan.move_structure(
+            Location::from_coords(20, 20),
+            Location::from_coords(10, 10),
+            StructureType::Extension,
+            &room.terrain,
+        );
+
+        assert!(matches!(result, Err(PlanInconsistency::Unreachable)));
+        assert!(plan
+            .tile_structures(Location::from_coords(10, 10))
+            .is_empty());
+        assert_eq!(
+            required_rcl(&plan, StructureType::Extension, 20, 20),
+            Some(2)
+        );
     }
 
-    fn get_maximum_score(
-        &self,
-        _position: PlanLocation,
-        _context: &mut NodeContext,
-        _state: &PlannerState,
-    ) -> Option<f32> {
-        None
+    #[test]
+    fn moving_road_away_from_its_structure_fails() {
+        // A pocket only open at the extension and the road beside it.
+        let room = TestRoom::with_walls(|x, y| {
+            (9..=12).contains(&x) && (9..=11).contains(&y) && !(y == 10 && (10..=11).contains(&x))
+        });
+        let mut plan = plan_from_items(&[
+            (StructureType::Extension, 10, 10, 2),
+            (StructureType::Road, 11, 10, 1),
+            (StructureType::Road, 20, 20, 1),
+        ]);
+
+        let result = plan.move_structure(
+            Location::from_coords(11, 10),
+            Location::from_coords(21, 20),
+            StructureType::Road,
+            &room.terrain,
+        );
+
+        assert!(matches!(
+            result,
+            Err(PlanInconsistency::Stranded(location)) if location == Location::from_coords(10, 10)
+        ));
+        assert!(plan.has_structure(Location::from_coords(11, 10), StructureType::Road));
+        assert!(!plan.has_structure(Location::from_coords(21, 20), StructureType::Road));
+    }
+
+    #[test]
+    fn tower_coverage_falls_off_with_range() {
+        let room = TestRoom::with_walls(|x, y| x == 10 && y == 12);
+        let plan = plan_from_items(&[
+            (StructureType::Tower, 10, 10, 3),
+            (StructureType::Tower, 12, 10, 5),
+        ]);
+
+        let coverage = plan.tower_coverage_map(&room.terrain);
+
+        // Next to both towers.
+        assert_eq!(*coverage.get(11, 10), 2 * TOWER_DAMAGE_MAX);
+        // Inside the falloff of both towers.
+        assert_eq!(
+            *coverage.get(10, 22),
+            tower_damage_at_range(12) + tower_damage_at_range(12)
+        );
+        assert_eq!(tower_damage_at_range(12), 390);
+        // Beyond the falloff range of both towers.
+        assert_eq!(*coverage.get(45, 45), 2 * TOWER_DAMAGE_MIN);
+        // Walls are left uncovered.
+        assert_eq!(*coverage.get(10, 12), 0);
+    }
+
+    #[test]
+    fn exposed_ramparts_have_defense_priority() {
+        // The test room's only exit is on the top edge.
+        let room = TestRoom::open();
+        let plan = plan_from_items(&[
+            (StructureType::Rampart, 25, 12, 4),
+            (StructureType::Rampart, 25, 3, 4),
+            (StructureType::Wall, 26, 3, 4),
+        ]);
+
+        assert_eq!(
+            plan.defense_priority(&room.terrain),
+            vec![
+                (Location::from_coords(25, 3), 1),
+                (Location::from_coords(26, 3), 1),
+                (Location::from_coords(25, 12), 2),
+            ]
+        );
     }
 
-    fn get_score(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> Option<f32> {
-        (self.scorer)(position, context, state)
-    }
+    #[test]
+    fn candidate_scores_treat_nan_as_worst() {
+        use std::cmp::Ordering;
 
-    fn ready_for_placement(&self, _context: &mut NodeContext, _state: &PlannerState) -> bool {
-        //TODO: Provide customization option?
-        true
+        assert_eq!(
+            compare_candidate_scores(Some(f32::NAN), Some(1.0)),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_candidate_scores(Some(1.0), Some(f32::NAN)),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_candidate_scores(Some(f32::NAN), Some(f32::NAN)),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare_candidate_scores(Some(f32::NAN), None),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare_candidate_scores(Some(2.0), Some(1.0)),
+            Ordering::Greater
+        );
     }
 
-    fn place(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &mut PlannerState,
-    ) -> Result<(), ()> {
-        let mut locations: FnvHashSet<_> = self
-            .start_offsets
-            .into_iter()
-            .map(|o| position + o)
-            .collect();
-        let mut next_locations: FnvHashSet<_> = FnvHashSet::default();
-        let mut visited_locations: FnvHashSet<_> = FnvHashSet::default();
+    #[test]
+    fn equal_candidate_scores_order_by_location() {
+        let low = PlanLocation::new(10, 10);
+        let high = PlanLocation::new(30, 30);
+        let best = PlanLocation::new(20, 20);
+        let nan = PlanLocation::new(40, 40);
 
-        let mut current_expansion = 0;
+        let mut candidates = vec![
+            (low, (), Some(1.0)),
+            (nan, (), Some(f32::NAN)),
+            (best, (), Some(2.0)),
+            (high, (), Some(1.0)),
+        ];
 
-        let mut candidates = Vec::new();
+        sort_candidates(&mut candidates);
 
-        while current_expansion < self.maximum_expansion && !locations.is_empty() {
-            let mut current_gather_data = PlanGatherChildrenData::<'a>::new();
+        // Evaluated from the end - best score first, then the lower of the tied locations.
+        let order: Vec<_> = candidates
+            .iter()
+            .rev()
+            .map(|(location, _, _)| *location)
+            .collect();
 
-            while current_expansion < self.maximum_expansion
-                && !locations.is_empty()
-                && candidates.len() < self.minimum_candidates
-            {
-                for root_location in locations.iter() {
-                    if !visited_locations.contains(root_location) {
-                        visited_locations.insert(*root_location);
+        assert_eq!(order, vec![best, low, high, nan]);
 
-                        let mut lod_locations = vec![*root_location];
+        candidates.reverse();
+        sort_candidates(&mut candidates);
 
-                        for lod in self.levels.iter() {
-                            let expanded_locations = lod_locations.iter().flat_map(|&location| {
-                                lod.offsets.iter().map(move |offset| location + *offset)
-                            });
+        let reordered: Vec<_> = candidates
+            .iter()
+            .rev()
+            .map(|(location, _, _)| *location)
+            .collect();
 
-                            let mut next_lod_locations = Vec::new();
+        assert_eq!(reordered, order);
+    }
 
-                            for lod_location in expanded_locations {
-                                if !current_gather_data
-                                    .has_visited_location(lod_location, lod.node.as_location())
-                                {
-                                    current_gather_data.mark_visited_location(
-                                        lod_location,
-                                        lod.node.as_location(),
-                                    );
+    #[test]
+    fn defense_upkeep_scales_with_ramparts() {
+        let one = plan_from_items(&[
+            (StructureType::Rampart, 20, 20, 4),
+            (StructureType::Wall, 21, 20, 4),
+        ]);
+        let two = plan_from_items(&[
+            (StructureType::Rampart, 20, 20, 4),
+            (StructureType::Rampart, 22, 20, 4),
+            (StructureType::Wall, 21, 20, 4),
+        ]);
 
-                                    let got_candidate = if current_gather_data.desires_placement(
-                                        lod.node.as_base(),
-                                        context,
-                                        state,
-                                    ) && current_gather_data
-                                        .desires_location(
-                                            lod_location,
-                                            lod.node.as_location(),
-                                            context,
-                                            state,
-                                        ) {
-                                        let max_score = lod.node.get_maximum_score(
-                                            lod_location,
-                                            context,
-                                            state,
-                                        );
+        let target_hits = 1_000_000;
 
-                                        candidates.push((lod_location, lod.node, max_score));
+        assert!(one.defense_upkeep_per_tick(target_hits) > 0.0);
+        assert_eq!(
+            two.defense_upkeep_per_tick(target_hits),
+            one.defense_upkeep_per_tick(target_hits) * 2.0
+        );
 
-                                        true
-                                    } else {
-                                        false
-                                    };
+        // Only the decay has to be replaced, however high the target.
+        assert_eq!(
+            one.defense_upkeep_per_tick(RAMPART_DECAY_AMOUNT),
+            one.defense_upkeep_per_tick(target_hits)
+        );
+        assert!(
+            one.defense_upkeep_per_tick(RAMPART_DECAY_AMOUNT / 2)
+                < one.defense_upkeep_per_tick(target_hits)
+        );
+    }
 
-                                    if got_candidate {
-                                        for offset in self.expansion_offsets.into_iter() {
-                                            let next_location = *root_location + *offset;
+    #[test]
+    fn defense_upkeep_of_zero_target_is_zero() {
+        let plan = plan_from_items(&[
+            (StructureType::Rampart, 20, 20, 4),
+            (StructureType::Rampart, 22, 20, 4),
+        ]);
 
-                                            next_locations.insert(next_location);
-                                        }
-                                    } else {
-                                        next_lod_locations.push(lod_location);
-                                    }
-                                }
-                            }
+        assert_eq!(plan.defense_upkeep_per_tick(0), 0.0);
+    }
 
-                            if next_lod_locations.is_empty() {
-                                break;
-                            }
+    #[test]
+    fn ramparts_above_max_rcl_are_left_out() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official().with_max_rcl(3);
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+        let mut state = hub_state();
 
-                            lod_locations = next_lod_locations;
-                        }
-                    }
-                }
+        assert!(TEST_WALLS.place(&mut context, &mut state).is_ok());
 
-                current_expansion += 1;
+        assert_eq!(state.get_count(StructureType::Rampart), 0);
+        assert_eq!(state.get_count(StructureType::Wall), 0);
+    }
 
-                locations = std::mem::replace(&mut next_locations, FnvHashSet::default());
-            }
+    #[test]
+    fn ramparts_respect_max_rcl() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official().with_max_rcl(4);
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+        let mut state = hub_state();
 
-            while (candidates.len() >= self.minimum_candidates
-                || locations.is_empty()
-                || current_expansion >= self.maximum_expansion)
-                && !candidates.is_empty()
-            {
-                candidates.sort_by(|(_, _, max_score_a), (_, _, max_score_b)| {
-                    max_score_a.partial_cmp(&max_score_b).unwrap()
-                });
+        assert!(TEST_WALLS.place(&mut context, &mut state).is_ok());
 
-                let mut current_gather_data = PlanGatherChildrenData::<'a>::new();
+        let defenses: Vec<_> = [StructureType::Rampart, StructureType::Wall]
+            .iter()
+            .flat_map(|structure_type| {
+                state
+                    .get_locations(*structure_type)
+                    .into_iter()
+                    .map(move |location| (location, *structure_type))
+            })
+            .collect();
 
-                let mut best_candidate = None;
+        assert!(!defenses.is_empty());
 
-                let mut to_remove = Vec::new();
+        for (location, structure_type) in defenses {
+            let required_rcl = state
+                .get(&location)
+                .iter()
+                .flat_map(|v| v.iter())
+                .find(|item| item.structure_type() == structure_type)
+                .map(|item| item.required_rcl())
+                .unwrap();
 
-                for (index, (location, node, max_score)) in candidates.iter_mut().enumerate().rev()
-                {
-                    let can_exceed_best_score = best_candidate
-                        .as_ref()
-                        .map(|(best_score, _)| best_score)
-                        .and_then(|best_score| max_score.map(|max| max > *best_score))
-                        .unwrap_or(true);
+            assert!(required_rcl <= 4);
+        }
+    }
 
-                    if can_exceed_best_score {
-                        let can_place =
-                            current_gather_data.desires_placement(node.as_base(), context, state)
-                                && current_gather_data.desires_location(
-                                    *location,
-                                    node.as_location(),
-                                    context,
-                                    state,
-                                );
+    #[test]
+    fn defense_rcl_queues_all_defenses_early() {
+        let planner = test_planner(PlanningConfig {
+            rcl_limits: RclLimits::official().with_defense_rcl(2),
+            ..PlanningConfig::default()
+        });
 
-                        if can_place {
-                            if let Some(score) = node.get_score(*location, context, state) {
-                                //TODO: Only allow modifying score if hint is set that score can only get worse?
-                                *max_score = Some(score);
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
 
-                                if best_candidate
-                                    .as_ref()
-                                    .map(|(best_score, _)| score > *best_score)
-                                    .unwrap_or(true)
-                                {
-                                    best_candidate = Some((score, (*location, node, index)));
-                                }
-                            } else {
-                                to_remove.push(index);
-                            }
-                        } else {
-                            //TODO: Should consider pushing to next LOD?
+        let defense_rcls: Vec<_> = plan
+            .build_order()
+            .iter()
+            .filter(|step| {
+                matches!(
+                    step.structure_type,
+                    StructureType::Rampart | StructureType::Wall
+                )
+            })
+            .map(|step| step.required_rcl)
+            .collect();
 
-                            to_remove.push(index);
-                        }
-                    }
-                }
+        assert!(!defense_rcls.is_empty());
+        assert!(defense_rcls.iter().all(|rcl| *rcl == 2));
 
-                if let Some((_, (location, node, index))) = best_candidate {
-                    node.place(location, context, state)?;
+        // Walls and ramparts can't be built before RCL 2.
+        assert_eq!(
+            RclLimits::official().with_defense_rcl(1).defense_rcl(),
+            Some(2)
+        );
+    }
 
-                    match to_remove.binary_search_by(|probe| probe.cmp(&index).reverse()) {
-                        Ok(_) => {}
-                        Err(pos) => to_remove.insert(pos, index),
-                    }
-                }
+    #[test]
+    fn max_rcl_leaves_out_higher_structures() {
+        let planner = test_planner(PlanningConfig {
+            rcl_limits: RclLimits::official().with_max_rcl(6),
+            ..PlanningConfig::default()
+        });
 
-                for index in to_remove.into_iter() {
-                    candidates.remove(index);
-                }
-            }
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        assert!(plan.build_order().iter().all(|step| step.required_rcl <= 6));
+
+        for structure_type in [
+            StructureType::Observer,
+            StructureType::Nuker,
+            StructureType::PowerSpawn,
+            StructureType::Factory,
+        ]
+        .iter()
+        {
+            assert_eq!(count(&plan, *structure_type), 0);
         }
 
-        (self.validator)(context, state)
+        assert_eq!(count(&plan, StructureType::Extension), 40);
     }
-}
 
-pub struct FirstPossiblePlanNode<'a> {
-    pub id: uuid::Uuid,
-    pub placement_phase: PlacementPhase,
-    pub must_place: bool,
-    pub options: &'a [&'a dyn PlanLocationPlacementNode],
-}
+    #[test]
+    fn fingerprint_is_stable_across_replans() {
+        let planner = test_planner(PlanningConfig::default());
+        let mut room = TestRoom::open();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for FirstPossiblePlanNode<'a> {
-    fn name(&self) -> &str {
-        "First Possible"
-    }
+        let plan = plan_room(&planner, &mut room).unwrap_or_else(|err| panic!("{}", err));
+        let replan = plan_room(&planner, &mut room).unwrap_or_else(|err| panic!("{}", err));
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        if data.insert_location_placement(*self.id(), self) {
-            for option in self.options.iter() {
-                option.gather_nodes(data);
-            }
-        }
-    }
+        assert_eq!(plan.fingerprint(), replan.fingerprint());
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        self.options
+        let extension = plan
+            .build_order()
             .iter()
-            .any(|option| option.desires_placement(context, state, gather_data))
+            .find(|step| step.structure_type == StructureType::Extension)
+            .map(|step| step.location)
+            .unwrap();
+
+        let free_tile = (3..47)
+            .flat_map(|y| (3..47).map(move |x| Location::from_coords(x, y)))
+            .find(|location| plan.tile_structures(*location).is_empty())
+            .unwrap();
+
+        let mut moved = replan;
+
+        assert!(moved
+            .move_structure(
+                extension,
+                free_tile,
+                StructureType::Extension,
+                &room.terrain
+            )
+            .is_ok());
+        assert_ne!(plan.fingerprint(), moved.fingerprint());
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationNode for FirstPossiblePlanNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
+    fn ordered_steps(plan: &Plan) -> Vec<(Location, StructureType, u8)> {
+        plan.iter_build_ordered()
+            .map(|step| (step.location, step.structure_type, step.required_rcl))
+            .collect()
     }
 
-    fn desires_location<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        self.options
-            .iter()
-            .any(|option| option.desires_location(position, context, state, gather_data))
-    }
+    #[test]
+    fn build_order_is_stable() {
+        let items = [
+            (StructureType::Road, 20, 21, 4),
+            (StructureType::Rampart, 20, 21, 4),
+            (StructureType::Extension, 21, 20, 2),
+            (StructureType::Extension, 20, 20, 2),
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Tower, 24, 25, 4),
+            (StructureType::Road, 26, 25, 1),
+        ];
+
+        let mut reversed = items;
+        reversed.reverse();
+
+        let plan = plan_from_items(&items);
+        let steps = ordered_steps(&plan);
+
+        assert_eq!(steps, ordered_steps(&plan_from_items(&reversed)));
+        assert_eq!(
+            steps[0],
+            (Location::from_coords(25, 25), StructureType::Spawn, 1)
+        );
+        assert_eq!(
+            steps[1..3],
+            [
+                (Location::from_coords(20, 20), StructureType::Extension, 2),
+                (Location::from_coords(21, 20), StructureType::Extension, 2),
+            ]
+        );
 
-    fn get_children<'s>(
-        &'s self,
-        _position: PlanLocation,
-        _context: &mut NodeContext,
-        _state: &PlannerState,
-        _gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-    }
-}
+        // Priority comes before RCL, so the RCL 1 road follows the RCL 4 tower and rampart.
+        assert_eq!(
+            steps[3..],
+            [
+                (Location::from_coords(24, 25), StructureType::Tower, 4),
+                (Location::from_coords(20, 21), StructureType::Rampart, 4),
+                (Location::from_coords(26, 25), StructureType::Road, 1),
+                (Location::from_coords(20, 21), StructureType::Road, 4),
+            ]
+        );
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationPlacementNode for FirstPossiblePlanNode<'a> {
-    fn as_location(&self) -> &dyn PlanLocationNode {
-        self
-    }
+        let planner = test_planner(PlanningConfig::default());
 
-    fn placement_phase(&self) -> PlacementPhase {
-        self.placement_phase
+        let planned =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+        let replanned =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
+
+        assert_eq!(ordered_steps(&planned), ordered_steps(&replanned));
     }
 
-    fn must_place(&self) -> bool {
-        self.must_place
+    #[test]
+    fn build_order_is_kept_until_the_plan_changes() {
+        let room = TestRoom::open();
+
+        let mut plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Extension, 20, 20, 2),
+        ]);
+
+        let first = plan.iter_build_ordered().next().unwrap();
+
+        assert!(std::ptr::eq(first, &plan.build_order()[0]));
+
+        assert!(plan
+            .move_structure(
+                Location::from_coords(20, 20),
+                Location::from_coords(30, 30),
+                StructureType::Extension,
+                &room.terrain
+            )
+            .is_ok());
+
+        assert_eq!(
+            ordered_steps(&plan),
+            vec![
+                (Location::from_coords(25, 25), StructureType::Spawn, 1),
+                (Location::from_coords(30, 30), StructureType::Extension, 2),
+            ]
+        );
     }
 
-    fn id(&self) -> &uuid::Uuid {
-        &self.id
-    }
+    #[test]
+    fn rebuilt_build_order_follows_moved_structures() {
+        let room = TestRoom::open();
 
-    fn get_maximum_score(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> Option<f32> {
-        self.options
-            .iter()
-            .filter_map(|option| option.get_maximum_score(position, context, state))
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
-    }
+        let mut plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Extension, 20, 20, 2),
+        ]);
 
-    fn get_score(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-    ) -> Option<f32> {
-        let mut current_gather_data = PlanGatherChildrenData::<'a>::new();
+        plan.build_order();
 
-        self.options
+        assert!(plan
+            .move_structure(
+                Location::from_coords(20, 20),
+                Location::from_coords(30, 30),
+                StructureType::Extension,
+                &room.terrain
+            )
+            .is_ok());
+
+        plan.rebuild_build_order();
+
+        let extensions: Vec<_> = plan
+            .build_order
+            .get()
+            .unwrap()
             .iter()
-            .filter_map(|option| {
-                if current_gather_data.desires_placement(option.as_base(), context, state)
-                    && current_gather_data.desires_location(
-                        position,
-                        option.as_location(),
-                        context,
-                        state,
-                    )
-                {
-                    option.get_score(position, context, state)
-                } else {
-                    None
-                }
-            })
-            .next()
-    }
+            .filter(|step| step.structure_type == StructureType::Extension)
+            .map(|step| step.location)
+            .collect();
 
-    fn ready_for_placement(&self, _context: &mut NodeContext, _state: &PlannerState) -> bool {
-        //TODO: Provide customization option?
-        true
+        assert_eq!(extensions, vec![Location::from_coords(30, 30)]);
     }
 
-    fn place(
-        &self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &mut PlannerState,
-    ) -> Result<(), ()> {
-        let mut current_gather_data = PlanGatherChildrenData::<'a>::new();
+    // Border walls with exits in the middle of the top and bottom edges.
+    fn two_exit_terrain() -> FastRoomTerrain {
+        let mut buffer = vec![0; (ROOM_WIDTH as usize) * (ROOM_HEIGHT as usize)];
 
-        for option in self.options.iter() {
-            if current_gather_data.desires_placement(option.as_base(), context, state)
-                && current_gather_data.desires_location(
-                    position,
-                    option.as_location(),
-                    context,
-                    state,
-                )
-                && current_gather_data.insert_location_placement(position, *option)
-            {
-                //TODO: Should this allow recovery?
-                option.place(position, context, state)?;
+        for y in 0..ROOM_HEIGHT {
+            for x in 0..ROOM_WIDTH {
+                let border = x == 0 || y == 0 || x == ROOM_WIDTH - 1 || y == ROOM_HEIGHT - 1;
+                let exit = (y == 0 || y == ROOM_HEIGHT - 1) && (20..30).contains(&x);
 
-                break;
+                if border && !exit {
+                    buffer[(y as usize) * (ROOM_WIDTH as usize) + (x as usize)] =
+                        TerrainFlags::WALL.bits();
+                }
             }
         }
 
-        Ok(())
+        FastRoomTerrain::new(buffer)
     }
-}
-
-pub struct NearestToStructureExpansionPlanNode<'a> {
-    pub structure_type: StructureType,
-    pub child: PlanNodeStorage<'a>,
-    pub path_distance: u32,
-    pub desires_placement: fn(context: &mut NodeContext, state: &PlannerState) -> bool,
-    pub desires_location:
-        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> bool,
-    pub scorer:
-        fn(position: PlanLocation, context: &mut NodeContext, state: &PlannerState) -> Option<f32>,
-}
 
-impl<'a> NearestToStructureExpansionPlanNode<'a> {
-    fn get_child_locations<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> Vec<PlanLocation> {
-        let mut result = Vec::new();
+    // A spawn in the middle of the room, with a wall or rampart line across the room on each of
+    // the given rows apart from the gaps.
+    fn walled_plan(rows: &[u8], gaps: &[(u8, u8)]) -> Plan {
+        let mut items = vec![(StructureType::Spawn, 25, 25, 1)];
 
-        if self.child.desires_placement(context, state, gather_data) {
-            if let Some((path, _distance)) = state.get_pathfinding_distance_to_structure(
-                position,
-                self.structure_type,
-                1,
-                context.terrain(),
-            ) {
-                for offset_location in path.iter() {
-                    let distance = offset_location.distance_to(position) as u32;
+        for y in rows {
+            for x in 1..(ROOM_WIDTH - 1) {
+                if !gaps.contains(&(x, *y)) {
+                    let structure_type = if x == 25 {
+                        StructureType::Rampart
+                    } else {
+                        StructureType::Wall
+                    };
 
-                    if distance == self.path_distance {
-                        result.push(*offset_location);
-                    } else if distance > self.path_distance {
-                        break;
-                    }
+                    items.push((structure_type, x, *y, 4));
                 }
             }
         }
 
-        result
+        plan_from_items(&items)
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanBaseNode for NearestToStructureExpansionPlanNode<'a> {
-    fn name(&self) -> &str {
-        "Nearest To Structure"
-    }
+    #[test]
+    fn exit_seal_status_of_walled_room() {
+        let terrain = two_exit_terrain();
+        let status = walled_plan(&[2, 47], &[]).exit_seal_status(&terrain);
 
-    fn gather_nodes<'b>(&'b self, data: &mut PlanGatherNodesData<'b>) {
-        self.child.gather_nodes(data);
+        assert_eq!(status.len(), 20);
+        assert!(status.iter().all(|(_, sealed)| *sealed));
     }
 
-    fn desires_placement<'s>(
-        &'s self,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        (self.desires_placement)(context, state)
-            && self.child.desires_placement(context, state, gather_data)
+    #[test]
+    fn exit_seal_status_with_gap() {
+        let terrain = two_exit_terrain();
+        let status = walled_plan(&[2, 47], &[(10, 47)]).exit_seal_status(&terrain);
+
+        assert_eq!(status.len(), 20);
+
+        for (exit, sealed) in status {
+            assert_eq!(sealed, exit.y() == 0);
+        }
     }
-}
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanLocationNode for NearestToStructureExpansionPlanNode<'a> {
-    fn as_base(&self) -> &dyn PlanBaseNode {
-        self
+    fn road_locations(plan: &Plan) -> Vec<Location> {
+        plan.build_order()
+            .iter()
+            .filter(|step| step.structure_type == StructureType::Road)
+            .map(|step| step.location)
+            .collect()
     }
 
-    fn desires_location<'s>(
-        &'s self,
-        _position: PlanLocation,
-        _context: &mut NodeContext,
-        _state: &PlannerState,
-        _gather_data: &mut PlanGatherChildrenData<'s>,
-    ) -> bool {
-        true
+    #[test]
+    fn exit_roads_reach_each_open_side() {
+        let mut room = TestRoom::open();
+        room.terrain = two_exit_terrain();
 
-        /*
-        self.allowed_offsets.iter().any(|offset| {
-            self.child
-                .desires_location(position + *offset, context, state, gather_data)
-        })
-        */
-    }
+        let plan = plan_room(
+            &test_planner(PlanningConfig {
+                exit_roads: true,
+                ..PlanningConfig::default()
+            }),
+            &mut room,
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
 
-    fn get_children<'s>(
-        &'s self,
-        position: PlanLocation,
-        context: &mut NodeContext,
-        state: &PlannerState,
-        gather_data: &mut PlanGatherChildrenData<'s>,
-    ) {
-        if !gather_data.has_visited_location(position, self) {
-            gather_data.mark_visited_location(position, self);
+        let roads = road_locations(&plan);
 
-            if self.child.desires_placement(context, state, gather_data) {
-                if let Some((path, _distance)) = state.get_pathfinding_distance_to_structure(
-                    position,
-                    self.structure_type,
-                    1,
-                    context.terrain(),
-                ) {
-                    for offset_location in path.iter() {
-                        let distance = offset_location.distance_to(position) as u32;
+        let required_rcl = |location: Location| {
+            plan.state
+                .get(&location)
+                .and_then(|entries| {
+                    entries
+                        .iter()
+                        .find(|e| e.structure_type == StructureType::Road)
+                })
+                .map(|road| road.required_rcl)
+        };
 
-                        if distance == self.path_distance {
-                            if self.child.desires_location(
-                                *offset_location,
-                                context,
-                                state,
-                                gather_data,
-                            ) {
-                                self.child.insert_or_expand(
-                                    *offset_location,
-                                    context,
-                                    state,
-                                    gather_data,
-                                );
+        for y in [0, ROOM_HEIGHT - 1].iter() {
+            let border_roads: Vec<_> = roads.iter().filter(|road| road.y() == *y).collect();
 
-                                break;
-                            }
-                        } else if distance > self.path_distance {
-                            break;
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
+            assert_eq!(border_roads.len(), 1);
+            assert!((20..30).contains(&border_roads[0].x()));
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a> PlanPlacementExpansionNode for NearestToStructureExpansionPlanNode<'a> {
-    fn as_location(&self) -> &dyn PlanLocationNode {
-        self
-    }
-}
+            // The border road takes the RCL of the road leading on into the room.
+            let border_road = *border_roads[0];
+            let rcl = required_rcl(border_road);
 
-pub struct FastRoomTerrain {
-    buffer: Vec<u8>,
-}
+            assert!(roads.iter().any(|road| *road != border_road
+                && road.distance_to(border_road) <= 1
+                && required_rcl(*road) == rcl));
+        }
 
-bitflags! {
-    pub struct TerrainFlags: u8 {
-        const NONE = 0;
-        const WALL = TERRAIN_MASK_WALL;
-        const SWAMP = TERRAIN_MASK_SWAMP;
-        const LAVA = TERRAIN_MASK_LAVA;
-    }
-}
+        assert!(roads
+            .iter()
+            .all(|road| road.x() != 0 && road.x() != ROOM_WIDTH - 1));
 
-enum ExitSide {
-    Top,
-    Right,
-    Bottom,
-    Left,
-}
+        let without_exit_roads = plan_room(&test_planner(PlanningConfig::default()), &mut room)
+            .unwrap_or_else(|err| panic!("{}", err));
 
-pub struct ExitIterator<'a> {
-    terrain: &'a FastRoomTerrain,
-    side: Option<ExitSide>,
-    index: u32,
-}
+        assert!(road_locations(&without_exit_roads)
+            .iter()
+            .all(|road| road.y() != 0 && road.y() != ROOM_HEIGHT - 1));
+    }
 
-impl<'a> Iterator for ExitIterator<'a> {
-    type Item = Location;
+    #[test]
+    fn align_exit_roads_of_planned_rooms() {
+        // The top exit of the lower room leads into the bottom exit of the upper room.
+        let mut lower_room = TestRoom::open();
+        let mut upper_room = TestRoom::open();
+        upper_room.terrain = two_exit_terrain();
 
-    fn next(&mut self) -> Option<Location> {
-        loop {
-            let current = match self.side {
-                Some(ExitSide::Top) => {
-                    let res = Location::from_coords(self.index, 0);
+        let planner = test_planner(PlanningConfig::default());
 
-                    self.index += 1;
+        let mut lower =
+            plan_room(&planner, &mut lower_room).unwrap_or_else(|err| panic!("{}", err));
+        let mut upper =
+            plan_room(&planner, &mut upper_room).unwrap_or_else(|err| panic!("{}", err));
 
-                    if self.index >= ROOM_WIDTH as u32 - 1 {
-                        self.index = 0;
-                        self.side = Some(ExitSide::Right)
-                    }
+        let border_roads = |plan: &Plan, y: u8| -> Vec<u8> {
+            road_locations(plan)
+                .iter()
+                .filter(|road| road.y() == y)
+                .map(|road| road.x())
+                .collect()
+        };
 
-                    res
-                }
-                Some(ExitSide::Right) => {
-                    let res = Location::from_coords(ROOM_WIDTH as u32 - 1, self.index);
+        assert!(border_roads(&lower, 0).is_empty());
+        assert!(border_roads(&upper, ROOM_HEIGHT - 1).is_empty());
+
+        assert!(align_exit_roads(
+            &mut lower,
+            &lower_room.terrain,
+            ExitSide::Top,
+            &mut upper,
+            &upper_room.terrain,
+            ExitSide::Left
+        )
+        .is_err());
+
+        align_exit_roads(
+            &mut lower,
+            &lower_room.terrain,
+            ExitSide::Top,
+            &mut upper,
+            &upper_room.terrain,
+            ExitSide::Bottom,
+        )
+        .unwrap();
+
+        let crossing = border_roads(&lower, 0);
+
+        assert_eq!(crossing.len(), 1);
+        assert_eq!(border_roads(&upper, ROOM_HEIGHT - 1), crossing);
+        assert!((20..30).contains(&crossing[0]));
+
+        // Each border road leads on into its room.
+        let x = crossing[0] as u32;
+
+        assert!((x - 1..=x + 1)
+            .any(|x| lower.has_structure(Location::from_coords(x, 1), StructureType::Road)));
+        assert!((x - 1..=x + 1).any(|x| upper.has_structure(
+            Location::from_coords(x, ROOM_HEIGHT as u32 - 2),
+            StructureType::Road
+        )));
+    }
+
+    #[test]
+    fn extension_road_distance_limits_connections() {
+        let room = TestRoom::open();
+        let items = [
+            (StructureType::Extension, 20, 20, 2),
+            (StructureType::Road, 20, 22, 1),
+            (StructureType::Road, 20, 23, 1),
+        ];
+
+        let mut plan = plan_from_items(&items);
+        plan.connect_extension_roads(&room.terrain, 2);
+
+        assert_eq!(road_locations(&plan).len(), 2);
+
+        let mut plan = plan_from_items(&items);
+        plan.connect_extension_roads(&room.terrain, 1);
+
+        assert!(road_locations(&plan)
+            .iter()
+            .any(|road| road.distance_to(Location::from_coords(20, 20)) <= 1));
+    }
 
-                    self.index += 1;
+    #[test]
+    fn reserved_tiles_stay_empty_and_are_listed() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
 
-                    if self.index >= ROOM_HEIGHT as u32 - 1 {
-                        self.index = 0;
-                        self.side = Some(ExitSide::Bottom)
-                    }
+        let reserved = Location::from_coords(21, 20);
 
-                    res
-                }
-                Some(ExitSide::Bottom) => {
-                    let res = Location::from_coords(
-                        (ROOM_WIDTH as u32 - 1) - self.index,
-                        ROOM_HEIGHT as u32 - 1,
-                    );
+        let mut state = PlannerState::new();
+        state.insert(
+            Location::from_coords(20, 20),
+            room_item(StructureType::Spawn, 1),
+        );
+        state.reserve(reserved);
 
-                    self.index += 1;
+        {
+            let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+            let extension = placement(StructureType::Extension, 0, 0);
 
-                    if self.index >= ROOM_WIDTH as u32 - 1 {
-                        self.index = 0;
-                        self.side = Some(ExitSide::Left)
-                    }
+            assert!(!extension.can_place(reserved.into(), &mut context, &state));
+            assert!(extension.can_place(PlanLocation::new(21, 21), &mut context, &state));
+        }
 
-                    res
-                }
-                Some(ExitSide::Left) => {
-                    let res = Location::from_coords(0, (ROOM_HEIGHT as u32 - 1) - self.index);
+        let plan = test_planner(PlanningConfig::default())
+            .finalize_plan(state.snapshot(), state.get_reserved_locations(), &mut room)
+            .unwrap_or_else(|err| panic!("{}", err));
 
-                    self.index += 1;
+        assert!(plan.tile_structures(reserved).is_empty());
+        assert_eq!(plan.reserved(), &[reserved]);
+    }
 
-                    if self.index >= ROOM_HEIGHT as u32 - 1 {
-                        self.index = 0;
-                        self.side = None;
-                    }
+    #[test]
+    fn connecting_roads_avoid_reserved_tiles() {
+        let room = TestRoom::open();
 
-                    res
-                }
-                None => {
-                    return None;
-                }
-            };
+        // The direct route down to the road is reserved.
+        let reserved: Vec<_> = (19..=21)
+            .map(|x| Location::from_coords(x, 21))
+            .chain((19..=21).map(|x| Location::from_coords(x, 22)))
+            .collect();
 
-            let terrain_mask = self.terrain.get_xy(current.x(), current.y());
+        for structure_type in &[StructureType::Extension, StructureType::Container] {
+            let mut plan = plan_from_items(&[
+                (*structure_type, 20, 20, 2),
+                (StructureType::Road, 20, 24, 1),
+            ]);
+            plan.reserved = reserved.clone();
 
-            if !terrain_mask.intersects(TerrainFlags::WALL) {
-                return Some(current);
+            if *structure_type == StructureType::Extension {
+                plan.connect_extension_roads(&room.terrain, 1);
+            } else {
+                assert!(plan.connect_container_roads(&room.terrain).is_ok());
             }
+
+            let roads = road_locations(&plan);
+
+            assert!(roads
+                .iter()
+                .any(|road| road.distance_to(Location::from_coords(20, 20)) <= 1));
+            assert!(roads.iter().all(|road| !reserved.contains(road)));
         }
     }
-}
 
-impl FastRoomTerrain {
-    pub fn new(buffer: Vec<u8>) -> FastRoomTerrain {
-        FastRoomTerrain { buffer }
+    #[test]
+    fn protected_types_are_not_cleaned_up() {
+        let plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Extension, 20, 20, 2),
+        ]);
+
+        let existing = [
+            (Location::from_coords(10, 10), StructureType::Container),
+            (Location::from_coords(11, 11), StructureType::Extension),
+            (Location::from_coords(20, 20), StructureType::Extension),
+            (Location::from_coords(30, 30), StructureType::Spawn),
+        ];
+
+        // The off plan spawn stays until the planned one is built.
+        assert_eq!(plan.cleanup_targets(&existing, &[]), vec![0, 1]);
+        assert_eq!(
+            plan.cleanup_targets(&existing, &[StructureType::Container]),
+            vec![1]
+        );
     }
 
-    pub fn get(&self, pos: &Location) -> TerrainFlags {
-        self.get_xy(pos.x(), pos.y())
-    }
+    #[test]
+    fn mineral_container_road_ends_next_to_a_lab() {
+        let room = TestRoom::open();
 
-    pub fn get_xy(&self, x: u8, y: u8) -> TerrainFlags {
-        let index = (y as usize * ROOM_WIDTH as usize) + (x as usize);
+        let mut plan = plan_from_items(&[
+            (StructureType::Extractor, 30, 40, 6),
+            (StructureType::Container, 30, 39, 6),
+            (StructureType::Lab, 24, 30, 6),
+            (StructureType::Lab, 25, 30, 6),
+            (StructureType::Lab, 24, 31, 6),
+        ]);
 
-        TerrainFlags::from_bits_truncate(self.buffer[index])
-    }
+        plan.connect_mineral_containers_to_labs(&room.terrain);
 
-    pub fn get_exits(&self) -> ExitIterator {
-        ExitIterator {
-            terrain: self,
-            side: Some(ExitSide::Top),
-            index: 0,
-        }
-    }
-}
+        let roads = road_locations(&plan);
+        let labs = [(24, 30), (25, 30), (24, 31)];
 
-struct EvaluationStackEntry<'b> {
-    children: Vec<PlanNodeChild<'b>>,
-}
+        // The road runs from beside the container to the labs.
+        let start = roads
+            .iter()
+            .find(|road| road.distance_to(Location::from_coords(30, 39)) <= 1)
+            .unwrap();
+        let end = roads
+            .iter()
+            .max_by_key(|road| road.distance_to(*start))
+            .unwrap();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'b> EvaluationStackEntry<'b> {
-    pub fn to_serialized(
-        &self,
-        index_lookup: &FnvHashMap<uuid::Uuid, usize>,
-    ) -> SerializedEvaluationStackEntry {
-        SerializedEvaluationStackEntry {
-            children: self
-                .children
-                .iter()
-                .map(|c| c.to_serialized(index_lookup))
-                .collect(),
-        }
+        assert!(labs
+            .iter()
+            .any(|(x, y)| end.distance_to(Location::from_coords(*x, *y)) <= 1));
     }
-}
 
-#[derive(Clone, Serialize, Deserialize)]
-struct SerializedEvaluationStackEntry {
-    #[serde(rename = "c")]
-    children: Vec<SerializedPlanNodeChild>,
-}
+    #[test]
+    fn prune_dead_end_roads_keeps_core_and_exit_roads() {
+        let mut room = TestRoom::open();
 
-impl SerializedEvaluationStackEntry {
-    pub fn as_entry<'b>(
-        &self,
-        nodes: &PlanGatherNodesData<'b>,
-        index_lookup: &Vec<uuid::Uuid>,
-    ) -> Result<EvaluationStackEntry<'b>, String> {
-        let mut children = Vec::new();
+        let mut items = vec![(StructureType::Storage, 25, 25, 4)];
 
-        for serialized_child in &self.children {
-            let child = serialized_child.as_entry(nodes, index_lookup)?;
+        // A ring around storage, a road out to the exit and a road ending in a wall pocket.
+        for (x, y) in ONE_OFFSET_SQUARE {
+            items.push((StructureType::Road, (25 + x) as u8, (25 + y) as u8, 1));
+        }
 
-            children.push(child);
+        for y in 1..24 {
+            items.push((StructureType::Road, 25, y, 1));
         }
 
-        Ok(EvaluationStackEntry { children })
-    }
-}
+        for x in 27..=32 {
+            items.push((StructureType::Road, x, 25, 1));
+        }
 
-#[derive(Clone, Serialize, Deserialize)]
-struct SerializedEvaluationStack {
-    identifiers: Vec<uuid::Uuid>,
-    entries: Vec<SerializedEvaluationStackEntry>,
-}
+        let mut plan = plan_from_items(&items);
+        plan.routed_roads = road_locations(&plan);
 
-impl SerializedEvaluationStack {
-    pub fn from_stack(
-        gathered_nodes: &PlanGatherNodesData,
-        entries: &Vec<EvaluationStackEntry>,
-    ) -> SerializedEvaluationStack {
-        let identifiers: Vec<_> = gathered_nodes.get_all_ids();
-        let index_lookup: FnvHashMap<_, _> = identifiers
-            .iter()
-            .enumerate()
-            .map(|(index, id)| (*id, index))
+        let served: Vec<PlanLocation> = room
+            .get_terrain()
+            .get_exits()
+            .map(PlanLocation::from)
             .collect();
 
-        let serialized_entries = entries
-            .iter()
-            .map(|e| e.to_serialized(&index_lookup))
-            .collect();
+        plan.prune_dead_end_roads(&served);
 
-        SerializedEvaluationStack {
-            identifiers,
-            entries: serialized_entries,
+        let roads = road_locations(&plan);
+
+        for (x, y) in ONE_OFFSET_SQUARE {
+            assert!(roads.contains(&Location::from_coords((25 + x) as u32, (25 + y) as u32)));
         }
+
+        assert!((1..24).all(|y| roads.contains(&Location::from_coords(25, y))));
+        assert!((28..=32).all(|x| !roads.contains(&Location::from_coords(x, 25))));
+        assert!((28..=32).all(|x| !plan.routed_roads.contains(&Location::from_coords(x, 25))));
     }
 
-    pub fn to_stack<'b>(
-        &self,
-        gathered_nodes: &PlanGatherNodesData<'b>,
-    ) -> Result<Vec<EvaluationStackEntry<'b>>, String> {
-        let mut stack = Vec::new();
+    #[test]
+    fn prune_dead_end_roads_only_removes_routed_roads() {
+        let mut items = vec![(StructureType::Storage, 25, 25, 4)];
 
-        for serialized_entry in self.entries.iter() {
-            let entry = serialized_entry.as_entry(&gathered_nodes, &self.identifiers)?;
+        // Dead end roads to the east, west and south of the storage ring.
+        for (x, y) in ONE_OFFSET_SQUARE {
+            items.push((StructureType::Road, (25 + x) as u8, (25 + y) as u8, 1));
+        }
 
-            stack.push(entry);
+        let seeded: Vec<_> = (28..=32).map(|x| Location::from_coords(x, 25)).collect();
+        let stamp: Vec<_> = (18..=22).map(|x| Location::from_coords(x, 25)).collect();
+        let routed: Vec<_> = (28..=32).map(|y| Location::from_coords(25, y)).collect();
+
+        for location in seeded.iter().chain(stamp.iter()).chain(routed.iter()) {
+            items.push((StructureType::Road, location.x(), location.y(), 1));
         }
 
-        Ok(stack)
-    }
-}
+        let mut plan = plan_from_items(&items);
+        plan.existing_roads = seeded.clone();
+        plan.routed_roads = routed.clone();
 
-enum TreePlannerResult {
-    Complete,
-    Running(SerializedEvaluationStack),
-}
+        assert_eq!(plan.road_origin(seeded[0]), Some(RoadOrigin::Existing));
+        assert_eq!(plan.road_origin(stamp[0]), Some(RoadOrigin::Stamp));
+        assert_eq!(plan.road_origin(routed[0]), Some(RoadOrigin::Routed));
+        assert_eq!(plan.road_origin(Location::from_coords(40, 40)), None);
 
-struct TreePlanner<'t, H>
-where
-    H: FnMut(&PlannerState, &mut NodeContext),
-{
-    data_source: &'t mut dyn PlannerRoomDataSource,
-    handler: H,
-}
+        plan.prune_dead_end_roads(&[]);
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'t, H> TreePlanner<'t, H>
-where
-    H: FnMut(&PlannerState, &mut NodeContext),
-{
-    pub fn new<'a>(
-        data_source: &'a mut dyn PlannerRoomDataSource,
-        handler: H,
-    ) -> TreePlanner<'a, H> {
-        TreePlanner {
-            data_source,
-            handler,
-        }
+        let roads = road_locations(&plan);
+
+        assert!(seeded.iter().all(|location| roads.contains(location)));
+        assert!(stamp.iter().all(|location| roads.contains(location)));
+        assert!(routed.iter().all(|location| !roads.contains(location)));
     }
 
-    pub fn seed<'r, 's>(
-        &mut self,
-        root_nodes: &[&'r dyn PlanGlobalExpansionNode],
-        state: &'s mut PlannerState,
-    ) -> Result<TreePlannerResult, String> {
-        let mut context = NodeContext::new(self.data_source);
+    #[test]
+    fn seeded_dead_end_road_survives_pruning() {
+        // Already built in the corner of the room, away from everything the plan serves.
+        let existing: Vec<_> = (6..=10).map(|y| Location::from_coords(4, y)).collect();
 
-        let mut stack = Vec::new();
+        let planner = test_planner(PlanningConfig {
+            prune_dead_end_roads: true,
+            ..PlanningConfig::default()
+        })
+        .with_existing_roads(existing.clone());
 
-        let mut gathered_children = PlanGatherChildrenData::<'s>::new();
+        let plan =
+            plan_room(&planner, &mut TestRoom::open()).unwrap_or_else(|err| panic!("{}", err));
 
-        for node in root_nodes.iter() {
-            if gathered_children.desires_placement(node.as_base(), &mut context, state) {
-                node.get_children(&mut context, state, &mut gathered_children);
-            }
+        for location in existing.iter() {
+            assert_eq!(plan.road_origin(*location), Some(RoadOrigin::Existing));
         }
+    }
 
-        let children = gathered_children.collect();
-
-        let mut ordered_children: Vec<_> = children
-            .into_iter()
-            .filter_map(|node| {
-                node.get_score(&mut context, state)
-                    .map(|score| (node, score))
-            })
-            .collect();
+    #[test]
+    fn every_container_has_a_road_to_the_hub() {
+        let plan = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
 
-        ordered_children.sort_by(|(node_a, score_a), (node_b, score_b)| {
-            node_a
-                .placement_phase()
-                .cmp(&node_b.placement_phase())
-                .reverse()
-                .then_with(|| node_a.must_place().cmp(&node_b.must_place()))
-                .then_with(|| score_a.partial_cmp(score_b).unwrap())
-        });
+        let locations_of = |structure_type| -> Vec<Location> {
+            plan.build_order()
+                .iter()
+                .filter(|step| step.structure_type == structure_type)
+                .map(|step| step.location)
+                .collect()
+        };
 
-        stack.push(EvaluationStackEntry {
-            children: ordered_children.into_iter().map(|(node, _)| node).collect(),
-        });
+        let roads = road_locations(&plan);
+        let storage = locations_of(StructureType::Storage)[0];
 
-        let mut gathered_nodes = PlanGatherNodesData::new::<'r>();
+        // Every road joined to a road next to storage, stepping between neighbouring roads.
+        let mut reached: FnvHashSet<Location> = roads
+            .iter()
+            .filter(|road| road.distance_to(storage) <= 1)
+            .copied()
+            .collect();
+        let mut frontier: Vec<_> = reached.iter().copied().collect();
 
-        for node in root_nodes {
-            node.gather_nodes(&mut gathered_nodes);
+        while let Some(current) = frontier.pop() {
+            for road in roads.iter() {
+                if road.distance_to(current) <= 1 && reached.insert(*road) {
+                    frontier.push(*road);
+                }
+            }
         }
 
-        let serialized = SerializedEvaluationStack::from_stack(&gathered_nodes, &stack);
+        let containers = locations_of(StructureType::Container);
 
-        Ok(TreePlannerResult::Running(serialized))
+        // One for each source, the controller and the mineral.
+        assert_eq!(containers.len(), 4);
+
+        for container in containers {
+            assert!(
+                reached.iter().any(|road| road.distance_to(container) <= 1),
+                "No road to the hub from the container at {:?}",
+                container
+            );
+        }
     }
 
-    pub fn process<'r, 's, F>(
-        &mut self,
-        root_nodes: &[&'r dyn PlanGlobalExpansionNode],
-        state: &'s mut PlannerState,
-        serialized_stack: &SerializedEvaluationStack,
-        should_continue: F,
-    ) -> Result<TreePlannerResult, String>
-    where
-        F: Fn() -> bool,
-    {
-        let mut context = NodeContext::new(self.data_source);
+    #[test]
+    fn execution_order_filters_structure_types() {
+        let plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Road, 25, 26, 1),
+            (StructureType::Road, 25, 27, 1),
+            (StructureType::Rampart, 25, 26, 4),
+            (StructureType::Extension, 24, 26, 2),
+        ]);
 
-        let mut processed_entries = 0;
+        let priorities = BuildPriorityMap::default();
 
-        let mut gathered_nodes = PlanGatherNodesData::new::<'r>();
+        let roads = plan.execution_order(8, Some(&[StructureType::Road]), &priorities);
 
-        for node in root_nodes {
-            node.gather_nodes(&mut gathered_nodes);
-        }
+        assert_eq!(roads.len(), 2);
+        assert!(roads
+            .iter()
+            .all(|(_, item)| item.structure_type() == StructureType::Road));
 
-        let mut stack = serialized_stack.to_stack(&gathered_nodes)?;
+        assert_eq!(plan.execution_order(8, None, &priorities).len(), 5);
+        assert!(plan.execution_order(8, Some(&[]), &priorities).is_empty());
+    }
 
-        while !stack.is_empty() && should_continue() {
-            let mut placed_nodes = Vec::new();
+    #[test]
+    fn execution_order_follows_build_priorities() {
+        let plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Road, 25, 26, 1),
+        ]);
 
-            let (entry_failed, finished_entry) = {
-                let entry = stack.last_mut().unwrap();
-                let mut entry_failed = false;
+        let order = |priorities: &BuildPriorityMap| {
+            plan.execution_order(8, None, priorities)
+                .iter()
+                .map(|(_, item)| item.structure_type())
+                .collect::<Vec<_>>()
+        };
 
-                while !entry.children.is_empty()
-                    && placed_nodes.is_empty()
-                    && !entry_failed
-                    && should_continue()
-                {
-                    let mut to_place = Vec::new();
+        assert_eq!(
+            order(&BuildPriorityMap::default()),
+            vec![StructureType::Spawn, StructureType::Road]
+        );
+        assert_eq!(
+            order(
+                &BuildPriorityMap::default()
+                    .with_priority(StructureType::Spawn, BuildPriority::VeryLow)
+                    .with_priority(StructureType::Road, BuildPriority::Critical)
+            ),
+            vec![StructureType::Road, StructureType::Spawn]
+        );
+    }
 
-                    let mut current_phase = None;
+    #[test]
+    fn nearest_spawn_of_three() {
+        let plan = plan_from_items(&[
+            (StructureType::Spawn, 10, 10, 1),
+            (StructureType::Spawn, 30, 10, 7),
+            (StructureType::Spawn, 20, 30, 8),
+            (StructureType::Extension, 12, 12, 2),
+        ]);
 
-                    let placeable_children = entry
-                        .children
-                        .iter()
-                        .enumerate()
-                        .rev()
-                        .filter(|(_, c)| c.ready_for_placement(&mut context, state));
+        let nearest = |x, y| plan.nearest_spawn(Location::from_coords(x, y));
 
-                    for (index, child) in placeable_children {
-                        let matches_phase = current_phase
-                            .map(|phase| phase == child.placement_phase())
-                            .unwrap_or(true);
+        assert_eq!(nearest(10, 10), Some(Location::from_coords(10, 10)));
+        assert_eq!(nearest(13, 13), Some(Location::from_coords(10, 10)));
+        assert_eq!(nearest(28, 5), Some(Location::from_coords(30, 10)));
+        assert_eq!(nearest(20, 40), Some(Location::from_coords(20, 30)));
 
-                        if child.must_place() && matches_phase {
-                            to_place.push(index);
+        // Equally far from the first two spawns - the lower packed location wins.
+        assert_eq!(nearest(20, 10), Some(Location::from_coords(10, 10)));
 
-                            current_phase = Some(child.placement_phase());
-                        } else if to_place.is_empty() {
-                            to_place.push(index);
+        assert_eq!(
+            plan_from_items(&[]).nearest_spawn(Location::from_coords(25, 25)),
+            None
+        );
+    }
 
-                            break;
-                        } else {
-                            break;
-                        }
-                    }
+    // Records every structure and heatmap tile it is asked to render.
+    #[derive(Default)]
+    struct RecordingVisualizer {
+        rendered: Vec<(Location, StructureType)>,
+        heat: Vec<(Location, f32)>,
+    }
+
+    impl RoomVisualizer for RecordingVisualizer {
+        fn render(&mut self, location: Location, structure: StructureType) {
+            self.rendered.push((location, structure));
+        }
 
-                    if !to_place.is_empty() {
-                        processed_entries += to_place.len();
+        fn render_heat(&mut self, location: Location, value: f32) {
+            self.heat.push((location, value));
+        }
+    }
 
-                        let to_place_nodes =
-                            to_place.iter().map(|index| entry.children.remove(*index));
+    #[test]
+    fn distance_transform_heat_covers_interior_tiles() {
+        let analysis = run_analysis(&mut TestRoom::with_walls(|x, y| x == 10 && y == 10));
 
-                        state.push_layer();
+        let mut visualizer = RecordingVisualizer::default();
+        analysis.visualize_distance_transform(&mut visualizer);
 
-                        let mut validate_location = false;
+        // Every buildable tile apart from the single wall.
+        assert_eq!(visualizer.heat.len(), 46 * 46 - 1);
+        assert!(visualizer
+            .heat
+            .iter()
+            .all(|(location, value)| location.in_room_from_edge(2)
+                && *location != Location::from_coords(10, 10)
+                && *value > 0.0
+                && *value <= 1.0));
+        assert!(visualizer.heat.iter().any(|(_, value)| *value == 1.0));
+        assert!(visualizer.rendered.is_empty());
+    }
+
+    #[test]
+    fn visualize_filtered_renders_only_given_types() {
+        let plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Road, 25, 26, 1),
+            (StructureType::Rampart, 25, 26, 4),
+            (StructureType::Road, 25, 27, 1),
+            (StructureType::Extension, 24, 26, 2),
+        ]);
+
+        let mut visualizer = RecordingVisualizer::default();
+        plan.visualize_filtered(&[StructureType::Road], &mut visualizer);
+
+        let mut rendered = visualizer.rendered;
+        rendered.sort_by_key(|(location, _)| location.packed_repr());
+
+        assert_eq!(
+            rendered,
+            vec![
+                (Location::from_coords(25, 26), StructureType::Road),
+                (Location::from_coords(25, 27), StructureType::Road),
+            ]
+        );
 
-                        for child in to_place_nodes {
-                            if validate_location
-                                && !child.desires_location(
-                                    &mut context,
-                                    state,
-                                    &mut PlanGatherChildrenData::new(),
-                                )
-                            {
-                                entry_failed = true;
+        let mut visualizer = RecordingVisualizer::default();
+        plan.visualize_filtered(&[], &mut visualizer);
 
-                                break;
-                            }
+        assert!(visualizer.rendered.is_empty());
+    }
 
-                            match child.place(&mut context, state) {
-                                Ok(()) => {}
-                                Err(()) => {
-                                    entry_failed = true;
+    fn structure_types_at(plan: &Plan, x: u8, y: u8) -> Vec<StructureType> {
+        let mut structure_types: Vec<_> = plan
+            .tile_structures(Location::from_coords(x as u32, y as u32))
+            .iter()
+            .map(|item| item.structure_type())
+            .collect();
 
-                                    break;
-                                }
-                            }
+        structure_types.sort_by_key(|structure_type| format!("{:?}", structure_type));
 
-                            placed_nodes.push(child);
+        structure_types
+    }
 
-                            validate_location = true;
-                        }
-                    } else {
-                        entry_failed = true;
-                    }
+    #[test]
+    fn ramparted_road_round_trips() {
+        let plan = plan_from_items(&[
+            (StructureType::Road, 25, 3, 1),
+            (StructureType::Rampart, 25, 3, 4),
+        ]);
 
-                    if !entry_failed {
-                        (self.handler)(state, &mut context);
-                    } else {
-                        state.pop_layer();
-                    }
-                }
+        let ramparted_road = vec![StructureType::Rampart, StructureType::Road];
 
-                (entry_failed, entry.children.is_empty())
-            };
+        assert_eq!(structure_types_at(&plan, 25, 3), ramparted_road);
 
-            if entry_failed {
-                state.pop_layer();
+        let serialized = serde_json::to_string(&plan).unwrap();
+        let deserialized: Plan = serde_json::from_str(&serialized).unwrap();
 
-                stack.pop();
-            } else if !placed_nodes.is_empty() {
-                let mut gathered_children = PlanGatherChildrenData::<'s>::new();
+        assert_eq!(structure_types_at(&deserialized, 25, 3), ramparted_road);
 
-                for child in placed_nodes.iter() {
-                    child.get_children(&mut context, state, &mut gathered_children);
-                }
+        let imported = Plan::from_shareable_json(&plan.to_shareable_json()).unwrap();
 
-                for existing_child in stack.last().unwrap().children.iter() {
-                    if existing_child.desires_placement(&mut context, state, &mut gathered_children)
-                        && existing_child.desires_location(
-                            &mut context,
-                            state,
-                            &mut gathered_children,
-                        )
-                    {
-                        existing_child.insert(&mut gathered_children);
-                    }
-                }
+        assert_eq!(structure_types_at(&imported, 25, 3), ramparted_road);
+    }
 
-                let children = gathered_children.collect();
+    #[test]
+    fn ramparts_may_share_a_tile_with_roads() {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
 
-                let mut ordered_children: Vec<_> = children
-                    .into_iter()
-                    .filter_map(|node| {
-                        node.get_score(&mut context, state)
-                            .map(|score| (node, score))
-                    })
-                    .collect();
+        let location = Location::from_coords(25, 25);
 
-                ordered_children.sort_by(|(node_a, score_a), (node_b, score_b)| {
-                    node_a
-                        .placement_phase()
-                        .cmp(&node_b.placement_phase())
-                        .reverse()
-                        .then_with(|| node_a.must_place().cmp(&node_b.must_place()))
-                        .then_with(|| score_a.partial_cmp(score_b).unwrap())
-                });
+        let mut road_state = PlannerState::new();
+        road_state.insert(location, room_item(StructureType::Road, 1));
 
-                stack.push(EvaluationStackEntry {
-                    children: ordered_children.into_iter().map(|(node, _)| node).collect(),
-                });
-            } else if finished_entry {
-                state.pop_layer();
+        let mut rampart_state = PlannerState::new();
+        rampart_state.insert(location, room_item(StructureType::Rampart, 4));
 
-                stack.pop();
-            }
-        }
+        let can_place = |structure_type, context: &mut NodeContext, state: &PlannerState| {
+            placement(structure_type, 0, 0).can_place(location.into(), context, state)
+        };
 
-        info!(
-            "Processed planning entries: {} - Known children: {}",
-            processed_entries,
-            stack.iter().map(|e| e.children.len()).sum::<usize>()
+        assert!(can_place(StructureType::Rampart, &mut context, &road_state));
+        assert!(can_place(StructureType::Road, &mut context, &rampart_state));
+        assert!(!can_place(
+            StructureType::Extension,
+            &mut context,
+            &road_state
+        ));
+
+        // Moving a rampart onto a road tile is allowed, as is moving a road onto a rampart.
+        let mut plan = plan_from_items(&[
+            (StructureType::Road, 25, 25, 1),
+            (StructureType::Rampart, 26, 25, 4),
+            (StructureType::Road, 24, 25, 1),
+        ]);
+
+        let moved = plan.move_structure(
+            Location::from_coords(26, 25),
+            location,
+            StructureType::Rampart,
+            &room.terrain,
         );
 
-        if stack.is_empty() {
-            Ok(TreePlannerResult::Complete)
-        } else {
-            let serialized = SerializedEvaluationStack::from_stack(&gathered_nodes, &stack);
+        assert!(moved.is_ok());
+        assert_eq!(
+            structure_types_at(&plan, 25, 25),
+            vec![StructureType::Rampart, StructureType::Road]
+        );
+    }
 
-            Ok(TreePlannerResult::Running(serialized))
+    #[test]
+    fn energy_capacity_of_full_bunker() {
+        let mut items = vec![
+            (StructureType::Spawn, 10, 10, 1),
+            (StructureType::Spawn, 12, 10, 7),
+            (StructureType::Spawn, 14, 10, 8),
+        ];
+
+        // The official extension schedule - 5, 10, 20, 30, 40, 50 and 60 from RCL 2.
+        let mut extension_rcls = Vec::new();
+
+        for (rcl, count) in &[(2, 5), (3, 5), (4, 10), (5, 10), (6, 10), (7, 10), (8, 10)] {
+            extension_rcls.extend((0..*count).map(|_| *rcl));
         }
+
+        for (index, rcl) in extension_rcls.into_iter().enumerate() {
+            items.push((
+                StructureType::Extension,
+                10 + (index % 10) as u8,
+                20 + (index / 10) as u8,
+                rcl,
+            ));
+        }
+
+        let plan = plan_from_items(&items);
+
+        assert_eq!(count(&plan, StructureType::Extension), 60);
+        assert_eq!(plan.energy_capacity_at_rcl(8), 300 * 3 + 200 * 60);
+        assert_eq!(plan.energy_capacity_at_rcl(7), 300 * 2 + 100 * 50);
+        assert_eq!(plan.energy_capacity_at_rcl(2), 300 + 50 * 5);
+        assert_eq!(plan.energy_capacity_at_rcl(1), 300);
     }
-}
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct BestPlanData {
-    score: f32,
-    state: PlanState,
-}
+    #[test]
+    fn build_steps_carry_their_group() {
+        let plan = plan_room(
+            &test_planner(PlanningConfig::default()),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct PlanRunningStateData {
-    planner_state: PlannerState,
-    stack: SerializedEvaluationStack,
-    best_plan: Option<BestPlanData>,
-}
+        let groups_of = |structure_type| -> Vec<BuildGroup> {
+            plan.build_order()
+                .iter()
+                .filter(|step| step.structure_type == structure_type)
+                .map(|step| step.group)
+                .collect()
+        };
 
-impl PlanRunningStateData {
-    pub fn visualize<V>(&self, visualizer: &mut V)
-    where
-        V: RoomVisualizer,
-    {
-        self.planner_state.visualize(visualizer);
+        for (structure_type, group) in &[
+            (StructureType::Spawn, BuildGroup::Core),
+            (StructureType::Storage, BuildGroup::Core),
+            (StructureType::Rampart, BuildGroup::Defense),
+            (StructureType::Extension, BuildGroup::Economy),
+        ] {
+            let groups = groups_of(*structure_type);
+
+            assert!(!groups.is_empty());
+            assert!(groups.iter().all(|step_group| step_group == group));
+        }
     }
 
-    pub fn visualize_best<V>(&self, visualizer: &mut V)
-    where
-        V: RoomVisualizer,
-    {
-        if let Some(best_plan) = &self.best_plan {
-            let items = best_plan
-                .state
+    #[test]
+    fn build_priority_overrides_reorder_steps() {
+        let plan = plan_from_items(&[
+            (StructureType::Storage, 20, 20, 4),
+            (StructureType::Tower, 30, 30, 4),
+        ]);
+
+        let order = |priorities: &BuildPriorityMap| {
+            plan.build_order_with_priorities(priorities)
                 .iter()
-                .flat_map(|(location, entries)| entries.iter().map(move |entry| (location, entry)));
+                .map(|step| step.structure_type)
+                .collect::<Vec<_>>()
+        };
 
-            visualize_room_items(items, visualizer);
+        assert_eq!(
+            order(&BuildPriorityMap::default()),
+            vec![StructureType::Storage, StructureType::Tower]
+        );
+        assert_eq!(
+            order(
+                &BuildPriorityMap::default()
+                    .with_priority(StructureType::Tower, BuildPriority::Critical)
+            ),
+            vec![StructureType::Tower, StructureType::Storage]
+        );
+    }
+
+    #[test]
+    fn build_schedule_totals_cost_over_rate() {
+        let plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Extension, 20, 20, 2),
+            (StructureType::Road, 21, 20, 1),
+        ]);
+
+        let schedule = plan.build_schedule(10.0);
+        let ticks: Vec<_> = schedule.iter().map(|(ticks, _)| *ticks).collect();
+
+        assert_eq!(schedule.len(), 3);
+        assert_eq!(schedule[0].1.structure_type, StructureType::Spawn);
+        assert_eq!(ticks[0], 1500);
+        assert!(ticks.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(ticks[2], (15_000 + 3_000 + 300) / 10);
+    }
+
+    #[test]
+    fn build_schedule_is_empty_without_energy() {
+        let plan = plan_from_items(&[(StructureType::Spawn, 25, 25, 1)]);
+
+        assert!(plan.build_schedule(0.0).is_empty());
+        assert!(plan.build_schedule(-10.0).is_empty());
+        assert!(plan.build_schedule(f32::NAN).is_empty());
+    }
+
+    #[test]
+    fn ascii_grid_places_structures() {
+        let plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 20, 1),
+            (StructureType::Rampart, 25, 20, 4),
+            (StructureType::Extension, 3, 7, 2),
+            (StructureType::Road, 4, 7, 1),
+            (StructureType::Rampart, 5, 7, 4),
+            (StructureType::Road, 6, 7, 1),
+            (StructureType::Rampart, 6, 7, 4),
+        ]);
+
+        let grid = plan.to_ascii_grid();
+        let rows: Vec<Vec<char>> = grid.lines().map(|row| row.chars().collect()).collect();
+
+        assert_eq!(rows.len(), ROOM_HEIGHT as usize);
+        assert!(rows.iter().all(|row| row.len() == ROOM_WIDTH as usize));
+
+        let tile = |x: usize, y: usize| rows[y][x];
+
+        assert_eq!(tile(25, 20), 'S');
+        assert_eq!(tile(3, 7), 'E');
+        assert_eq!(tile(4, 7), '.');
+        assert_eq!(tile(5, 7), 'r');
+        assert_eq!(tile(6, 7), 'r');
+        assert_eq!(tile(20, 25), ' ');
+        assert_eq!(grid.chars().filter(|c| *c == 'S').count(), 1);
+    }
+
+    fn plan_with_metadata() -> Plan {
+        let mut plan = plan_from_items(&[
+            (StructureType::Spawn, 25, 25, 1),
+            (StructureType::Container, 25, 10, 1),
+            (StructureType::Link, 26, 10, 5),
+            (StructureType::Road, 25, 26, 1),
+            (StructureType::Road, 25, 27, 1),
+            (StructureType::Road, 25, 28, 1),
+        ]);
+
+        plan.existing_roads = vec![Location::from_coords(25, 27)];
+        plan.routed_roads = vec![Location::from_coords(25, 28)];
+        plan.reserved = vec![
+            Location::from_coords(20, 20),
+            Location::from_coords(21, 20),
+            Location::from_coords(25, 26),
+        ];
+        plan.controller_container = Some(Location::from_coords(25, 10));
+        plan.controller_link = Some(Location::from_coords(26, 10));
+        plan.filler_position = Some(Location::from_coords(24, 24));
+
+        plan
+    }
+
+    #[test]
+    fn compact_only_drops_road_origins() {
+        let mut plan = plan_with_metadata();
+        let fingerprint = plan.fingerprint();
+        let build_order = ordered_steps(&plan);
+        let reserved = plan.reserved().to_vec();
+        let size = plan.estimated_serialized_size();
+
+        plan.compact();
+
+        assert!(plan.estimated_serialized_size() < size);
+        assert_eq!(plan.fingerprint(), fingerprint);
+        assert_eq!(ordered_steps(&plan), build_order);
+        assert_eq!(plan.reserved(), &reserved[..]);
+        assert_eq!(
+            plan.controller_container(),
+            Some(Location::from_coords(25, 10))
+        );
+        assert_eq!(plan.controller_link(), Some(Location::from_coords(26, 10)));
+        assert_eq!(plan.filler_position(), Some(Location::from_coords(24, 24)));
+
+        for y in 26..=28 {
+            assert_eq!(
+                plan.road_origin(Location::from_coords(25, y)),
+                Some(RoadOrigin::Stamp)
+            );
         }
     }
-}
 
-pub enum PlanSeedResult {
-    Complete(Option<Plan>),
-    Running(PlanRunningStateData),
-}
+    #[test]
+    fn compact_shrinks_planned_room() {
+        let mut room = TestRoom::open();
+        let mut plan = plan_room(&test_planner(PlanningConfig::default()), &mut room)
+            .unwrap_or_else(|err| panic!("{}", err));
 
-pub enum PlanEvaluationResult {
-    Complete(Option<Plan>),
-    Running(),
-}
+        let build_order = ordered_steps(&plan);
+        let haul_routes = |plan: &Plan| -> Vec<_> {
+            plan.haul_routes(&room.terrain)
+                .iter()
+                .map(|route| (route.from, route.to, route.distance))
+                .collect()
+        };
+        let routes = haul_routes(&plan);
+        let filler_position = plan.filler_position();
+        let size = plan.estimated_serialized_size();
 
-pub trait PlannerRoomDataSource {
-    fn get_terrain(&mut self) -> &FastRoomTerrain;
-    fn get_controllers(&mut self) -> &[PlanLocation];
-    fn get_sources(&mut self) -> &[PlanLocation];
-    fn get_minerals(&mut self) -> &[PlanLocation];
-}
+        assert!(!plan.routed_roads.is_empty());
+        assert!(filler_position.is_some());
 
-pub struct Planner<S>
-where
-    S: Fn(&PlannerState, &mut NodeContext) -> Option<f32>,
-{
-    scorer: S,
-}
+        plan.compact();
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<S> Planner<S>
-where
-    S: Fn(&PlannerState, &mut NodeContext) -> Option<f32>,
-{
-    pub fn new(scorer: S) -> Planner<S> {
-        Planner { scorer }
+        assert!(plan.estimated_serialized_size() < size);
+        assert_eq!(ordered_steps(&plan), build_order);
+        assert_eq!(haul_routes(&plan), routes);
+        assert_eq!(plan.filler_position(), filler_position);
     }
 
-    pub fn seed(
-        &self,
-        root_nodes: &[&dyn PlanGlobalExpansionNode],
-        data_source: &mut dyn PlannerRoomDataSource,
-    ) -> Result<PlanSeedResult, String> {
-        let mut planner_state = PlannerState::new();
+    #[test]
+    fn haul_routes_reach_every_source_container() {
+        let mut room = TestRoom::open();
+        let plan = plan_room(&test_planner(PlanningConfig::default()), &mut room)
+            .unwrap_or_else(|err| panic!("{}", err));
 
-        let mut best_plan = None;
+        let storage = plan
+            .build_order()
+            .iter()
+            .find(|step| step.structure_type == StructureType::Storage)
+            .map(|step| step.location)
+            .unwrap();
 
-        let mut state_handler = |new_state: &PlannerState, context: &mut NodeContext| {
-            if let Some(score) = (self.scorer)(new_state, context) {
-                best_plan = Some(BestPlanData {
-                    score,
-                    state: new_state.snapshot(),
-                });
-            }
-        };
+        let routes = plan.haul_routes(&room.terrain);
 
-        let mut planner = TreePlanner::new(data_source, &mut state_handler);
+        for source in &room.sources {
+            let route = routes
+                .iter()
+                .find(|route| source.distance_to(route.from.into()) <= 1)
+                .unwrap_or_else(|| panic!("No route from source at {:?}", source));
 
-        let seed_result = match planner.seed(root_nodes, &mut planner_state)? {
-            TreePlannerResult::Complete => {
-                let plan = best_plan.take().map(|p| Plan { state: p.state });
+            assert_eq!(route.to, storage);
+            assert!(route.distance > 0 && route.distance < 200);
 
-                PlanSeedResult::Complete(plan)
-            }
-            TreePlannerResult::Running(stack) => {
-                let running_data = PlanRunningStateData {
-                    planner_state,
-                    stack,
-                    best_plan,
-                };
+            // The round trip is there and back again, and walking it the other way takes as long.
+            let reverse = plan
+                .find_path_to(storage, &[route.from], 1, &room.terrain)
+                .map(|path| 2 * (path.len() as u32 - 1));
 
-                PlanSeedResult::Running(running_data)
-            }
-        };
+            assert_eq!(reverse, Some(route.distance));
+        }
 
-        Ok(seed_result)
+        assert!(routes
+            .iter()
+            .any(|route| route.from == storage && Some(route.to) == plan.controller_container()));
     }
 
-    pub fn evaluate<F>(
-        &self,
-        root_nodes: &[&dyn PlanGlobalExpansionNode],
-        data_source: &mut dyn PlannerRoomDataSource,
-        evaluation_state: &mut PlanRunningStateData,
-        should_continue: F,
-    ) -> Result<PlanEvaluationResult, String>
-    where
-        F: Fn() -> bool,
-    {
-        let mut current_best = evaluation_state.best_plan.as_ref().map(|p| p.score);
-        let mut new_best_plan = None;
-
-        let mut state_handler = |new_state: &PlannerState, context: &mut NodeContext| {
-            if let Some(score) = (self.scorer)(new_state, context) {
-                if current_best.map(|s| score > s).unwrap_or(true) {
-                    new_best_plan = Some(BestPlanData {
-                        score,
-                        state: new_state.snapshot(),
-                    });
+    #[test]
+    fn best_plan_snapshot_during_search() {
+        let planner = test_planner(PlanningConfig::default());
+        let mut room = TestRoom::open();
 
-                    current_best = Some(score);
-                }
-            }
+        let mut running = match planner.seed(layout::ALL_ROOT_NODES, &mut room).unwrap() {
+            PlanSeedResult::Running(running) => running,
+            PlanSeedResult::Complete(_) => panic!("Expected the search to take several steps"),
         };
 
-        let mut planner = TreePlanner::new(data_source, &mut state_handler);
+        assert!(running.best_plan_snapshot().is_none());
 
-        let evaluate_result = match planner.process(
-            root_nodes,
-            &mut evaluation_state.planner_state,
-            &evaluation_state.stack,
-            should_continue,
-        )? {
-            TreePlannerResult::Complete => {
-                if new_best_plan.is_some() {
-                    evaluation_state.best_plan = new_best_plan;
-                }
+        let mut snapshot = None;
 
-                let plan = evaluation_state
-                    .best_plan
-                    .take()
-                    .map(|p| Plan { state: p.state });
+        for _ in 0..1000 {
+            // A handful of nodes per step, as if spread across ticks.
+            let budget = std::cell::Cell::new(20);
+            let should_continue = || {
+                budget.set(budget.get() - 1);
+                budget.get() > 0
+            };
 
-                PlanEvaluationResult::Complete(plan)
-            }
-            TreePlannerResult::Running(stack) => {
-                if new_best_plan.is_some() {
-                    evaluation_state.best_plan = new_best_plan;
+            match planner
+                .evaluate(
+                    layout::ALL_ROOT_NODES,
+                    &mut room,
+                    &mut running,
+                    should_continue,
+                )
+                .unwrap()
+            {
+                PlanEvaluationResult::Running() => {
+                    snapshot = running.best_plan_snapshot();
+
+                    if snapshot.is_some() {
+                        break;
+                    }
                 }
+                PlanEvaluationResult::Complete(_) => break,
+            }
+        }
 
-                evaluation_state.stack = stack;
+        let (score, plan) = snapshot.expect("Expected a best plan before the search completed");
 
-                PlanEvaluationResult::Running()
-            }
-        };
+        assert!(score > 0.0);
 
-        Ok(evaluate_result)
+        let mut visualizer = RecordingVisualizer::default();
+        plan.visualize(&mut visualizer);
+
+        assert!(visualizer
+            .rendered
+            .iter()
+            .any(|(_, structure_type)| *structure_type == StructureType::Spawn));
     }
 }
@@ -1,3 +1,4 @@
+use super::layout::DEFENSE_RCL;
 use super::planner::*;
 use super::utility::*;
 use super::*;
@@ -10,12 +11,24 @@ struct StateScore {
     weight: f32,
 }
 
-fn has_ramparts(state: &PlannerState, _context: &mut NodeContext) -> bool {
-    state.get_count(StructureType::Rampart) >= 1
+fn has_ramparts(state: &PlannerState, context: &mut NodeContext) -> bool {
+    let rcl_limits = context.rcl_limits();
+    let defense_rcl = rcl_limits.defense_rcl().unwrap_or(DEFENSE_RCL);
+
+    defense_rcl > rcl_limits.max_rcl() || state.get_count(StructureType::Rampart) >= 1
 }
 
 fn has_source_containers(state: &PlannerState, context: &mut NodeContext) -> bool {
-    let mut source_locations = context.sources().to_vec();
+    let mut source_locations: Vec<_> = context
+        .sources()
+        .to_vec()
+        .into_iter()
+        .filter(|source_location| {
+            context
+                .source_infra_policy(*source_location)
+                .places_container()
+        })
+        .collect();
     let mut container_locations = state.get_locations(StructureType::Container);
 
     let mut matched_sources = Vec::new();
@@ -36,13 +49,99 @@ fn has_source_containers(state: &PlannerState, context: &mut NodeContext) -> boo
     source_locations.is_empty()
 }
 
-fn has_source_links(state: &PlannerState, context: &mut NodeContext) -> bool {
+// Every source container needs a road next to it, and every source with only a link needs a road
+// next to both for the harvester to stand on.
+fn has_source_roads(state: &PlannerState, context: &mut NodeContext) -> bool {
     let source_locations = context.sources().to_vec();
+    let road_locations = state.get_locations(StructureType::Road);
+
+    let has_container_roads = state
+        .get_locations(StructureType::Container)
+        .iter()
+        .filter(|container_location| {
+            source_locations.iter().any(|source_location| {
+                source_location.distance_to((*container_location).into()) <= 1
+            })
+        })
+        .all(|container_location| {
+            road_locations
+                .iter()
+                .any(|road_location| road_location.distance_to(*container_location) <= 1)
+        });
+
+    if !has_container_roads {
+        return false;
+    }
+
+    let link_locations = state.get_locations(StructureType::Link);
+
+    source_locations
+        .iter()
+        .filter(|&source_location| {
+            let policy = context.source_infra_policy(*source_location);
+
+            policy.places_link() && !policy.places_container()
+        })
+        .all(|source_location| {
+            link_locations
+                .iter()
+                .filter(|link_location| source_location.distance_to((*link_location).into()) <= 1)
+                .all(|link_location| {
+                    road_locations.iter().any(|road_location| {
+                        road_location.distance_to(*link_location) <= 1
+                            && source_location.distance_to(road_location.into()) <= 1
+                    })
+                })
+        })
+}
+
+// When planning below RCL 8 there may not be enough links for every source and controller.
+fn has_exhausted_links(state: &PlannerState, context: &mut NodeContext) -> bool {
+    let rcl_limits = context.rcl_limits();
+
+    rcl_limits.max_rcl() < 8
+        && state.get_count(StructureType::Link) >= rcl_limits.get_max_count(StructureType::Link)
+}
+
+fn has_source_links(state: &PlannerState, context: &mut NodeContext) -> bool {
+    if has_exhausted_links(state, context) {
+        return true;
+    }
+
+    let all_source_locations = context.sources().to_vec();
     let link_locations = state.get_locations(StructureType::Link);
     let container_locations = state.get_locations(StructureType::Container);
 
+    let has_direct_links = all_source_locations
+        .iter()
+        .filter(|&source_location| {
+            let policy = context.source_infra_policy(*source_location);
+
+            policy.places_link() && !policy.places_container()
+        })
+        .all(|source_location| {
+            link_locations
+                .iter()
+                .any(|link_location| source_location.distance_to(link_location.into()) <= 1)
+        });
+
+    if !has_direct_links {
+        return false;
+    }
+
+    let source_locations: Vec<_> = all_source_locations
+        .into_iter()
+        .filter(|source_location| {
+            let policy = context.source_infra_policy(*source_location);
+
+            policy.places_link() && policy.places_container()
+        })
+        .collect();
+
     //TODO: This currently validates that there is a link for sources at least 8 distance from storage - that is not currently
     //      possible with the layout due to 'must place flag'.
+    let required_distance = 8.max(context.source_link_min_distance() + 1);
+
     let matching_containers = state.with_structure_distances(
         StructureType::Storage,
         context.terrain(),
@@ -61,7 +160,7 @@ fn has_source_links(state: &PlannerState, context: &mut NodeContext) -> bool {
                                 container_location.x() as usize,
                                 container_location.y() as usize,
                             )
-                            .map(|d| d >= 8)
+                            .map(|d| d >= required_distance)
                             .unwrap_or(false)
                     })
                     .collect()
@@ -79,6 +178,10 @@ fn has_source_links(state: &PlannerState, context: &mut NodeContext) -> bool {
 }
 
 fn has_mineral_extractors(state: &PlannerState, context: &mut NodeContext) -> bool {
+    if context.rcl_limits().get_max_count(StructureType::Extractor) == 0 {
+        return true;
+    }
+
     let mineral_locations = context.minerals();
     let extractor_locations = state.get_locations(StructureType::Extractor);
 
@@ -91,7 +194,11 @@ fn has_mineral_extractors(state: &PlannerState, context: &mut NodeContext) -> bo
     })
 }
 
-fn has_mineral_containers(state: &PlannerState, _context: &mut NodeContext) -> bool {
+fn has_mineral_containers(state: &PlannerState, context: &mut NodeContext) -> bool {
+    if context.rcl_limits().get_max_count(StructureType::Extractor) == 0 {
+        return true;
+    }
+
     let mut extractor_locations = state.get_locations(StructureType::Extractor);
     let mut container_locations = state.get_locations(StructureType::Container);
 
@@ -137,6 +244,10 @@ fn has_controller_containers(state: &PlannerState, context: &mut NodeContext) ->
 }
 
 fn has_controller_links(state: &PlannerState, context: &mut NodeContext) -> bool {
+    if has_exhausted_links(state, context) {
+        return true;
+    }
+
     let controller_locations = context.controllers().to_vec();
     let link_locations = state.get_locations(StructureType::Link);
     let container_locations = state.get_locations(StructureType::Container);
@@ -178,6 +289,39 @@ fn has_controller_links(state: &PlannerState, context: &mut NodeContext) -> bool
     })
 }
 
+pub fn has_open_space_around(
+    state: &PlannerState,
+    context: &mut NodeContext,
+    structure_type: StructureType,
+    minimum_open: usize,
+) -> bool {
+    let terrain = context.terrain();
+
+    state.get_locations(structure_type).iter().all(|location| {
+        let open_tiles = ONE_OFFSET_SQUARE
+            .iter()
+            .filter_map(|offset| (PlanLocation::from(location) + offset).as_location())
+            .filter(|adjacent| !terrain.get(adjacent).contains(TerrainFlags::WALL))
+            .filter(|adjacent| {
+                state
+                    .get(adjacent)
+                    .iter()
+                    .flat_map(|v| v.iter())
+                    .all(|item| is_walkable_structure(item.structure_type()))
+            })
+            .count();
+
+        open_tiles >= minimum_open
+    })
+}
+
+fn has_open_hub_space(state: &PlannerState, context: &mut NodeContext) -> bool {
+    let minimum_open = context.layout_options().min_open_hub_tiles;
+
+    has_open_space_around(state, context, StructureType::Spawn, minimum_open)
+        && has_open_space_around(state, context, StructureType::Storage, minimum_open)
+}
+
 fn has_reachable_structures(state: &PlannerState, context: &mut NodeContext) -> bool {
     let placements: Vec<_> = state.get_all();
 
@@ -402,9 +546,17 @@ fn source_distance_balance_score(
     scores
 }
 
-fn extension_distance_score(state: &PlannerState, context: &mut NodeContext) -> Vec<StateScore> {
+/// Filler efficiency of the extension layout, defined as `1 - mean / max` where `mean` is the
+/// average path distance from storage to the nearest tile adjacent to each extension and `max` is
+/// the furthest path distance from storage in the room. Returns a value in `0.0..=1.0` where
+/// higher is better, or `None` if there is no storage or no extensions.
+pub fn extension_efficiency(state: &PlannerState, context: &mut NodeContext) -> Option<f32> {
     let extension_locations = state.get_locations(StructureType::Extension);
 
+    if extension_locations.is_empty() {
+        return None;
+    }
+
     let total_distance = state.with_structure_distances(
         StructureType::Storage,
         context.terrain(),
@@ -441,15 +593,156 @@ fn extension_distance_score(state: &PlannerState, context: &mut NodeContext) ->
         },
     );
 
-    let (total_extension_distance, max_distance) =
-        total_distance.unwrap_or((f32::INFINITY, f32::INFINITY));
+    let (total_extension_distance, max_distance) = total_distance?;
 
     let average_distance = total_extension_distance / (extension_locations.len() as f32);
-    let average_distance_score = 1.0 - average_distance / max_distance;
+
+    Some(1.0 - average_distance / max_distance)
+}
+
+fn extension_distance_score(state: &PlannerState, context: &mut NodeContext) -> Vec<StateScore> {
+    extension_efficiency(state, context)
+        .map(|score| StateScore { score, weight: 1.0 })
+        .into_iter()
+        .collect()
+}
+
+// Upkeep is measured in hits decayed per tick for an owned room. Roads on swamp decay five times
+// as fast as on plain, and roads tunnelled through natural walls ten times.
+const ROAD_UPKEEP: f32 = 100.0 / 1000.0;
+const SWAMP_ROAD_UPKEEP_MULTIPLIER: f32 = 5.0;
+const TUNNEL_ROAD_UPKEEP_MULTIPLIER: f32 = 10.0;
+const CONTAINER_UPKEEP: f32 = 5000.0 / 500.0;
+const MAX_UPKEEP: f32 = 200.0;
+
+fn upkeep_score(state: &PlannerState, context: &mut NodeContext) -> Vec<StateScore> {
+    let terrain = context.terrain();
+
+    let total_upkeep: f32 = state
+        .get_all()
+        .iter()
+        .map(|(location, item)| match item.structure_type() {
+            StructureType::Road => {
+                let terrain_mask = terrain.get(location);
+
+                if terrain_mask.contains(TerrainFlags::WALL) {
+                    ROAD_UPKEEP * TUNNEL_ROAD_UPKEEP_MULTIPLIER
+                } else if terrain_mask.contains(TerrainFlags::SWAMP) {
+                    ROAD_UPKEEP * SWAMP_ROAD_UPKEEP_MULTIPLIER
+                } else {
+                    ROAD_UPKEEP
+                }
+            }
+            StructureType::Container => CONTAINER_UPKEEP,
+            _ => 0.0,
+        })
+        .sum();
+
+    let upkeep_score = 1.0 - (total_upkeep / MAX_UPKEEP).min(1.0);
 
     vec![StateScore {
-        score: average_distance_score,
-        weight: 1.0,
+        score: upkeep_score,
+        weight: 0.25,
+    }]
+}
+
+// Structures this close to an exit are easy to snipe from outside the room.
+const EXIT_PROXIMITY_RANGE: u8 = 3;
+
+fn exit_proximity_score(state: &PlannerState, context: &mut NodeContext) -> Vec<StateScore> {
+    let exits = context.exits().to_vec();
+
+    let structures: Vec<_> = state
+        .get_all()
+        .into_iter()
+        .filter(|(_, item)| {
+            !matches!(
+                item.structure_type(),
+                StructureType::Road | StructureType::Rampart | StructureType::Wall
+            )
+        })
+        .map(|(location, _)| location)
+        .collect();
+
+    if structures.is_empty() {
+        return Vec::new();
+    }
+
+    let exposed = structures
+        .iter()
+        .filter(|location| {
+            exits
+                .iter()
+                .any(|exit| exit.distance_to(**location) <= EXIT_PROXIMITY_RANGE)
+        })
+        .count();
+
+    vec![StateScore {
+        score: 1.0 - (exposed as f32 / structures.len() as f32),
+        weight: 0.25,
+    }]
+}
+
+// Share of the tiles around the best filler tile - a road next to a spawn and a link that feeds
+// it - taken up by spawns, extensions, links, storage and terminal a stationary filler creep can
+// refill. Only scored when the fast filler hub is enabled, to weigh it against the bunker.
+fn filler_score(state: &PlannerState, context: &mut NodeContext) -> Vec<StateScore> {
+    if !context.layout_options().fast_filler_core {
+        return Vec::new();
+    }
+
+    let items = state.get_all();
+
+    let locations_of = |structure_types: &[StructureType]| -> fnv::FnvHashSet<Location> {
+        items
+            .iter()
+            .filter(|(_, item)| structure_types.contains(&item.structure_type()))
+            .map(|(location, _)| *location)
+            .collect()
+    };
+
+    let spawns = locations_of(&[StructureType::Spawn]);
+    let links = locations_of(&[StructureType::Link]);
+    let fillable = locations_of(&[
+        StructureType::Spawn,
+        StructureType::Extension,
+        StructureType::Link,
+        StructureType::Storage,
+        StructureType::Terminal,
+    ]);
+
+    let best = state
+        .get_locations(StructureType::Road)
+        .iter()
+        .filter_map(|road| {
+            let neighbours: Vec<_> = ONE_OFFSET_SQUARE
+                .iter()
+                .filter_map(|offset| (PlanLocation::from(road) + offset).as_location())
+                .collect();
+
+            let next_to_any = |locations: &fnv::FnvHashSet<Location>| {
+                neighbours
+                    .iter()
+                    .any(|neighbour| locations.contains(neighbour))
+            };
+
+            if next_to_any(&spawns) && next_to_any(&links) {
+                Some(
+                    neighbours
+                        .iter()
+                        .filter(|neighbour| fillable.contains(neighbour))
+                        .count(),
+                )
+            } else {
+                None
+            }
+        })
+        .max()
+        .unwrap_or(0);
+
+    vec![StateScore {
+        score: best as f32 / ONE_OFFSET_SQUARE.len() as f32,
+        weight: 0.5,
     }]
 }
 
@@ -470,7 +763,9 @@ pub fn score_state(state: &PlannerState, context: &mut NodeContext) -> Option<f3
         has_ramparts,
         has_mandatory_buildings,
         has_mineral_extractors,
+        has_open_hub_space,
         has_source_containers,
+        has_source_roads,
         has_controller_containers,
         has_mineral_containers,
         has_controller_links,
@@ -488,14 +783,19 @@ pub fn score_state(state: &PlannerState, context: &mut NodeContext) -> Option<f3
         Scoring needed:
         - Mineral to storage length.
         - Controller to storage length.
-        - Upkeep cost (ramparts, tunnels, containers)
+        - Upkeep cost of ramparts.
     */
 
+    // Source and controller scorers return no scores for rooms without them (such as sourceless
+    // sim rooms), leaving the plan to be judged by the remaining scorers.
     let scorers = [
         source_distance_score,
         source_distance_balance_score,
         controller_distance_score,
         extension_distance_score,
+        upkeep_score,
+        exit_proximity_score,
+        filler_score,
     ];
 
     let weights: Vec<_> = scorers
@@ -509,8 +809,269 @@ pub fn score_state(state: &PlannerState, context: &mut NodeContext) -> Option<f3
     if total_weight > 0.0 {
         let score = total_score / total_weight;
 
-        Some(score)
+        // Plans allowed to fall short of the extension limit are penalized by how full they are.
+        let extension_target = context.rcl_limits().get_max_count(StructureType::Extension);
+        let extension_fill = if extension_target > 0 {
+            (state.get_count(StructureType::Extension) as f32 / extension_target as f32).min(1.0)
+        } else {
+            1.0
+        };
+
+        Some(score * extension_fill)
     } else {
         None
     }
 }
+
+/// Wraps `score_state`, blending in how many of the reference plan's structures the state keeps in
+/// place. Re-planning an already built room with this scorer favors layouts that need the fewest
+/// structures demolished and rebuilt. `weight` is the share of the final score given to
+/// similarity, from 0 to 1.
+pub fn score_state_with_reference<'a>(
+    reference: &'a Plan,
+    weight: f32,
+) -> impl Fn(&PlannerState, &mut NodeContext) -> Option<f32> + 'a {
+    let weight = weight.clamp(0.0, 1.0);
+
+    let reference_count = reference
+        .build_order()
+        .into_iter()
+        .filter(|step| step.structure_type != StructureType::Road)
+        .count();
+
+    move |state: &PlannerState, context: &mut NodeContext| {
+        let score = score_state(state, context)?;
+
+        if reference_count == 0 {
+            return Some(score);
+        }
+
+        let similarity = 1.0 - (reference.diff_count(state) as f32 / reference_count as f32);
+
+        Some(score * (1.0 - weight) + similarity * weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::*;
+    use crate::planner::tests::*;
+
+    fn spawn_with_neighbours(neighbours: &[(i8, i8)]) -> PlannerState {
+        let mut state = PlannerState::new();
+
+        state.insert(
+            Location::from_coords(25, 25),
+            room_item(StructureType::Spawn, 1),
+        );
+
+        for (x, y) in neighbours {
+            state.insert(
+                Location::from_coords((25 + x) as u32, (25 + y) as u32),
+                room_item(StructureType::Extension, 2),
+            );
+        }
+
+        state
+    }
+
+    fn open_hub_space(state: &PlannerState, min_open_hub_tiles: usize) -> bool {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra)
+            .with_layout_options(LayoutOptions {
+                min_open_hub_tiles,
+                ..LayoutOptions::default()
+            });
+
+        has_open_hub_space(state, &mut context)
+    }
+
+    #[test]
+    fn boxed_in_spawn_is_rejected() {
+        let state = spawn_with_neighbours(ONE_OFFSET_SQUARE);
+
+        assert!(!open_hub_space(&state, 2));
+        assert!(open_hub_space(&state, 0));
+    }
+
+    #[test]
+    fn spawn_with_open_tiles_is_accepted() {
+        let state = spawn_with_neighbours(&ONE_OFFSET_SQUARE[2..]);
+
+        assert!(open_hub_space(&state, 2));
+        assert!(!open_hub_space(&state, 3));
+    }
+
+    fn upkeep(state: &PlannerState) -> f32 {
+        upkeep_in(&mut TestRoom::open(), state)
+    }
+
+    fn upkeep_in(room: &mut TestRoom, state: &PlannerState) -> f32 {
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(room, &rcl_limits, &source_infra);
+
+        upkeep_score(state, &mut context)[0].score
+    }
+
+    #[test]
+    fn roads_and_containers_lower_upkeep_score() {
+        let state = spawn_with_neighbours(&[]);
+        let mut roads = spawn_with_neighbours(&[]);
+        let mut containers = spawn_with_neighbours(&[]);
+
+        for x in 10..20 {
+            roads.insert(
+                Location::from_coords(x, 10),
+                room_item(StructureType::Road, 1),
+            );
+        }
+
+        containers.insert(
+            Location::from_coords(10, 10),
+            room_item(StructureType::Container, 1),
+        );
+
+        assert_eq!(upkeep(&state), 1.0);
+        assert!(upkeep(&roads) < upkeep(&state));
+        assert!(upkeep(&containers) < upkeep(&state));
+    }
+
+    #[test]
+    fn swamp_and_tunnel_roads_cost_more_upkeep() {
+        let mut roads = spawn_with_neighbours(&[]);
+
+        for x in 10..20 {
+            roads.insert(
+                Location::from_coords(x, 10),
+                room_item(StructureType::Road, 1),
+            );
+        }
+
+        let on_road_row = |x: u8, y: u8| y == 10 && (10..20).contains(&x);
+
+        let mut tunnel_room = TestRoom::with_walls(on_road_row);
+
+        let mut swamp_buffer = vec![0; (ROOM_WIDTH as usize) * (ROOM_HEIGHT as usize)];
+
+        for x in 10..20 {
+            swamp_buffer[10 * (ROOM_WIDTH as usize) + x] = TerrainFlags::SWAMP.bits();
+        }
+
+        let mut swamp_room = TestRoom {
+            terrain: FastRoomTerrain::new(swamp_buffer),
+            ..TestRoom::open()
+        };
+
+        let plain = upkeep(&roads);
+        let swamp = upkeep_in(&mut swamp_room, &roads);
+        let tunnel = upkeep_in(&mut tunnel_room, &roads);
+
+        assert!(swamp < plain);
+        assert!(tunnel < swamp);
+    }
+
+    fn efficiency(extensions: &[(u32, u32)]) -> Option<f32> {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+
+        let mut state = PlannerState::new();
+
+        state.insert(
+            Location::from_coords(25, 25),
+            room_item(StructureType::Storage, 4),
+        );
+
+        for (x, y) in extensions {
+            state.insert(
+                Location::from_coords(*x, *y),
+                room_item(StructureType::Extension, 2),
+            );
+        }
+
+        extension_efficiency(&state, &mut context)
+    }
+
+    #[test]
+    fn clustered_extensions_are_more_efficient() {
+        let clustered = efficiency(&[(27, 24), (27, 25), (27, 26)]).unwrap();
+        let scattered = efficiency(&[(5, 5), (45, 45), (5, 45)]).unwrap();
+
+        assert!(clustered > scattered);
+        assert!(clustered <= 1.0 && scattered >= 0.0);
+        assert_eq!(efficiency(&[]), None);
+    }
+
+    fn exit_proximity(extensions: &[(u32, u32)]) -> f32 {
+        let mut room = TestRoom::open();
+        let rcl_limits = RclLimits::official();
+        let source_infra = SourceInfraPolicies::default();
+        let mut context = NodeContext::new(&mut room, &rcl_limits, &source_infra);
+
+        let mut state = spawn_with_neighbours(&[]);
+
+        for (x, y) in extensions {
+            state.insert(
+                Location::from_coords(*x, *y),
+                room_item(StructureType::Extension, 2),
+            );
+        }
+
+        exit_proximity_score(&state, &mut context)[0].score
+    }
+
+    #[test]
+    fn extensions_near_exits_score_worse() {
+        // The open test room has exits along the top edge.
+        let border = exit_proximity(&[(22, 2), (24, 3), (26, 3)]);
+        let deep = exit_proximity(&[(22, 30), (24, 30), (26, 30)]);
+
+        assert_eq!(deep, 1.0);
+        assert!(border < deep);
+    }
+
+    #[test]
+    fn reference_scorer_reproduces_the_reference_plan() {
+        let config = |max_root_candidates| PlanningConfig {
+            max_root_candidates: Some(max_root_candidates),
+            ..PlanningConfig::default()
+        };
+
+        let differences = |reference: &Plan, plan: &Plan| {
+            reference
+                .build_order()
+                .iter()
+                .filter(|step| step.structure_type != StructureType::Road)
+                .filter(|step| !plan.has_structure(step.location, step.structure_type))
+                .count()
+        };
+
+        // Searching fewer anchors settles on a different layout than the default search below.
+        let reference = plan_room(
+            &Planner::with_config(score_state, config(2)),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        let unbiased = plan_room(
+            &Planner::with_config(score_state, config(3)),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        let biased = plan_room(
+            &Planner::with_config(score_state_with_reference(&reference, 0.5), config(3)),
+            &mut TestRoom::open(),
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        assert!(differences(&reference, &unbiased) > 0);
+        assert_eq!(differences(&reference, &biased), 0);
+    }
+}
@@ -4,6 +4,20 @@ pub const TERRAIN_MASK_WALL: u8 = 1;
 pub const TERRAIN_MASK_SWAMP: u8 = 2;
 pub const TERRAIN_MASK_LAVA: u8 = 4;
 
+pub const RAMPART_DECAY_AMOUNT: u32 = 300;
+pub const RAMPART_DECAY_TIME: u32 = 100;
+pub const REPAIR_COST: f32 = 0.01;
+
+pub const SPAWN_ENERGY_CAPACITY: u32 = 300;
+
+pub fn extension_energy_capacity(rcl: u32) -> u32 {
+    match rcl {
+        r if r < 7 => 50,
+        7 => 100,
+        _ => 200,
+    }
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub enum StructureType {
     Spawn = 0,
@@ -29,6 +43,34 @@ pub enum StructureType {
     InvaderCore = 20,
 }
 
+impl StructureType {
+    pub fn construction_cost(self) -> Option<u32> {
+        use StructureType::*;
+
+        let cost = match self {
+            Spawn => 15_000,
+            Extension => 3_000,
+            Road => 300,
+            Wall => 1,
+            Rampart => 1,
+            Link => 5_000,
+            Storage => 30_000,
+            Tower => 5_000,
+            Observer => 8_000,
+            PowerSpawn => 100_000,
+            Extractor => 5_000,
+            Lab => 50_000,
+            Terminal => 100_000,
+            Container => 5_000,
+            Nuker => 100_000,
+            Factory => 100_000,
+            KeeperLair | Controller | PowerBank | Portal | InvaderCore => return None,
+        };
+
+        Some(cost)
+    }
+}
+
 #[derive(Clone, Default, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CircleStyle {
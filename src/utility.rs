@@ -2,15 +2,22 @@ use super::planner::*;
 use super::*;
 
 pub fn has_mandatory_buildings(state: &PlannerState, context: &mut NodeContext) -> bool {
-    state.get_count(StructureType::Spawn) >= 3
-        && state.get_count(StructureType::Extension) >= 60
-        && state.get_count(StructureType::Storage) >= 1
-        && state.get_count(StructureType::Terminal) >= 1
-        && state.get_count(StructureType::Lab) >= 10
-        && state.get_count(StructureType::Factory) >= 1
-        && state.get_count(StructureType::Observer) >= 1
-        && state.get_count(StructureType::PowerSpawn) >= 1
-        && state.get_count(StructureType::Nuker) >= 1
-        && state.get_count(StructureType::Tower) >= 6
-        && (state.get_count(StructureType::Extractor) as usize) == context.minerals().len()
+    let rcl_limits = context.rcl_limits();
+
+    let has_max_count = |structure_type: StructureType| {
+        state.get_count(structure_type) >= rcl_limits.get_max_count(structure_type)
+    };
+
+    has_max_count(StructureType::Spawn)
+        && state.get_count(StructureType::Extension) >= context.min_extension_count()
+        && has_max_count(StructureType::Storage)
+        && has_max_count(StructureType::Terminal)
+        && has_max_count(StructureType::Lab)
+        && has_max_count(StructureType::Factory)
+        && has_max_count(StructureType::Observer)
+        && has_max_count(StructureType::PowerSpawn)
+        && has_max_count(StructureType::Nuker)
+        && has_max_count(StructureType::Tower)
+        && (rcl_limits.get_max_count(StructureType::Extractor) == 0
+            || (state.get_count(StructureType::Extractor) as usize) == context.minerals().len())
 }
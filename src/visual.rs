@@ -8,6 +8,9 @@ use super::location::*;
 
 pub trait RoomVisualizer {
     fn render(&mut self, location: Location, structure: StructureType);
+
+    /// Render a tile of a heatmap, where `value` is in `0.0..=1.0`. Ignored unless implemented.
+    fn render_heat(&mut self, _location: Location, _value: f32) {}
 }
 
 #[cfg(not(feature = "shim"))]
@@ -122,4 +125,22 @@ impl RoomVisualizer for RoomVisual {
             }
         }
     }
+
+    fn render_heat(&mut self, location: Location, value: f32) {
+        let value = value.max(0.0).min(1.0);
+        let color = format!(
+            "#{:02x}00{:02x}",
+            (value * 255.0) as u8,
+            ((1.0 - value) * 255.0) as u8
+        );
+
+        RoomVisual::rect(
+            self,
+            location.x() as f32,
+            location.y() as f32,
+            1.0,
+            1.0,
+            Some(RectStyle::default().fill(&color).opacity(0.4)),
+        );
+    }
 }